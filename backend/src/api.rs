@@ -1,6 +1,7 @@
 use crate::{auth, db, error::Unauthorized, Error};
 use db::admin::{AdminDB, Project, User};
 use db::user::table::RowJson;
+use futures::StreamExt;
 use std::convert::Infallible;
 use std::sync::Arc;
 use tokio::sync::Mutex;
@@ -34,7 +35,9 @@ fn base_routes(
     db: DBRef,
     prefix: &str,
 ) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
-    let routes = health(db.clone())
+    // Groups are boxed so that the combined future stays small enough for
+    // the stack in debug builds
+    let user_routes = health(db.clone())
         .or(generate_session_token(db.clone()))
         .or(refresh_token(db.clone()))
         .or(remove_token(db.clone()))
@@ -42,19 +45,52 @@ fn base_routes(
         .or(get_users(db.clone()))
         .or(create_user(db.clone()))
         .or(remove_user(db.clone()))
-        .or(create_project(db.clone()))
+        .boxed();
+    let project_routes = create_project(db.clone())
         .or(get_user_project(db.clone()))
         .or(get_user_projects(db.clone()))
         .or(delete_project(db.clone()))
-        .or(create_table(db.clone()))
+        .boxed();
+    let table_routes = create_table(db.clone())
         .or(remove_table(db.clone()))
+        .or(alter_table(db.clone()))
+        .or(rename_table(db.clone()))
+        .or(get_table_dependents(db.clone()))
+        .or(get_table_indexes(db.clone()))
+        .or(create_index(db.clone()))
+        .or(remove_index(db.clone()))
         .or(get_table_names(db.clone()))
         .or(get_all_meta(db.clone()))
+        .or(plan_schema(db.clone()))
+        .or(apply_schema(db.clone()))
         .or(get_table_meta(db.clone()))
-        .or(get_table_data(db.clone()))
+        .boxed();
+    let file_routes = export_table_csv(db.clone())
+        .or(export_project_csv(db.clone()))
+        .or(export_table_arrow(db.clone()))
+        .or(export_project_arrow(db.clone()))
+        .or(export_table_stats(db.clone()))
+        .or(export_project_stats(db.clone()))
+        .or(export_project_xlsx(db.clone()))
+        .or(import_xlsx(db.clone()))
+        .or(export_project_odm(db.clone()))
+        .or(import_odm(db.clone()))
+        .or(import_csv(db.clone()))
+        .boxed();
+    let data_routes = get_table_data(db.clone())
+        .or(stream_table_data(db.clone()))
         .or(insert_data(db.clone()))
+        .or(bulk_insert_data(db.clone()))
+        .or(update_data(db.clone()))
+        .or(remove_rows(db.clone()))
         .or(remove_all_user_table_data(db))
         .boxed();
+    let routes = user_routes
+        .or(project_routes)
+        .or(table_routes)
+        .or(file_routes)
+        .or(data_routes)
+        .boxed();
     if prefix.is_empty() {
         return routes;
     }
@@ -67,7 +103,7 @@ fn base_routes(
 fn get_cors() -> warp::cors::Builder {
     warp::cors()
         .allow_any_origin()
-        .allow_methods(vec!["GET", "POST", "PUT", "DELETE"])
+        .allow_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE"])
         .allow_headers(vec!["Content-Type", "Authorization"])
 }
 
@@ -87,12 +123,37 @@ async fn handle_rejection(
             }
             Error::ProjectAlreadyExists(_, _)
             | Error::TableAlreadyExists(_)
+            | Error::TableHasDependents(_, _)
+            | Error::IndexAlreadyExists(_)
+            | Error::ColumnAlreadyExists(_)
+            | Error::PrimaryKeyChange(_)
+            | Error::GeneratedColumnChange(_, _)
+            | Error::DestructivePlan(_)
+            | Error::UnsupportedConstraint(_, _, _)
+            | Error::UnsupportedPostgresType(_)
+            | Error::NoPrimaryKey(_)
+            | Error::SelectionMatchesAll(_)
             | Error::NoSuchColumns(_) => {
                 status = StatusCode::CONFLICT;
                 message = format!("{:?}", e)
             }
+            Error::InvalidRows(_)
+            | Error::InvalidDefinition(_)
+            | Error::InvalidFilter(_)
+            | Error::InvalidCursor(_)
+            | Error::InvalidCsv(_)
+            | Error::InvalidXlsx(_)
+            | Error::InvalidSheetRows(_)
+            | Error::InvalidOdm(_)
+            | Error::InvalidItemGroupRows(_) => {
+                status = StatusCode::BAD_REQUEST;
+                message = format!("{:?}", e)
+            }
             Error::NoSuchProject(_, _)
             | Error::NoSuchTable(_)
+            | Error::NoSuchConstraint(_, _)
+            | Error::NoSuchUniqueConstraint(_, _)
+            | Error::NoSuchIndex(_)
             | Error::NoSuchToken(_) => {
                 status = StatusCode::NOT_FOUND;
                 message = format!("{:?}", e);
@@ -121,6 +182,9 @@ async fn handle_rejection(
     {
         status = StatusCode::BAD_REQUEST;
         message = e.to_string();
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        status = StatusCode::BAD_REQUEST;
+        message = e.to_string();
     } else if let Some(e) = err.find::<warp::reject::MethodNotAllowed>() {
         status = StatusCode::METHOD_NOT_ALLOWED;
         message = e.to_string();
@@ -199,6 +263,21 @@ async fn extract_project_and_table(
     Ok((extract_project(project_name, user, db).await?, table_name))
 }
 
+/// Finds the project's database.
+/// Handlers that stream or load a lot of data use the handle directly rather
+/// than going through `AdminDB` so that the admin database is only locked
+/// while the handle is looked up.
+async fn get_user_db(
+    db: &DBRef,
+    project: &Project,
+) -> std::result::Result<Arc<db::user::UserDB>, warp::Rejection> {
+    db.lock()
+        .await
+        .get_user_db(project)
+        .await
+        .map_err(warp::reject::custom)
+}
+
 /// Extracts the database reference
 fn with_db(
     db: DBRef,
@@ -206,6 +285,18 @@ fn with_db(
     warp::any().map(move || db.clone())
 }
 
+/// Copies the chunks of a streamed request body
+fn body_chunks(
+    body: impl futures::Stream<
+        Item = std::result::Result<impl warp::hyper::body::Buf, warp::Error>,
+    >,
+) -> impl futures::Stream<Item = crate::Result<Vec<u8>>> {
+    body.map(|chunk| match chunk {
+        Ok(mut buf) => Ok(buf.copy_to_bytes(buf.remaining()).to_vec()),
+        Err(e) => Err(std::io::Error::other(e).into()),
+    })
+}
+
 /// Reply with the no content status
 fn reply_no_content() -> impl warp::Reply {
     warp::reply::with_status(warp::reply(), StatusCode::NO_CONTENT)
@@ -455,7 +546,8 @@ fn create_table(
         )
 }
 
-/// Remove table from a user's database
+/// Remove table from a user's database, replies with the dependents that
+/// were dropped along with it
 fn remove_table(
     db: DBRef,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
@@ -464,14 +556,212 @@ fn remove_table(
         .and(sufficient_access(db.clone(), auth::Access::User))
         .and(with_db(db.clone()))
         .and_then(extract_project_and_table)
+        .and(warp::query::<db::user::table::RemoveTableOptions>())
+        .and(with_db(db))
+        .and_then(
+            move |(project, table_name): (Project, String),
+                  opt: db::user::table::RemoveTableOptions,
+                  db: DBRef| async move {
+                match db
+                    .lock()
+                    .await
+                    .remove_user_table(
+                        &project,
+                        table_name.as_str(),
+                        opt.cascade,
+                    )
+                    .await
+                {
+                    Ok(deps) => Ok(warp::reply::json(&deps)),
+                    Err(e) => Err(warp::reject::custom(e)),
+                }
+            },
+        )
+}
+
+/// Rename a table in a user's database
+fn rename_table(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("project" / String / "rename" / "table" / String / String)
+        .and(warp::patch())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(
+            move |project_name: String,
+                  table_name: String,
+                  new_name: String,
+                  user: User,
+                  db: DBRef| async move {
+                let project = extract_project(project_name, user, db).await?;
+                Ok::<_, warp::Rejection>((project, table_name, new_name))
+            },
+        )
+        .and(with_db(db))
+        .and_then(
+            move |(project, table_name, new_name): (
+                Project,
+                String,
+                String,
+            ),
+                  db: DBRef| async move {
+                match db
+                    .lock()
+                    .await
+                    .rename_user_table(
+                        &project,
+                        table_name.as_str(),
+                        new_name.as_str(),
+                    )
+                    .await
+                {
+                    Ok(()) => Ok(reply_no_content()),
+                    Err(e) => Err(warp::reject::custom(e)),
+                }
+            },
+        )
+}
+
+/// Get the objects that would be dropped with a table on cascade
+fn get_table_dependents(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("project" / String / "get" / "table" / String / "dependents")
+        .and(warp::get())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(extract_project_and_table)
+        .and(with_db(db))
+        .and_then(move |(project, table_name): (Project, String), db: DBRef| {
+            async move {
+                match db
+                    .lock()
+                    .await
+                    .get_user_table_dependents(&project, table_name.as_str())
+                    .await
+                {
+                    Ok(deps) => Ok(warp::reply::json(&deps)),
+                    Err(e) => Err(warp::reject::custom(e)),
+                }
+            }
+        })
+}
+
+/// Get the indexes of a user's table
+fn get_table_indexes(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("project" / String / "get" / "table" / String / "indexes")
+        .and(warp::get())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(extract_project_and_table)
+        .and(with_db(db))
+        .and_then(move |(project, table_name): (Project, String), db: DBRef| {
+            async move {
+                match db
+                    .lock()
+                    .await
+                    .get_user_table_indexes(&project, table_name.as_str())
+                    .await
+                {
+                    Ok(indexes) => Ok(warp::reply::json(&indexes)),
+                    Err(e) => Err(warp::reject::custom(e)),
+                }
+            }
+        })
+}
+
+/// Create an index on a user's table
+fn create_index(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("project" / String / "create" / "index" / String)
+        .and(warp::put())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(extract_project_and_table)
+        .and(warp::body::json())
+        .and(with_db(db))
+        .and_then(
+            move |(project, table_name): (Project, String),
+                  index: db::user::table::TableIndex,
+                  db: DBRef| async move {
+                match db
+                    .lock()
+                    .await
+                    .create_user_index(&project, table_name.as_str(), &index)
+                    .await
+                {
+                    Ok(()) => Ok(reply_no_content()),
+                    Err(e) => Err(warp::reject::custom(e)),
+                }
+            },
+        )
+}
+
+/// Remove an index of a user's table
+fn remove_index(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("project" / String / "remove" / "index" / String / String)
+        .and(warp::delete())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(
+            move |project_name: String,
+                  table_name: String,
+                  index_name: String,
+                  user: User,
+                  db: DBRef| async move {
+                let project = extract_project(project_name, user, db).await?;
+                Ok::<_, warp::Rejection>((project, table_name, index_name))
+            },
+        )
+        .and(with_db(db))
+        .and_then(
+            move |(project, table_name, index_name): (
+                Project,
+                String,
+                String,
+            ),
+                  db: DBRef| async move {
+                match db
+                    .lock()
+                    .await
+                    .remove_user_index(
+                        &project,
+                        table_name.as_str(),
+                        index_name.as_str(),
+                    )
+                    .await
+                {
+                    Ok(()) => Ok(reply_no_content()),
+                    Err(e) => Err(warp::reject::custom(e)),
+                }
+            },
+        )
+}
+
+/// Alter a table in a user's database
+fn alter_table(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("project" / String / "alter" / "table" / String)
+        .and(warp::patch())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(extract_project_and_table)
+        .and(warp::body::json())
         .and(with_db(db))
         .and_then(
             move |(project, table_name): (Project, String),
+                  alters: Vec<db::user::table::TableAlter>,
                   db: DBRef| async move {
                 match db
                     .lock()
                     .await
-                    .remove_user_table(&project, table_name.as_str())
+                    .alter_user_table(&project, table_name.as_str(), &alters)
                     .await
                 {
                     Ok(()) => Ok(reply_no_content()),
@@ -542,6 +832,64 @@ fn get_all_meta(
         })
 }
 
+/// Plan the changes that take a project's schema to the given one
+fn plan_schema(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("project" / String / "plan" / "schema")
+        .and(warp::post())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(extract_project)
+        .and(warp::body::json())
+        .and(with_db(db))
+        .and_then(
+            move |project: Project,
+                  desired: db::user::table::TableSpec,
+                  db: DBRef| async move {
+                match db.lock().await.plan_user_schema(&project, &desired).await
+                {
+                    Ok(plan) => Ok(warp::reply::json(&plan)),
+                    Err(e) => Err(warp::reject::custom(e)),
+                }
+            },
+        )
+}
+
+/// Take a project's schema to the given one in one transaction
+fn apply_schema(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("project" / String / "apply" / "schema")
+        .and(warp::post())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(extract_project)
+        .and(warp::query::<db::user::plan::ApplyOptions>())
+        .and(warp::body::json())
+        .and(with_db(db))
+        .and_then(
+            move |project: Project,
+                  opt: db::user::plan::ApplyOptions,
+                  desired: db::user::table::TableSpec,
+                  db: DBRef| async move {
+                match db
+                    .lock()
+                    .await
+                    .apply_user_schema(
+                        &project,
+                        &desired,
+                        opt.allow_destructive,
+                    )
+                    .await
+                {
+                    Ok(plan) => Ok(warp::reply::json(&plan)),
+                    Err(e) => Err(warp::reject::custom(e)),
+                }
+            },
+        )
+}
+
 /// Insert data into a user's table
 fn insert_data(
     db: DBRef,
@@ -551,10 +899,12 @@ fn insert_data(
         .and(sufficient_access(db.clone(), auth::Access::User))
         .and(with_db(db.clone()))
         .and_then(extract_project_and_table)
+        .and(warp::query::<db::user::table::InsertOptions>())
         .and(warp::body::json())
         .and(with_db(db))
         .and_then(
             move |(project, table_name): (Project, String),
+                  opt: db::user::table::InsertOptions,
                   data: Vec<RowJson>,
                   db: DBRef| {
                 async move {
@@ -565,10 +915,11 @@ fn insert_data(
                             &project,
                             table_name.as_str(),
                             &data,
+                            &opt,
                         )
                         .await
                     {
-                        Ok(()) => Ok(reply_no_content()),
+                        Ok(report) => Ok(warp::reply::json(&report)),
                         Err(e) => Err(warp::reject::custom(e)),
                     }
                 }
@@ -576,26 +927,29 @@ fn insert_data(
         )
 }
 
-/// Remove all data from a user's table
-fn remove_all_user_table_data(
+/// Update rows of a user's table by primary key
+fn update_data(
     db: DBRef,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("project" / String / "remove" / String / "all")
-        .and(warp::delete())
+    warp::path!("project" / String / "update" / String)
+        .and(warp::patch())
         .and(sufficient_access(db.clone(), auth::Access::User))
         .and(with_db(db.clone()))
         .and_then(extract_project_and_table)
+        .and(warp::body::json())
         .and(with_db(db))
         .and_then(
             move |(project, table_name): (Project, String),
+                  data: Vec<RowJson>,
                   db: DBRef| {
                 async move {
                     match db
                         .lock()
                         .await
-                        .remove_all_user_table_data(
+                        .update_user_table_data(
                             &project,
                             table_name.as_str(),
+                            &data,
                         )
                         .await
                     {
@@ -607,42 +961,696 @@ fn remove_all_user_table_data(
         )
 }
 
-/// Get data from a user's table
-fn get_table_data(
+/// Remove rows of a user's table by primary key or filter
+fn remove_rows(
     db: DBRef,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("project" / String / "get" / "table" / String / "data")
-        .and(warp::get())
+    warp::path!("project" / String / "remove" / String / "rows")
+        .and(warp::delete())
         .and(sufficient_access(db.clone(), auth::Access::User))
         .and(with_db(db.clone()))
         .and_then(extract_project_and_table)
+        .and(warp::query::<db::user::filter::RemoveRowsOptions>())
+        .and(warp::body::json())
         .and(with_db(db))
-        .and_then(move |(project, table_name): (Project, String), db: DBRef| {
-            async move {
-                match db
-                    .lock()
-                    .await
-                    .get_user_table_data(&project, table_name.as_str())
-                    .await
-                {
-                    Ok(td) => Ok(warp::reply::json(&td)),
-                    Err(e) => Err(warp::reject::custom(e))
+        .and_then(
+            move |(project, table_name): (Project, String),
+                  opt: db::user::filter::RemoveRowsOptions,
+                  selection: db::user::filter::RowSelection,
+                  db: DBRef| {
+                async move {
+                    match db
+                        .lock()
+                        .await
+                        .remove_user_table_rows(
+                            &project,
+                            table_name.as_str(),
+                            &selection,
+                            opt.confirm_all,
+                        )
+                        .await
+                    {
+                        Ok(removed) => Ok(warp::reply::json(&removed)),
+                        Err(e) => Err(warp::reject::custom(e)),
+                    }
                 }
-            }
-        })
+            },
+        )
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::db::admin;
-    use crate::tests;
-    use std::sync::Arc;
-    use warp::http::StatusCode;
-
-    const TEST_DB_NAME: &str = "postgres_test_api";
+/// Bulk load newline-delimited JSON rows into a user's table.
+/// Rows are loaded as the body arrives.
+fn bulk_insert_data(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("project" / String / "insert" / String / "bulk")
+        .and(warp::put())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(extract_project_and_table)
+        .and(warp::body::stream())
+        .and(with_db(db))
+        .and_then(
+            move |(project, table_name): (Project, String),
+                  body,
+                  db: DBRef| {
+                async move {
+                    let user_db = get_user_db(&db, &project).await?;
+                    log::debug!(
+                        "copying rows into table \"{}\" from project \"{}\"",
+                        table_name,
+                        project.get_name()
+                    );
+                    let rows = db::user::import::read_ndjson(Box::pin(
+                        body_chunks(body),
+                    ));
+                    match user_db
+                        .copy_table_data(table_name.as_str(), rows)
+                        .await
+                    {
+                        Ok(rows) => Ok(warp::reply::json(&rows)),
+                        Err(e) => Err(warp::reject::custom(e)),
+                    }
+                }
+            },
+        )
+}
 
-    async fn gen_user_tok(admindb: DBRef) -> auth::Token {
+/// Insert a CSV file into a user's table
+fn import_csv(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("project" / String / "insert" / String / "csv")
+        .and(warp::put())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(extract_project_and_table)
+        .and(warp::query::<db::user::import::CsvOptions>())
+        .and(warp::query::<db::user::table::InsertOptions>())
+        .and(warp::body::bytes())
+        .and(with_db(db))
+        .and_then(
+            move |(project, table_name): (Project, String),
+                  csv_opt: db::user::import::CsvOptions,
+                  opt: db::user::table::InsertOptions,
+                  data: warp::hyper::body::Bytes,
+                  db: DBRef| {
+                async move {
+                    let user_db = get_user_db(&db, &project).await?;
+                    log::debug!(
+                        "importing csv into table \"{}\" from project \"{}\" \
+                        ({:?}, {:?})",
+                        table_name,
+                        project.get_name(),
+                        csv_opt,
+                        opt
+                    );
+                    match user_db
+                        .import_csv(table_name.as_str(), &data, &csv_opt, &opt)
+                        .await
+                    {
+                        Ok(report) => Ok(warp::reply::json(&report)),
+                        Err(e) => Err(warp::reject::custom(e)),
+                    }
+                }
+            },
+        )
+}
+
+/// Remove all data from a user's table
+fn remove_all_user_table_data(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("project" / String / "remove" / String / "all")
+        .and(warp::delete())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(extract_project_and_table)
+        .and(with_db(db))
+        .and_then(
+            move |(project, table_name): (Project, String),
+                  db: DBRef| {
+                async move {
+                    match db
+                        .lock()
+                        .await
+                        .remove_all_user_table_data(
+                            &project,
+                            table_name.as_str(),
+                        )
+                        .await
+                    {
+                        Ok(()) => Ok(reply_no_content()),
+                        Err(e) => Err(warp::reject::custom(e)),
+                    }
+                }
+            },
+        )
+}
+
+/// Get data from a user's table
+fn get_table_data(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("project" / String / "get" / "table" / String / "data")
+        .and(warp::get())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(extract_project_and_table)
+        .and(warp::query::<db::user::page::ReadOptions>())
+        .and(with_db(db))
+        .and_then(
+            move |(project, table_name): (Project, String),
+                  opt: db::user::page::ReadOptions,
+                  db: DBRef| {
+                async move {
+                    match db
+                        .lock()
+                        .await
+                        .get_user_table_data(
+                            &project,
+                            table_name.as_str(),
+                            &opt,
+                        )
+                        .await
+                    {
+                        Ok(page) => Ok(warp::reply::json(&page)),
+                        Err(e) => Err(warp::reject::custom(e)),
+                    }
+                }
+            },
+        )
+}
+
+/// Stream data from a user's table as newline-delimited json
+fn stream_table_data(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!(
+        "project" / String / "get" / "table" / String / "data" / "stream"
+    )
+    .and(warp::get())
+    .and(sufficient_access(db.clone(), auth::Access::User))
+    .and(with_db(db.clone()))
+    .and_then(extract_project_and_table)
+    .and(warp::query::<db::user::page::ReadOptions>())
+    .and(with_db(db))
+    .and_then(
+        move |(project, table_name): (Project, String),
+              opt: db::user::page::ReadOptions,
+              db: DBRef| {
+            async move {
+                let rows = match db
+                    .lock()
+                    .await
+                    .stream_user_table_data(&project, table_name.as_str(), &opt)
+                    .await
+                {
+                    Ok(rows) => rows,
+                    Err(e) => return Err(warp::reject::custom(e)),
+                };
+                // An error ends the body early
+                let lines = rows.map(|row| {
+                    let mut line = serde_json::to_vec(&row?)?;
+                    line.push(b'\n');
+                    Ok::<Vec<u8>, Error>(line)
+                });
+                Ok(warp::http::Response::builder()
+                    .header("Content-Type", "application/x-ndjson")
+                    .body(warp::hyper::Body::wrap_stream(lines))
+                    .unwrap())
+            }
+        },
+    )
+}
+
+/// Export a user's table as a CSV file
+fn export_table_csv(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("project" / String / "export" / "table" / String / "csv")
+        .and(warp::get())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(extract_project_and_table)
+        .and(warp::query::<db::user::page::ReadOptions>())
+        .and(warp::query::<db::user::export::CsvExportOptions>())
+        .and(with_db(db))
+        .and_then(
+            move |(project, table_name): (Project, String),
+                  opt: db::user::page::ReadOptions,
+                  csv_opt: db::user::export::CsvExportOptions,
+                  db: DBRef| {
+                async move {
+                    let user_db = get_user_db(&db, &project).await?;
+                    log::debug!(
+                        "exporting table \"{}\" from project \"{}\" as csv \
+                        ({:?}, {:?})",
+                        table_name,
+                        project.get_name(),
+                        opt,
+                        csv_opt
+                    );
+                    let file = user_db
+                        .export_table_csv(table_name.as_str(), &opt, &csv_opt)
+                        .await
+                        .map_err(warp::reject::custom)?;
+                    // An error ends the body early
+                    Ok::<_, warp::Rejection>(
+                        warp::http::Response::builder()
+                            .header("Content-Type", "text/csv")
+                            .header(
+                                "Content-Disposition",
+                                format!(
+                                    "attachment; filename=\"{}.csv\"",
+                                    table_name
+                                ),
+                            )
+                            .body(warp::hyper::Body::wrap_stream(file))
+                            .unwrap(),
+                    )
+                }
+            },
+        )
+}
+
+/// Export all of a user's tables as CSV files in a zip archive
+fn export_project_csv(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("project" / String / "export" / "csv")
+        .and(warp::get())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(extract_project)
+        .and(warp::query::<db::user::export::CsvExportOptions>())
+        .and(with_db(db))
+        .and_then(
+            move |project: Project,
+                  csv_opt: db::user::export::CsvExportOptions,
+                  db: DBRef| {
+                async move {
+                    let user_db = get_user_db(&db, &project).await?;
+                    log::debug!(
+                        "exporting project \"{}\" as csv ({:?})",
+                        project.get_name(),
+                        csv_opt
+                    );
+                    let archive = user_db
+                        .export_project_csv(&csv_opt)
+                        .await
+                        .map_err(warp::reject::custom)?;
+                    Ok::<_, warp::Rejection>(
+                        warp::http::Response::builder()
+                            .header("Content-Type", "application/zip")
+                            .header(
+                                "Content-Disposition",
+                                format!(
+                                    "attachment; filename=\"{}.zip\"",
+                                    project.get_name()
+                                ),
+                            )
+                            .body(warp::hyper::Body::wrap_stream(archive))
+                            .unwrap(),
+                    )
+                }
+            },
+        )
+}
+
+/// Export a user's table as a Parquet file or an Arrow stream
+fn export_table_arrow(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    use db::user::columnar::{ArrowFormat, ArrowOptions};
+    warp::path!("project" / String / "export" / "table" / String / ArrowFormat)
+        .and(warp::get())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(|project_name, table_name, format, user, db| async move {
+            extract_project_and_table(project_name, table_name, user, db)
+                .await
+                .map(|project_and_table| (project_and_table, format))
+        })
+        .and(warp::query::<db::user::page::ReadOptions>())
+        .and(warp::query::<ArrowOptions>())
+        .and(with_db(db))
+        .and_then(
+            move |((project, table_name), format): (
+                (Project, String),
+                ArrowFormat,
+            ),
+                  opt: db::user::page::ReadOptions,
+                  arrow_opt: ArrowOptions,
+                  db: DBRef| {
+                async move {
+                    let user_db = get_user_db(&db, &project).await?;
+                    log::debug!(
+                        "exporting table \"{}\" from project \"{}\" as {:?} \
+                    ({:?}, {:?})",
+                        table_name,
+                        project.get_name(),
+                        format,
+                        opt,
+                        arrow_opt
+                    );
+                    let file = user_db
+                        .export_table_arrow(
+                            table_name.as_str(),
+                            &opt,
+                            &arrow_opt,
+                            format,
+                        )
+                        .await
+                        .map_err(warp::reject::custom)?;
+                    // An error ends the body early
+                    Ok::<_, warp::Rejection>(
+                        warp::http::Response::builder()
+                            .header("Content-Type", format.content_type())
+                            .header(
+                                "Content-Disposition",
+                                format!(
+                                    "attachment; filename=\"{}.{}\"",
+                                    table_name,
+                                    format.extension()
+                                ),
+                            )
+                            .body(warp::hyper::Body::wrap_stream(file))
+                            .unwrap(),
+                    )
+                }
+            },
+        )
+}
+
+/// Export all of a user's tables as Parquet files or Arrow streams in a zip
+/// archive
+fn export_project_arrow(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    use db::user::columnar::{ArrowFormat, ArrowOptions};
+    warp::path!("project" / String / "export" / ArrowFormat)
+        .and(warp::get())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(|project_name, format, user, db| async move {
+            extract_project(project_name, user, db)
+                .await
+                .map(|project| (project, format))
+        })
+        .and(warp::query::<ArrowOptions>())
+        .and(with_db(db))
+        .and_then(
+            move |(project, format): (Project, ArrowFormat),
+                  arrow_opt: ArrowOptions,
+                  db: DBRef| {
+                async move {
+                    let user_db = get_user_db(&db, &project).await?;
+                    log::debug!(
+                        "exporting project \"{}\" as {:?} ({:?})",
+                        project.get_name(),
+                        format,
+                        arrow_opt
+                    );
+                    let archive = user_db
+                        .export_project_arrow(&arrow_opt, format)
+                        .await
+                        .map_err(warp::reject::custom)?;
+                    Ok::<_, warp::Rejection>(
+                        warp::http::Response::builder()
+                            .header("Content-Type", "application/zip")
+                            .header(
+                                "Content-Disposition",
+                                format!(
+                                    "attachment; filename=\"{}.zip\"",
+                                    project.get_name()
+                                ),
+                            )
+                            .body(warp::hyper::Body::wrap_stream(archive))
+                            .unwrap(),
+                    )
+                }
+            },
+        )
+}
+
+/// Export a user's table for a statistical package
+fn export_table_stats(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    use db::user::stats::{StatsExport, StatsFormat};
+    warp::path!("project" / String / "export" / "table" / String / StatsFormat)
+        .and(warp::get())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(|project_name, table_name, format, user, db| async move {
+            extract_project_and_table(project_name, table_name, user, db)
+                .await
+                .map(|project_and_table| (project_and_table, format))
+        })
+        .and(with_db(db))
+        .and_then(
+            move |((project, table_name), format): (
+                (Project, String),
+                StatsFormat,
+            ),
+                  db: DBRef| {
+                async move {
+                    let user_db = get_user_db(&db, &project).await?;
+                    log::debug!(
+                        "exporting table \"{}\" from project \"{}\" as {:?}",
+                        table_name,
+                        project.get_name(),
+                        format
+                    );
+                    let body = match user_db
+                        .export_table_stats(table_name.as_str(), format)
+                        .await
+                        .map_err(warp::reject::custom)?
+                    {
+                        StatsExport::File(data) => {
+                            warp::hyper::Body::from(data)
+                        }
+                        StatsExport::Archive(archive) => {
+                            warp::hyper::Body::wrap_stream(archive)
+                        }
+                    };
+                    Ok::<_, warp::Rejection>(
+                        warp::http::Response::builder()
+                            .header("Content-Type", format.content_type())
+                            .header(
+                                "Content-Disposition",
+                                format!(
+                                    "attachment; filename=\"{}.{}\"",
+                                    table_name,
+                                    format.extension()
+                                ),
+                            )
+                            .body(body)
+                            .unwrap(),
+                    )
+                }
+            },
+        )
+}
+
+/// Export all of a user's tables for a statistical package in a zip archive
+fn export_project_stats(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    use db::user::stats::StatsFormat;
+    warp::path!("project" / String / "export" / StatsFormat)
+        .and(warp::get())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(|project_name, format, user, db| async move {
+            extract_project(project_name, user, db)
+                .await
+                .map(|project| (project, format))
+        })
+        .and(with_db(db))
+        .and_then(
+            move |(project, format): (Project, StatsFormat), db: DBRef| {
+                async move {
+                    let user_db = get_user_db(&db, &project).await?;
+                    log::debug!(
+                        "exporting project \"{}\" as {:?}",
+                        project.get_name(),
+                        format
+                    );
+                    let archive = user_db
+                        .export_project_stats(format)
+                        .await
+                        .map_err(warp::reject::custom)?;
+                    Ok::<_, warp::Rejection>(
+                        warp::http::Response::builder()
+                            .header("Content-Type", "application/zip")
+                            .header(
+                                "Content-Disposition",
+                                format!(
+                                    "attachment; filename=\"{}.zip\"",
+                                    project.get_name()
+                                ),
+                            )
+                            .body(warp::hyper::Body::wrap_stream(archive))
+                            .unwrap(),
+                    )
+                }
+            },
+        )
+}
+
+/// Export all of a user's tables as sheets of a workbook
+fn export_project_xlsx(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("project" / String / "export" / "xlsx")
+        .and(warp::get())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(extract_project)
+        .and(with_db(db))
+        .and_then(move |project: Project, db: DBRef| async move {
+            let user_db = get_user_db(&db, &project).await?;
+            log::debug!("exporting project \"{}\" as xlsx", project.get_name());
+            let workbook =
+                user_db.export_xlsx().await.map_err(warp::reject::custom)?;
+            Ok::<_, warp::Rejection>(
+                warp::http::Response::builder()
+                    .header(
+                        "Content-Type",
+                        "application/vnd.openxmlformats-officedocument.\
+                        spreadsheetml.sheet",
+                    )
+                    .header(
+                        "Content-Disposition",
+                        format!(
+                            "attachment; filename=\"{}.xlsx\"",
+                            project.get_name()
+                        ),
+                    )
+                    .body(workbook)
+                    .unwrap(),
+            )
+        })
+}
+
+/// Insert the sheets of a workbook into a user's tables
+fn import_xlsx(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("project" / String / "import" / "xlsx")
+        .and(warp::put())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(extract_project)
+        .and(warp::query::<db::user::workbook::XlsxOptions>())
+        .and(warp::query::<db::user::table::InsertOptions>())
+        .and(warp::body::bytes())
+        .and(with_db(db))
+        .and_then(
+            move |project: Project,
+                  xlsx_opt: db::user::workbook::XlsxOptions,
+                  opt: db::user::table::InsertOptions,
+                  data: warp::hyper::body::Bytes,
+                  db: DBRef| {
+                async move {
+                    let user_db = get_user_db(&db, &project).await?;
+                    log::debug!(
+                        "importing xlsx into project \"{}\" ({:?}, {:?})",
+                        project.get_name(),
+                        xlsx_opt,
+                        opt
+                    );
+                    match user_db.import_xlsx(&data, &xlsx_opt, &opt).await {
+                        Ok(report) => Ok(warp::reply::json(&report)),
+                        Err(e) => Err(warp::reject::custom(e)),
+                    }
+                }
+            },
+        )
+}
+
+/// Export all of a user's tables as an ODM document
+fn export_project_odm(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("project" / String / "export" / "odm")
+        .and(warp::get())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(extract_project)
+        .and(with_db(db))
+        .and_then(move |project: Project, db: DBRef| async move {
+            let user_db = get_user_db(&db, &project).await?;
+            log::debug!("exporting project \"{}\" as odm", project.get_name());
+            let document = user_db
+                .export_odm(project.get_name())
+                .await
+                .map_err(warp::reject::custom)?;
+            Ok::<_, warp::Rejection>(
+                warp::http::Response::builder()
+                    .header("Content-Type", "application/xml")
+                    .header(
+                        "Content-Disposition",
+                        format!(
+                            "attachment; filename=\"{}.xml\"",
+                            project.get_name()
+                        ),
+                    )
+                    .body(document)
+                    .unwrap(),
+            )
+        })
+}
+
+/// Create a user's tables from an ODM document and insert its data
+fn import_odm(
+    db: DBRef,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("project" / String / "import" / "odm")
+        .and(warp::put())
+        .and(sufficient_access(db.clone(), auth::Access::User))
+        .and(with_db(db.clone()))
+        .and_then(extract_project)
+        .and(warp::query::<db::user::odm::OdmOptions>())
+        .and(warp::query::<db::user::table::InsertOptions>())
+        .and(warp::body::bytes())
+        .and(with_db(db))
+        .and_then(
+            move |project: Project,
+                  odm_opt: db::user::odm::OdmOptions,
+                  opt: db::user::table::InsertOptions,
+                  data: warp::hyper::body::Bytes,
+                  db: DBRef| {
+                async move {
+                    let user_db = get_user_db(&db, &project).await?;
+                    log::debug!(
+                        "importing odm into project \"{}\" ({:?}, {:?})",
+                        project.get_name(),
+                        odm_opt,
+                        opt
+                    );
+                    match user_db.import_odm(&data, &odm_opt, &opt).await {
+                        Ok(report) => Ok(warp::reply::json(&report)),
+                        Err(e) => Err(warp::reject::custom(e)),
+                    }
+                }
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::admin;
+    use crate::tests;
+    use std::sync::Arc;
+    use warp::http::StatusCode;
+
+    const TEST_DB_NAME: &str = "postgres_test_api";
+
+    async fn gen_user_tok(admindb: DBRef) -> auth::Token {
         admindb
             .lock()
             .await
@@ -671,6 +1679,7 @@ mod tests {
         method: String,
         path: String,
         json: Option<Box<dyn erased_serde::Serialize>>,
+        raw: Option<Vec<u8>>,
         headers: std::collections::HashMap<String, String>,
         status: Option<StatusCode>,
         body: Option<Vec<u8>>,
@@ -683,6 +1692,7 @@ mod tests {
                 method: "".to_string(),
                 path: "".to_string(),
                 json: None,
+                raw: None,
                 headers: std::collections::HashMap::new(),
                 status: None,
                 body: None,
@@ -701,6 +1711,10 @@ mod tests {
             self.json = Some(Box::new(val));
             self
         }
+        pub fn raw(mut self, val: &[u8]) -> Self {
+            self.raw = Some(val.to_vec());
+            self
+        }
         pub fn header<T: AsRef<str>>(mut self, name: &str, value: T) -> Self {
             self.headers
                 .insert(name.to_string(), value.as_ref().to_string());
@@ -723,12 +1737,16 @@ mod tests {
             if let Some(v) = &self.json {
                 req = req.json(&v);
             }
+            if let Some(v) = &self.raw {
+                req = req.body(v);
+            }
             for (name, value) in &self.headers {
                 req = req.header(name, value);
             }
-            let resp = req.reply(f).await;
+            // Boxed so that the test future stays small enough for the stack
+            let resp = Box::pin(req.reply(f)).await;
             self.status = Some(resp.status());
-            self.body = Some((&*resp.body()).to_vec());
+            self.body = Some(resp.body().to_vec());
             self.headers_response = Some(resp.headers().clone());
             self
         }
@@ -755,6 +1773,21 @@ mod tests {
             );
             bod.unwrap()
         }
+        /// Newline-delimited json body
+        pub fn expect_lines<T>(&self) -> Vec<T>
+        where
+            T: serde::de::DeserializeOwned,
+        {
+            serde_json::Deserializer::from_slice(&self.body.clone().unwrap())
+                .into_iter::<T>()
+                .collect::<std::result::Result<Vec<T>, _>>()
+                .unwrap_or_else(|e| {
+                    panic!(
+                        "lines of {} method to {} path: {}",
+                        self.method, self.path, e
+                    )
+                })
+        }
         pub fn expect_error<T: AsRef<str>>(self, msg: T) {
             let bod = self.expect_body::<String>();
             assert_eq!(
@@ -798,221 +1831,622 @@ mod tests {
     async fn test_api() {
         let _ = pretty_env_logger::try_init();
 
-        let admindb =
-            tests::create_test_admindb(TEST_DB_NAME, true, true).await;
-        tests::insert_test_user(&admindb).await;
+        let admindb =
+            tests::create_test_admindb(TEST_DB_NAME, true, true).await;
+        tests::insert_test_user(&admindb).await;
+
+        let admindb_ref = Arc::new(Mutex::new(admindb));
+
+        assert_eq!(admindb_ref.lock().await.get_name(), TEST_DB_NAME);
+
+        // Individual filters given good input --------------------------------
+
+        // Health check
+        FilterTester::new()
+            .method("GET")
+            .path("/health")
+            .reply(&health(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::OK)
+            .expect_body::<bool>();
+
+        // Create/refresh/remove session token
+        let tok = FilterTester::new()
+            .method("POST")
+            .path("/auth/session-token")
+            .json(auth::EmailPassword {
+                email: "user@example.com".to_string(),
+                password: "user".to_string(),
+            })
+            .reply(&generate_session_token(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::OK)
+            .expect_body::<auth::Token>();
+        let tok = FilterTester::new()
+            .method("POST")
+            .path(format!("/auth/refresh-token/{}", tok.token()))
+            .reply(&refresh_token(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::OK)
+            .expect_body::<auth::Token>();
+        FilterTester::new()
+            .method("DELETE")
+            .path(format!("/auth/remove-token/{}", tok.token()))
+            .reply(&remove_token(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::NO_CONTENT);
+        drop(tok);
+
+        // Generate tokens to be used below
+        let admin_token_full = gen_admin_tok(admindb_ref.clone()).await;
+        let admin_token = admin_token_full.token();
+        let user_token_full = gen_user_tok(admindb_ref.clone()).await;
+        let user_token = user_token_full.token();
+
+        // Get user by token
+        let usr = FilterTester::new()
+            .method("GET")
+            .path(format!("/get/user/by/token/{}", user_token))
+            .reply(&get_user_by_token(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::OK)
+            .expect_body::<admin::User>();
+        assert_eq!(usr.email(), "user@example.com");
+        assert_eq!(usr.access(), auth::Access::User);
+        drop(usr);
+
+        // Get users
+        FilterTester::new()
+            .method("GET")
+            .path("/get/users")
+            .bearer_header(admin_token)
+            .reply(&get_users(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::OK)
+            .expect_body::<Vec<admin::User>>();
+
+        // Create/remove user
+        FilterTester::new()
+            .method("PUT")
+            .path("/create/user")
+            .json(auth::EmailPassword {
+                email: "newuser@example.com".to_string(),
+                password: "newpassword".to_string(),
+            })
+            .reply(&create_user(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::NO_CONTENT);
+        FilterTester::new()
+            .method("DELETE")
+            .path("/remove/user/newuser@example.com")
+            .bearer_header(admin_token)
+            .reply(&remove_user(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::NO_CONTENT);
+
+        // Test projects
+        let test_project1 = db::admin::Project::new(1, "test");
+
+        // Make sure the corresponding databases aren't present
+        log::info!("remove test projects");
+        crate::tests::remove_dbs(
+            &*admindb_ref.lock().await,
+            &[test_project1.get_dbname(TEST_DB_NAME).as_str()],
+        )
+        .await;
+
+        // Create projects
+        FilterTester::new()
+            .method("PUT")
+            .path(format!("/create/project/{}", test_project1.get_name()))
+            .bearer_header(admin_token)
+            .reply(&create_project(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::NO_CONTENT);
+        // Get them
+        let projects_obtained = FilterTester::new()
+            .method("GET")
+            .path("/get/projects")
+            .bearer_header(admin_token)
+            .reply(&get_user_projects(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::OK)
+            .expect_body::<Vec<admin::Project>>();
+        assert_eq!(projects_obtained.len(), 1);
+        assert_eq!(projects_obtained[0].get_name(), test_project1.get_name());
+        drop(projects_obtained);
+        let project_obtained = FilterTester::new()
+            .method("GET")
+            .path(format!("/get/project/{}", test_project1.get_name()))
+            .bearer_header(admin_token)
+            .reply(&get_user_project(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::OK)
+            .expect_body::<admin::Project>();
+        assert_eq!(project_obtained.get_name(), test_project1.get_name());
+        drop(project_obtained);
+
+        // Create table
+        let table = crate::tests::get_test_primary_table();
 
-        let admindb_ref = Arc::new(Mutex::new(admindb));
+        FilterTester::new()
+            .method("PUT")
+            .path("/project/test/create/table")
+            .bearer_header(admin_token)
+            .json(table.clone())
+            .reply(&create_table(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::NO_CONTENT);
 
-        assert_eq!(admindb_ref.lock().await.get_name(), TEST_DB_NAME);
+        // Get table list
+        let table_list = FilterTester::new()
+            .method("GET")
+            .path("/project/test/get/tablenames")
+            .bearer_header(admin_token)
+            .reply(&get_table_names(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::OK)
+            .expect_body::<Vec<String>>();
+        assert_eq!(table_list, vec![table.name.clone()]);
+        drop(table_list);
 
-        // Individual filters given good input --------------------------------
+        // Get table metadata
+        let table_meta = FilterTester::new()
+            .method("GET")
+            .path(format!("/project/test/get/table/{}/meta", table.name))
+            .bearer_header(admin_token)
+            .reply(&get_table_meta(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::OK)
+            .expect_body::<db::user::table::TableMeta>();
+        assert_eq!(table_meta, table);
+        drop(table_meta);
 
-        // Health check
+        // Alter table
+        let alters = vec![
+            db::user::table::TableAlter::AddColumn(
+                db::user::table::ColMeta::new().name("extra"),
+            ),
+            db::user::table::TableAlter::DropColumn("extra".to_string()),
+        ];
         FilterTester::new()
+            .method("PATCH")
+            .path(format!("/project/test/alter/table/{}", table.name))
+            .bearer_header(admin_token)
+            .json(alters)
+            .reply(&alter_table(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::NO_CONTENT);
+
+        // Get all metadata
+        let all_meta = FilterTester::new()
             .method("GET")
-            .path("/health")
-            .reply(&health(admindb_ref.clone()))
+            .path("/project/test/get/meta")
+            .bearer_header(admin_token)
+            .reply(&get_all_meta(admindb_ref.clone()))
             .await
             .expect_status(StatusCode::OK)
-            .expect_body::<bool>();
+            .expect_body::<db::user::table::TableSpec>();
+        assert_eq!(all_meta, vec![table.clone()]);
+        drop(all_meta);
 
-        // Create/refresh/remove session token
-        let tok = FilterTester::new()
+        // Plan and apply a schema
+        let mut desired = vec![table.clone()];
+        desired[0].cols.pop();
+        let plan = FilterTester::new()
             .method("POST")
-            .path("/auth/session-token")
-            .json(auth::EmailPassword {
-                email: "user@example.com".to_string(),
-                password: "user".to_string(),
-            })
-            .reply(&generate_session_token(admindb_ref.clone()))
+            .path("/project/test/plan/schema")
+            .bearer_header(admin_token)
+            .json(desired.clone())
+            .reply(&plan_schema(admindb_ref.clone()))
             .await
             .expect_status(StatusCode::OK)
-            .expect_body::<auth::Token>();
-        let tok = FilterTester::new()
+            .expect_body::<db::user::plan::SchemaPlan>();
+        assert_eq!(plan.len(), 1);
+        assert!(plan[0].warning.is_some());
+        let applied = FilterTester::new()
             .method("POST")
-            .path(format!("/auth/refresh-token/{}", tok.token()))
-            .reply(&refresh_token(admindb_ref.clone()))
+            .path("/project/test/apply/schema?allow_destructive=true")
+            .bearer_header(admin_token)
+            .json(desired)
+            .reply(&apply_schema(admindb_ref.clone()))
             .await
             .expect_status(StatusCode::OK)
-            .expect_body::<auth::Token>();
+            .expect_body::<db::user::plan::SchemaPlan>();
+        assert_eq!(applied, plan);
         FilterTester::new()
-            .method("DELETE")
-            .path(format!("/auth/remove-token/{}", tok.token()))
-            .reply(&remove_token(admindb_ref.clone()))
+            .method("POST")
+            .path("/project/test/apply/schema?allow_destructive=true")
+            .bearer_header(admin_token)
+            .json(vec![table.clone()])
+            .reply(&apply_schema(admindb_ref.clone()))
             .await
-            .expect_status(StatusCode::NO_CONTENT);
-        drop(tok);
+            .expect_status(StatusCode::OK);
 
-        // Generate tokens to be used below
-        let admin_token_full = gen_admin_tok(admindb_ref.clone()).await;
-        let admin_token = admin_token_full.token();
-        let user_token_full = gen_user_tok(admindb_ref.clone()).await;
-        let user_token = user_token_full.token();
+        // Insert table data
+        let data = crate::tests::get_primary_data();
+        let keys = FilterTester::new()
+            .method("PUT")
+            .path(format!("/project/test/insert/{}", table.name.as_str()))
+            .bearer_header(admin_token)
+            .json(data.clone())
+            .reply(&insert_data(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::OK)
+            .expect_body::<db::user::table::InsertReport>()
+            .keys;
+        let expected_keys: Vec<RowJson> = data
+            .iter()
+            .map(|row| {
+                let mut key = RowJson::new();
+                key.insert("id".to_string(), row["id"].clone());
+                key
+            })
+            .collect();
+        assert_eq!(keys, expected_keys);
 
-        // Get user by token
-        let usr = FilterTester::new()
+        // Get table data
+        let data_obtained = FilterTester::new()
             .method("GET")
-            .path(format!("/get/user/by/token/{}", user_token))
-            .reply(&get_user_by_token(admindb_ref.clone()))
+            .path(format!(
+                "/project/test/get/table/{}/data",
+                table.name.as_str()
+            ))
+            .bearer_header(admin_token)
+            .reply(&get_table_data(admindb_ref.clone()))
             .await
             .expect_status(StatusCode::OK)
-            .expect_body::<admin::User>();
-        assert_eq!(usr.email(), "user@example.com");
-        assert_eq!(usr.access(), auth::Access::User);
-        drop(usr);
+            .expect_body::<db::user::page::TablePage>();
+        assert_eq!(data_obtained.rows, data);
+        assert_eq!(data_obtained.total as usize, data.len());
+        assert!(data_obtained.next_cursor.is_none());
 
-        // Get users
-        FilterTester::new()
+        // Get a page of table data
+        let page = FilterTester::new()
             .method("GET")
-            .path("/get/users")
+            .path(format!(
+                "/project/test/get/table/{}/data?limit=1&sort=-id&columns=id",
+                table.name.as_str()
+            ))
             .bearer_header(admin_token)
-            .reply(&get_users(admindb_ref.clone()))
+            .reply(&get_table_data(admindb_ref.clone()))
             .await
             .expect_status(StatusCode::OK)
-            .expect_body::<Vec<admin::User>>();
+            .expect_body::<db::user::page::TablePage>();
+        assert_eq!(page.rows.len(), 1);
+        assert_eq!(page.rows[0].keys().collect::<Vec<&String>>(), vec!["id"]);
+        assert_eq!(page.rows[0]["id"], data.last().unwrap()["id"]);
+        let next = FilterTester::new()
+            .method("GET")
+            .path(format!(
+                "/project/test/get/table/{}/data?limit=1&sort=-id&cursor={}",
+                table.name.as_str(),
+                page.next_cursor.unwrap()
+            ))
+            .bearer_header(admin_token)
+            .reply(&get_table_data(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::OK)
+            .expect_body::<db::user::page::TablePage>();
+        assert_eq!(next.rows[0]["id"], data[data.len() - 2]["id"]);
+        let filtered = FilterTester::new()
+            .method("GET")
+            .path(format!(
+                "/project/test/get/table/{}/data\
+                ?filter=or(id.eq.1,email.like.email2%25)",
+                table.name.as_str()
+            ))
+            .bearer_header(admin_token)
+            .reply(&get_table_data(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::OK)
+            .expect_body::<db::user::page::TablePage>();
+        assert_eq!(filtered.rows, data);
+        let filtered = FilterTester::new()
+            .method("GET")
+            .path(format!(
+                "/project/test/get/table/{}/data?filter=\
+                %7B%22ne%22%3A%7B%22column%22%3A%22id%22%2C\
+                %22value%22%3A1%7D%7D",
+                table.name.as_str()
+            ))
+            .bearer_header(admin_token)
+            .reply(&get_table_data(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::OK)
+            .expect_body::<db::user::page::TablePage>();
+        assert_eq!(filtered.total, 1);
+        let streamed = FilterTester::new()
+            .method("GET")
+            .path(format!(
+                "/project/test/get/table/{}/data/stream?sort=-id",
+                table.name.as_str()
+            ))
+            .bearer_header(admin_token)
+            .reply(&stream_table_data(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::OK)
+            .expect_lines::<RowJson>();
+        assert_eq!(streamed, data.iter().rev().cloned().collect::<Vec<_>>());
+        FilterTester::new()
+            .method("GET")
+            .path(format!(
+                "/project/test/get/table/{}/data?filter=age.is.null",
+                table.name.as_str()
+            ))
+            .bearer_header(admin_token)
+            .reply(&routes(admindb_ref.clone(), ""))
+            .await
+            .expect_status(StatusCode::CONFLICT);
+        FilterTester::new()
+            .method("GET")
+            .path(format!(
+                "/project/test/get/table/{}/data?filter=id.between.1",
+                table.name.as_str()
+            ))
+            .bearer_header(admin_token)
+            .reply(&routes(admindb_ref.clone(), ""))
+            .await
+            .expect_status(StatusCode::BAD_REQUEST);
+        FilterTester::new()
+            .method("GET")
+            .path(format!(
+                "/project/test/get/table/{}/data?cursor=nonsense",
+                table.name.as_str()
+            ))
+            .bearer_header(admin_token)
+            .reply(&routes(admindb_ref.clone(), ""))
+            .await
+            .expect_status(StatusCode::BAD_REQUEST);
 
-        // Create/remove user
+        // Export table data
+        let exported = FilterTester::new()
+            .method("GET")
+            .path(format!(
+                "/project/test/export/table/{}/csv?sort=-id&columns=id",
+                table.name.as_str()
+            ))
+            .bearer_header(admin_token)
+            .reply(&export_table_csv(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::OK);
+        let ids: Vec<String> =
+            data.iter().rev().map(|row| row["id"].to_string()).collect();
+        assert_eq!(
+            String::from_utf8(exported.body.clone().unwrap()).unwrap(),
+            format!("id\n{}\n", ids.join("\n"))
+        );
+        exported.expect_header("Content-Type", "text/csv");
         FilterTester::new()
-            .method("PUT")
-            .path("/create/user")
-            .json(auth::EmailPassword {
-                email: "newuser@example.com".to_string(),
-                password: "newpassword".to_string(),
-            })
-            .reply(&create_user(admindb_ref.clone()))
+            .method("GET")
+            .path(format!(
+                "/project/test/export/table/{}/csv?delimiter=%E2%86%92",
+                table.name.as_str()
+            ))
+            .bearer_header(admin_token)
+            .reply(&routes(admindb_ref.clone(), ""))
             .await
-            .expect_status(StatusCode::NO_CONTENT);
+            .expect_status(StatusCode::BAD_REQUEST);
+        let archive = FilterTester::new()
+            .method("GET")
+            .path("/project/test/export/csv?json=flatten")
+            .bearer_header(admin_token)
+            .reply(&export_project_csv(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::OK);
+        assert!(archive.body.clone().unwrap().starts_with(b"PK"));
+        archive.expect_header("Content-Type", "application/zip");
+
+        // Export Parquet and Arrow
+        let parquet = FilterTester::new()
+            .method("GET")
+            .path(format!(
+                "/project/test/export/table/{}/parquet?json=struct",
+                table.name.as_str()
+            ))
+            .bearer_header(admin_token)
+            .reply(&export_table_arrow(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::OK);
+        assert!(parquet.body.clone().unwrap().starts_with(b"PAR1"));
+        parquet.expect_header("Content-Type", "application/vnd.apache.parquet");
+        let stream = FilterTester::new()
+            .method("GET")
+            .path(format!(
+                "/project/test/export/table/{}/arrow?columns=id",
+                table.name.as_str()
+            ))
+            .bearer_header(admin_token)
+            .reply(&export_table_arrow(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::OK);
+        stream.expect_header(
+            "Content-Type",
+            "application/vnd.apache.arrow.stream",
+        );
         FilterTester::new()
-            .method("DELETE")
-            .path("/remove/user/newuser@example.com")
+            .method("GET")
+            .path(format!(
+                "/project/test/export/table/{}/arrow?columns=nope",
+                table.name.as_str()
+            ))
+            .bearer_header(admin_token)
+            .reply(&routes(admindb_ref.clone(), ""))
+            .await
+            .expect_status(StatusCode::CONFLICT);
+        let archive = FilterTester::new()
+            .method("GET")
+            .path("/project/test/export/parquet")
+            .bearer_header(admin_token)
+            .reply(&export_project_arrow(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::OK);
+        assert!(archive.body.clone().unwrap().starts_with(b"PK"));
+        archive.expect_header("Content-Type", "application/zip");
+
+        // Export for statistical packages
+        let dta = FilterTester::new()
+            .method("GET")
+            .path(format!(
+                "/project/test/export/table/{}/dta",
+                table.name.as_str()
+            ))
+            .bearer_header(admin_token)
+            .reply(&export_table_stats(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::OK);
+        assert!(dta.body.clone().unwrap().starts_with(b"<stata_dta>"));
+        dta.expect_header("Content-Type", "application/x-stata-dta");
+        let archive = FilterTester::new()
+            .method("GET")
+            .path("/project/test/export/r")
+            .bearer_header(admin_token)
+            .reply(&routes(admindb_ref.clone(), ""))
+            .await
+            .expect_status(StatusCode::OK);
+        assert!(archive.body.clone().unwrap().starts_with(b"PK"));
+        archive.expect_header("Content-Type", "application/zip");
+        FilterTester::new()
+            .method("GET")
+            .path("/project/test/export/table/missing/sav")
+            .bearer_header(admin_token)
+            .reply(&routes(admindb_ref.clone(), ""))
+            .await
+            .expect_status(StatusCode::NOT_FOUND);
+
+        // Export and import a workbook
+        let workbook = FilterTester::new()
+            .method("GET")
+            .path("/project/test/export/xlsx")
+            .bearer_header(admin_token)
+            .reply(&export_project_xlsx(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::OK);
+        let workbook_data = workbook.body.clone().unwrap();
+        workbook.expect_header(
+            "Content-Type",
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        );
+        let report = FilterTester::new()
+            .method("PUT")
+            .path(
+                "/project/test/import/xlsx?dry_run=true&on_conflict=do_nothing",
+            )
             .bearer_header(admin_token)
-            .reply(&remove_user(admindb_ref.clone()))
+            .raw(&workbook_data)
+            .reply(&import_xlsx(admindb_ref.clone()))
             .await
-            .expect_status(StatusCode::NO_CONTENT);
-
-        // Test projects
-        let test_project1 = db::admin::Project::new(1, "test");
-
-        // Make sure the corresponding databases aren't present
-        log::info!("remove test projects");
-        crate::tests::remove_dbs(
-            &*admindb_ref.lock().await,
-            &[test_project1.get_dbname(TEST_DB_NAME).as_str()],
-        )
-        .await;
-
-        // Create projects
+            .expect_status(StatusCode::OK)
+            .expect_body::<db::user::workbook::XlsxReport>();
+        let imported = report
+            .sheets
+            .iter()
+            .find(|s| s.table == table.name)
+            .unwrap();
+        assert_eq!(imported.insert.unchanged as usize, data.len());
         FilterTester::new()
             .method("PUT")
-            .path(format!("/create/project/{}", test_project1.get_name()))
+            .path("/project/test/import/xlsx")
             .bearer_header(admin_token)
-            .reply(&create_project(admindb_ref.clone()))
+            .raw(b"not a workbook")
+            .reply(&routes(admindb_ref.clone(), ""))
             .await
-            .expect_status(StatusCode::NO_CONTENT);
-        // Get them
-        let projects_obtained = FilterTester::new()
+            .expect_status(StatusCode::BAD_REQUEST);
+
+        // Export and import an ODM document
+        let document = FilterTester::new()
             .method("GET")
-            .path("/get/projects")
+            .path("/project/test/export/odm")
             .bearer_header(admin_token)
-            .reply(&get_user_projects(admindb_ref.clone()))
+            .reply(&export_project_odm(admindb_ref.clone()))
             .await
-            .expect_status(StatusCode::OK)
-            .expect_body::<Vec<admin::Project>>();
-        assert_eq!(projects_obtained.len(), 1);
-        assert_eq!(projects_obtained[0].get_name(), test_project1.get_name());
-        drop(projects_obtained);
-        let project_obtained = FilterTester::new()
-            .method("GET")
-            .path(format!("/get/project/{}", test_project1.get_name()))
+            .expect_status(StatusCode::OK);
+        let document_data = document.body.clone().unwrap();
+        document.expect_header("Content-Type", "application/xml");
+        assert!(document_data.starts_with(b"<?xml"));
+        FilterTester::new()
+            .method("PUT")
+            .path("/project/test/import/odm?dry_run=true")
             .bearer_header(admin_token)
-            .reply(&get_user_project(admindb_ref.clone()))
+            .raw(&document_data)
+            .reply(&routes(admindb_ref.clone(), ""))
             .await
-            .expect_status(StatusCode::OK)
-            .expect_body::<admin::Project>();
-        assert_eq!(project_obtained.get_name(), test_project1.get_name());
-        drop(project_obtained);
-
-        // Create table
-        let table = crate::tests::get_test_primary_table();
-
+            .expect_status(StatusCode::CONFLICT);
         FilterTester::new()
             .method("PUT")
-            .path("/project/test/create/table")
+            .path("/project/test/import/odm")
             .bearer_header(admin_token)
-            .json(table.clone())
-            .reply(&create_table(admindb_ref.clone()))
+            .raw(b"<ODM/>")
+            .reply(&routes(admindb_ref.clone(), ""))
             .await
-            .expect_status(StatusCode::NO_CONTENT);
+            .expect_status(StatusCode::BAD_REQUEST);
+        drop(data_obtained);
 
-        // Get table list
-        let table_list = FilterTester::new()
-            .method("GET")
-            .path("/project/test/get/tablenames")
+        // Remove all table data
+        FilterTester::new()
+            .method("DELETE")
+            .path(format!("/project/test/remove/{}/all", table.name.as_str()))
             .bearer_header(admin_token)
-            .reply(&get_table_names(admindb_ref.clone()))
+            .reply(&remove_all_user_table_data(admindb_ref.clone()))
             .await
-            .expect_status(StatusCode::OK)
-            .expect_body::<Vec<String>>();
-        assert_eq!(table_list, vec![table.name.clone()]);
-        drop(table_list);
+            .expect_status(StatusCode::NO_CONTENT);
 
-        // Get table metadata
-        let table_meta = FilterTester::new()
-            .method("GET")
-            .path(format!("/project/test/get/table/{}/meta", table.name))
+        // Rename table
+        FilterTester::new()
+            .method("PATCH")
+            .path(format!("/project/test/rename/table/{}/renamed", table.name))
             .bearer_header(admin_token)
-            .reply(&get_table_meta(admindb_ref.clone()))
+            .reply(&rename_table(admindb_ref.clone()))
             .await
-            .expect_status(StatusCode::OK)
-            .expect_body::<db::user::table::TableMeta>();
-        assert_eq!(table_meta, table);
-        drop(table_meta);
+            .expect_status(StatusCode::NO_CONTENT);
+        FilterTester::new()
+            .method("PATCH")
+            .path(format!("/project/test/rename/table/renamed/{}", table.name))
+            .bearer_header(admin_token)
+            .reply(&rename_table(admindb_ref.clone()))
+            .await
+            .expect_status(StatusCode::NO_CONTENT);
 
-        // Get all metadata
-        let all_meta = FilterTester::new()
+        // Get table dependents
+        let dependents = FilterTester::new()
             .method("GET")
-            .path("/project/test/get/meta")
+            .path(format!("/project/test/get/table/{}/dependents", table.name))
             .bearer_header(admin_token)
-            .reply(&get_all_meta(admindb_ref.clone()))
+            .reply(&get_table_dependents(admindb_ref.clone()))
             .await
             .expect_status(StatusCode::OK)
-            .expect_body::<db::user::table::TableSpec>();
-        assert_eq!(all_meta, vec![table.clone()]);
-        drop(all_meta);
+            .expect_body::<Vec<db::user::table::TableDependent>>();
+        assert!(dependents.is_empty());
 
-        // Insert table data
-        let data = crate::tests::get_primary_data();
+        // Create, get and remove an index
+        let index =
+            db::user::table::TableIndex::new("primary_email", &["email"])
+                .predicate("(id > 0)");
         FilterTester::new()
             .method("PUT")
-            .path(format!("/project/test/insert/{}", table.name.as_str()))
+            .path(format!("/project/test/create/index/{}", table.name))
             .bearer_header(admin_token)
-            .json(data.clone())
-            .reply(&insert_data(admindb_ref.clone()))
+            .json(index.clone())
+            .reply(&create_index(admindb_ref.clone()))
             .await
             .expect_status(StatusCode::NO_CONTENT);
-
-        // Get table data
-        let data_obtained = FilterTester::new()
+        let indexes = FilterTester::new()
             .method("GET")
-            .path(format!(
-                "/project/test/get/table/{}/data",
-                table.name.as_str()
-            ))
+            .path(format!("/project/test/get/table/{}/indexes", table.name))
             .bearer_header(admin_token)
-            .reply(&get_table_data(admindb_ref.clone()))
+            .reply(&get_table_indexes(admindb_ref.clone()))
             .await
             .expect_status(StatusCode::OK)
-            .expect_body::<Vec<RowJson>>();
-        assert_eq!(data_obtained, data);
-        drop(data_obtained);
-
-        // Remove all table data
+            .expect_body::<Vec<db::user::table::TableIndex>>();
+        assert_eq!(indexes, vec![index.clone()]);
         FilterTester::new()
             .method("DELETE")
-            .path(format!("/project/test/remove/{}/all", table.name.as_str()))
+            .path(format!(
+                "/project/test/remove/index/{}/{}",
+                table.name, index.name
+            ))
             .bearer_header(admin_token)
-            .reply(&remove_all_user_table_data(admindb_ref.clone()))
+            .reply(&remove_index(admindb_ref.clone()))
             .await
             .expect_status(StatusCode::NO_CONTENT);
 
@@ -1026,7 +2460,8 @@ mod tests {
             .bearer_header(admin_token)
             .reply(&remove_table(admindb_ref.clone()))
             .await
-            .expect_status(StatusCode::NO_CONTENT);
+            .expect_status(StatusCode::OK)
+            .expect_body::<Vec<db::user::table::TableDependent>>();
 
         // Delete projects
         FilterTester::new()
@@ -1206,6 +2641,274 @@ mod tests {
             .expect_status(StatusCode::NOT_FOUND)
             .expect_error("NoSuchTable(\"nonexistent\")");
 
+        log::info!("insert values that don't convert");
+        FilterTester::new()
+            .method("PUT")
+            .path("/project/test/create/table")
+            .bearer_header(admin_token)
+            .json(crate::tests::get_test_primary_table())
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::NO_CONTENT);
+        let mut bad_data = crate::tests::get_primary_data();
+        bad_data[1].insert("id".to_string(), serde_json::json!("two"));
+        FilterTester::new()
+            .method("PUT")
+            .path("/project/test/insert/primary")
+            .bearer_header(admin_token)
+            .json(bad_data.clone())
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::BAD_REQUEST)
+            .expect_error(
+                "InvalidRows([RowError { row: 1, \
+                column: Some(\"id\"), \
+                value: Some(String(\"two\")), \
+                reason: \"\\\"two\\\" is not an integer\" }])",
+            );
+        let report = FilterTester::new()
+            .method("PUT")
+            .path("/project/test/insert/primary?partial=true")
+            .bearer_header(admin_token)
+            .json(bad_data)
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::OK)
+            .expect_body::<db::user::table::InsertReport>();
+        assert_eq!(report.keys.len(), 1);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].row, 1);
+
+        log::info!("bulk load");
+        let bulk_data = "{\"id\": 10, \"email\": \"bulk1@example.com\"}\n\
+            {\"id\": \"11\", \"email\": \"bulk2@example.com\"}\n";
+        let rows = FilterTester::new()
+            .method("PUT")
+            .path("/project/test/insert/primary/bulk")
+            .bearer_header(admin_token)
+            .raw(bulk_data.as_bytes())
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::OK)
+            .expect_body::<u64>();
+        assert_eq!(rows, 2);
+        FilterTester::new()
+            .method("PUT")
+            .path("/project/test/insert/primary/bulk")
+            .bearer_header(admin_token)
+            .raw(bulk_data.as_bytes())
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::BAD_REQUEST);
+
+        log::info!("import csv");
+        let csv = "id,email\n20,csv20@example.com\n21,csv20@example.com\n";
+        let preview = FilterTester::new()
+            .method("PUT")
+            .path("/project/test/insert/primary/csv?dry_run=true")
+            .bearer_header(admin_token)
+            .raw(csv.as_bytes())
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::OK)
+            .expect_body::<db::user::import::CsvReport>();
+        assert_eq!(preview.rows.len(), 2);
+        assert_eq!(preview.insert.errors.len(), 1);
+        assert_eq!(preview.insert.errors[0].row, 1);
+        FilterTester::new()
+            .method("PUT")
+            .path("/project/test/insert/primary/csv")
+            .bearer_header(admin_token)
+            .raw(csv.as_bytes())
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::BAD_REQUEST);
+        let report = FilterTester::new()
+            .method("PUT")
+            .path(
+                "/project/test/insert/primary/csv\
+                ?delimiter=%3B&header=false&partial=true\
+                &mapping=%7B%221%22%3A%22id%22%2C%222%22%3A%22email%22%7D",
+            )
+            .bearer_header(admin_token)
+            .raw(b"22;csv22@example.com\n23;NA\n")
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::OK)
+            .expect_body::<db::user::import::CsvReport>();
+        assert_eq!(report.insert.inserted, 2);
+        FilterTester::new()
+            .method("PUT")
+            .path("/project/test/insert/primary/csv")
+            .bearer_header(admin_token)
+            .raw(b"id,age\n24,30\n")
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::CONFLICT);
+
+        log::info!("upsert");
+        let bulk_data: Vec<RowJson> = bulk_data
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let report = FilterTester::new()
+            .method("PUT")
+            .path("/project/test/insert/primary?on_conflict=update_all")
+            .bearer_header(admin_token)
+            .json(bulk_data.clone())
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::OK)
+            .expect_body::<db::user::table::InsertReport>();
+        assert_eq!((report.inserted, report.updated), (0, 2));
+        FilterTester::new()
+            .method("PUT")
+            .path(
+                "/project/test/insert/primary\
+                ?on_conflict=do_nothing&constraint=nonexistent",
+            )
+            .bearer_header(admin_token)
+            .json(bulk_data)
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::NOT_FOUND);
+
+        log::info!("remove rows");
+        let removed = FilterTester::new()
+            .method("DELETE")
+            .path("/project/test/remove/primary/rows")
+            .bearer_header(admin_token)
+            .json(serde_json::json!({"keys": [{"id": 11}]}))
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::OK)
+            .expect_body::<u64>();
+        assert_eq!(removed, 1);
+        FilterTester::new()
+            .method("DELETE")
+            .path("/project/test/remove/primary/rows")
+            .bearer_header(admin_token)
+            .json(serde_json::json!({"filter": {"and": []}}))
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::CONFLICT)
+            .expect_error("SelectionMatchesAll(\"primary\")");
+        FilterTester::new()
+            .method("DELETE")
+            .path("/project/test/remove/primary/rows")
+            .bearer_header(admin_token)
+            .json(serde_json::json!({"filter": {"eq": {"column": "id", "value": "x"}}}))
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::BAD_REQUEST);
+
+        log::info!("update by primary key");
+        FilterTester::new()
+            .method("PATCH")
+            .path("/project/test/update/primary")
+            .bearer_header(admin_token)
+            .json(serde_json::json!([{"id": 10, "email": "new@example.com"}]))
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::NO_CONTENT);
+        FilterTester::new()
+            .method("PATCH")
+            .path("/project/test/update/primary")
+            .bearer_header(admin_token)
+            .json(serde_json::json!([{"id": 12, "email": "new@example.com"}]))
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::BAD_REQUEST)
+            .expect_error(
+                "InvalidRows([RowError { row: 0, column: None, value: None, \
+                reason: \"no row with this primary key\" }])",
+            );
+
+        log::info!("rename a column to one that exists");
+        FilterTester::new()
+            .method("PATCH")
+            .path("/project/test/alter/table/primary")
+            .bearer_header(admin_token)
+            .json(vec![db::user::table::TableAlter::RenameColumn {
+                name: "id".to_string(),
+                new_name: "email".to_string(),
+            }])
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::CONFLICT)
+            .expect_error("ColumnAlreadyExists(\"email\")");
+
+        log::info!("apply a destructive schema without allowing it");
+        FilterTester::new()
+            .method("POST")
+            .path("/project/test/apply/schema?allow_destructive=false")
+            .bearer_header(admin_token)
+            .json(Vec::<db::user::table::TableMeta>::new())
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::CONFLICT)
+            .expect_error(
+                "DestructivePlan([\"table \\\"primary\\\" will be dropped \
+                with its data\"])",
+            );
+
+        log::info!("bad query string");
+        FilterTester::new()
+            .method("POST")
+            .path("/project/test/apply/schema?allow_destructive=maybe")
+            .bearer_header(admin_token)
+            .json(Vec::<db::user::table::TableMeta>::new())
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::BAD_REQUEST)
+            .expect_error("Invalid query string");
+
+        log::info!("remove an index that is not there");
+        FilterTester::new()
+            .method("DELETE")
+            .path("/project/test/remove/index/primary/nonexistent")
+            .bearer_header(admin_token)
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::NOT_FOUND)
+            .expect_error("NoSuchIndex(\"nonexistent\")");
+
+        log::info!("remove a table that others reference");
+        FilterTester::new()
+            .method("PUT")
+            .path("/project/test/create/table")
+            .bearer_header(admin_token)
+            .json(crate::tests::get_test_secondary_table())
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::NO_CONTENT);
+        FilterTester::new()
+            .method("DELETE")
+            .path("/project/test/remove/table/primary")
+            .bearer_header(admin_token)
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::CONFLICT)
+            .expect_error(
+                "TableHasDependents(\"primary\", [ForeignKey { \
+                table: \"secondary\", constraint: \"secondary_id_fkey\" }])",
+            );
+        let dropped = FilterTester::new()
+            .method("DELETE")
+            .path("/project/test/remove/table/primary?cascade=true")
+            .bearer_header(admin_token)
+            .reply(&routes)
+            .await
+            .expect_status(StatusCode::OK)
+            .expect_body::<Vec<db::user::table::TableDependent>>();
+        assert_eq!(
+            dropped,
+            vec![db::user::table::TableDependent::ForeignKey {
+                table: "secondary".to_string(),
+                constraint: "secondary_id_fkey".to_string(),
+            }]
+        );
+
         // Delete the project created earlier ---------------------------------
         FilterTester::new()
             .method("DELETE")
@@ -1320,6 +3023,6 @@ mod tests {
 
         // Remove the test database -------------------------------------------
 
-        crate::tests::remove_test_db(&*admindb_ref.lock().await.get_db()).await;
+        crate::tests::remove_test_db(admindb_ref.lock().await.get_db()).await;
     }
 }
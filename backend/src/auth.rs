@@ -21,7 +21,7 @@ pub fn hash_fast(token: &str) -> String {
     let mut hasher = sha2::Sha256::new();
     hasher.update(token.as_bytes());
     let hash_result = hasher.finalize();
-    hex::encode(&hash_result)
+    hex::encode(hash_result)
 }
 
 /// Generates a random string
@@ -45,23 +45,6 @@ pub fn parse_bearer_header(raw: &str) -> Result<&str> {
     Ok(header[1])
 }
 
-/// Authentication outcome for email/password
-#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
-pub enum PasswordOutcome {
-    /// Contains the auth token
-    Ok(Token),
-    WrongPassword,
-    EmailNotFound,
-}
-
-/// Authentication outcome for id/token
-#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
-pub enum TokenOutcome {
-    Ok(Access),
-    TokenTooOld,
-    TokenNotFound,
-}
-
 /// Auth token
 #[derive(
     serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq, sqlx::FromRow,
@@ -144,7 +127,8 @@ mod tests {
         use chrono::prelude::*;
         let mut tok = Token::new(1);
         assert!(tok.age_hours() < 1);
-        tok.created = chrono::Utc.ymd(2000, 1, 1).and_hms(0, 0, 0);
+        tok.created =
+            chrono::Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap();
         assert!(tok.age_hours() > 1000);
     }
 }
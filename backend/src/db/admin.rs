@@ -1,12 +1,19 @@
 use crate::db::{user, Database, Pool, DB};
 use crate::{auth, error::Unauthorized, Error, Result};
-use user::table::{RowJson, TableMeta, TableSpec};
-use user::UserDB;
+use std::sync::Arc;
+use user::filter::RowSelection;
+use user::page::{ReadOptions, TablePage};
+use user::plan::SchemaPlan;
+use user::table::{
+    InsertOptions, InsertReport, RowJson, TableAlter, TableDependent,
+    TableIndex, TableMeta, TableSpec,
+};
+use user::{RowStream, UserDB};
 
 /// Administrative database
 pub struct AdminDB {
     db: DB,
-    user_dbs: Vec<UserDB>,
+    user_dbs: Vec<Arc<UserDB>>,
 }
 
 impl AdminDB {
@@ -91,17 +98,23 @@ impl AdminDB {
 
     /// Locate user db in the vector
     /// If not present, will append an entry.
-    async fn get_user_db(&mut self, project: &Project) -> Result<&UserDB> {
+    /// The handle can be used after releasing the admin database so that
+    /// long operations don't hold up other requests.
+    pub async fn get_user_db(
+        &mut self,
+        project: &Project,
+    ) -> Result<Arc<UserDB>> {
         let name = project.get_dbname(self.get_name());
         if let Some(i) =
             self.user_dbs.iter().position(|db| db.get_name() == name)
         {
-            return Ok(&self.user_dbs[i]);
+            return Ok(self.user_dbs[i].clone());
         };
-        let db =
-            UserDB::new(self.db.get_config().clone(), name.as_str()).await?;
-        self.user_dbs.push(db);
-        Ok(&self.user_dbs.last().unwrap())
+        let db = Arc::new(
+            UserDB::new(self.db.get_config().clone(), name.as_str()).await?,
+        );
+        self.user_dbs.push(db.clone());
+        Ok(db)
     }
 
     /// Creates tables
@@ -516,12 +529,116 @@ impl AdminDB {
         &mut self,
         project: &Project,
         table_name: &str,
-    ) -> Result<()> {
+        cascade: bool,
+    ) -> Result<Vec<TableDependent>> {
         let db_name = project.get_dbname(self.get_name());
         log::debug!("removing table {} in database {}", table_name, db_name);
         self.get_user_db(project)
             .await?
-            .remove_table(table_name)
+            .remove_table(table_name, cascade)
+            .await
+    }
+    /// Renames a table in a user's database
+    pub async fn rename_user_table(
+        &mut self,
+        project: &Project,
+        table_name: &str,
+        new_name: &str,
+    ) -> Result<()> {
+        log::debug!(
+            "renaming table \"{}\" to \"{}\" in project \"{}\"",
+            table_name,
+            new_name,
+            project.name
+        );
+        self.get_user_db(project)
+            .await?
+            .rename_table(table_name, new_name)
+            .await
+    }
+    /// Get the objects that depend on a user's table
+    pub async fn get_user_table_dependents(
+        &mut self,
+        project: &Project,
+        table_name: &str,
+    ) -> Result<Vec<TableDependent>> {
+        log::debug!(
+            "getting dependents of table \"{}\" in project \"{}\"",
+            table_name,
+            project.name
+        );
+        self.get_user_db(project)
+            .await?
+            .get_table_dependents(table_name)
+            .await
+    }
+    /// Get the indexes of a user's table
+    pub async fn get_user_table_indexes(
+        &mut self,
+        project: &Project,
+        table_name: &str,
+    ) -> Result<Vec<TableIndex>> {
+        log::debug!(
+            "getting indexes of table \"{}\" in project \"{}\"",
+            table_name,
+            project.name
+        );
+        self.get_user_db(project)
+            .await?
+            .get_indexes(table_name)
+            .await
+    }
+    /// Creates an index on a user's table
+    pub async fn create_user_index(
+        &mut self,
+        project: &Project,
+        table_name: &str,
+        index: &TableIndex,
+    ) -> Result<()> {
+        log::debug!(
+            "creating index \"{}\" on table \"{}\" in project \"{}\"",
+            index.name,
+            table_name,
+            project.name
+        );
+        self.get_user_db(project)
+            .await?
+            .create_index(table_name, index)
+            .await
+    }
+    /// Removes an index of a user's table
+    pub async fn remove_user_index(
+        &mut self,
+        project: &Project,
+        table_name: &str,
+        index_name: &str,
+    ) -> Result<()> {
+        log::debug!(
+            "removing index \"{}\" of table \"{}\" in project \"{}\"",
+            index_name,
+            table_name,
+            project.name
+        );
+        self.get_user_db(project)
+            .await?
+            .remove_index(table_name, index_name)
+            .await
+    }
+    /// Alters a table in a user's database
+    pub async fn alter_user_table(
+        &mut self,
+        project: &Project,
+        table_name: &str,
+        alters: &[TableAlter],
+    ) -> Result<()> {
+        log::debug!(
+            "altering table \"{}\" in project \"{}\"",
+            table_name,
+            project.name
+        );
+        self.get_user_db(project)
+            .await?
+            .alter_table(table_name, alters)
             .await
     }
     /// Get table names from a user db
@@ -559,21 +676,81 @@ impl AdminDB {
         );
         self.get_user_db(project).await?.get_all_meta().await
     }
+    /// Plan the changes to a user's schema
+    pub async fn plan_user_schema(
+        &mut self,
+        project: &Project,
+        desired: &[TableMeta],
+    ) -> Result<SchemaPlan> {
+        log::debug!("planning schema for project \"{}\"", project.name);
+        self.get_user_db(project).await?.plan_schema(desired).await
+    }
+    /// Apply the changes to a user's schema
+    pub async fn apply_user_schema(
+        &mut self,
+        project: &Project,
+        desired: &[TableMeta],
+        allow_destructive: bool,
+    ) -> Result<SchemaPlan> {
+        log::debug!("applying schema to project \"{}\"", project.name);
+        self.get_user_db(project)
+            .await?
+            .apply_schema(desired, allow_destructive)
+            .await
+    }
     /// Insert data into a user's table
     pub async fn insert_user_table_data(
         &mut self,
         project: &Project,
         table_name: &str,
         data: &[RowJson],
+        options: &InsertOptions,
+    ) -> Result<InsertReport> {
+        log::debug!(
+            "inserting into table \"{}\" from project \"{}\" ({:?})",
+            table_name,
+            project.name,
+            options
+        );
+        self.get_user_db(project)
+            .await?
+            .insert_table_data(table_name, data, options)
+            .await
+    }
+    /// Update rows of a user's table by primary key
+    pub async fn update_user_table_data(
+        &mut self,
+        project: &Project,
+        table_name: &str,
+        data: &[RowJson],
     ) -> Result<()> {
         log::debug!(
-            "inserting into table \"{}\" from project \"{}\"",
+            "updating table \"{}\" from project \"{}\"",
             table_name,
             project.name
         );
         self.get_user_db(project)
             .await?
-            .insert_table_data(table_name, data)
+            .update_table_data(table_name, data)
+            .await
+    }
+    /// Remove the selected rows of a user's table
+    pub async fn remove_user_table_rows(
+        &mut self,
+        project: &Project,
+        table_name: &str,
+        selection: &RowSelection,
+        confirm_all: bool,
+    ) -> Result<u64> {
+        log::debug!(
+            "removing rows {:?} of table \"{}\" from project \"{}\"",
+            selection,
+            table_name,
+            project.name
+        );
+        self.get_user_db(project)
+            .await?
+            .remove_table_rows(table_name, selection, confirm_all)
             .await
     }
     /// Remove all data from a user's table
@@ -592,20 +769,40 @@ impl AdminDB {
             .remove_all_table_data(table_name)
             .await
     }
-    /// Get data from a user's table
+    /// Get a page of data from a user's table
     pub async fn get_user_table_data(
         &mut self,
         project: &Project,
         table_name: &str,
-    ) -> Result<Vec<RowJson>> {
+        options: &ReadOptions,
+    ) -> Result<TablePage> {
         log::debug!(
-            "getting table \"{}\" metadata in project \"{}\"",
+            "getting table \"{}\" data in project \"{}\" ({:?})",
             table_name,
-            project.name
+            project.name,
+            options
+        );
+        self.get_user_db(project)
+            .await?
+            .get_table_page(table_name, options)
+            .await
+    }
+    /// Stream data from a user's table
+    pub async fn stream_user_table_data(
+        &mut self,
+        project: &Project,
+        table_name: &str,
+        options: &ReadOptions,
+    ) -> Result<RowStream> {
+        log::debug!(
+            "streaming table \"{}\" data in project \"{}\" ({:?})",
+            table_name,
+            project.name,
+            options
         );
         self.get_user_db(project)
             .await?
-            .get_table_data(table_name)
+            .stream_table_data(table_name, options)
             .await
     }
 }
@@ -738,7 +935,7 @@ mod tests {
 
     /// Verify that a project exists
     async fn project_exists(db: &AdminDB, project: &Project) -> bool {
-        let db_exists = get_db_list(&db)
+        let db_exists = get_db_list(db)
             .await
             .contains(&project.get_dbname(db.get_name()));
         let project_exists = db
@@ -1038,7 +1235,11 @@ mod tests {
 
         log::info!("remove that table");
         test_db
-            .remove_user_table(&user2_test_project, primary_table.name.as_str())
+            .remove_user_table(
+                &user2_test_project,
+                primary_table.name.as_str(),
+                false,
+            )
             .await
             .unwrap();
         let user_db = test_db.get_user_db(&user2_test_project).await.unwrap();
@@ -66,7 +66,7 @@ impl DB {
         // Vector of rows
         let res = sqlx::query(
             "SELECT tablename FROM pg_catalog.pg_tables \
-            WHERE schemaname = 'public';",
+            WHERE schemaname = 'public' ORDER BY tablename;",
         )
         .fetch_all(self.get_pool())
         .await?;
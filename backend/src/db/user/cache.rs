@@ -0,0 +1,172 @@
+use super::table::TableMeta;
+use crate::db::Pool;
+use crate::Result;
+use sqlx::Row;
+use std::collections::HashMap;
+
+/// Table names and metadata of a project, valid for one schema version.
+/// Every drop of the entries starts a new generation, entries read before
+/// it are not stored so that a read racing a schema change can't bring back
+/// what it read.
+#[derive(Debug, Default)]
+pub struct SchemaCache {
+    /// Schema version the entries were read at
+    version: i64,
+    /// Number of times the entries were dropped
+    generation: u64,
+    table_names: Option<Vec<String>>,
+    tables: HashMap<String, TableMeta>,
+}
+
+impl SchemaCache {
+    /// Drops the entries if they were read at another version.
+    /// Returns the generation reads starting now must be stored with.
+    pub fn validate(&mut self, version: i64) -> u64 {
+        if self.version != version {
+            self.clear();
+            self.version = version;
+        }
+        self.generation
+    }
+    /// Drops all entries
+    pub fn clear(&mut self) {
+        self.generation += 1;
+        self.table_names = None;
+        self.tables.clear();
+    }
+    pub fn get_table_names(&self) -> Option<Vec<String>> {
+        self.table_names.clone()
+    }
+    /// Stores the names if nothing was dropped since they were read
+    pub fn set_table_names(&mut self, generation: u64, names: Vec<String>) {
+        if self.generation == generation {
+            self.table_names = Some(names);
+        }
+    }
+    pub fn get_table(&self, name: &str) -> Option<TableMeta> {
+        self.tables.get(name).cloned()
+    }
+    /// Stores the table if nothing was dropped since it was read
+    pub fn set_table(&mut self, generation: u64, table: TableMeta) {
+        if self.generation == generation {
+            self.tables.insert(table.name.clone(), table);
+        }
+    }
+}
+
+/// Installs an event trigger that increments the schema version on every DDL
+/// command, so that changes made outside of the app are noticed.
+/// The version is a table row rather than a sequence so that it only changes
+/// once the DDL commits, concurrent DDL waits on the row.
+/// Event triggers need a superuser, returns false if it could not be
+/// installed.
+pub async fn track_schema_version(pool: &Pool) -> bool {
+    match install_version_trigger(pool).await {
+        Ok(()) => true,
+        Err(e) => {
+            log::warn!(
+                "schema changes made outside of the app won't be noticed, \
+                could not install version trigger: {}",
+                e
+            );
+            false
+        }
+    }
+}
+
+async fn install_version_trigger(pool: &Pool) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("CREATE SCHEMA IF NOT EXISTS \"odc\"")
+        .execute(&mut tx)
+        .await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS \"odc\".\"schema_state\"(\
+            \"version\" bigint NOT NULL\
+        )",
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query(
+        "INSERT INTO \"odc\".\"schema_state\"(\"version\") SELECT 0 \
+        WHERE NOT EXISTS(SELECT 1 FROM \"odc\".\"schema_state\")",
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query(
+        "CREATE OR REPLACE FUNCTION \"odc\".\"bump_schema_version\"() \
+        RETURNS event_trigger LANGUAGE plpgsql AS $$ \
+        BEGIN \
+            UPDATE \"odc\".\"schema_state\" SET \"version\" = \"version\" + 1; \
+        END $$",
+    )
+    .execute(&mut tx)
+    .await?;
+    let installed: bool = sqlx::query(
+        "SELECT EXISTS(SELECT 1 FROM pg_catalog.pg_event_trigger \
+        WHERE evtname = 'odc_schema_version')",
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .get(0);
+    if !installed {
+        sqlx::query(
+            "CREATE EVENT TRIGGER \"odc_schema_version\" ON ddl_command_end \
+            EXECUTE FUNCTION \"odc\".\"bump_schema_version\"()",
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Current schema version, increases with every committed DDL command
+pub async fn get_schema_version(pool: &Pool) -> Result<i64> {
+    let version =
+        sqlx::query("SELECT \"version\" FROM \"odc\".\"schema_state\"")
+            .fetch_one(pool)
+            .await?
+            .get(0);
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn validate_cache() {
+        let _ = pretty_env_logger::try_init();
+        let table = crate::tests::get_test_primary_table();
+        let mut cache = SchemaCache::default();
+        let generation = cache.validate(0);
+        cache.set_table_names(generation, vec![table.name.clone()]);
+        cache.set_table(generation, table.clone());
+        assert_eq!(cache.get_table_names(), Some(vec![table.name.clone()]));
+        assert_eq!(cache.get_table(table.name.as_str()), Some(table.clone()));
+
+        log::info!("same version keeps entries");
+        cache.validate(0);
+        assert_eq!(cache.get_table(table.name.as_str()), Some(table.clone()));
+
+        log::info!("new version drops entries");
+        cache.validate(1);
+        assert!(cache.get_table_names().is_none());
+        assert!(cache.get_table(table.name.as_str()).is_none());
+
+        log::info!("entries read at an old version are not stored");
+        let stale = cache.validate(1) - 1;
+        cache.set_table(stale, table.clone());
+        assert!(cache.get_table(table.name.as_str()).is_none());
+
+        log::info!("entries read before a clear are not stored");
+        let generation = cache.validate(1);
+        cache.clear();
+        assert_eq!(cache.validate(1), generation + 1);
+        cache.set_table_names(generation, vec![table.name.clone()]);
+        cache.set_table(generation, table.clone());
+        assert!(cache.get_table_names().is_none());
+        assert!(cache.get_table(table.name.as_str()).is_none());
+        cache.set_table(generation + 1, table.clone());
+        assert_eq!(cache.get_table(table.name.as_str()), Some(table));
+    }
+}
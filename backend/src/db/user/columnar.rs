@@ -0,0 +1,534 @@
+use super::export::JsonShape;
+use super::table::{ColMeta, RowJson, TableMeta};
+use super::types::PostgresType;
+use super::value::BindValue;
+use super::{ByteStream, RowStream, STREAM_BUFFER_ROWS};
+use crate::Result;
+use arrow::datatypes::{DataType, Field, Fields, Schema, SchemaRef, TimeUnit};
+use arrow::record_batch::RecordBatch;
+use futures::StreamExt;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Field metadata naming the column's Postgres type
+pub const POSTGRES_TYPE_KEY: &str = "postgres_type";
+/// Field metadata marking strings that hold json text
+const EXTENSION_KEY: &str = "ARROW:extension:name";
+const JSON_EXTENSION: &str = "arrow.json";
+/// Largest precision of a 128 bit decimal
+const MAX_DECIMAL_PRECISION: u32 = 38;
+/// Rows of a Parquet row group
+const ROW_GROUP_ROWS: usize = 1 << 16;
+
+/// File format of a columnar export
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArrowFormat {
+    /// Parquet file compressed with Snappy
+    Parquet,
+    /// Arrow IPC stream
+    Arrow,
+}
+
+impl ArrowFormat {
+    /// Extension of files in this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Parquet => "parquet",
+            Self::Arrow => "arrows",
+        }
+    }
+    /// Media type of files in this format
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Parquet => "application/vnd.apache.parquet",
+            Self::Arrow => "application/vnd.apache.arrow.stream",
+        }
+    }
+}
+
+impl std::str::FromStr for ArrowFormat {
+    type Err = ();
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        match s {
+            "parquet" => Ok(Self::Parquet),
+            "arrow" => Ok(Self::Arrow),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How the values of json columns are written
+#[derive(
+    Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum JsonArrow {
+    /// Strings of json text
+    #[default]
+    String,
+    /// Structs with a field per key found in the table's objects.
+    /// Keys whose values are all booleans, numbers or strings get that
+    /// type, others json text. Columns with values that are not objects
+    /// are written as strings.
+    Struct,
+}
+
+/// How to write a table as Parquet or Arrow
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct ArrowOptions {
+    #[serde(default)]
+    pub json: JsonArrow,
+}
+
+impl ArrowOptions {
+    pub fn json(mut self, val: JsonArrow) -> Self {
+        self.json = val;
+        self
+    }
+}
+
+/// Rows of a table to write as Parquet or Arrow
+#[derive(Debug, Clone)]
+pub struct ArrowExport {
+    pub table_name: String,
+    pub schema: SchemaRef,
+    /// Query for the rows and the values of its parameters
+    pub query: String,
+    pub values: Vec<BindValue>,
+}
+
+/// Columns that are read as text to keep their values exact
+pub fn text_columns(table: &TableMeta) -> Vec<&str> {
+    table
+        .cols
+        .iter()
+        .filter(|c| matches!(c.postgres_type, PostgresType::Numeric(_)))
+        .map(|c| c.name.as_str())
+        .collect()
+}
+
+/// Arrow schema of the table's columns, all of them if none are given.
+/// Json columns become structs by their shapes if the options say so.
+pub fn arrow_schema(
+    table: &TableMeta,
+    columns: &[String],
+    shapes: &HashMap<String, JsonShape>,
+    options: &ArrowOptions,
+) -> Result<SchemaRef> {
+    table.verify_cols_present(columns)?;
+    let cols: Vec<&ColMeta> = if columns.is_empty() {
+        table.cols.iter().collect()
+    } else {
+        columns.iter().filter_map(|c| table.get_col(c)).collect()
+    };
+    let fields: Vec<Field> = cols
+        .into_iter()
+        .map(|col| {
+            let shape = match options.json {
+                JsonArrow::Struct => shapes
+                    .get(&col.name)
+                    .filter(|s| !s.other && !s.keys.is_empty()),
+                JsonArrow::String => None,
+            };
+            let field = match shape {
+                Some(shape) => Field::new(
+                    col.name.as_str(),
+                    DataType::Struct(shape_fields(shape)),
+                    !col.not_null,
+                ),
+                None => column_field(
+                    col.name.as_str(),
+                    &col.postgres_type,
+                    !col.not_null,
+                ),
+            };
+            let mut metadata = field.metadata().clone();
+            metadata.insert(
+                POSTGRES_TYPE_KEY.to_string(),
+                col.postgres_type.to_string(),
+            );
+            field.with_metadata(metadata)
+        })
+        .collect();
+    Ok(Arc::new(Schema::new(fields)))
+}
+
+/// Field of a column's values
+fn column_field(name: &str, postgres_type: &PostgresType, null: bool) -> Field {
+    use PostgresType as T;
+    let data_type = match postgres_type {
+        T::SmallInt => DataType::Int16,
+        T::Integer => DataType::Int32,
+        T::BigInt => DataType::Int64,
+        T::Real => DataType::Float32,
+        T::DoublePrecision => DataType::Float64,
+        T::Numeric(Some((precision, scale)))
+            if *precision <= MAX_DECIMAL_PRECISION =>
+        {
+            DataType::Decimal128(*precision as u8, *scale as i8)
+        }
+        T::Boolean => DataType::Boolean,
+        T::Date => DataType::Date32,
+        T::Time => DataType::Time64(TimeUnit::Microsecond),
+        T::Timestamp => DataType::Timestamp(TimeUnit::Microsecond, None),
+        T::Timestamptz => {
+            DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()))
+        }
+        T::Bytea => DataType::Binary,
+        T::Json | T::Jsonb => return json_field(name, null),
+        T::Array(element) => {
+            DataType::List(Arc::new(column_field("item", element, true)))
+        }
+        T::Numeric(_) | T::Text | T::Varchar(_) | T::Uuid | T::Interval => {
+            DataType::Utf8
+        }
+    };
+    Field::new(name, data_type, null)
+}
+
+/// Field of json text
+fn json_field(name: &str, null: bool) -> Field {
+    Field::new(name, DataType::Utf8, null).with_metadata(HashMap::from([(
+        EXTENSION_KEY.to_string(),
+        JSON_EXTENSION.to_string(),
+    )]))
+}
+
+/// Fields of a struct for the objects of a json column
+fn shape_fields(shape: &JsonShape) -> Fields {
+    shape
+        .keys
+        .iter()
+        .map(|key| {
+            let types: Vec<&str> =
+                key.types.iter().map(|t| t.as_str()).collect();
+            let data_type = match types.as_slice() {
+                ["boolean"] => DataType::Boolean,
+                ["number"] => DataType::Float64,
+                ["string"] => DataType::Utf8,
+                _ => return json_field(key.name.as_str(), true),
+            };
+            Field::new(key.name.as_str(), data_type, true)
+        })
+        .collect()
+}
+
+/// Whether the field holds json text
+fn is_json(field: &Field) -> bool {
+    field.metadata().get(EXTENSION_KEY).map(|e| e.as_str())
+        == Some(JSON_EXTENSION)
+}
+
+/// Value as the json decoder reads it for the field
+fn prepare_value(value: &Value, field: &Field) -> Value {
+    match (field.data_type(), value) {
+        (_, Value::Null) => Value::Null,
+        (DataType::Utf8, value) if is_json(field) => {
+            Value::String(value.to_string())
+        }
+        (DataType::Utf8, Value::String(_)) => value.clone(),
+        (DataType::Utf8, value) => Value::String(value.to_string()),
+        // Postgres writes bytea as hex with a prefix
+        (DataType::Binary, Value::String(s)) => {
+            Value::String(s.trim_start_matches("\\x").to_string())
+        }
+        (DataType::Struct(fields), Value::Object(o)) => Value::Object(
+            fields
+                .iter()
+                .map(|f| {
+                    let value = o.get(f.name()).unwrap_or(&Value::Null);
+                    (f.name().clone(), prepare_value(value, f))
+                })
+                .collect(),
+        ),
+        (DataType::Struct(_), _) => Value::Null,
+        (DataType::List(element), Value::Array(a)) => {
+            Value::Array(a.iter().map(|v| prepare_value(v, element)).collect())
+        }
+        _ => value.clone(),
+    }
+}
+
+/// Record batch of the rows
+fn record_batch(schema: &SchemaRef, rows: &[RowJson]) -> Result<RecordBatch> {
+    let rows: Vec<RowJson> = rows
+        .iter()
+        .map(|row| {
+            schema
+                .fields()
+                .iter()
+                .map(|f| {
+                    let value = row.get(f.name()).unwrap_or(&Value::Null);
+                    (f.name().clone(), prepare_value(value, f))
+                })
+                .collect()
+        })
+        .collect();
+    let mut decoder = arrow::json::ReaderBuilder::new(schema.clone())
+        .with_batch_size(rows.len().max(1))
+        .build_decoder()?;
+    decoder.serialize(&rows)?;
+    Ok(decoder
+        .flush()?
+        .unwrap_or_else(|| RecordBatch::new_empty(schema.clone())))
+}
+
+/// Writer of record batches into a buffer that is taken as it fills
+enum BatchWriter {
+    Parquet(parquet::arrow::ArrowWriter<Vec<u8>>),
+    Arrow(arrow::ipc::writer::StreamWriter<Vec<u8>>),
+}
+
+impl BatchWriter {
+    fn new(schema: &SchemaRef, format: ArrowFormat) -> Result<Self> {
+        Ok(match format {
+            ArrowFormat::Parquet => {
+                let properties =
+                    parquet::file::properties::WriterProperties::builder()
+                        .set_compression(parquet::basic::Compression::SNAPPY)
+                        .set_max_row_group_row_count(Some(ROW_GROUP_ROWS))
+                        .build();
+                Self::Parquet(parquet::arrow::ArrowWriter::try_new(
+                    Vec::new(),
+                    schema.clone(),
+                    Some(properties),
+                )?)
+            }
+            ArrowFormat::Arrow => Self::Arrow(
+                arrow::ipc::writer::StreamWriter::try_new(Vec::new(), schema)?,
+            ),
+        })
+    }
+
+    /// Bytes written for the batch so far
+    fn write(&mut self, batch: &RecordBatch) -> Result<Vec<u8>> {
+        Ok(match self {
+            Self::Parquet(writer) => {
+                writer.write(batch)?;
+                std::mem::take(writer.inner_mut())
+            }
+            Self::Arrow(writer) => {
+                writer.write(batch)?;
+                std::mem::take(writer.get_mut())
+            }
+        })
+    }
+
+    /// Bytes left to write, with the footer of the file
+    fn finish(self) -> Result<Vec<u8>> {
+        Ok(match self {
+            Self::Parquet(writer) => writer.into_inner()?,
+            Self::Arrow(mut writer) => {
+                writer.finish()?;
+                std::mem::take(writer.get_mut())
+            }
+        })
+    }
+}
+
+/// Parquet file or Arrow stream of the rows, written as they are read.
+/// Rows that are ready together are written as one batch.
+/// The stream ends after an error.
+pub fn write_arrow(
+    schema: SchemaRef,
+    rows: RowStream,
+    format: ArrowFormat,
+) -> Result<ByteStream> {
+    let writer = BatchWriter::new(&schema, format)?;
+    let chunks = rows.ready_chunks(STREAM_BUFFER_ROWS);
+    Ok(Box::pin(futures::stream::unfold(
+        Some((chunks, writer)),
+        move |state| {
+            let schema = schema.clone();
+            async move {
+                let (mut chunks, mut writer) = state?;
+                let bytes = match chunks.next().await {
+                    Some(rows) => {
+                        match rows.into_iter().collect::<Result<Vec<_>>>() {
+                            Ok(rows) => record_batch(&schema, &rows)
+                                .and_then(|batch| writer.write(&batch)),
+                            Err(e) => Err(e),
+                        }
+                    }
+                    None => return Some((writer.finish(), None)),
+                };
+                match bytes {
+                    Ok(bytes) => Some((Ok(bytes), Some((chunks, writer)))),
+                    Err(e) => Some((Err(e), None)),
+                }
+            }
+        },
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::export::JsonKey;
+    use super::*;
+    use arrow::array::{
+        Array, BinaryArray, BooleanArray, Date32Array, Decimal128Array,
+        Float64Array, Int32Array, ListArray, StringArray, StructArray,
+        TimestampMicrosecondArray,
+    };
+    use futures::TryStreamExt;
+    use serde_json::json;
+
+    fn read_batches(data: Vec<u8>, format: ArrowFormat) -> Vec<RecordBatch> {
+        match format {
+            ArrowFormat::Parquet => {
+                parquet::arrow::arrow_reader::ParquetRecordBatchReader::try_new(
+                    bytes::Bytes::from(data),
+                    1024,
+                )
+                .unwrap()
+                .collect::<std::result::Result<_, _>>()
+                .unwrap()
+            }
+            ArrowFormat::Arrow => arrow::ipc::reader::StreamReader::try_new(
+                std::io::Cursor::new(data),
+                None,
+            )
+            .unwrap()
+            .collect::<std::result::Result<_, _>>()
+            .unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn export_arrow() {
+        let _ = pretty_env_logger::try_init();
+        let mut table = crate::tests::get_visit_table();
+        table.cols.push(ColMeta::new().name("scores").postgres_type(
+            PostgresType::Array(Box::new(PostgresType::Integer)),
+        ));
+        table.cols.push(
+            ColMeta::new()
+                .name("scan")
+                .postgres_type(PostgresType::Bytea),
+        );
+        assert_eq!(text_columns(&table), vec!["weight"]);
+        let shapes = HashMap::from([(
+            "symptoms".to_string(),
+            JsonShape {
+                keys: vec![
+                    JsonKey::new("cough", &["boolean"]),
+                    JsonKey::new("fever", &["number"]),
+                    JsonKey::new("note", &["number", "string"]),
+                ],
+                other: false,
+            },
+        )]);
+        // Numerics are read as text
+        let mut rows = crate::tests::get_visit_data();
+        rows[0]["weight"] = json!("72.5");
+        rows[0]["symptoms"] = json!({"cough": true, "fever": 38.5, "note": 1});
+        rows[1]["symptoms"] = json!({"note": "mild"});
+        rows[0].insert("scores".to_string(), json!([1, null, 3]));
+        rows[0].insert("scan".to_string(), json!("\\x0aff"));
+        rows[1].insert("scores".to_string(), json!(null));
+        rows[1].insert("scan".to_string(), json!(null));
+
+        log::info!("schema");
+        let schema = arrow_schema(
+            &table,
+            &[],
+            &shapes,
+            &ArrowOptions::default().json(JsonArrow::Struct),
+        )
+        .unwrap();
+        assert_eq!(schema.field(0).data_type(), &DataType::Int32);
+        assert!(!schema.field(6).is_nullable());
+        assert_eq!(
+            schema.field(3).data_type(),
+            &DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()))
+        );
+        assert_eq!(schema.field(5).data_type(), &DataType::Decimal128(5, 1));
+        assert_eq!(
+            schema.field(5).metadata().get(POSTGRES_TYPE_KEY).unwrap(),
+            "numeric(5,1)"
+        );
+        let string_schema =
+            arrow_schema(&table, &[], &shapes, &ArrowOptions::default())
+                .unwrap();
+        assert!(is_json(string_schema.field(7)));
+        let selected = arrow_schema(
+            &table,
+            &["sick".to_string(), "id".to_string()],
+            &shapes,
+            &ArrowOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(selected.field(0).name(), "sick");
+        assert!(arrow_schema(
+            &table,
+            &["nope".to_string()],
+            &shapes,
+            &ArrowOptions::default(),
+        )
+        .is_err());
+
+        for format in [ArrowFormat::Parquet, ArrowFormat::Arrow] {
+            log::info!("round trip {:?}", format);
+            let stream: RowStream = Box::pin(futures::stream::iter(
+                rows.clone().into_iter().map(Ok),
+            ));
+            let data: Vec<Vec<u8>> =
+                write_arrow(schema.clone(), stream, format)
+                    .unwrap()
+                    .try_collect()
+                    .await
+                    .unwrap();
+            let batches = read_batches(data.concat(), format);
+            assert_eq!(batches.len(), 1);
+            let batch = &batches[0];
+            assert_eq!(batch.num_rows(), 2);
+            let column = |name: &str| batch.column_by_name(name).unwrap();
+            let ids = column("id").as_any().downcast_ref::<Int32Array>();
+            assert_eq!(ids.unwrap().values(), &[1, 2]);
+            let dates = column("date").as_any().downcast_ref::<Date32Array>();
+            assert_eq!(dates.unwrap().value(0), 18687);
+            assert!(dates.unwrap().is_null(1));
+            let seen = column("seen")
+                .as_any()
+                .downcast_ref::<TimestampMicrosecondArray>()
+                .unwrap();
+            assert_eq!(seen.value(0), 1_614_580_200_000_000);
+            let sick = column("sick").as_any().downcast_ref::<BooleanArray>();
+            assert!(sick.unwrap().value(0));
+            let weight = column("weight")
+                .as_any()
+                .downcast_ref::<Decimal128Array>()
+                .unwrap();
+            assert_eq!(weight.value_as_string(0), "72.5");
+            let scores = column("scores")
+                .as_any()
+                .downcast_ref::<ListArray>()
+                .unwrap();
+            assert_eq!(scores.value(0).len(), 3);
+            assert!(scores.is_null(1));
+            let scan = column("scan").as_any().downcast_ref::<BinaryArray>();
+            assert_eq!(scan.unwrap().value(0), &[0x0a, 0xff]);
+            let symptoms = column("symptoms")
+                .as_any()
+                .downcast_ref::<StructArray>()
+                .unwrap();
+            let fever = symptoms
+                .column_by_name("fever")
+                .unwrap()
+                .as_any()
+                .downcast_ref::<Float64Array>()
+                .unwrap();
+            assert_eq!(fever.value(0), 38.5);
+            assert!(fever.is_null(1));
+            let note = symptoms
+                .column_by_name("note")
+                .unwrap()
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap();
+            assert_eq!(note.value(0), "1");
+            assert_eq!(note.value(1), "\"mild\"");
+        }
+    }
+}
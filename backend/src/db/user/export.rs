@@ -0,0 +1,545 @@
+use super::table::{ColMeta, RowJson, TableMeta};
+use super::types::PostgresType;
+use super::value::BindValue;
+use super::{fetch_rows, ByteStream, RowStream, STREAM_BUFFER_ROWS};
+use crate::db::Pool;
+use crate::{Error, Result};
+use futures::{AsyncWriteExt, StreamExt, TryStreamExt};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Bytes of a zip archive buffered ahead of a slow reader
+const ZIP_BUFFER_SIZE: usize = 1 << 16;
+
+/// How CSV fields are quoted
+#[derive(
+    Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Quoting {
+    /// Only fields with delimiters, quotes or line breaks
+    #[default]
+    Necessary,
+    /// Every field
+    Always,
+    /// Every field that is not a number
+    NonNumeric,
+    /// No field, the file may not be read back
+    Never,
+}
+
+impl From<Quoting> for csv::QuoteStyle {
+    fn from(quoting: Quoting) -> Self {
+        match quoting {
+            Quoting::Necessary => csv::QuoteStyle::Necessary,
+            Quoting::Always => csv::QuoteStyle::Always,
+            Quoting::NonNumeric => csv::QuoteStyle::NonNumeric,
+            Quoting::Never => csv::QuoteStyle::Never,
+        }
+    }
+}
+
+/// How the values of json columns are written
+#[derive(
+    Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum JsonColumns {
+    /// One CSV column with the values as json text
+    #[default]
+    Text,
+    /// One CSV column per key found in the table's objects, named
+    /// `column.key`. Values that are not objects go to a CSV column named
+    /// after the table column, which is left out if there are none.
+    Flatten,
+}
+
+/// How to write a table as a CSV file
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CsvExportOptions {
+    /// Field delimiter, an ASCII character
+    #[serde(default = "default_delimiter")]
+    pub delimiter: char,
+    /// Whether the first record names the columns
+    #[serde(default = "default_header")]
+    pub header: bool,
+    #[serde(default)]
+    pub quote: Quoting,
+    /// Field written for nulls
+    #[serde(default)]
+    pub null: String,
+    /// `strftime` format of date columns, ISO 8601 if not given
+    #[serde(default)]
+    pub date_format: Option<String>,
+    /// `strftime` format of timestamp columns, ISO 8601 if not given
+    #[serde(default)]
+    pub datetime_format: Option<String>,
+    #[serde(default)]
+    pub json: JsonColumns,
+}
+
+impl Default for CsvExportOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: default_delimiter(),
+            header: default_header(),
+            quote: Quoting::default(),
+            null: String::new(),
+            date_format: None,
+            datetime_format: None,
+            json: JsonColumns::default(),
+        }
+    }
+}
+
+impl CsvExportOptions {
+    pub fn delimiter(mut self, val: char) -> Self {
+        self.delimiter = val;
+        self
+    }
+    pub fn header(mut self, val: bool) -> Self {
+        self.header = val;
+        self
+    }
+    pub fn quote(mut self, val: Quoting) -> Self {
+        self.quote = val;
+        self
+    }
+    pub fn null(mut self, val: &str) -> Self {
+        self.null = val.to_string();
+        self
+    }
+    pub fn date_format(mut self, val: &str) -> Self {
+        self.date_format = Some(val.to_string());
+        self
+    }
+    pub fn datetime_format(mut self, val: &str) -> Self {
+        self.datetime_format = Some(val.to_string());
+        self
+    }
+    pub fn json(mut self, val: JsonColumns) -> Self {
+        self.json = val;
+        self
+    }
+    /// Builder of writers of records into buffers
+    fn builder(&self) -> Result<csv::WriterBuilder> {
+        if !self.delimiter.is_ascii() {
+            return Err(Error::InvalidCsv(format!(
+                "delimiter {:?} is not an ASCII character",
+                self.delimiter
+            )));
+        }
+        let mut builder = csv::WriterBuilder::new();
+        builder
+            .delimiter(self.delimiter as u8)
+            .quote_style(self.quote.clone().into());
+        Ok(builder)
+    }
+}
+
+fn default_delimiter() -> char {
+    ','
+}
+
+fn default_header() -> bool {
+    true
+}
+
+/// Objects found in a json column
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JsonShape {
+    /// Keys of all objects in order
+    pub keys: Vec<JsonKey>,
+    /// Whether some values are not objects
+    pub other: bool,
+}
+
+/// Key of the objects in a json column
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JsonKey {
+    pub name: String,
+    /// Json types of the key's values other than null, e.g. `number`
+    pub types: Vec<String>,
+}
+
+impl JsonKey {
+    pub fn new(name: &str, types: &[&str]) -> Self {
+        Self {
+            name: name.to_string(),
+            types: types.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+}
+
+/// Part of a column's values that goes into a CSV column
+#[derive(Debug, Clone, PartialEq)]
+pub enum CsvSource {
+    /// The whole value
+    Value,
+    /// The value of a key of an object
+    Key(String),
+    /// The whole value if it's not an object
+    NotObject,
+}
+
+/// CSV column of a table export
+#[derive(Debug, Clone, PartialEq)]
+pub struct CsvColumn {
+    /// Name in the header
+    pub name: String,
+    /// Table column the fields come from
+    pub column: String,
+    pub postgres_type: PostgresType,
+    pub source: CsvSource,
+}
+
+impl CsvColumn {
+    /// Field for a row
+    fn field(&self, row: &RowJson, options: &CsvExportOptions) -> String {
+        let value = row.get(&self.column).unwrap_or(&Value::Null);
+        match (&self.source, value) {
+            (CsvSource::Value, value) => {
+                format_value(value, &self.postgres_type, options)
+            }
+            (CsvSource::Key(key), Value::Object(o)) => match o.get(key) {
+                Some(value) => {
+                    format_value(value, &PostgresType::Jsonb, options)
+                }
+                None => options.null.clone(),
+            },
+            (CsvSource::NotObject, value) if !value.is_object() => {
+                format_value(value, &PostgresType::Jsonb, options)
+            }
+            _ => options.null.clone(),
+        }
+    }
+}
+
+/// CSV columns for the table's columns, all of them if none are given.
+/// Json columns are flattened by their shapes if the options say so.
+pub fn csv_columns(
+    table: &TableMeta,
+    columns: &[String],
+    shapes: &HashMap<String, JsonShape>,
+    options: &CsvExportOptions,
+) -> Result<Vec<CsvColumn>> {
+    table.verify_cols_present(columns)?;
+    let cols: Vec<&ColMeta> = if columns.is_empty() {
+        table.cols.iter().collect()
+    } else {
+        columns.iter().filter_map(|c| table.get_col(c)).collect()
+    };
+    let mut csv_columns = Vec::new();
+    for col in cols {
+        let column = |name: String, source: CsvSource| CsvColumn {
+            name,
+            column: col.name.clone(),
+            postgres_type: col.postgres_type.clone(),
+            source,
+        };
+        let shape = match options.json {
+            JsonColumns::Flatten => shapes.get(&col.name),
+            JsonColumns::Text => None,
+        };
+        match shape {
+            Some(shape) => {
+                for key in &shape.keys {
+                    csv_columns.push(column(
+                        format!("{}.{}", col.name, key.name),
+                        CsvSource::Key(key.name.clone()),
+                    ));
+                }
+                if shape.other {
+                    csv_columns
+                        .push(column(col.name.clone(), CsvSource::NotObject));
+                }
+            }
+            None => {
+                csv_columns.push(column(col.name.clone(), CsvSource::Value))
+            }
+        }
+    }
+    Ok(csv_columns)
+}
+
+/// Field for a value of the given type. Dates and timestamps that can't be
+/// parsed are written as they are.
+fn format_value(
+    value: &Value,
+    postgres_type: &PostgresType,
+    options: &CsvExportOptions,
+) -> String {
+    use PostgresType as T;
+    let s = match value {
+        Value::Null => return options.null.clone(),
+        Value::String(s) => s,
+        _ => return value.to_string(),
+    };
+    match (
+        postgres_type,
+        &options.date_format,
+        &options.datetime_format,
+    ) {
+        (T::Date, Some(format), _) => {
+            chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .map(|d| d.format(format).to_string())
+                .unwrap_or_else(|_| s.clone())
+        }
+        (T::Timestamp, _, Some(format)) => {
+            chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f")
+                .map(|t| t.format(format).to_string())
+                .unwrap_or_else(|_| s.clone())
+        }
+        (T::Timestamptz, _, Some(format)) => {
+            chrono::DateTime::parse_from_rfc3339(s)
+                .map(|t| t.format(format).to_string())
+                .unwrap_or_else(|_| s.clone())
+        }
+        _ => s.clone(),
+    }
+}
+
+/// Records written by the writer
+fn finish(writer: csv::Writer<Vec<u8>>) -> Result<Vec<u8>> {
+    writer.into_inner().map_err(|e| e.into_error().into())
+}
+
+/// CSV file of the rows, written as they are read. Rows that are ready
+/// together are written together.
+/// The stream ends after an error.
+pub fn write_csv(
+    columns: Vec<CsvColumn>,
+    rows: RowStream,
+    options: &CsvExportOptions,
+) -> Result<ByteStream> {
+    let builder = options.builder()?;
+    let mut writer = builder.from_writer(Vec::new());
+    if options.header {
+        writer.write_record(columns.iter().map(|c| c.name.as_str()))?;
+    }
+    let header = finish(writer)?;
+    let options = options.clone();
+    let records = rows.ready_chunks(STREAM_BUFFER_ROWS).map(move |rows| {
+        let mut writer = builder.from_writer(Vec::new());
+        for row in rows {
+            let row = row?;
+            writer.write_record(
+                columns.iter().map(|c| c.field(&row, &options)),
+            )?;
+        }
+        finish(writer)
+    });
+    Ok(Box::pin(
+        futures::stream::once(async move { Ok(header) }).chain(records),
+    ))
+}
+
+/// Rows of a table to write as a CSV file
+#[derive(Debug, Clone)]
+pub struct CsvExport {
+    pub table_name: String,
+    pub columns: Vec<CsvColumn>,
+    /// Query for the rows and the values of its parameters
+    pub query: String,
+    pub values: Vec<BindValue>,
+}
+
+/// Zip archive with a CSV file for every table, written as it is read.
+/// Each table's rows are only fetched once its file is reached.
+/// The stream ends after an error.
+pub fn zip_csv(
+    pool: Pool,
+    exports: Vec<CsvExport>,
+    options: &CsvExportOptions,
+) -> Result<ByteStream> {
+    // Fail before anything is written
+    options.builder()?;
+    let files = exports
+        .into_iter()
+        .map(|export| {
+            let pool = pool.clone();
+            let options = options.clone();
+            let file: ArchiveFile = (
+                format!("{}.csv", export.table_name),
+                Box::new(move || {
+                    let rows = fetch_rows(pool, export.query, export.values);
+                    write_csv(export.columns, rows, &options)
+                }),
+            );
+            file
+        })
+        .collect();
+    Ok(zip_files(files))
+}
+
+/// Name of a file in an archive and what makes its contents
+pub type ArchiveFile = (String, Box<dyn FnOnce() -> Result<ByteStream> + Send>);
+
+/// Zip archive of the files, written as it is read. The contents of a file
+/// are only made once the archive reaches it.
+/// The stream ends after an error.
+pub fn zip_files(files: Vec<ArchiveFile>) -> ByteStream {
+    let (zip_writer, zip_reader) = tokio::io::duplex(ZIP_BUFFER_SIZE);
+    let task = tokio::spawn(async move {
+        let mut zip =
+            async_zip::tokio::write::ZipFileWriter::with_tokio(zip_writer);
+        for (name, contents) in files {
+            let entry = async_zip::ZipEntryBuilder::new(
+                name.into(),
+                async_zip::Compression::Deflate,
+            );
+            let mut file =
+                zip.write_entry_stream(entry).await.map_err(zip_error)?;
+            let mut chunks = contents()?;
+            while let Some(chunk) = chunks.try_next().await? {
+                file.write_all(&chunk).await?;
+            }
+            file.close().await.map_err(zip_error)?;
+        }
+        zip.close().await.map_err(zip_error)?;
+        Ok::<(), Error>(())
+    });
+    let archive = tokio_util::io::ReaderStream::new(zip_reader)
+        .map_ok(|bytes| bytes.to_vec())
+        .map_err(Error::from);
+    // The archive stops early if writing it failed
+    let outcome = futures::stream::once(async move {
+        match task.await {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(Err(e)),
+            Err(e) => Some(Err(std::io::Error::other(e).into())),
+        }
+    })
+    .filter_map(futures::future::ready);
+    Box::pin(archive.chain(outcome))
+}
+
+/// Zip archive of files that are already written
+pub fn zip_data(files: Vec<(String, Vec<u8>)>) -> ByteStream {
+    zip_files(
+        files
+            .into_iter()
+            .map(|(name, data)| {
+                let file: ArchiveFile = (
+                    name,
+                    Box::new(move || {
+                        let data: ByteStream = Box::pin(futures::stream::once(
+                            async move { Ok(data) },
+                        ));
+                        Ok(data)
+                    }),
+                );
+                file
+            })
+            .collect(),
+    )
+}
+
+fn zip_error(e: async_zip::error::ZipError) -> Error {
+    std::io::Error::other(e).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[tokio::test]
+    async fn export_csv() {
+        let _ = pretty_env_logger::try_init();
+        let table = crate::tests::get_visit_table();
+        let rows = crate::tests::get_visit_data();
+        let shapes: HashMap<String, JsonShape> = vec![(
+            "symptoms".to_string(),
+            JsonShape {
+                keys: vec![
+                    JsonKey::new("cough", &["boolean"]),
+                    JsonKey::new("fever", &["number"]),
+                ],
+                other: true,
+            },
+        )]
+        .into_iter()
+        .collect();
+        async fn written(
+            columns: Vec<CsvColumn>,
+            rows: Vec<RowJson>,
+            options: &CsvExportOptions,
+        ) -> String {
+            let rows = futures::stream::iter(rows.into_iter().map(Ok));
+            let chunks: Vec<Vec<u8>> =
+                write_csv(columns, Box::pin(rows), options)
+                    .unwrap()
+                    .try_collect()
+                    .await
+                    .unwrap();
+            String::from_utf8(chunks.concat()).unwrap()
+        }
+
+        log::info!("defaults");
+        let options = CsvExportOptions::default();
+        let columns = csv_columns(&table, &[], &shapes, &options).unwrap();
+        assert_eq!(
+            written(columns, rows.clone(), &options).await,
+            "id,subject,date,seen,sick,weight,sex,symptoms,note\n\
+            1,1001,2021-03-01,2021-03-01T08:30:00+02:00,true,72.5,1,\
+            \"{\"\"cough\"\":true,\"\"fever\"\":38.5}\",\
+            \"said \"\"fine\"\", went home\"\n\
+            2,1002,,,,,2,\"[1,2]\",\n"
+        );
+
+        log::info!("flattened with formats");
+        let options = CsvExportOptions::default()
+            .delimiter(';')
+            .quote(Quoting::NonNumeric)
+            .null("NA")
+            .date_format("%d/%m/%Y")
+            .datetime_format("%d/%m/%Y %H:%M")
+            .json(JsonColumns::Flatten);
+        let columns = csv_columns(
+            &table,
+            &["id".to_string(), "date".to_string(), "symptoms".to_string()],
+            &shapes,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(
+            columns.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(),
+            vec!["id", "date", "symptoms.cough", "symptoms.fever", "symptoms"]
+        );
+        assert_eq!(
+            written(columns, rows.clone(), &options).await,
+            "\"id\";\"date\";\"symptoms.cough\";\"symptoms.fever\";\
+            \"symptoms\"\n\
+            1;\"01/03/2021\";\"true\";38.5;\"NA\"\n\
+            2;\"NA\";\"NA\";\"NA\";\"[1,2]\"\n"
+        );
+        let columns = csv_columns(
+            &table,
+            &["seen".to_string()],
+            &shapes,
+            &options.clone().header(false),
+        )
+        .unwrap();
+        assert_eq!(
+            written(columns, rows.clone(), &options.clone().header(false))
+                .await,
+            "\"01/03/2021 08:30\"\n\"NA\"\n"
+        );
+
+        log::info!("invalid");
+        assert!(matches!(
+            csv_columns(&table, &["age".to_string()], &shapes, &options)
+                .unwrap_err(),
+            Error::NoSuchColumns(cols) if cols == vec!["age"]
+        ));
+        assert!(matches!(
+            write_csv(
+                Vec::new(),
+                Box::pin(futures::stream::empty()),
+                &options.delimiter('→')
+            )
+            .err()
+            .unwrap(),
+            Error::InvalidCsv(_)
+        ));
+    }
+}
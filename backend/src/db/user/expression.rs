@@ -0,0 +1,674 @@
+use crate::{Error, Result};
+
+/// Functions expressions may call. They only compute on their arguments,
+/// none of them reach files, other tables or settings.
+const FUNCTIONS: &[&str] = &[
+    // Comparison and null handling
+    "coalesce",
+    "nullif",
+    "greatest",
+    "least",
+    // Maths
+    "abs",
+    "cbrt",
+    "ceil",
+    "ceiling",
+    "div",
+    "exp",
+    "floor",
+    "ln",
+    "log",
+    "log10",
+    "mod",
+    "power",
+    "round",
+    "sign",
+    "sqrt",
+    "trunc",
+    // Text
+    "ascii",
+    "btrim",
+    "char_length",
+    "character_length",
+    "chr",
+    "concat",
+    "concat_ws",
+    "initcap",
+    "left",
+    "length",
+    "lower",
+    "lpad",
+    "ltrim",
+    "md5",
+    "octet_length",
+    "position",
+    "regexp_replace",
+    "replace",
+    "reverse",
+    "right",
+    "rpad",
+    "rtrim",
+    "split_part",
+    "starts_with",
+    "strpos",
+    "substr",
+    "substring",
+    "translate",
+    "upper",
+    // Dates and times
+    "age",
+    "clock_timestamp",
+    "date_part",
+    "date_trunc",
+    "extract",
+    "isfinite",
+    "justify_days",
+    "justify_hours",
+    "justify_interval",
+    "make_date",
+    "make_interval",
+    "make_time",
+    "make_timestamp",
+    "make_timestamptz",
+    "now",
+    "statement_timestamp",
+    "to_char",
+    "to_date",
+    "to_number",
+    "to_timestamp",
+    "transaction_timestamp",
+    // Arrays and json
+    "array_length",
+    "array_position",
+    "array_to_string",
+    "cardinality",
+    "json_array_length",
+    "json_typeof",
+    "jsonb_array_length",
+    "jsonb_build_array",
+    "jsonb_build_object",
+    "jsonb_extract_path",
+    "jsonb_extract_path_text",
+    "jsonb_typeof",
+    "to_json",
+    "to_jsonb",
+    // Generated keys
+    "gen_random_uuid",
+    "nextval",
+];
+
+/// Words with a meaning of their own, never column names
+const KEYWORDS: &[&str] = &[
+    "all",
+    "and",
+    "any",
+    "array",
+    "between",
+    "case",
+    "distinct",
+    "else",
+    "end",
+    "for",
+    "from",
+    "ilike",
+    "in",
+    "is",
+    "like",
+    "not",
+    "null",
+    "or",
+    "select",
+    "similar",
+    "some",
+    "symmetric",
+    "table",
+    "then",
+    "to",
+    "values",
+    "when",
+    "where",
+    "with",
+];
+
+/// Keywords that are values on their own, e.g. `CURRENT_DATE`
+const VALUE_KEYWORDS: &[&str] = &[
+    "true",
+    "false",
+    "current_date",
+    "current_time",
+    "current_timestamp",
+    "localtime",
+    "localtimestamp",
+];
+
+/// Types that can prefix a string literal, e.g. `interval '1 day'`
+const LITERAL_TYPES: &[&str] = &[
+    "date",
+    "interval",
+    "json",
+    "jsonb",
+    "numeric",
+    "text",
+    "time",
+    "timestamp",
+    "timestamptz",
+    "uuid",
+];
+
+/// Words that continue a type name, e.g. `timestamp with time zone`
+const TYPE_WORDS: &[&str] =
+    &["precision", "varying", "with", "without", "time", "zone"];
+
+/// Checks an SQL expression given by the caller, e.g. a column default, a
+/// check or an index predicate.
+/// Expressions are evaluated by the database owner, who can reach anything
+/// on the server, so only a fixed grammar is accepted: literals, column
+/// names, operators, casts, `IS`, `CASE`, `ARRAY`, `ANY`/`ALL` and calls to
+/// the functions in `FUNCTIONS`. Subqueries, qualified names, parameters,
+/// comments, escaped or dollar-quoted strings and any other function are
+/// rejected.
+pub fn check_expression(expression: &str) -> Result<()> {
+    let invalid = |reason: String| {
+        Error::InvalidDefinition(format!(
+            "{} in expression \"{}\"",
+            reason, expression
+        ))
+    };
+    let tokens = tokenize(expression).map_err(invalid)?;
+    let mut parser = ExpressionParser { tokens, pos: 0 };
+    parser.expression().map_err(invalid)?;
+    match parser.peek() {
+        None => Ok(()),
+        Some(token) => Err(invalid(format!("unexpected {}", token))),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// Unquoted word, lowercase like Postgres folds it
+    Word(String),
+    /// Double-quoted identifier
+    Quoted(String),
+    Number,
+    String,
+    Operator(String),
+    /// One of `(`, `)`, `[`, `]` and `,`
+    Punct(char),
+    /// `::`
+    Cast,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Word(w) => write!(f, "\"{}\"", w),
+            Self::Quoted(q) => write!(f, "identifier \"{}\"", q),
+            Self::Number => write!(f, "number"),
+            Self::String => write!(f, "string"),
+            Self::Operator(op) => write!(f, "\"{}\"", op),
+            Self::Punct(c) => write!(f, "\"{}\"", c),
+            Self::Cast => write!(f, "\"::\""),
+        }
+    }
+}
+
+fn is_operator_char(c: char) -> bool {
+    "+-*/<>=~!@#%^&|`?".contains(c)
+}
+
+/// Splits the expression the way Postgres would, anything it would read
+/// differently (escapes, comments, prefixed strings) is an error
+fn tokenize(s: &str) -> std::result::Result<Vec<Token>, String> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        i += 1;
+        match c {
+            _ if c.is_whitespace() => {}
+            _ if c.is_alphabetic() || c == '_' => {
+                while i < chars.len()
+                    && (chars[i].is_alphanumeric() || chars[i] == '_')
+                {
+                    i += 1;
+                }
+                // E'..', B'..', U&".." and the like
+                let prefixes = match chars.get(i) {
+                    Some('\'') => true,
+                    Some('&') => matches!(chars.get(i + 1), Some('\'' | '"')),
+                    _ => false,
+                };
+                if prefixes {
+                    return Err("prefixed strings are not supported".into());
+                }
+                let word: String = chars[start..i].iter().collect();
+                tokens.push(Token::Word(word.to_ascii_lowercase()));
+            }
+            _ if c.is_ascii_digit()
+                || (c == '.'
+                    && chars.get(i).is_some_and(|c| c.is_ascii_digit())) =>
+            {
+                while i < chars.len()
+                    && (chars[i].is_ascii_digit() || chars[i] == '.')
+                {
+                    i += 1;
+                }
+                if matches!(chars.get(i), Some('e' | 'E')) {
+                    i += 1;
+                    if matches!(chars.get(i), Some('+' | '-')) {
+                        i += 1;
+                    }
+                    if !chars.get(i).is_some_and(|c| c.is_ascii_digit()) {
+                        return Err("invalid number".into());
+                    }
+                    while chars.get(i).is_some_and(|c| c.is_ascii_digit()) {
+                        i += 1;
+                    }
+                }
+                if chars
+                    .get(i)
+                    .is_some_and(|c| c.is_alphanumeric() || *c == '_')
+                {
+                    return Err("invalid number".into());
+                }
+                tokens.push(Token::Number);
+            }
+            '\'' | '"' => {
+                let mut content = String::new();
+                loop {
+                    match chars.get(i) {
+                        None => return Err(format!("unterminated {}", c)),
+                        Some(q) if *q == c => {
+                            // Doubled to escape
+                            if chars.get(i + 1) == Some(&c) {
+                                content.push(c);
+                                i += 2;
+                            } else {
+                                i += 1;
+                                break;
+                            }
+                        }
+                        // Read as an escape when standard_conforming_strings
+                        // is off
+                        Some('\\') => {
+                            return Err("backslashes are not supported".into())
+                        }
+                        Some(other) => {
+                            content.push(*other);
+                            i += 1;
+                        }
+                    }
+                }
+                if c == '\'' {
+                    tokens.push(Token::String);
+                } else if content.is_empty() {
+                    return Err("empty identifier".into());
+                } else {
+                    tokens.push(Token::Quoted(content));
+                }
+            }
+            ':' if chars.get(i) == Some(&':') => {
+                i += 1;
+                tokens.push(Token::Cast);
+            }
+            '(' | ')' | '[' | ']' | ',' => tokens.push(Token::Punct(c)),
+            _ if is_operator_char(c) => {
+                while chars.get(i).is_some_and(|c| is_operator_char(*c)) {
+                    i += 1;
+                }
+                let mut op: String = chars[start..i].iter().collect();
+                if op.contains("--") || op.contains("/*") {
+                    return Err("comments are not supported".into());
+                }
+                // Postgres splits a trailing + or - off operators made of
+                // the common characters only, e.g. `>-1` is `>` and `-1`
+                while op.len() > 1
+                    && op.ends_with(['+', '-'])
+                    && !op.contains(|c| "~!@#%^&|`?".contains(c))
+                {
+                    op.pop();
+                    i -= 1;
+                }
+                tokens.push(Token::Operator(op));
+            }
+            _ => return Err(format!("unexpected character '{}'", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+/// Accepts the grammar described on `check_expression`.
+/// Operator precedence doesn't matter to what is allowed so it isn't
+/// modelled, Postgres rejects what doesn't parse.
+struct ExpressionParser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+type ParseResult = std::result::Result<(), String>;
+
+impl ExpressionParser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+    fn peek_at(&self, offset: usize) -> Option<&Token> {
+        self.tokens.get(self.pos + offset)
+    }
+    fn next(&mut self) -> std::result::Result<Token, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| "unexpected end".to_string())?;
+        self.pos += 1;
+        Ok(token)
+    }
+    fn is_word(&self, offset: usize, words: &[&str]) -> bool {
+        matches!(self.peek_at(offset), Some(Token::Word(w)) if words.contains(&w.as_str()))
+    }
+    fn eat_word(&mut self, word: &str) -> bool {
+        let found = self.is_word(0, &[word]);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+    fn expect_word(&mut self, word: &str) -> ParseResult {
+        if self.eat_word(word) {
+            return Ok(());
+        }
+        Err(self.unexpected(&format!("\"{}\"", word)))
+    }
+    fn eat_punct(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(&Token::Punct(c));
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+    fn expect_punct(&mut self, c: char) -> ParseResult {
+        if self.eat_punct(c) {
+            return Ok(());
+        }
+        Err(self.unexpected(&format!("\"{}\"", c)))
+    }
+    fn unexpected(&self, expected: &str) -> String {
+        match self.peek() {
+            Some(token) => format!("expected {}, found {}", expected, token),
+            None => format!("expected {} at the end", expected),
+        }
+    }
+    /// Operands joined by binary operators
+    fn expression(&mut self) -> ParseResult {
+        self.operand()?;
+        while self.binary_operator() {
+            self.operand()?;
+        }
+        Ok(())
+    }
+    /// Expressions separated by commas
+    fn list(&mut self) -> ParseResult {
+        self.expression()?;
+        while self.eat_punct(',') {
+            self.expression()?;
+        }
+        Ok(())
+    }
+    fn binary_operator(&mut self) -> bool {
+        let len = match self.peek() {
+            Some(Token::Operator(_)) => 1,
+            _ if self.is_word(0, &["and", "or", "like", "ilike", "in"]) => 1,
+            _ if self.is_word(0, &["between"]) => {
+                if self.is_word(1, &["symmetric"]) {
+                    2
+                } else {
+                    1
+                }
+            }
+            _ if self.is_word(0, &["similar"]) && self.is_word(1, &["to"]) => 2,
+            _ if self.is_word(0, &["not"])
+                && self.is_word(1, &["like", "ilike", "in", "between"]) =>
+            {
+                2
+            }
+            _ => 0,
+        };
+        self.pos += len;
+        len > 0
+    }
+    /// Prefixed and suffixed value
+    fn operand(&mut self) -> ParseResult {
+        loop {
+            if self.eat_word("not") {
+                continue;
+            }
+            match self.peek() {
+                Some(Token::Operator(_)) => self.pos += 1,
+                _ => break,
+            }
+        }
+        self.primary()?;
+        loop {
+            if self.peek() == Some(&Token::Cast) {
+                self.pos += 1;
+                self.type_name()?;
+            } else if self.eat_punct('[') {
+                self.expression()?;
+                self.expect_punct(']')?;
+            } else if self.eat_word("is") {
+                self.eat_word("not");
+                if self.eat_word("distinct") {
+                    self.expect_word("from")?;
+                    self.operand()?;
+                } else if !(self.eat_word("null")
+                    || self.eat_word("true")
+                    || self.eat_word("false")
+                    || self.eat_word("unknown"))
+                {
+                    return Err(self.unexpected("null, true or false"));
+                }
+            } else {
+                return Ok(());
+            }
+        }
+    }
+    fn primary(&mut self) -> ParseResult {
+        let followed_by_parenthesis =
+            self.peek_at(1) == Some(&Token::Punct('('));
+        match self.next()? {
+            Token::Number | Token::String => Ok(()),
+            Token::Punct('(') => {
+                self.list()?;
+                self.expect_punct(')')
+            }
+            Token::Quoted(name) if followed_by_parenthesis => {
+                self.function(&name)
+            }
+            Token::Quoted(_) => Ok(()),
+            Token::Word(word) => match word.as_str() {
+                "null" => Ok(()),
+                "case" => self.case(),
+                "array" => {
+                    self.expect_punct('[')?;
+                    if !self.eat_punct(']') {
+                        self.list()?;
+                        self.expect_punct(']')?;
+                    }
+                    Ok(())
+                }
+                "any" | "all" | "some" => {
+                    self.expect_punct('(')?;
+                    self.expression()?;
+                    self.expect_punct(')')
+                }
+                w if VALUE_KEYWORDS.contains(&w) => Ok(()),
+                w if KEYWORDS.contains(&w) => {
+                    Err(format!("unexpected \"{}\"", w))
+                }
+                w if followed_by_parenthesis => self.function(w),
+                w if LITERAL_TYPES.contains(&w)
+                    && self.peek() == Some(&Token::String) =>
+                {
+                    self.pos += 1;
+                    Ok(())
+                }
+                // Column
+                _ => Ok(()),
+            },
+            token => Err(format!("unexpected {}", token)),
+        }
+    }
+    /// Call of an allowed function, `from` and `for` separating arguments
+    /// as in `extract(year from x)`
+    fn function(&mut self, name: &str) -> ParseResult {
+        if !FUNCTIONS.contains(&name) {
+            return Err(format!("function \"{}\" is not allowed", name));
+        }
+        self.expect_punct('(')?;
+        if self.eat_punct(')') {
+            return Ok(());
+        }
+        self.expression()?;
+        while self.eat_punct(',')
+            || self.eat_word("from")
+            || self.eat_word("for")
+        {
+            self.expression()?;
+        }
+        self.expect_punct(')')
+    }
+    fn case(&mut self) -> ParseResult {
+        if !self.is_word(0, &["when"]) {
+            self.expression()?;
+        }
+        self.expect_word("when")?;
+        loop {
+            self.expression()?;
+            self.expect_word("then")?;
+            self.expression()?;
+            if !self.eat_word("when") {
+                break;
+            }
+        }
+        if self.eat_word("else") {
+            self.expression()?;
+        }
+        self.expect_word("end")
+    }
+    /// Type of a cast, e.g. `character varying(20)[]`
+    fn type_name(&mut self) -> ParseResult {
+        match self.next()? {
+            Token::Word(_) | Token::Quoted(_) => {}
+            token => return Err(format!("expected a type, found {}", token)),
+        }
+        while self.is_word(0, TYPE_WORDS) {
+            self.pos += 1;
+        }
+        if self.eat_punct('(') {
+            loop {
+                match self.next()? {
+                    Token::Number => {}
+                    token => {
+                        return Err(format!(
+                            "expected a type modifier, found {}",
+                            token
+                        ))
+                    }
+                }
+                if !self.eat_punct(',') {
+                    break;
+                }
+            }
+            self.expect_punct(')')?;
+        }
+        while self.eat_punct('[') {
+            if self.peek() == Some(&Token::Number) {
+                self.pos += 1;
+            }
+            self.expect_punct(']')?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn check_expressions() {
+        let _ = pretty_env_logger::try_init();
+        for good in &[
+            "0",
+            "'none'",
+            "age >= 0",
+            "((age >= 0) AND (age < 150))",
+            "weight / (height * height)",
+            "nextval('measurement_seq'::regclass)",
+            "now()",
+            "CURRENT_TIMESTAMP",
+            "gen_random_uuid()",
+            "(email <> ''::text)",
+            "'x; y -- z'::character varying(20)",
+            "'2020-01-01'::timestamp with time zone",
+            "(score)::numeric(10, 2)",
+            "'{}'::text[]",
+            "sick",
+            "\"Visit date\" IS NOT NULL",
+            "x IS DISTINCT FROM y",
+            "(x = ANY (ARRAY[1, 2]))",
+            "x NOT IN (1, 2) AND y BETWEEN 1 AND 2",
+            "(note ~~ 'a%'::text)",
+            "lower(note) LIKE 'a%'",
+            "CASE WHEN age < 18 THEN 'child' ELSE 'adult' END",
+            "CASE sex WHEN 1 THEN 'm' END",
+            "EXTRACT(year FROM visit)",
+            "interval '1 day' + visit",
+            "-1.5e3 < +x",
+            "x>-1",
+            "symptoms->>'cough' = 'dry'",
+            "NOT sick",
+            "\"now\"()",
+            "scores[1] > 0",
+        ] {
+            assert!(check_expression(good).is_ok(), "{}", good);
+        }
+        for bad in &[
+            "",
+            "0; DROP TABLE \"primary\"",
+            "0 -- rest",
+            "0 /* rest */",
+            "pg_read_file('/etc/passwd')",
+            "PG_READ_FILE('/etc/passwd')",
+            "\"pg_read_file\"('/etc/passwd')",
+            "pg_catalog.pg_read_file('/etc/passwd')",
+            "lo_export(1, '/tmp/x')",
+            "query_to_xml('select 1', true, true, '')",
+            "(SELECT count(*) FROM \"primary\")",
+            "EXISTS (SELECT 1)",
+            "length((TABLE \"primary\")::text)",
+            "lower(current_setting('data_directory'))",
+            "E'\\x27'",
+            "U&\"pg_read_file\"('x')",
+            "'a\\'",
+            "$$text$$",
+            "$1",
+            "x.y",
+            "'unterminated",
+            "CAST(x AS text)",
+            "x IS",
+            "age >= 0)",
+            "CASE WHEN x THEN 1",
+        ] {
+            assert!(
+                matches!(
+                    check_expression(bad),
+                    Err(Error::InvalidDefinition(_))
+                ),
+                "{}",
+                bad
+            );
+        }
+    }
+}
@@ -0,0 +1,695 @@
+use super::table::{RowJson, TableMeta};
+use super::types::PostgresType;
+use super::value::BindValue;
+use crate::{Error, Result};
+use serde_json::Value;
+
+/// Condition on the rows of a table.
+/// Besides json, filters can be written in a compact form for query strings,
+/// see the `FromStr` implementation.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RowFilter {
+    Eq(Comparison),
+    Ne(Comparison),
+    Lt(Comparison),
+    Le(Comparison),
+    Gt(Comparison),
+    Ge(Comparison),
+    /// Column's text matches the pattern, `%` and `_` being wildcards
+    Like(Comparison),
+    /// Like `Like` but ignoring case
+    #[serde(rename = "ilike")]
+    ILike(Comparison),
+    /// Column is in the range, `from` included and `to` not, either end
+    /// may be open
+    Range {
+        column: String,
+        #[serde(default)]
+        from: Option<Value>,
+        #[serde(default)]
+        to: Option<Value>,
+    },
+    /// Value at the path in a json column equals the value
+    Path {
+        column: String,
+        path: Vec<String>,
+        value: Value,
+    },
+    /// Column equals one of the values
+    In {
+        column: String,
+        values: Vec<Value>,
+    },
+    IsNull(String),
+    NotNull(String),
+    /// All of the conditions, true if there are none
+    And(Vec<RowFilter>),
+    /// Any of the conditions, false if there are none
+    Or(Vec<RowFilter>),
+    Not(Box<RowFilter>),
+}
+
+/// Column compared to a value
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Comparison {
+    pub column: String,
+    pub value: Value,
+}
+
+/// Filter compiled to a parameterized condition
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledFilter {
+    /// Condition to go after `WHERE`
+    pub condition: String,
+    /// Values of the condition's parameters in order
+    pub values: Vec<BindValue>,
+}
+
+impl RowFilter {
+    /// Filter for the rows with the given primary keys
+    pub fn from_keys(table: &TableMeta, keys: &[RowJson]) -> Result<Self> {
+        let key_cols = table.get_primary_key();
+        if key_cols.is_empty() {
+            return Err(Error::NoPrimaryKey(table.name.clone()));
+        }
+        let mut rows = Vec::with_capacity(keys.len());
+        for (i, key) in keys.iter().enumerate() {
+            let mut cols = Vec::with_capacity(key_cols.len());
+            for col in &key_cols {
+                match key.get(*col) {
+                    Some(value) if !value.is_null() => {
+                        cols.push(Self::Eq(Comparison {
+                            column: col.to_string(),
+                            value: value.clone(),
+                        }))
+                    }
+                    _ => {
+                        return Err(Error::InvalidFilter(format!(
+                            "key {} is missing primary key column \"{}\"",
+                            i, col
+                        )))
+                    }
+                }
+            }
+            rows.push(Self::And(cols));
+        }
+        Ok(Self::Or(rows))
+    }
+
+    /// Columns the filter refers to
+    pub fn columns(&self) -> Vec<&str> {
+        match self {
+            Self::Eq(c)
+            | Self::Ne(c)
+            | Self::Lt(c)
+            | Self::Le(c)
+            | Self::Gt(c)
+            | Self::Ge(c)
+            | Self::Like(c)
+            | Self::ILike(c) => vec![c.column.as_str()],
+            Self::In { column, .. }
+            | Self::Range { column, .. }
+            | Self::Path { column, .. } => vec![column.as_str()],
+            Self::IsNull(column) | Self::NotNull(column) => {
+                vec![column.as_str()]
+            }
+            Self::And(filters) | Self::Or(filters) => {
+                filters.iter().flat_map(|f| f.columns()).collect()
+            }
+            Self::Not(filter) => filter.columns(),
+        }
+    }
+
+    /// Compiles the filter for the table. Values are converted to their
+    /// columns' types and passed as parameters, the first one being `$1`.
+    pub fn compile(&self, table: &TableMeta) -> Result<CompiledFilter> {
+        table.verify_cols_present(&self.columns())?;
+        let mut values = Vec::new();
+        let condition = self.to_sql(table, &mut values)?;
+        Ok(CompiledFilter { condition, values })
+    }
+
+    fn to_sql(
+        &self,
+        table: &TableMeta,
+        values: &mut Vec<BindValue>,
+    ) -> Result<String> {
+        let compare = |c: &Comparison,
+                       op: &str,
+                       values: &mut Vec<BindValue>| {
+            let param = push_param(table, &c.column, &c.value, values)?;
+            Ok::<String, Error>(format!("\"{}\" {} {}", c.column, op, param))
+        };
+        let join =
+            |filters: &[RowFilter], op: &str, values: &mut Vec<BindValue>| {
+                let mut conditions = Vec::with_capacity(filters.len());
+                for f in filters {
+                    conditions.push(format!("({})", f.to_sql(table, values)?));
+                }
+                Ok::<String, Error>(conditions.join(op))
+            };
+        let sql = match self {
+            Self::Eq(c) => compare(c, "=", values)?,
+            Self::Ne(c) => compare(c, "<>", values)?,
+            Self::Lt(c) => compare(c, "<", values)?,
+            Self::Le(c) => compare(c, "<=", values)?,
+            Self::Gt(c) => compare(c, ">", values)?,
+            Self::Ge(c) => compare(c, ">=", values)?,
+            Self::Like(c) | Self::ILike(c) => {
+                let pattern = c.value.as_str().ok_or_else(|| {
+                    Error::InvalidFilter(format!(
+                        "column \"{}\" matched with a pattern that is not a \
+                        string",
+                        c.column
+                    ))
+                })?;
+                values.push(BindValue::Text(pattern.to_string()));
+                format!(
+                    "\"{}\"::text {} ${}::text",
+                    c.column,
+                    if matches!(self, Self::Like(_)) {
+                        "LIKE"
+                    } else {
+                        "ILIKE"
+                    },
+                    values.len()
+                )
+            }
+            Self::Range { column, from, to } => {
+                let mut conditions = Vec::with_capacity(2);
+                if let Some(from) = from {
+                    let param = push_param(table, column, from, values)?;
+                    conditions.push(format!("\"{}\" >= {}", column, param));
+                }
+                if let Some(to) = to {
+                    let param = push_param(table, column, to, values)?;
+                    conditions.push(format!("\"{}\" < {}", column, param));
+                }
+                if conditions.is_empty() {
+                    "TRUE".to_string()
+                } else {
+                    conditions.join(" AND ")
+                }
+            }
+            Self::Path {
+                column,
+                path,
+                value,
+            } => {
+                let typ = &table.get_col(column).unwrap().postgres_type;
+                if !matches!(typ, PostgresType::Json | PostgresType::Jsonb) {
+                    return Err(Error::InvalidFilter(format!(
+                        "column \"{}\" of type {} has no json paths",
+                        column, typ
+                    )));
+                }
+                values.push(BindValue::Array(
+                    path.iter().map(|key| Some(key.clone())).collect(),
+                ));
+                values.push(BindValue::Json(value.clone()));
+                format!(
+                    "(\"{}\"::jsonb #> ${}::text[]) = ${}::jsonb",
+                    column,
+                    values.len() - 1,
+                    values.len()
+                )
+            }
+            Self::In { values: vs, .. } if vs.is_empty() => "FALSE".to_string(),
+            Self::In { column, values: vs } => {
+                let mut params = Vec::with_capacity(vs.len());
+                for v in vs {
+                    params.push(push_param(table, column, v, values)?);
+                }
+                format!("\"{}\" IN ({})", column, params.join(","))
+            }
+            Self::IsNull(column) => format!("\"{}\" IS NULL", column),
+            Self::NotNull(column) => format!("\"{}\" IS NOT NULL", column),
+            Self::And(filters) if filters.is_empty() => "TRUE".to_string(),
+            Self::Or(filters) if filters.is_empty() => "FALSE".to_string(),
+            Self::And(filters) => join(filters, " AND ", values)?,
+            Self::Or(filters) => join(filters, " OR ", values)?,
+            Self::Not(filter) => {
+                format!("NOT ({})", filter.to_sql(table, values)?)
+            }
+        };
+        Ok(sql)
+    }
+}
+
+/// Adds the value converted to the column's type, returns its placeholder
+fn push_param(
+    table: &TableMeta,
+    column: &str,
+    value: &Value,
+    values: &mut Vec<BindValue>,
+) -> Result<String> {
+    if value.is_null() {
+        return Err(Error::InvalidFilter(format!(
+            "column \"{}\" compared with null, use is_null instead",
+            column
+        )));
+    }
+    let col = table.get_col(column).unwrap();
+    let converted =
+        BindValue::from_json(value, &col.postgres_type).map_err(|e| {
+            Error::InvalidFilter(format!("column \"{}\": {}", column, e))
+        })?;
+    values.push(converted);
+    Ok(format!("${}::{}", values.len(), col.postgres_type))
+}
+
+/// Compact form for query strings, e.g.
+/// `and(sick.eq.true,timepoint.ge.2,or(id.in.(1,2),symptoms.is.null))`.
+///
+/// A condition is `column.op.value` with the ops `eq`, `ne`, `lt`, `le`,
+/// `gt`, `ge`, `like`, `ilike`, `in` with a list `(a,b)`, `is` with `null`
+/// or `not_null`, and `range` with `from..to` where either end may be left
+/// out. `column->key->key.eq.value` compares the value at a json path, the
+/// value being json or a string. Values with `,` or parentheses are double
+/// quoted, `\"` and `\\` escaping within. Conditions are combined with
+/// `and(..)`, `or(..)` and `not(..)`.
+impl std::str::FromStr for RowFilter {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parser = FilterParser { s, pos: 0 };
+        let filter = parser.parse_expr()?;
+        if parser.pos != s.len() {
+            return Err(parser.error("unexpected input"));
+        }
+        Ok(filter)
+    }
+}
+
+struct FilterParser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl FilterParser<'_> {
+    fn error(&self, reason: &str) -> Error {
+        Error::InvalidFilter(format!(
+            "{} at {} in \"{}\"",
+            reason, self.pos, self.s
+        ))
+    }
+    fn rest(&self) -> &str {
+        &self.s[self.pos..]
+    }
+    fn parse_expr(&mut self) -> Result<RowFilter> {
+        for group in &["and(", "or(", "not("] {
+            if !self.rest().starts_with(group) {
+                continue;
+            }
+            self.pos += group.len();
+            let mut filters = vec![self.parse_expr()?];
+            while self.rest().starts_with(',') {
+                self.pos += 1;
+                filters.push(self.parse_expr()?);
+            }
+            if !self.rest().starts_with(')') {
+                return Err(self.error("expected \")\""));
+            }
+            self.pos += 1;
+            return match *group {
+                "and(" => Ok(RowFilter::And(filters)),
+                "or(" => Ok(RowFilter::Or(filters)),
+                _ if filters.len() == 1 => {
+                    Ok(RowFilter::Not(Box::new(filters.remove(0))))
+                }
+                _ => Err(self.error("not takes one condition")),
+            };
+        }
+        let start = self.pos;
+        let len = split_top_level(self.rest())
+            .next()
+            .map_or(0, |cond| cond.len());
+        self.pos += len;
+        parse_condition(&self.s[start..self.pos]).map_err(|reason| {
+            Error::InvalidFilter(format!(
+                "{} in condition \"{}\"",
+                reason,
+                &self.s[start..self.pos]
+            ))
+        })
+    }
+}
+
+/// Splits at the commas outside of quotes and parentheses, stops at an
+/// unmatched `)`
+fn split_top_level(s: &str) -> impl Iterator<Item = &str> {
+    let mut entries = Vec::new();
+    let mut start = 0;
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            _ if quoted => {}
+            '(' => depth += 1,
+            ')' if depth == 0 => {
+                entries.push(&s[start..i]);
+                return entries.into_iter();
+            }
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                entries.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    entries.push(&s[start..]);
+    entries.into_iter()
+}
+
+/// Value with the quotes and escapes removed
+fn unquote(s: &str) -> String {
+    match s.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        Some(inner) => {
+            let mut unquoted = String::with_capacity(inner.len());
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => unquoted.extend(chars.next()),
+                    _ => unquoted.push(c),
+                }
+            }
+            unquoted
+        }
+        None => s.to_string(),
+    }
+}
+
+fn parse_condition(cond: &str) -> std::result::Result<RowFilter, String> {
+    let mut parts = cond.splitn(3, '.');
+    let column = parts.next().filter(|c| !c.is_empty()).ok_or("no column")?;
+    let op = parts.next().ok_or("no operator")?;
+    let raw = parts.next();
+    let value = || {
+        raw.map(|v| Value::String(unquote(v)))
+            .ok_or_else(|| format!("no value for \"{}\"", op))
+    };
+    let mut path = column.split("->");
+    let column = path.next().unwrap().to_string();
+    let path: Vec<String> = path.map(unquote).collect();
+    if !path.is_empty() {
+        if op != "eq" {
+            return Err("json paths can only be compared with eq".to_string());
+        }
+        let raw = unquote(raw.ok_or("no value for \"eq\"")?);
+        return Ok(RowFilter::Path {
+            column,
+            path,
+            value: serde_json::from_str(&raw).unwrap_or(Value::String(raw)),
+        });
+    }
+    let comparison = || {
+        Ok::<Comparison, String>(Comparison {
+            column: column.clone(),
+            value: value()?,
+        })
+    };
+    let filter = match op {
+        "eq" => RowFilter::Eq(comparison()?),
+        "ne" => RowFilter::Ne(comparison()?),
+        "lt" => RowFilter::Lt(comparison()?),
+        "le" => RowFilter::Le(comparison()?),
+        "gt" => RowFilter::Gt(comparison()?),
+        "ge" => RowFilter::Ge(comparison()?),
+        "like" => RowFilter::Like(comparison()?),
+        "ilike" => RowFilter::ILike(comparison()?),
+        "in" => {
+            let list = raw
+                .and_then(|v| v.strip_prefix('('))
+                .and_then(|v| v.strip_suffix(')'))
+                .ok_or("expected a list in parentheses for \"in\"")?;
+            let values = if list.is_empty() {
+                vec![]
+            } else {
+                split_top_level(list)
+                    .map(|v| Value::String(unquote(v)))
+                    .collect()
+            };
+            RowFilter::In { column, values }
+        }
+        "is" => match raw {
+            Some("null") => RowFilter::IsNull(column),
+            Some("not_null") => RowFilter::NotNull(column),
+            _ => return Err("expected null or not_null for \"is\"".to_string()),
+        },
+        "range" => {
+            let (from, to) = raw
+                .and_then(|v| v.split_once(".."))
+                .ok_or("expected from..to for \"range\"")?;
+            let end = |v: &str| {
+                if v.is_empty() {
+                    None
+                } else {
+                    Some(Value::String(unquote(v)))
+                }
+            };
+            RowFilter::Range {
+                column,
+                from: end(from),
+                to: end(to),
+            }
+        }
+        _ => return Err(format!("unknown operator \"{}\"", op)),
+    };
+    Ok(filter)
+}
+
+/// Rows to address, by primary key or by filter
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RowSelection {
+    /// Primary key values of the rows
+    Keys(Vec<RowJson>),
+    Filter(RowFilter),
+}
+
+impl RowSelection {
+    pub fn to_filter(&self, table: &TableMeta) -> Result<RowFilter> {
+        match self {
+            Self::Keys(keys) => RowFilter::from_keys(table, keys),
+            Self::Filter(filter) => Ok(filter.clone()),
+        }
+    }
+}
+
+/// Options for removing rows
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct RemoveRowsOptions {
+    /// Remove the rows even if the filter matches all of them
+    #[serde(default)]
+    pub confirm_all: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    #[test]
+    fn compile_filter() {
+        let _ = pretty_env_logger::try_init();
+        let table = crate::tests::get_test_secondary_table();
+
+        log::info!("comparisons");
+        let filter: RowFilter = serde_json::from_value(json!({
+            "and": [
+                {"eq": {"column": "sick", "value": true}},
+                {"ge": {"column": "timepoint", "value": "2"}},
+                {"or": [
+                    {"in": {"column": "id", "values": [1, 2]}},
+                    {"not": {"is_null": "symptoms"}}
+                ]}
+            ]
+        }))
+        .unwrap();
+        let compiled = filter.compile(&table).unwrap();
+        assert_eq!(
+            compiled.condition,
+            "(\"sick\" = $1::boolean) AND (\"timepoint\" >= $2::integer) \
+            AND ((\"id\" IN ($3::integer,$4::integer)) \
+            OR (NOT (\"symptoms\" IS NULL)))"
+        );
+        assert_eq!(
+            compiled.values,
+            vec![
+                BindValue::Bool(true),
+                BindValue::Integer(2),
+                BindValue::Integer(1),
+                BindValue::Integer(2)
+            ]
+        );
+        assert_eq!(
+            RowFilter::And(vec![]).compile(&table).unwrap().condition,
+            "TRUE"
+        );
+        assert_eq!(
+            RowFilter::Or(vec![]).compile(&table).unwrap().condition,
+            "FALSE"
+        );
+
+        log::info!("checked against the table");
+        let unknown = RowFilter::And(vec![
+            RowFilter::IsNull("age".to_string()),
+            RowFilter::NotNull("height".to_string()),
+        ]);
+        assert!(matches!(
+            unknown.compile(&table).unwrap_err(),
+            Error::NoSuchColumns(cols) if cols == vec!["age", "height"]
+        ));
+        let unconvertible = RowFilter::Eq(Comparison {
+            column: "timepoint".to_string(),
+            value: json!("late"),
+        });
+        assert!(matches!(
+            unconvertible.compile(&table).unwrap_err(),
+            Error::InvalidFilter(_)
+        ));
+        let null = RowFilter::Eq(Comparison {
+            column: "sick".to_string(),
+            value: Value::Null,
+        });
+        assert!(matches!(
+            null.compile(&table).unwrap_err(),
+            Error::InvalidFilter(_)
+        ));
+
+        log::info!("primary keys");
+        let keys = crate::tests::rows(json!([
+            {"id": 1, "timepoint": 1},
+            {"id": 1, "timepoint": 2, "sick": true}
+        ]));
+        let compiled = RowFilter::from_keys(&table, &keys)
+            .unwrap()
+            .compile(&table)
+            .unwrap();
+        assert_eq!(
+            compiled.condition,
+            "((\"id\" = $1::integer) AND (\"timepoint\" = $2::integer)) \
+            OR ((\"id\" = $3::integer) AND (\"timepoint\" = $4::integer))"
+        );
+        assert!(matches!(
+            RowFilter::from_keys(&table, &[keys[0].clone(), RowJson::new()])
+                .unwrap_err(),
+            Error::InvalidFilter(_)
+        ));
+    }
+    #[test]
+    fn parse_filter() {
+        let _ = pretty_env_logger::try_init();
+        let table = crate::tests::get_test_secondary_table();
+
+        log::info!("compact form");
+        let filter: RowFilter = "and(sick.eq.true,timepoint.ge.2,\
+            or(id.in.(1,2),not(symptoms.is.null)))"
+            .parse()
+            .unwrap();
+        let json: RowFilter = serde_json::from_value(json!({
+            "and": [
+                {"eq": {"column": "sick", "value": "true"}},
+                {"ge": {"column": "timepoint", "value": "2"}},
+                {"or": [
+                    {"in": {"column": "id", "values": ["1", "2"]}},
+                    {"not": {"is_null": "symptoms"}}
+                ]}
+            ]
+        }))
+        .unwrap();
+        assert_eq!(filter, json);
+        assert_eq!(
+            "symptoms->s1.eq.true".parse::<RowFilter>().unwrap(),
+            RowFilter::Path {
+                column: "symptoms".to_string(),
+                path: vec!["s1".to_string()],
+                value: json!(true),
+            }
+        );
+        assert_eq!(
+            "or(symptoms.is.not_null,locations.like.\"%,\\\"(%\",id.in.())"
+                .parse::<RowFilter>()
+                .unwrap(),
+            RowFilter::Or(vec![
+                RowFilter::NotNull("symptoms".to_string()),
+                RowFilter::Like(Comparison {
+                    column: "locations".to_string(),
+                    value: json!("%,\"(%"),
+                }),
+                RowFilter::In {
+                    column: "id".to_string(),
+                    values: vec![],
+                },
+            ])
+        );
+        for invalid in &[
+            "sick",
+            "sick.eq",
+            "sick.is.true",
+            "sick.between.1",
+            "and(sick.eq.true",
+            "not(sick.eq.true,id.eq.1)",
+            "sick.eq.true)",
+            "symptoms->s1.ne.true",
+        ] {
+            assert!(
+                matches!(
+                    invalid.parse::<RowFilter>(),
+                    Err(Error::InvalidFilter(_))
+                ),
+                "{}",
+                invalid
+            );
+        }
+
+        log::info!("patterns, ranges and json paths");
+        let compiled = "and(symptoms.ilike.%s1%,timepoint.range.2..4,\
+            timepoint.range...3,symptoms->s1->\"a b\".eq.{\"c\":1})"
+            .parse::<RowFilter>()
+            .unwrap()
+            .compile(&table)
+            .unwrap();
+        assert_eq!(
+            compiled.condition,
+            "(\"symptoms\"::text ILIKE $1::text) \
+            AND (\"timepoint\" >= $2::integer AND \"timepoint\" < $3::integer) \
+            AND (\"timepoint\" < $4::integer) \
+            AND ((\"symptoms\"::jsonb #> $5::text[]) = $6::jsonb)"
+        );
+        assert_eq!(
+            compiled.values,
+            vec![
+                BindValue::Text("%s1%".to_string()),
+                BindValue::Integer(2),
+                BindValue::Integer(4),
+                BindValue::Integer(3),
+                BindValue::Array(vec![
+                    Some("s1".to_string()),
+                    Some("a b".to_string())
+                ]),
+                BindValue::Json(json!({"c": 1})),
+            ]
+        );
+        assert!(matches!(
+            "sick->s1.eq.true"
+                .parse::<RowFilter>()
+                .unwrap()
+                .compile(&table)
+                .unwrap_err(),
+            Error::InvalidFilter(_)
+        ));
+        assert!(matches!(
+            "age.range.1..2"
+                .parse::<RowFilter>()
+                .unwrap()
+                .compile(&table)
+                .unwrap_err(),
+            Error::NoSuchColumns(cols) if cols == vec!["age"]
+        ));
+    }
+}
@@ -0,0 +1,524 @@
+use super::table::{ColMeta, RowJson, TableMeta};
+use super::types::PostgresType;
+use super::{ByteStream, RowStream};
+use crate::{error::RowError, Error, Result};
+use futures::StreamExt;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// How to read a CSV file into a table
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CsvOptions {
+    /// Field delimiter, an ASCII character
+    #[serde(default = "default_delimiter")]
+    pub delimiter: char,
+    /// Whether the first record names the columns. Without one, columns
+    /// are named by their position starting at 1.
+    #[serde(default = "default_header")]
+    pub header: bool,
+    /// Cells that are null. Comma separated in a query string, empty cells
+    /// are null by default.
+    #[serde(default = "default_null", deserialize_with = "null_markers")]
+    pub null: Vec<String>,
+    /// `strftime` format of the cells of date columns, ISO 8601 if not
+    /// given
+    #[serde(default)]
+    pub date_format: Option<String>,
+    /// `strftime` format of the cells of timestamp columns, ISO 8601 if not
+    /// given. Timestamps without an offset are UTC.
+    #[serde(default)]
+    pub datetime_format: Option<String>,
+    /// Table column of each CSV column, null to skip the CSV column.
+    /// Unmapped CSV columns go to the table column of the same name.
+    /// A json object in a query string.
+    #[serde(default, deserialize_with = "json_mapping")]
+    pub mapping: HashMap<String, Option<String>>,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: default_delimiter(),
+            header: default_header(),
+            null: default_null(),
+            date_format: None,
+            datetime_format: None,
+            mapping: HashMap::new(),
+        }
+    }
+}
+
+impl CsvOptions {
+    pub fn delimiter(mut self, val: char) -> Self {
+        self.delimiter = val;
+        self
+    }
+    pub fn header(mut self, val: bool) -> Self {
+        self.header = val;
+        self
+    }
+    pub fn null(mut self, val: &[&str]) -> Self {
+        self.null = val.iter().map(|s| s.to_string()).collect();
+        self
+    }
+    pub fn date_format(mut self, val: &str) -> Self {
+        self.date_format = Some(val.to_string());
+        self
+    }
+    pub fn datetime_format(mut self, val: &str) -> Self {
+        self.datetime_format = Some(val.to_string());
+        self
+    }
+    pub fn map(mut self, csv_column: &str, column: Option<&str>) -> Self {
+        self.mapping
+            .insert(csv_column.to_string(), column.map(|c| c.to_string()));
+        self
+    }
+}
+
+fn default_delimiter() -> char {
+    ','
+}
+
+fn default_header() -> bool {
+    true
+}
+
+fn default_null() -> Vec<String> {
+    vec![String::new()]
+}
+
+/// Comma separated, empty entries included
+fn null_markers<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+    Ok(s.split(',').map(|marker| marker.to_string()).collect())
+}
+
+/// Json object of names to names or null
+pub fn json_mapping<'de, D>(
+    deserializer: D,
+) -> std::result::Result<HashMap<String, Option<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+    serde_json::from_str(&s).map_err(serde::de::Error::custom)
+}
+
+/// Result of importing a CSV file
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct CsvReport {
+    /// Rows as read for the table, only for dry runs
+    pub rows: Vec<RowJson>,
+    /// Outcome of inserting the rows, with the rows numbered by CSV record
+    /// starting at 0 after the header
+    pub insert: super::table::InsertReport,
+}
+
+/// Rows read from a CSV file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CsvRows {
+    pub rows: Vec<RowJson>,
+    /// Record each row was read from
+    pub records: Vec<usize>,
+    /// Failures of the records that were not read
+    pub errors: Vec<RowError>,
+}
+
+/// Reads the CSV records into rows for the table.
+/// Cells are read as strings for the insert to convert, except for null
+/// markers, dates and timestamps in the given formats, and json and array
+/// cells.
+pub fn read_csv(
+    table: &TableMeta,
+    data: &[u8],
+    options: &CsvOptions,
+) -> Result<CsvRows> {
+    if !options.delimiter.is_ascii() {
+        return Err(Error::InvalidCsv(format!(
+            "delimiter {:?} is not an ASCII character",
+            options.delimiter
+        )));
+    }
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(options.delimiter as u8)
+        .has_headers(options.header)
+        .flexible(true)
+        .from_reader(data);
+    let headers: Option<Vec<String>> = if options.header {
+        let headers = reader
+            .headers()
+            .map_err(|e| Error::InvalidCsv(e.to_string()))?;
+        Some(headers.iter().map(|h| h.to_string()).collect())
+    } else {
+        None
+    };
+    let mut records = reader.records().peekable();
+    let names = match (headers, records.peek()) {
+        (Some(headers), _) => headers,
+        (None, Some(Ok(record))) => {
+            (1..=record.len()).map(|i| i.to_string()).collect()
+        }
+        (None, _) => Vec::new(),
+    };
+    let targets = map_columns(table, &names, options)?;
+    let mut read = CsvRows::default();
+    for (i, record) in records.enumerate() {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                read.errors.push(RowError {
+                    row: i,
+                    column: None,
+                    value: None,
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+        };
+        if record.len() != names.len() {
+            read.errors.push(RowError {
+                row: i,
+                column: None,
+                value: None,
+                reason: format!(
+                    "has {} fields, expected {}",
+                    record.len(),
+                    names.len()
+                ),
+            });
+            continue;
+        }
+        let mut row = RowJson::new();
+        let mut failed = false;
+        for (cell, target) in record.iter().zip(&targets) {
+            let col = match target {
+                Some(col) => col,
+                None => continue,
+            };
+            match read_cell(cell, col, options) {
+                Ok(value) => {
+                    row.insert(col.name.clone(), value);
+                }
+                Err(reason) => {
+                    failed = true;
+                    read.errors.push(RowError {
+                        row: i,
+                        column: Some(col.name.clone()),
+                        value: Some(Value::String(cell.to_string())),
+                        reason,
+                    });
+                }
+            }
+        }
+        if !failed {
+            read.rows.push(row);
+            read.records.push(i);
+        }
+    }
+    Ok(read)
+}
+
+/// Table column of each CSV column, none if skipped
+fn map_columns<'a>(
+    table: &'a TableMeta,
+    names: &[String],
+    options: &CsvOptions,
+) -> Result<Vec<Option<&'a ColMeta>>> {
+    let unknown: Vec<&String> = options
+        .mapping
+        .keys()
+        .filter(|name| !names.contains(name))
+        .collect();
+    if !unknown.is_empty() {
+        return Err(Error::InvalidCsv(format!(
+            "mapped columns {:?} are not in the file",
+            unknown
+        )));
+    }
+    let target_names: Vec<Option<&str>> = names
+        .iter()
+        .map(|name| match options.mapping.get(name) {
+            Some(target) => target.as_deref(),
+            None => Some(name.as_str()),
+        })
+        .collect();
+    let present: Vec<&str> = target_names.iter().flatten().copied().collect();
+    table.verify_cols_present(&present)?;
+    for (i, name) in present.iter().enumerate() {
+        if present[..i].contains(name) {
+            return Err(Error::InvalidCsv(format!(
+                "more than one column goes to column \"{}\"",
+                name
+            )));
+        }
+    }
+    Ok(target_names
+        .iter()
+        .map(|name| name.and_then(|name| table.get_col(name)))
+        .collect())
+}
+
+/// Value of a cell for the column's type, returns the reason on failure
+fn read_cell(
+    cell: &str,
+    col: &ColMeta,
+    options: &CsvOptions,
+) -> std::result::Result<Value, String> {
+    use PostgresType as T;
+    if options.null.iter().any(|marker| marker == cell) {
+        return Ok(Value::Null);
+    }
+    let value = match (&col.postgres_type, &options.date_format) {
+        (T::Date, Some(format)) => {
+            chrono::NaiveDate::parse_from_str(cell, format)
+                .map_err(|e| format!("\"{}\" is not a date: {}", cell, e))?
+                .format("%Y-%m-%d")
+                .to_string()
+                .into()
+        }
+        (T::Timestamp, _) | (T::Timestamptz, _)
+            if options.datetime_format.is_some() =>
+        {
+            read_timestamp(
+                cell,
+                col,
+                options.datetime_format.as_ref().unwrap(),
+            )?
+        }
+        (T::Json, _) | (T::Jsonb, _) | (T::Array(_), _) => {
+            serde_json::from_str(cell)
+                .map_err(|e| format!("\"{}\" is not json: {}", cell, e))?
+        }
+        _ => Value::String(cell.to_string()),
+    };
+    Ok(value)
+}
+
+/// ISO 8601 timestamp, UTC for timestamps with a zone but no offset
+fn read_timestamp(
+    cell: &str,
+    col: &ColMeta,
+    format: &str,
+) -> std::result::Result<Value, String> {
+    let invalid = |e: chrono::ParseError| {
+        format!("\"{}\" is not a timestamp: {}", cell, e)
+    };
+    if col.postgres_type == PostgresType::Timestamptz {
+        if let Ok(t) = chrono::DateTime::parse_from_str(cell, format) {
+            return Ok(t.to_rfc3339().into());
+        }
+    }
+    let naive =
+        chrono::NaiveDateTime::parse_from_str(cell, format).map_err(invalid)?;
+    if col.postgres_type == PostgresType::Timestamptz {
+        use chrono::TimeZone;
+        return Ok(chrono::Utc.from_utc_datetime(&naive).to_rfc3339().into());
+    }
+    Ok(naive.format("%Y-%m-%dT%H:%M:%S%.f").to_string().into())
+}
+
+/// Rows of newline-delimited json, a json object per line, read as the
+/// chunks arrive. Lines may be split across chunks, blank lines are skipped.
+/// A line that is not an object fails as a row, numbered from 0 among the
+/// rows.
+pub fn read_ndjson(chunks: ByteStream) -> RowStream {
+    let mut pending: Vec<u8> = Vec::new();
+    let mut row_index = 0;
+    let rows = chunks
+        .map(Some)
+        // The last line may not end in a newline
+        .chain(futures::stream::once(async { None }))
+        .flat_map(move |chunk| {
+            let lines = match chunk {
+                Some(Ok(chunk)) => {
+                    pending.extend(chunk);
+                    match pending.iter().rposition(|b| *b == b'\n') {
+                        Some(end) => {
+                            let rest = pending.split_off(end + 1);
+                            std::mem::replace(&mut pending, rest)
+                        }
+                        None => Vec::new(),
+                    }
+                }
+                Some(Err(e)) => return futures::stream::iter(vec![Err(e)]),
+                None => std::mem::take(&mut pending),
+            };
+            let mut rows = Vec::new();
+            for line in lines.split(|b| *b == b'\n') {
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                rows.push(read_ndjson_line(row_index, line));
+                row_index += 1;
+            }
+            futures::stream::iter(rows)
+        });
+    Box::pin(rows)
+}
+
+fn read_ndjson_line(row_index: usize, line: &[u8]) -> Result<RowJson> {
+    let reason = match serde_json::from_slice::<Value>(line) {
+        Ok(Value::Object(row)) => return Ok(row),
+        Ok(_) => "not a json object".to_string(),
+        Err(e) => e.to_string(),
+    };
+    Err(Error::InvalidRows(vec![RowError {
+        row: row_index,
+        column: None,
+        value: None,
+        reason,
+    }]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    #[test]
+    fn read_csv_rows() {
+        let _ = pretty_env_logger::try_init();
+        let table = TableMeta::new(
+            "visit",
+            vec![
+                ColMeta::new()
+                    .name("id")
+                    .postgres_type(PostgresType::Integer)
+                    .primary_key(true),
+                ColMeta::new()
+                    .name("date")
+                    .postgres_type(PostgresType::Date),
+                ColMeta::new()
+                    .name("taken")
+                    .postgres_type(PostgresType::Timestamptz),
+                ColMeta::new()
+                    .name("result")
+                    .postgres_type(PostgresType::Jsonb),
+            ],
+        );
+
+        log::info!("header, mapping, nulls and formats");
+        let data = "Subject;Visit date;taken;result;comment\n\
+            1;01/03/2021;01/03/2021 08:30;{\"hb\": 13.5};fasting\n\
+            2;NA;;NA;\n\
+            3;31/02/2021;;;\n\
+            4;;\n\
+            5;;;[1, ;\n";
+        let options = CsvOptions::default()
+            .delimiter(';')
+            .null(&["", "NA"])
+            .date_format("%d/%m/%Y")
+            .datetime_format("%d/%m/%Y %H:%M")
+            .map("Subject", Some("id"))
+            .map("Visit date", Some("date"))
+            .map("comment", None);
+        let read = read_csv(&table, data.as_bytes(), &options).unwrap();
+        let expected = crate::tests::rows(json!([
+            {
+                "id": "1",
+                "date": "2021-03-01",
+                "taken": "2021-03-01T08:30:00+00:00",
+                "result": {"hb": 13.5}
+            },
+            {"id": "2", "date": null, "taken": null, "result": null}
+        ]));
+        assert_eq!(read.rows, expected);
+        assert_eq!(read.records, vec![0, 1]);
+        assert_eq!(
+            read.errors.iter().map(|e| e.row).collect::<Vec<usize>>(),
+            vec![2, 3, 4]
+        );
+        assert_eq!(read.errors[0].column, Some("date".to_string()));
+        assert_eq!(read.errors[0].value, Some(json!("31/02/2021")));
+        assert_eq!(read.errors[1].reason, "has 3 fields, expected 5");
+        assert_eq!(read.errors[2].column, Some("result".to_string()));
+
+        log::info!("no header");
+        let read = read_csv(
+            &table,
+            b"1,2021-03-01\n2,2021-03-02\n",
+            &CsvOptions::default()
+                .header(false)
+                .map("1", Some("id"))
+                .map("2", Some("date")),
+        )
+        .unwrap();
+        assert_eq!(read.rows.len(), 2);
+        assert_eq!(read.rows[1]["date"], "2021-03-02");
+
+        log::info!("columns that don't fit the table");
+        assert!(matches!(
+            read_csv(&table, b"id,age\n1,2\n", &CsvOptions::default())
+                .unwrap_err(),
+            Error::NoSuchColumns(cols) if cols == vec!["age"]
+        ));
+        assert!(matches!(
+            read_csv(
+                &table,
+                b"id,subject\n1,2\n",
+                &CsvOptions::default().map("subject", Some("id"))
+            )
+            .unwrap_err(),
+            Error::InvalidCsv(_)
+        ));
+        assert!(matches!(
+            read_csv(
+                &table,
+                b"id\n1\n",
+                &CsvOptions::default().map("subject", Some("id"))
+            )
+            .unwrap_err(),
+            Error::InvalidCsv(_)
+        ));
+        assert!(matches!(
+            read_csv(&table, b"id\n1\n", &CsvOptions::default().delimiter('§'))
+                .unwrap_err(),
+            Error::InvalidCsv(_)
+        ));
+
+        log::info!("options from a query string");
+        let options: CsvOptions = serde_urlencoded::from_str(
+            "delimiter=%09&header=false&null=NA,&mapping=%7B%22a%22%3Anull%7D",
+        )
+        .unwrap();
+        assert_eq!(options.delimiter, '\t');
+        assert!(!options.header);
+        assert_eq!(options.null, vec!["NA", ""]);
+        assert_eq!(options.mapping.get("a"), Some(&None));
+    }
+
+    #[tokio::test]
+    async fn read_ndjson_rows() {
+        let chunks: Vec<Result<Vec<u8>>> = vec![
+            Ok(b"{\"id\": 1}\n{\"id\"".to_vec()),
+            Ok(b": 2}\n\n[3]\n".to_vec()),
+            Ok(b"{\"id\": 4}".to_vec()),
+        ];
+        let rows: Vec<Result<RowJson>> =
+            read_ndjson(Box::pin(futures::stream::iter(chunks)))
+                .collect()
+                .await;
+        assert_eq!(rows.len(), 4);
+        assert_eq!(
+            rows[0].as_ref().unwrap(),
+            json!({"id": 1}).as_object().unwrap()
+        );
+        assert_eq!(
+            rows[1].as_ref().unwrap(),
+            json!({"id": 2}).as_object().unwrap()
+        );
+        match &rows[2] {
+            Err(Error::InvalidRows(errors)) => assert_eq!(errors[0].row, 2),
+            e => panic!("unexpected {:?}", e),
+        }
+        assert_eq!(
+            rows[3].as_ref().unwrap(),
+            json!({"id": 4}).as_object().unwrap()
+        );
+    }
+}
@@ -1,22 +1,73 @@
-use sqlx::Row;
+use futures::{StreamExt, TryStreamExt};
+use sqlx::{Acquire, Row};
+use std::collections::HashMap;
 
 use crate::db::{ConnectionConfig, Pool, DB};
+use crate::error::{ItemGroupRowError, RowError, SheetRowError};
 use crate::{Error, Result};
 
+pub mod cache;
+pub mod columnar;
+pub mod export;
+pub mod expression;
+pub mod filter;
+pub mod import;
+pub mod odm;
+pub mod page;
+pub mod plan;
+pub mod stats;
 pub mod table;
+pub mod types;
+pub mod value;
+pub mod workbook;
 
-use table::{ColMeta, ColSpec, ForeignKey, RowJson, TableMeta, TableSpec};
+use cache::SchemaCache;
+use columnar::{ArrowExport, ArrowFormat, ArrowOptions};
+use export::{csv_columns, CsvExport, CsvExportOptions, JsonKey, JsonShape};
+use filter::{RowFilter, RowSelection};
+use import::{read_csv, CsvOptions, CsvReport};
+use odm::{ItemGroupReport, OdmOptions, OdmReport, OdmWriter};
+use page::{construct_page_query, encode_cursor, ReadOptions, TablePage};
+use plan::{SchemaChange, SchemaPlan};
+use stats::{StatsExport, StatsFormat, StatsTable};
+use table::{
+    ColMeta, ColSpec, ExclusionElement, IndexMethod, InsertOptions,
+    InsertReport, OnConflict, ReferentialAction, RowJson, TableAlter,
+    TableConstraint, TableDependent, TableIndex, TableMeta, TableSpec,
+};
+use value::BindValue;
+use workbook::{SheetReport, XlsxOptions, XlsxReport, XlsxWriter};
+
+/// Bytes sent per message when copying rows
+const COPY_CHUNK_SIZE: usize = 1 << 16;
+/// Rows fetched ahead of a slow reader of a stream
+const STREAM_BUFFER_ROWS: usize = 256;
+
+/// Rows of a table, read as they are fetched
+pub type RowStream =
+    std::pin::Pin<Box<dyn futures::Stream<Item = Result<RowJson>> + Send>>;
+
+/// Contents of a file, written as they are read
+pub type ByteStream =
+    std::pin::Pin<Box<dyn futures::Stream<Item = Result<Vec<u8>>> + Send>>;
 
 /// User project database
 #[derive(Debug)]
 pub struct UserDB {
     db: DB,
+    /// Whether schema changes made outside of the app are noticed
+    version_tracked: bool,
+    cache: parking_lot::Mutex<SchemaCache>,
 }
 
 impl UserDB {
     pub async fn new(config: ConnectionConfig, name: &str) -> Result<Self> {
+        let db = DB::new(config, name).await?;
+        let version_tracked = cache::track_schema_version(db.get_pool()).await;
         Ok(Self {
-            db: DB::new(config, name).await?,
+            db,
+            version_tracked,
+            cache: parking_lot::Mutex::new(SchemaCache::default()),
         })
     }
     pub fn get_name(&self) -> &str {
@@ -25,8 +76,52 @@ impl UserDB {
     pub fn get_pool(&self) -> &Pool {
         self.db.get_pool()
     }
+    /// Drops cached entries of other schema versions, returns the cache
+    /// generation to store what is read next with.
+    /// With version tracking this is a query on every call, reading one
+    /// row is far cheaper than the catalog queries it saves and it's what
+    /// notices changes made outside of the app.
+    async fn validate_cache(&self) -> Result<u64> {
+        let version = if self.version_tracked {
+            cache::get_schema_version(self.get_pool()).await?
+        } else {
+            0
+        };
+        Ok(self.cache.lock().validate(version))
+    }
+    /// Drops the cached metadata after the app changed the schema
+    fn invalidate_cache(&self) {
+        self.cache.lock().clear();
+    }
     pub async fn get_all_table_names(&self) -> Result<Vec<String>> {
-        self.db.get_all_table_names().await
+        let generation = self.validate_cache().await?;
+        let cached = self.cache.lock().get_table_names();
+        if let Some(names) = cached {
+            return Ok(names);
+        }
+        let names = self.db.get_all_table_names().await?;
+        self.cache.lock().set_table_names(generation, names.clone());
+        Ok(names)
+    }
+    /// Checks that the table has a primary key or unique constraint of the
+    /// name, returns Err if not
+    async fn check_unique_constraint_exists(
+        &self,
+        table_name: &str,
+        name: &str,
+    ) -> Result<()> {
+        let mut con = self.get_pool().acquire().await?;
+        if !get_constraints(&mut con, "public", table_name)
+            .await?
+            .iter()
+            .any(|c| c.name == name && (c.kind == "u" || c.kind == "p"))
+        {
+            return Err(Error::NoSuchUniqueConstraint(
+                table_name.to_string(),
+                name.to_string(),
+            ));
+        }
+        Ok(())
     }
     /// Checks that the table exists, returns Err if not
     async fn check_table_exists(&self, name: &str) -> Result<()> {
@@ -39,114 +134,379 @@ impl UserDB {
         }
         Ok(())
     }
-    /// Creates the given table
+    /// Creates the given table with its indexes
     pub async fn create_table(&self, table: &TableMeta) -> Result<()> {
         if self.get_all_table_names().await?.contains(&table.name) {
             return Err(Error::TableAlreadyExists(table.name.clone()));
         }
-        sqlx::query(table.construct_create_query().as_str())
-            .execute(self.get_pool())
-            .await?;
+        let mut tx = self.get_pool().begin().await?;
+        execute_create_table(&mut tx, table).await?;
+        tx.commit().await?;
+        self.invalidate_cache();
         Ok(())
     }
 
-    /// Removes a table
-    pub async fn remove_table(&self, table_name: &str) -> Result<()> {
-        log::debug!("removing table {}", table_name);
-
+    /// Get the indexes of a table
+    pub async fn get_indexes(
+        &self,
+        table_name: &str,
+    ) -> Result<Vec<TableIndex>> {
         self.check_table_exists(table_name).await?;
+        let mut con = self.get_pool().acquire().await?;
+        get_indexes(&mut con, "public", table_name).await
+    }
+
+    /// Creates an index on a table
+    pub async fn create_index(
+        &self,
+        table_name: &str,
+        index: &TableIndex,
+    ) -> Result<()> {
+        log::debug!("creating index {:?} on {}", index, table_name);
+        self.get_table_meta(table_name)
+            .await?
+            .verify_cols_present(&index.columns)?;
+        // Index names share a namespace with tables
+        let taken: bool = sqlx::query(
+            "SELECT pg_catalog.to_regclass(\
+            'public.' || pg_catalog.quote_ident($1)) IS NOT NULL",
+        )
+        .bind(index.name.as_str())
+        .fetch_one(self.get_pool())
+        .await?
+        .get(0);
+        if taken {
+            return Err(Error::IndexAlreadyExists(index.name.clone()));
+        }
+        index.check_expressions()?;
+        let mut con = self.get_pool().acquire().await?;
+        execute_definition(
+            &mut con,
+            index.construct_create_query(table_name).as_str(),
+        )
+        .await?;
+        self.invalidate_cache();
+        Ok(())
+    }
 
-        sqlx::query(format!("DROP TABLE \"{}\"", table_name).as_str())
+    /// Removes an index of a table
+    pub async fn remove_index(
+        &self,
+        table_name: &str,
+        index_name: &str,
+    ) -> Result<()> {
+        log::debug!("removing index {} of {}", index_name, table_name);
+        if !self
+            .get_indexes(table_name)
+            .await?
+            .iter()
+            .any(|i| i.name == index_name)
+        {
+            return Err(Error::NoSuchIndex(index_name.to_string()));
+        }
+        sqlx::query(format!("DROP INDEX \"{}\"", index_name).as_str())
             .execute(self.get_pool())
             .await?;
+        self.invalidate_cache();
         Ok(())
     }
 
-    /// Get all table metadata
-    pub async fn get_table_meta(&self, table_name: &str) -> Result<TableMeta> {
-        log::debug!("get metadata for {}", table_name);
-
+    /// Removes a table.
+    /// Without cascading, errors if anything depends on the table.
+    /// With cascading, the dependents are dropped too and returned.
+    pub async fn remove_table(
+        &self,
+        table_name: &str,
+        cascade: bool,
+    ) -> Result<Vec<TableDependent>> {
+        log::debug!("removing table {} (cascade: {})", table_name, cascade);
         self.check_table_exists(table_name).await?;
 
-        let mut cols = ColSpec::new();
-
-        // Non-constraint-related metadata
-        let res = sqlx::query(
-            r#"
-        SELECT
-            cols.column_name,
-            cols.data_type,
-            cols.is_nullable
-        FROM
-            information_schema.columns AS cols
-        WHERE cols.table_name = $1
-        "#,
+        let mut tx = self.get_pool().begin().await?;
+        // Nothing new can come to depend on the table before it's dropped
+        sqlx::query(
+            format!("LOCK TABLE \"{}\" IN ACCESS EXCLUSIVE MODE", table_name)
+                .as_str(),
         )
-        .bind(table_name)
-        .fetch_all(self.get_pool())
+        .execute(&mut tx)
         .await?;
-
-        for row in res {
-            cols.push(
-                ColMeta::new()
-                    .name(row.get("column_name"))
-                    .postgres_type(row.get("data_type"))
-                    .not_null(row.get::<&str, &str>("is_nullable") == "NO"),
+        let dependents = get_dependents(&mut tx, table_name).await?;
+        if !dependents.is_empty() {
+            if !cascade {
+                return Err(Error::TableHasDependents(
+                    table_name.to_string(),
+                    dependents,
+                ));
+            }
+            log::info!(
+                "dropping dependents of {}: {:?}",
+                table_name,
+                dependents
             );
         }
 
-        // Constraint-related metadata
-        let res = sqlx::query(
-            r#"
-        SELECT
-            kcu.column_name,
-            tc.constraint_type,
-            ccu.table_name AS foreign_table_name,
-            ccu.column_name AS foreign_column_name
-        FROM
-            information_schema.table_constraints AS tc
-            JOIN information_schema.key_column_usage AS kcu
-                ON tc.constraint_name = kcu.constraint_name
-                AND tc.table_schema = kcu.table_schema
-            LEFT JOIN information_schema.constraint_column_usage AS ccu
-                ON ccu.constraint_name = tc.constraint_name
-                AND ccu.table_schema = tc.table_schema
-                AND tc.constraint_type = 'FOREIGN KEY'
-        WHERE kcu.table_name = $1
-        "#,
-        )
-        .bind(table_name)
-        .fetch_all(self.get_pool())
+        let mut query = format!("DROP TABLE \"{}\"", table_name);
+        if cascade {
+            query = format!("{} CASCADE", query);
+        }
+        sqlx::query(query.as_str()).execute(&mut tx).await?;
+        tx.commit().await?;
+        self.invalidate_cache();
+        Ok(dependents)
+    }
+
+    /// Objects that would be dropped along with the table on cascade
+    pub async fn get_table_dependents(
+        &self,
+        table_name: &str,
+    ) -> Result<Vec<TableDependent>> {
+        self.check_table_exists(table_name).await?;
+        let mut con = self.get_pool().acquire().await?;
+        get_dependents(&mut con, table_name).await
+    }
+
+    /// Renames a table. Foreign keys follow the table.
+    pub async fn rename_table(
+        &self,
+        table_name: &str,
+        new_name: &str,
+    ) -> Result<()> {
+        log::debug!("renaming table {} to {}", table_name, new_name);
+        let table_names = self.get_all_table_names().await?;
+        if !table_names.contains(&table_name.to_string()) {
+            return Err(Error::NoSuchTable(table_name.to_string()));
+        }
+        if table_names.contains(&new_name.to_string()) {
+            return Err(Error::TableAlreadyExists(new_name.to_string()));
+        }
+        sqlx::query(
+            format!(
+                "ALTER TABLE \"{}\" RENAME TO \"{}\"",
+                table_name, new_name
+            )
+            .as_str(),
+        )
+        .execute(self.get_pool())
         .await?;
+        self.invalidate_cache();
+        Ok(())
+    }
 
-        for row in res {
-            // Can't have a column appear in this query but not the other one,
-            // so unwrap is ok here.
-            let i = cols
+    /// Applies the changes to a table in one transaction.
+    /// All changes are checked against the current metadata first.
+    pub async fn alter_table(
+        &self,
+        table_name: &str,
+        alters: &[TableAlter],
+    ) -> Result<()> {
+        log::debug!("altering table {} with {:?}", table_name, alters);
+        let mut table = self.get_table_meta(table_name).await?;
+        for alter in alters {
+            table.apply_alter(alter)?;
+        }
+        let mut tx = self.get_pool().begin().await?;
+        for alter in alters {
+            execute_alter(&mut tx, table_name, alter).await?;
+        }
+        tx.commit().await?;
+        self.invalidate_cache();
+        Ok(())
+    }
+
+    /// Changes that would take the current schema to the desired one.
+    /// Desired expressions are compared in the form the catalog stores them.
+    pub async fn plan_schema(
+        &self,
+        desired: &[TableMeta],
+    ) -> Result<SchemaPlan> {
+        let desired = self.canonicalize(desired).await?;
+        plan::plan(&self.get_all_meta().await?, &desired)
+    }
+
+    /// Tables with their defaults, checks, generation expressions and index
+    /// predicates as the catalog reads them back, e.g. `age >= 0` as
+    /// `(age >= 0)`.
+    /// Each table with expressions is created as a temporary table in a
+    /// transaction that is rolled back, so nothing is left behind and other
+    /// sessions don't see it. Foreign keys are left out since the tables
+    /// they reference may not exist yet.
+    async fn canonicalize(&self, tables: &[TableMeta]) -> Result<TableSpec> {
+        let mut tables = tables.to_vec();
+        let mut tx = self.get_pool().begin().await?;
+        for table in tables.iter_mut() {
+            let has_expressions = table.cols.iter().any(|c| {
+                c.default.is_some()
+                    || c.check.is_some()
+                    || c.generated.is_some()
+            }) || table
+                .constraints
                 .iter()
-                .position(|c| c.name == row.get::<&str, &str>("column_name"))
-                .unwrap();
-            match row.get("constraint_type") {
-                "PRIMARY KEY" => {
-                    cols[i].primary_key = true;
+                .any(|c| matches!(c, TableConstraint::Check(_)))
+                || table.indexes.iter().any(|i| i.predicate.is_some());
+            if !has_expressions {
+                continue;
+            }
+            let mut scratch = table.clone();
+            for col in scratch.cols.iter_mut() {
+                col.foreign_key = None;
+            }
+            scratch
+                .constraints
+                .retain(|c| !matches!(c, TableConstraint::ForeignKey { .. }));
+            scratch.check_expressions()?;
+            // Temporary tables and their indexes shadow the project's own
+            execute_definition(
+                &mut tx,
+                scratch.construct_create_temp_query().as_str(),
+            )
+            .await?;
+            for query in scratch.construct_create_index_queries() {
+                execute_definition(&mut tx, query.as_str()).await?;
+            }
+            let canonical =
+                read_catalog_meta(&mut tx, "pg_temp", &scratch.name).await?;
+            sqlx::query(
+                format!("DROP TABLE pg_temp.\"{}\"", scratch.name).as_str(),
+            )
+            .execute(&mut tx)
+            .await?;
+            for (col, canonical_col) in
+                table.cols.iter_mut().zip(canonical.cols)
+            {
+                col.default = canonical_col.default;
+                col.check = canonical_col.check;
+                col.generated = canonical_col.generated;
+            }
+            table
+                .constraints
+                .retain(|c| !matches!(c, TableConstraint::Check(_)));
+            table.constraints.extend(
+                canonical
+                    .constraints
+                    .into_iter()
+                    .filter(|c| matches!(c, TableConstraint::Check(_))),
+            );
+            for index in table.indexes.iter_mut() {
+                index.predicate = canonical
+                    .indexes
+                    .iter()
+                    .find(|c| c.name == index.name)
+                    .and_then(|c| c.predicate.clone());
+            }
+        }
+        tx.rollback().await?;
+        Ok(tables)
+    }
+
+    /// Takes the current schema to the desired one in one transaction.
+    /// Refuses plans with warnings unless destructive changes are allowed.
+    /// Returns the applied plan.
+    pub async fn apply_schema(
+        &self,
+        desired: &[TableMeta],
+        allow_destructive: bool,
+    ) -> Result<SchemaPlan> {
+        let plan = self.plan_schema(desired).await?;
+        let warnings: Vec<String> =
+            plan.iter().filter_map(|c| c.warning.clone()).collect();
+        if !warnings.is_empty() && !allow_destructive {
+            return Err(Error::DestructivePlan(warnings));
+        }
+        log::debug!("applying schema plan {:?}", plan);
+        let mut tx = self.get_pool().begin().await?;
+        for planned in &plan {
+            match &planned.change {
+                SchemaChange::CreateTable(table) => {
+                    execute_create_table(&mut tx, table).await?;
+                }
+                SchemaChange::AlterTable(table_name, alter) => {
+                    execute_alter(&mut tx, table_name, alter).await?;
+                }
+                SchemaChange::CreateIndex(table_name, index) => {
+                    index.check_expressions()?;
+                    execute_definition(
+                        &mut tx,
+                        index.construct_create_query(table_name).as_str(),
+                    )
+                    .await?;
                 }
-                "FOREIGN KEY" => {
-                    cols[i].foreign_key = Some(ForeignKey::new(
-                        row.get("foreign_table_name"),
-                        row.get("foreign_column_name"),
-                    ));
+                SchemaChange::DropIndex(_, index_name) => {
+                    sqlx::query(
+                        format!("DROP INDEX \"{}\"", index_name).as_str(),
+                    )
+                    .execute(&mut tx)
+                    .await?;
                 }
-                "UNIQUE" => {
-                    cols[i].unique = true;
+                SchemaChange::AddConstraint(table_name, constraint) => {
+                    constraint.check_expressions()?;
+                    execute_definition(
+                        &mut tx,
+                        format!(
+                            "ALTER TABLE \"{}\" ADD {}",
+                            table_name,
+                            constraint.create_query_entry()
+                        )
+                        .as_str(),
+                    )
+                    .await?;
+                }
+                SchemaChange::DropConstraint(table_name, constraint) => {
+                    let name = get_constraints(&mut tx, "public", table_name)
+                        .await?
+                        .into_iter()
+                        .find(|c| {
+                            c.to_table_constraint(table_name).ok().as_ref()
+                                == Some(constraint)
+                        })
+                        .ok_or_else(|| {
+                            Error::NoSuchConstraint(
+                                table_name.clone(),
+                                constraint.clone(),
+                            )
+                        })?
+                        .name;
+                    sqlx::query(
+                        format!(
+                            "ALTER TABLE \"{}\" DROP CONSTRAINT \"{}\"",
+                            table_name, name
+                        )
+                        .as_str(),
+                    )
+                    .execute(&mut tx)
+                    .await?;
+                }
+                SchemaChange::DropTable(table_name) => {
+                    sqlx::query(
+                        format!("DROP TABLE \"{}\"", table_name).as_str(),
+                    )
+                    .execute(&mut tx)
+                    .await?;
                 }
-                // Do nothing with these for now
-                "CHECK" => {}
-                // There shouldn't be any other contraint types in Postgres
-                s => panic!("unexpected contraint type: {}", s),
             }
         }
+        tx.commit().await?;
+        self.invalidate_cache();
+        Ok(plan)
+    }
+
+    /// Get all table metadata, cached until the schema changes
+    pub async fn get_table_meta(&self, table_name: &str) -> Result<TableMeta> {
+        let generation = self.validate_cache().await?;
+        let cached = self.cache.lock().get_table(table_name);
+        if let Some(table) = cached {
+            return Ok(table);
+        }
+        let table = self.read_table_meta(table_name).await?;
+        self.cache.lock().set_table(generation, table.clone());
+        Ok(table)
+    }
 
-        Ok(TableMeta::new(table_name, cols))
+    /// Reads the table metadata from the catalog
+    async fn read_table_meta(&self, table_name: &str) -> Result<TableMeta> {
+        log::debug!("get metadata for {}", table_name);
+        self.check_table_exists(table_name).await?;
+        let mut con = self.get_pool().acquire().await?;
+        read_catalog_meta(&mut con, "public", table_name).await
     }
 
     /// Get all tables metadata
@@ -159,62 +519,365 @@ impl UserDB {
         Ok(table_spec)
     }
 
-    /// Insert data into a table
+    /// Insert rows into a table in one transaction.
+    /// Values are converted to their columns' types first. Every row is
+    /// tried and the failures of all rows are reported together. Unless
+    /// partial, nothing is inserted if any row fails.
+    /// Conflicting rows fail unless the options say to update them or leave
+    /// them be.
+    /// Returns the primary key of every inserted or updated row, including
+    /// generated ones.
     pub async fn insert_table_data(
         &self,
         table_name: &str,
         data: &[RowJson],
+        options: &InsertOptions,
+    ) -> Result<InsertReport> {
+        let table = self.get_table_meta(table_name).await?;
+        if data.is_empty() {
+            return Err(Error::InsertEmptyData);
+        }
+        if let Some(constraint) = &options.constraint {
+            self.check_unique_constraint_exists(table_name, constraint)
+                .await?;
+        }
+        let mut tx = self.get_pool().begin().await?;
+        let report = insert_rows(&mut tx, &table, data, options).await?;
+        if options.dry_run {
+            tx.rollback().await?;
+            return Ok(report);
+        }
+        if !report.errors.is_empty() && !options.partial {
+            tx.rollback().await?;
+            return Err(Error::InvalidRows(report.errors));
+        }
+        tx.commit().await?;
+        Ok(report)
+    }
+
+    /// Insert the records of a CSV file into a table.
+    /// Records that can't be read fail like rows that can't be inserted,
+    /// failures are numbered by record.
+    /// A dry run also returns the rows as read.
+    pub async fn import_csv(
+        &self,
+        table_name: &str,
+        data: &[u8],
+        csv_options: &CsvOptions,
+        options: &InsertOptions,
+    ) -> Result<CsvReport> {
+        let table = self.get_table_meta(table_name).await?;
+        let read = read_csv(&table, data, csv_options)?;
+        if read.rows.is_empty() && read.errors.is_empty() {
+            return Err(Error::InsertEmptyData);
+        }
+        // Unless partial, a record that can't be read stops the insert
+        let dry_run =
+            options.dry_run || (!read.errors.is_empty() && !options.partial);
+        let inserted = if read.rows.is_empty() {
+            Ok(InsertReport::default())
+        } else {
+            self.insert_table_data(
+                table_name,
+                &read.rows,
+                &options.clone().dry_run(dry_run),
+            )
+            .await
+        };
+        let mut report = match inserted {
+            Ok(report) => report,
+            Err(Error::InvalidRows(errors)) => InsertReport {
+                errors,
+                ..Default::default()
+            },
+            Err(e) => return Err(e),
+        };
+        for error in &mut report.errors {
+            error.row = read.records[error.row];
+        }
+        report.errors.extend(read.errors);
+        report.errors.sort_by_key(|e| e.row);
+        if !report.errors.is_empty() && !options.partial && !options.dry_run {
+            return Err(Error::InvalidRows(report.errors));
+        }
+        Ok(CsvReport {
+            rows: if options.dry_run {
+                read.rows
+            } else {
+                Vec::new()
+            },
+            insert: report,
+        })
+    }
+
+    /// Updates rows addressed by their primary key in one transaction.
+    /// Every row has the values of all primary key columns and of the
+    /// columns to set, null sets null. Values are converted like for
+    /// inserts. Nothing is updated if any row fails, the failures of all
+    /// rows are reported together.
+    pub async fn update_table_data(
+        &self,
+        table_name: &str,
+        data: &[RowJson],
     ) -> Result<()> {
-        use serde_json::Value;
-        use std::str::FromStr;
         let table = self.get_table_meta(table_name).await?;
         if data.is_empty() {
             return Err(Error::InsertEmptyData);
         }
-        for row in data {
-            // Only keep the columns that are not null
-            let col_names: Vec<String> = row
+        let key = table.get_primary_key();
+        if key.is_empty() {
+            return Err(Error::NoPrimaryKey(table.name.clone()));
+        }
+        let mut errors = Vec::new();
+        let mut tx = self.get_pool().begin().await?;
+        for (row_index, row) in data.iter().enumerate() {
+            let row_error = |column: Option<&str>, reason: &str| RowError {
+                row: row_index,
+                column: column.map(|c| c.to_string()),
+                value: None,
+                reason: reason.to_string(),
+            };
+            if let Some(missing) = key
                 .iter()
-                .filter_map(|(k, v)| {
-                    if v.is_null() {
-                        None
-                    } else {
-                        Some(k.to_string())
-                    }
-                })
+                .find(|k| row.get(**k).is_none_or(|v| v.is_null()))
+            {
+                errors.push(row_error(
+                    Some(missing),
+                    "missing primary key value",
+                ));
+                continue;
+            }
+            let set_cols: Vec<&str> = row
+                .keys()
+                .map(|k| k.as_str())
+                .filter(|k| !key.contains(k))
                 .collect();
-            let query = table.construct_param_insert_query(&col_names)?;
+            if set_cols.is_empty() {
+                errors.push(row_error(None, "no columns to set"));
+                continue;
+            }
+            let query = table.construct_param_update_query(&set_cols)?;
+            let param_cols: Vec<&str> =
+                set_cols.iter().chain(key.iter()).copied().collect();
+            let values = match convert_row(&table, row_index, row, &param_cols)
+            {
+                Ok(values) => values,
+                Err(Error::InvalidRows(row_errors)) => {
+                    errors.extend(row_errors);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            // A failed row only rolls back its own savepoint
+            let mut savepoint = tx.begin().await?;
             let mut row_query = sqlx::query(query.as_str());
-            for col_name in &col_names {
-                // Can't possibly not find this column name after having
-                // successfully constructed the query above
-                let col_meta =
-                    table.cols.iter().find(|c| &c.name == col_name).unwrap();
-                match &row[col_name] {
-                    Value::Number(n) => row_query = row_query.bind(n.as_f64()),
-                    Value::String(s) => {
-                        // This might be a date
-                        if col_meta.postgres_type == "timestamp with time zone"
-                        {
-                            row_query = row_query.bind(chrono::DateTime::<
-                                chrono::Utc,
-                            >::from_str(
-                                s.as_str()
-                            )?)
-                        } else {
-                            row_query = row_query.bind(s.as_str())
-                        }
+            for value in values {
+                row_query = value.bind(row_query);
+            }
+            match row_query.execute(&mut savepoint).await {
+                Ok(res) => {
+                    savepoint.commit().await?;
+                    if res.rows_affected() == 0 {
+                        errors.push(row_error(
+                            None,
+                            "no row with this primary key",
+                        ));
                     }
-                    Value::Bool(b) => row_query = row_query.bind(b),
-                    // Everything else is just a json
-                    other => row_query = row_query.bind(other),
                 }
+                Err(sqlx::Error::Database(e)) if is_row_error(e.as_ref()) => {
+                    savepoint.rollback().await?;
+                    let error = get_row_error(
+                        &mut tx,
+                        table_name,
+                        row_index,
+                        e.as_ref(),
+                    )
+                    .await?;
+                    errors.push(error);
+                }
+                Err(e) => return Err(e.into()),
             }
-            row_query.execute(self.get_pool()).await?;
         }
+        if !errors.is_empty() {
+            tx.rollback().await?;
+            return Err(Error::InvalidRows(errors));
+        }
+        tx.commit().await?;
         Ok(())
     }
 
+    /// Bulk loads rows with `COPY ... FROM STDIN`, all or nothing.
+    /// Rows are sent on as they are read. The first row's columns are
+    /// loaded, later rows can leave them out but can't add others. Missing
+    /// values are null rather than the column defaults. Values are
+    /// converted to their columns' types as they are streamed, all
+    /// conversion failures are reported together. Postgres stops at the
+    /// first row it rejects.
+    /// Much faster than `insert_table_data` for large batches but returns no
+    /// keys. Returns the number of rows loaded.
+    pub async fn copy_table_data(
+        &self,
+        table_name: &str,
+        mut rows: RowStream,
+    ) -> Result<u64> {
+        let table = self.get_table_meta(table_name).await?;
+        let first = rows.try_next().await?.ok_or(Error::InsertEmptyData)?;
+        let present: Vec<&String> = first.keys().collect();
+        if present.is_empty() {
+            return Err(Error::InsertEmptyData);
+        }
+        table.verify_cols_present(&present)?;
+        // In table order
+        let col_names: Vec<&str> = table
+            .cols
+            .iter()
+            .filter(|c| first.contains_key(&c.name))
+            .map(|c| c.name.as_str())
+            .collect();
+        let query = format!(
+            "COPY \"{}\"(\"{}\") FROM STDIN",
+            table.name,
+            col_names.join("\",\"")
+        );
+        log::debug!("copying rows with {}", query);
+
+        let mut con = self.get_pool().acquire().await?;
+        let mut copy = con.copy_in_raw(query.as_str()).await?;
+        let mut errors = Vec::new();
+        let mut chunk = String::with_capacity(COPY_CHUNK_SIZE);
+        let mut rows = futures::stream::iter(Some(Ok(first)))
+            .chain(rows)
+            .enumerate();
+        while let Some((row_index, row)) = rows.next().await {
+            let converted = row.and_then(|row| {
+                if let Some(extra) =
+                    row.keys().find(|k| !col_names.contains(&k.as_str()))
+                {
+                    return Err(Error::InvalidRows(vec![RowError {
+                        row: row_index,
+                        column: Some(extra.clone()),
+                        value: row.get(extra).cloned(),
+                        reason: "not a column of the first row".to_string(),
+                    }]));
+                }
+                convert_row(&table, row_index, &row, &col_names)
+            });
+            let values = match converted {
+                Ok(values) => values,
+                Err(Error::InvalidRows(row_errors)) => {
+                    errors.extend(row_errors);
+                    continue;
+                }
+                Err(e) => {
+                    copy.abort(e.to_string()).await?;
+                    return Err(e);
+                }
+            };
+            // Nothing will be loaded, keep converting to report every failure
+            if !errors.is_empty() {
+                continue;
+            }
+            let fields: Vec<String> =
+                values.iter().map(|v| v.to_copy_text()).collect();
+            chunk.push_str(fields.join("\t").as_str());
+            chunk.push('\n');
+            if chunk.len() >= COPY_CHUNK_SIZE {
+                copy.send(chunk.as_bytes()).await?;
+                chunk.clear();
+            }
+        }
+        if !errors.is_empty() {
+            copy.abort("rows could not be converted").await?;
+            return Err(Error::InvalidRows(errors));
+        }
+        if !chunk.is_empty() {
+            copy.send(chunk.as_bytes()).await?;
+        }
+        match copy.finish().await {
+            Ok(rows) => Ok(rows),
+            Err(sqlx::Error::Database(e)) if is_row_error(e.as_ref()) => {
+                // Context is "COPY <table>, line <n>[, column <c>]: ..."
+                let row_index = e
+                    .try_downcast_ref::<sqlx::postgres::PgDatabaseError>()
+                    .and_then(|e| e.r#where())
+                    .and_then(|w| w.split(", line ").nth(1))
+                    .and_then(|w| {
+                        w.split(|c: char| !c.is_ascii_digit())
+                            .next()
+                            .and_then(|line| line.parse::<usize>().ok())
+                    })
+                    .map_or(0, |line| line.saturating_sub(1));
+                // The connection is left out of sync after a failed copy
+                drop(con.detach());
+                let mut con = self.get_pool().acquire().await?;
+                let error =
+                    get_row_error(&mut con, table_name, row_index, e.as_ref())
+                        .await?;
+                Err(Error::InvalidRows(vec![error]))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Removes the selected rows, returns how many were removed.
+    /// Refuses a filter that matches every row of a non-empty table unless
+    /// confirmed.
+    pub async fn remove_table_rows(
+        &self,
+        table_name: &str,
+        selection: &RowSelection,
+        confirm_all: bool,
+    ) -> Result<u64> {
+        let table = self.get_table_meta(table_name).await?;
+        let filter = selection.to_filter(&table)?.compile(&table)?;
+        // Keys name the rows they remove, only filters can match everything
+        if !matches!(selection, RowSelection::Filter(_)) || confirm_all {
+            let query = format!(
+                "DELETE FROM \"{}\" WHERE {}",
+                table.name, filter.condition
+            );
+            let mut delete_query = sqlx::query(query.as_str());
+            for value in filter.values {
+                delete_query = value.bind(delete_query);
+            }
+            return Ok(delete_query
+                .execute(self.get_pool())
+                .await
+                .map_err(filter_error)?
+                .rows_affected());
+        }
+        // Counting and deleting in one statement means they see the same
+        // rows, so a concurrent insert can't slip between the check and
+        // the delete
+        let query = format!(
+            "WITH \"counts\" AS (\
+                SELECT count(*) FILTER (WHERE {1}) AS \"matched\", \
+                count(*) AS \"total\" FROM \"{0}\"\
+            ), \"deleted\" AS (\
+                DELETE FROM \"{0}\" WHERE ({1}) AND \
+                (SELECT \"matched\" < \"total\" FROM \"counts\") \
+                RETURNING 1\
+            ) \
+            SELECT \"matched\", \"total\", \
+            (SELECT count(*) FROM \"deleted\") FROM \"counts\"",
+            table.name, filter.condition
+        );
+        let mut delete_query = sqlx::query(query.as_str());
+        for value in filter.values {
+            delete_query = value.bind(delete_query);
+        }
+        let counts = delete_query
+            .fetch_one(self.get_pool())
+            .await
+            .map_err(filter_error)?;
+        let (matched, total, deleted): (i64, i64, i64) =
+            (counts.get(0), counts.get(1), counts.get(2));
+        if matched > 0 && matched == total {
+            return Err(Error::SelectionMatchesAll(table.name.clone()));
+        }
+        Ok(deleted as u64)
+    }
+
     /// Remove all data from a table
     pub async fn remove_all_table_data(&self, table_name: &str) -> Result<()> {
         self.check_table_exists(table_name).await?;
@@ -245,52 +908,1320 @@ impl UserDB {
         }
         Ok(rows)
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    const TEST_DB_NAME: &str = "postgres_test_user";
 
-    // Test database
-    #[tokio::test]
-    async fn test_user() {
-        let _ = pretty_env_logger::try_init();
-        let test_config = crate::tests::gen_test_config("anything");
-        crate::tests::setup_test_db(TEST_DB_NAME).await;
-        let db = UserDB::new(test_config.clone(), TEST_DB_NAME)
+    /// Get a page of the table's rows that match the filter, ordered by the
+    /// sort columns and then by primary key.
+    /// The next page starts after the returned cursor, or at the next
+    /// offset if the table has no primary key.
+    pub async fn get_table_page(
+        &self,
+        table_name: &str,
+        options: &ReadOptions,
+    ) -> Result<TablePage> {
+        let table = self.get_table_meta(table_name).await?;
+        let (order, filter) = options.compile(&table)?;
+        let counted = RowFilter::And(options.filter.iter().cloned().collect())
+            .compile(&table)?;
+        // One more row than asked for tells whether there is a next page
+        let query = construct_page_query(
+            &table,
+            &options.columns,
+            &[],
+            &order,
+            filter.condition.as_str(),
+            options.limit.map(|limit| limit + 1),
+            options.offset,
+        )?;
+        let mut tx = self.get_pool().begin().await?;
+        // Count and page see the same rows
+        sqlx::query(
+            "SET TRANSACTION ISOLATION LEVEL REPEATABLE READ READ ONLY",
+        )
+        .execute(&mut tx)
+        .await?;
+        let count_query = format!(
+            "SELECT count(*) FROM \"{}\" WHERE {}",
+            table.name, counted.condition
+        );
+        let mut count_query = sqlx::query(count_query.as_str());
+        for value in counted.values {
+            count_query = value.bind(count_query);
+        }
+        let total: i64 = count_query
+            .fetch_one(&mut tx)
             .await
-            .unwrap();
+            .map_err(filter_error)?
+            .get(0);
+        let mut page_query = sqlx::query(query.as_str());
+        for value in filter.values {
+            page_query = value.bind(page_query);
+        }
+        let res = page_query.fetch_all(&mut tx).await.map_err(filter_error)?;
+        tx.commit().await?;
+        let mut page = TablePage {
+            rows: Vec::with_capacity(res.len()),
+            total,
+            next_cursor: None,
+        };
+        for (i, row) in res.iter().enumerate() {
+            if Some(i as u64) == options.limit {
+                // Only a unique order can be continued from
+                if i > 0 && !table.get_primary_key().is_empty() {
+                    let last = res[i - 1].get::<serde_json::Value, usize>(1);
+                    match serde_json::from_value::<Vec<Option<String>>>(
+                        last.clone(),
+                    ) {
+                        Ok(values) => {
+                            page.next_cursor = Some(encode_cursor(&values))
+                        }
+                        Err(_) => return Err(Error::RowParse(last)),
+                    }
+                }
+                break;
+            }
+            match row.get::<serde_json::Value, usize>(0).as_object() {
+                Some(o) => page.rows.push(o.clone()),
+                None => return Err(Error::RowParse(row.get(0))),
+            }
+        }
+        Ok(page)
+    }
 
-        assert!(db.get_all_table_names().await.unwrap().is_empty());
+    /// Stream the table's rows that match the filter, in the same order and
+    /// with the same limit, offset and cursor as pages but without counting
+    /// them.
+    /// Rows are fetched as the stream is read, at most `STREAM_BUFFER_ROWS`
+    /// ahead of it. The stream ends after an error.
+    pub async fn stream_table_data(
+        &self,
+        table_name: &str,
+        options: &ReadOptions,
+    ) -> Result<RowStream> {
+        let table = self.get_table_meta(table_name).await?;
+        let (order, filter) = options.compile(&table)?;
+        let query = construct_page_query(
+            &table,
+            &options.columns,
+            &[],
+            &order,
+            filter.condition.as_str(),
+            options.limit,
+            options.offset,
+        )?;
+        Ok(fetch_rows(self.get_pool().clone(), query, filter.values))
+    }
 
-        log::info!("create table");
+    /// Write the table's rows that match the filter as a CSV file, in the
+    /// same order as they are streamed.
+    /// Flattened json columns get the keys found anywhere in the table.
+    pub async fn export_table_csv(
+        &self,
+        table_name: &str,
+        options: &ReadOptions,
+        csv_options: &CsvExportOptions,
+    ) -> Result<ByteStream> {
+        let export = self
+            .prepare_csv_export(table_name, options, csv_options)
+            .await?;
+        export::write_csv(
+            export.columns,
+            fetch_rows(self.get_pool().clone(), export.query, export.values),
+            csv_options,
+        )
+    }
 
-        let primary_table = crate::tests::get_test_primary_table();
+    /// Write every table as a CSV file into a zip archive.
+    /// Tables are written one after another in the order of their names.
+    pub async fn export_project_csv(
+        &self,
+        csv_options: &CsvExportOptions,
+    ) -> Result<ByteStream> {
+        let options = ReadOptions::default();
+        let mut exports = Vec::new();
+        for table_name in self.get_all_table_names().await? {
+            exports.push(
+                self.prepare_csv_export(&table_name, &options, csv_options)
+                    .await?,
+            );
+        }
+        export::zip_csv(self.get_pool().clone(), exports, csv_options)
+    }
 
-        db.create_table(&primary_table).await.unwrap();
-        assert!(!db.get_all_table_names().await.unwrap().is_empty());
-        assert!(db
-            .get_all_table_names()
-            .await
-            .unwrap()
-            .contains(&primary_table.name));
+    /// Write the table's rows that match the filter as a Parquet file or an
+    /// Arrow stream, in the same order as they are streamed.
+    /// The schema follows the table's column types.
+    pub async fn export_table_arrow(
+        &self,
+        table_name: &str,
+        options: &ReadOptions,
+        arrow_options: &ArrowOptions,
+        format: ArrowFormat,
+    ) -> Result<ByteStream> {
+        let export = self
+            .prepare_arrow_export(table_name, options, arrow_options)
+            .await?;
+        columnar::write_arrow(
+            export.schema,
+            fetch_rows(self.get_pool().clone(), export.query, export.values),
+            format,
+        )
+    }
 
-        let secondary_table = crate::tests::get_test_secondary_table();
+    /// Write every table as a Parquet file or an Arrow stream into a zip
+    /// archive.
+    /// Tables are written one after another in the order of their names.
+    pub async fn export_project_arrow(
+        &self,
+        arrow_options: &ArrowOptions,
+        format: ArrowFormat,
+    ) -> Result<ByteStream> {
+        let options = ReadOptions::default();
+        let mut files = Vec::new();
+        for table_name in self.get_all_table_names().await? {
+            let export = self
+                .prepare_arrow_export(&table_name, &options, arrow_options)
+                .await?;
+            let pool = self.get_pool().clone();
+            let file: export::ArchiveFile = (
+                format!("{}.{}", table_name, format.extension()),
+                Box::new(move || {
+                    let rows = fetch_rows(pool, export.query, export.values);
+                    columnar::write_arrow(export.schema, rows, format)
+                }),
+            );
+            files.push(file);
+        }
+        Ok(export::zip_files(files))
+    }
 
-        db.create_table(&secondary_table).await.unwrap();
-        assert!(!db.get_all_table_names().await.unwrap().is_empty());
-        assert!(db
-            .get_all_table_names()
-            .await
-            .unwrap()
-            .contains(&secondary_table.name));
+    /// Write the table for a statistical package, as a Stata or SPSS file
+    /// or as a zip archive of a CSV file and an R script that reads it.
+    /// Variable types follow the column types and the columns' labels and
+    /// value labels are kept.
+    /// Stata and SPSS files are written whole, the archive as it is read.
+    pub async fn export_table_stats(
+        &self,
+        table_name: &str,
+        format: StatsFormat,
+    ) -> Result<StatsExport> {
+        let export = self.prepare_stats_export(table_name).await?;
+        let write = match format {
+            StatsFormat::R => {
+                return Ok(StatsExport::Archive(stats::zip_stats(
+                    self.get_pool().clone(),
+                    vec![export],
+                    format,
+                )))
+            }
+            StatsFormat::Stata => stats::write_dta,
+            StatsFormat::Spss => stats::write_sav,
+        };
+        let rows: Vec<RowJson> =
+            fetch_rows(self.get_pool().clone(), export.query, export.values)
+                .try_collect()
+                .await?;
+        Ok(StatsExport::File(write(&export.table, &rows)?))
+    }
 
-        let date_table = crate::tests::get_date_table();
+    /// Write every table for a statistical package into a zip archive.
+    /// Tables are written one after another in the order of their names.
+    pub async fn export_project_stats(
+        &self,
+        format: StatsFormat,
+    ) -> Result<ByteStream> {
+        let mut exports = Vec::new();
+        for table_name in self.get_all_table_names().await? {
+            exports.push(self.prepare_stats_export(&table_name).await?);
+        }
+        Ok(stats::zip_stats(self.get_pool().clone(), exports, format))
+    }
 
-        db.create_table(&date_table).await.unwrap();
-        assert!(!db.get_all_table_names().await.unwrap().is_empty());
+    /// Write every table into a workbook, one sheet per table after a
+    /// dictionary sheet describing their columns.
+    /// Rows are fetched and written a table at a time.
+    pub async fn export_xlsx(&self) -> Result<Vec<u8>> {
+        let tables = self.get_all_meta().await?;
+        let mut writer = XlsxWriter::new(&tables)?;
+        for table in &tables {
+            let mut rows = self
+                .stream_table_data(&table.name, &ReadOptions::default())
+                .await?;
+            while let Some(row) = rows.try_next().await? {
+                writer.write_row(table, &row)?;
+            }
+        }
+        writer.finish()
+    }
+
+    /// Insert the sheets of a workbook into their tables in one
+    /// transaction, tables referenced by foreign keys first.
+    /// Rows go through the same conversion as inserted json rows. Failures
+    /// are numbered by sheet row and reported together, unless partial
+    /// nothing is inserted if any row fails.
+    pub async fn import_xlsx(
+        &self,
+        data: &[u8],
+        xlsx_options: &XlsxOptions,
+        options: &InsertOptions,
+    ) -> Result<XlsxReport> {
+        let sheets = workbook::read_workbook(data)?;
+        let mut tables = Vec::new();
+        let mut reads = Vec::new();
+        for (sheet, table_name) in
+            workbook::sheet_tables(&sheets, xlsx_options)?
+        {
+            let table = match self.get_table_meta(&table_name).await {
+                Ok(table) => table,
+                Err(Error::NoSuchTable(_)) => {
+                    return Err(Error::InvalidXlsx(format!(
+                        "sheet \"{}\" goes to table \"{}\" that does not \
+                        exist",
+                        sheet, table_name
+                    )))
+                }
+                Err(e) => return Err(e),
+            };
+            if let Some(constraint) = &options.constraint {
+                self.check_unique_constraint_exists(&table_name, constraint)
+                    .await?;
+            }
+            let range =
+                &sheets.iter().find(|(name, _)| *name == sheet).unwrap().1;
+            reads.push((sheet, workbook::read_sheet(&table, range)?));
+            tables.push(table);
+        }
+        if reads
+            .iter()
+            .all(|(_, read)| read.rows.is_empty() && read.errors.is_empty())
+        {
+            return Err(Error::InsertEmptyData);
+        }
+        let mut report = XlsxReport::default();
+        let mut errors = Vec::new();
+        let mut tx = self.get_pool().begin().await?;
+        for i in workbook::insert_order(&tables) {
+            let (sheet, read) = &reads[i];
+            let mut insert =
+                insert_rows(&mut tx, &tables[i], &read.rows, options).await?;
+            for error in &mut insert.errors {
+                error.row = read.records[error.row];
+            }
+            insert.errors.extend(read.errors.iter().cloned());
+            insert.errors.sort_by_key(|e| e.row);
+            errors.extend(
+                insert
+                    .errors
+                    .iter()
+                    .map(|e| SheetRowError::new(sheet, e.clone())),
+            );
+            report.sheets.push(SheetReport {
+                sheet: sheet.clone(),
+                table: tables[i].name.clone(),
+                insert,
+            });
+        }
+        if options.dry_run {
+            tx.rollback().await?;
+            return Ok(report);
+        }
+        if !errors.is_empty() && !options.partial {
+            tx.rollback().await?;
+            return Err(Error::InvalidSheetRows(errors));
+        }
+        tx.commit().await?;
+        Ok(report)
+    }
+
+    /// Write every table into an ODM document of the study, as the item
+    /// groups of the study's metadata and their data.
+    /// Rows are fetched and written a table at a time.
+    pub async fn export_odm(&self, study: &str) -> Result<Vec<u8>> {
+        let tables = self.get_all_meta().await?;
+        let mut writer = OdmWriter::new(study, &tables)?;
+        for table in &tables {
+            let mut rows = self
+                .stream_table_data(&table.name, &ReadOptions::default())
+                .await?;
+            while let Some(row) = rows.try_next().await? {
+                writer.write_row(table, &row)?;
+            }
+        }
+        writer.finish()
+    }
+
+    /// Create a table for every item group of an ODM document and insert
+    /// the item groups' data in one transaction.
+    /// Rows go through the same conversion as inserted json rows. Failures
+    /// are numbered by item group data and reported together, unless
+    /// partial nothing is created if any row fails.
+    pub async fn import_odm(
+        &self,
+        data: &[u8],
+        odm_options: &OdmOptions,
+        options: &InsertOptions,
+    ) -> Result<OdmReport> {
+        let tables = odm::read_odm(data, odm_options)?;
+        let existing = self.get_all_table_names().await?;
+        if let Some(table) =
+            tables.iter().find(|t| existing.contains(&t.table.name))
+        {
+            return Err(Error::TableAlreadyExists(table.table.name.clone()));
+        }
+        let mut report = OdmReport::default();
+        let mut errors = Vec::new();
+        let mut tx = self.get_pool().begin().await?;
+        for table in &tables {
+            execute_create_table(&mut tx, &table.table).await?;
+        }
+        for table in &tables {
+            let mut insert =
+                insert_rows(&mut tx, &table.table, &table.rows, options)
+                    .await?;
+            for error in &mut insert.errors {
+                error.row = table.records[error.row];
+            }
+            insert.errors.extend(table.errors.iter().cloned());
+            insert.errors.sort_by_key(|e| e.row);
+            errors.extend(
+                insert.errors.iter().map(|e| {
+                    ItemGroupRowError::new(&table.item_group, e.clone())
+                }),
+            );
+            report.item_groups.push(ItemGroupReport {
+                item_group: table.item_group.clone(),
+                table: table.table.name.clone(),
+                insert,
+            });
+        }
+        if options.dry_run {
+            tx.rollback().await?;
+            return Ok(report);
+        }
+        if !errors.is_empty() && !options.partial {
+            tx.rollback().await?;
+            return Err(Error::InvalidItemGroupRows(errors));
+        }
+        tx.commit().await?;
+        self.invalidate_cache();
+        Ok(report)
+    }
+
+    /// Columns and query of a table's CSV file
+    async fn prepare_csv_export(
+        &self,
+        table_name: &str,
+        options: &ReadOptions,
+        csv_options: &CsvExportOptions,
+    ) -> Result<CsvExport> {
+        let table = self.get_table_meta(table_name).await?;
+        let (order, filter) = options.compile(&table)?;
+        let query = construct_page_query(
+            &table,
+            &options.columns,
+            &[],
+            &order,
+            filter.condition.as_str(),
+            options.limit,
+            options.offset,
+        )?;
+        let shapes = match csv_options.json {
+            export::JsonColumns::Flatten => {
+                self.get_json_shapes(&table).await?
+            }
+            export::JsonColumns::Text => HashMap::new(),
+        };
+        Ok(CsvExport {
+            table_name: table.name.clone(),
+            columns: csv_columns(
+                &table,
+                &options.columns,
+                &shapes,
+                csv_options,
+            )?,
+            query,
+            values: filter.values,
+        })
+    }
+
+    /// Table to export for a statistical package and the query for all of
+    /// its rows
+    async fn prepare_stats_export(
+        &self,
+        table_name: &str,
+    ) -> Result<StatsTable> {
+        let table = self.get_table_meta(table_name).await?;
+        let options = ReadOptions::default();
+        let (order, filter) = options.compile(&table)?;
+        let query = construct_page_query(
+            &table,
+            &options.columns,
+            &[],
+            &order,
+            filter.condition.as_str(),
+            options.limit,
+            options.offset,
+        )?;
+        Ok(StatsTable {
+            table,
+            query,
+            values: filter.values,
+        })
+    }
+
+    /// Columns of a table export as Parquet or Arrow and the query for
+    /// their rows
+    async fn prepare_arrow_export(
+        &self,
+        table_name: &str,
+        options: &ReadOptions,
+        arrow_options: &ArrowOptions,
+    ) -> Result<ArrowExport> {
+        let table = self.get_table_meta(table_name).await?;
+        let (order, filter) = options.compile(&table)?;
+        let query = construct_page_query(
+            &table,
+            &options.columns,
+            &columnar::text_columns(&table),
+            &order,
+            filter.condition.as_str(),
+            options.limit,
+            options.offset,
+        )?;
+        let shapes = match arrow_options.json {
+            columnar::JsonArrow::Struct => self.get_json_shapes(&table).await?,
+            columnar::JsonArrow::String => HashMap::new(),
+        };
+        Ok(ArrowExport {
+            table_name: table.name.clone(),
+            schema: columnar::arrow_schema(
+                &table,
+                &options.columns,
+                &shapes,
+                arrow_options,
+            )?,
+            query,
+            values: filter.values,
+        })
+    }
+
+    /// Shapes of the table's json columns by column name
+    async fn get_json_shapes(
+        &self,
+        table: &TableMeta,
+    ) -> Result<HashMap<String, JsonShape>> {
+        let mut shapes = HashMap::new();
+        for col in &table.cols {
+            if matches!(
+                col.postgres_type,
+                types::PostgresType::Json | types::PostgresType::Jsonb
+            ) {
+                shapes.insert(
+                    col.name.clone(),
+                    self.get_json_shape(&table.name, &col.name).await?,
+                );
+            }
+        }
+        Ok(shapes)
+    }
+
+    /// Keys of the objects in a json column with the types of their values,
+    /// and whether the column has values that are not objects
+    async fn get_json_shape(
+        &self,
+        table_name: &str,
+        column: &str,
+    ) -> Result<JsonShape> {
+        let keys = sqlx::query(
+            format!(
+                "SELECT \"key\", array_agg(DISTINCT jsonb_typeof(\"value\")) \
+                FILTER (WHERE jsonb_typeof(\"value\") <> 'null') \
+                FROM \"{0}\", jsonb_each(\
+                CASE WHEN jsonb_typeof(\"{1}\"::jsonb) = 'object' \
+                THEN \"{1}\"::jsonb END) \
+                GROUP BY \"key\" ORDER BY \"key\"",
+                table_name, column
+            )
+            .as_str(),
+        )
+        .fetch_all(self.get_pool())
+        .await?;
+        let other: bool = sqlx::query(
+            format!(
+                "SELECT EXISTS (SELECT 1 FROM \"{0}\" \
+                WHERE jsonb_typeof(\"{1}\"::jsonb) NOT IN ('object', 'null'))",
+                table_name, column
+            )
+            .as_str(),
+        )
+        .fetch_one(self.get_pool())
+        .await?
+        .get(0);
+        Ok(JsonShape {
+            keys: keys
+                .iter()
+                .map(|row| JsonKey {
+                    name: row.get(0),
+                    types: row
+                        .get::<Option<Vec<String>>, usize>(1)
+                        .unwrap_or_default(),
+                })
+                .collect(),
+            other,
+        })
+    }
+}
+
+/// Rows of a query, fetched at most `STREAM_BUFFER_ROWS` ahead of the
+/// stream's reader
+fn fetch_rows(pool: Pool, query: String, values: Vec<BindValue>) -> RowStream {
+    let (sender, receiver) = tokio::sync::mpsc::channel(STREAM_BUFFER_ROWS);
+    tokio::spawn(async move {
+        let mut row_query = sqlx::query(query.as_str());
+        for value in values {
+            row_query = value.bind(row_query);
+        }
+        let mut rows = row_query.fetch(&pool);
+        loop {
+            let row = match rows.try_next().await {
+                Ok(Some(row)) => match row.get::<serde_json::Value, usize>(0) {
+                    serde_json::Value::Object(o) => Ok(o),
+                    other => Err(Error::RowParse(other)),
+                },
+                Ok(None) => break,
+                Err(e) => Err(filter_error(e)),
+            };
+            let failed = row.is_err();
+            // Nobody is reading anymore
+            if sender.send(row).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    Box::pin(futures::stream::unfold(
+        receiver,
+        |mut receiver| async move {
+            receiver.recv().await.map(|row| (row, receiver))
+        },
+    ))
+}
+
+/// Inserts rows into a table, each in its own savepoint. Failed rows are
+/// reported and the rest are kept.
+async fn insert_rows(
+    con: &mut sqlx::PgConnection,
+    table: &TableMeta,
+    data: &[RowJson],
+    options: &InsertOptions,
+) -> Result<InsertReport> {
+    let upsert = options.on_conflict != OnConflict::Error;
+    let mut report = InsertReport::default();
+    for (row_index, row) in data.iter().enumerate() {
+        // Only keep the columns that are not null
+        let col_names: Vec<String> = row
+            .iter()
+            .filter_map(|(k, v)| {
+                if v.is_null() {
+                    None
+                } else {
+                    Some(k.to_string())
+                }
+            })
+            .collect();
+        let null_cols: Vec<&str> = row
+            .iter()
+            .filter(|(_, v)| v.is_null())
+            .map(|(k, _)| k.as_str())
+            .collect();
+        let query = table.construct_param_upsert_query(
+            &col_names,
+            &null_cols,
+            &options.on_conflict,
+            options.constraint.as_deref(),
+        )?;
+        let values = match convert_row(table, row_index, row, &col_names) {
+            Ok(values) => values,
+            Err(Error::InvalidRows(errors)) => {
+                report.errors.extend(errors);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        // A failed row only rolls back its own savepoint
+        let mut savepoint = con.begin().await?;
+        let mut row_query = sqlx::query(query.as_str());
+        for value in values {
+            row_query = value.bind(row_query);
+        }
+        match row_query.fetch_optional(&mut savepoint).await {
+            Ok(Some(res)) => {
+                savepoint.commit().await?;
+                match res.get::<serde_json::Value, usize>(0).as_object() {
+                    Some(o) => report.keys.push(o.clone()),
+                    None => return Err(Error::RowParse(res.get(0))),
+                }
+                if !upsert || res.get::<bool, usize>(1) {
+                    report.inserted += 1;
+                } else {
+                    report.updated += 1;
+                }
+            }
+            // Left as is on conflict
+            Ok(None) => {
+                savepoint.commit().await?;
+                report.unchanged += 1;
+            }
+            Err(sqlx::Error::Database(e)) if is_row_error(e.as_ref()) => {
+                savepoint.rollback().await?;
+                let error = get_row_error(
+                    &mut *con,
+                    table.name.as_str(),
+                    row_index,
+                    e.as_ref(),
+                )
+                .await?;
+                report.errors.push(error);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(report)
+}
+
+/// Reads the metadata of an existing table from the catalog
+async fn read_catalog_meta(
+    con: &mut sqlx::PgConnection,
+    schema: &str,
+    table_name: &str,
+) -> Result<TableMeta> {
+    let mut cols = ColSpec::new();
+
+    // Non-constraint-related metadata.
+    // information_schema drops type modifiers and array element types,
+    // format_type keeps them.
+    // The default of a generated column is its generation expression.
+    let res = sqlx::query(
+        r#"
+    SELECT
+        attr.attname AS column_name,
+        pg_catalog.format_type(attr.atttypid, attr.atttypmod) AS data_type,
+        attr.attnotnull AS not_null,
+        pg_catalog.pg_get_expr(def.adbin, def.adrelid) AS default_expr,
+        attr.attgenerated = 's' AS generated,
+        attr.attidentity <> '' AS identity,
+        pg_catalog.col_description(attr.attrelid, attr.attnum) AS comment
+    FROM
+        pg_catalog.pg_attribute AS attr
+        LEFT JOIN pg_catalog.pg_attrdef AS def
+            ON def.adrelid = attr.attrelid
+            AND def.adnum = attr.attnum
+    WHERE attr.attrelid = (
+        pg_catalog.quote_ident($2) || '.' || pg_catalog.quote_ident($1)
+    )::regclass
+        AND attr.attnum > 0
+        AND NOT attr.attisdropped
+    ORDER BY attr.attnum
+    "#,
+    )
+    .bind(table_name)
+    .bind(schema)
+    .fetch_all(&mut *con)
+    .await?;
+
+    for row in res {
+        let mut col = ColMeta::new()
+            .name(row.get("column_name"))
+            .postgres_type(row.get::<&str, &str>("data_type").parse()?)
+            .not_null(row.get("not_null"))
+            .identity(row.get("identity"));
+        let default_expr: Option<String> = row.get("default_expr");
+        if row.get("generated") {
+            col.generated = default_expr;
+        } else {
+            col.default = default_expr;
+        }
+        if let Some(comment) = row.get::<Option<&str>, &str>("comment") {
+            (col.label, col.value_labels) = table::parse_comment(comment);
+        }
+        cols.push(col);
+    }
+
+    // Constraints. Single-column checks are column-level, several on one
+    // column are joined with AND.
+    let mut constraints = Vec::new();
+    for catalog in get_constraints(&mut *con, schema, table_name).await? {
+        match (catalog.kind.as_str(), catalog.columns.as_slice()) {
+            ("p", _) if !catalog.deferrable => {
+                for col in cols.iter_mut() {
+                    if catalog.columns.contains(&col.name) {
+                        col.primary_key = true;
+                    }
+                }
+            }
+            ("c", [col_name]) => {
+                if let Some(col) = cols.iter_mut().find(|c| &c.name == col_name)
+                {
+                    col.check = match (col.check.take(), catalog.expression) {
+                        (Some(a), Some(b)) => Some(format!("{} AND {}", a, b)),
+                        (a, b) => a.or(b),
+                    };
+                }
+            }
+            // Not null constraints, read from the columns above
+            ("n", _) => {}
+            _ => constraints.push(catalog.to_table_constraint(table_name)?),
+        }
+    }
+
+    let indexes = get_indexes(&mut *con, schema, table_name).await?;
+
+    let mut table = TableMeta::new(table_name, cols)
+        .constraints(constraints)
+        .indexes(indexes);
+    table.normalize_constraints();
+    Ok(table)
+}
+
+/// Runs the queries for one table change, looking up the constraints it
+/// replaces
+async fn execute_alter(
+    con: &mut sqlx::PgConnection,
+    table_name: &str,
+    alter: &TableAlter,
+) -> Result<()> {
+    alter.check_expressions()?;
+    let replaced = match alter {
+        TableAlter::SetUnique { name, .. } => {
+            get_col_constraint_names(&mut *con, table_name, name, "u").await?
+        }
+        TableAlter::SetForeignKey { name, .. } => {
+            get_col_constraint_names(&mut *con, table_name, name, "f").await?
+        }
+        TableAlter::SetCheck { name, .. } => {
+            get_col_constraint_names(&mut *con, table_name, name, "c").await?
+        }
+        _ => Vec::new(),
+    };
+    for query in alter.construct_queries(table_name, &replaced) {
+        execute_definition(&mut *con, query.as_str()).await?;
+    }
+    Ok(())
+}
+
+/// Creates a table and its indexes
+async fn execute_create_table(
+    con: &mut sqlx::PgConnection,
+    table: &TableMeta,
+) -> Result<()> {
+    table.check_expressions()?;
+    execute_definition(&mut *con, table.construct_create_query().as_str())
+        .await?;
+    for query in table
+        .construct_comment_queries()
+        .into_iter()
+        .chain(table.construct_create_index_queries())
+    {
+        execute_definition(&mut *con, query.as_str()).await?;
+    }
+    Ok(())
+}
+
+/// Runs a query that defines tables, columns, constraints or indexes.
+/// Syntax errors and references to objects that don't exist come from the
+/// caller's expressions and names, and are reported as such.
+async fn execute_definition(
+    con: &mut sqlx::PgConnection,
+    query: &str,
+) -> Result<()> {
+    match sqlx::query(query).execute(con).await {
+        Ok(_) => Ok(()),
+        Err(sqlx::Error::Database(e))
+            if matches!(
+                e.code(),
+                // Insufficient privilege is the server's problem
+                Some(code) if code.starts_with("42") && code != "42501"
+            ) =>
+        {
+            Err(Error::InvalidDefinition(e.message().to_string()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Indexes of a table that don't back key constraints.
+/// Expression indexes and unknown access methods are skipped.
+async fn get_indexes(
+    con: &mut sqlx::PgConnection,
+    schema: &str,
+    table_name: &str,
+) -> Result<Vec<TableIndex>> {
+    let res = sqlx::query(
+        r#"
+    SELECT
+        index_class.relname::text AS name,
+        am.amname::text AS method,
+        idx.indisunique AS is_unique,
+        pg_catalog.pg_get_expr(idx.indpred, idx.indrelid) AS predicate,
+        ARRAY(
+            SELECT attr.attname::text
+            FROM
+                unnest(idx.indkey::int2[]) WITH ORDINALITY AS k(attnum, ord)
+                JOIN pg_catalog.pg_attribute AS attr
+                    ON attr.attrelid = idx.indrelid
+                    AND attr.attnum = k.attnum
+            WHERE k.ord <= idx.indnkeyatts
+            ORDER BY k.ord
+        ) AS columns
+    FROM
+        pg_catalog.pg_index AS idx
+        JOIN pg_catalog.pg_class AS index_class
+            ON index_class.oid = idx.indexrelid
+        JOIN pg_catalog.pg_am AS am ON am.oid = index_class.relam
+    WHERE idx.indrelid = (
+        pg_catalog.quote_ident($2) || '.' || pg_catalog.quote_ident($1)
+    )::regclass
+        AND 0 <> ALL(idx.indkey::int2[])
+        AND NOT EXISTS (
+            SELECT 1
+            FROM pg_catalog.pg_constraint AS con
+            WHERE con.conrelid = idx.indrelid
+                AND con.conindid = idx.indexrelid
+        )
+    ORDER BY index_class.relname
+    "#,
+    )
+    .bind(table_name)
+    .bind(schema)
+    .fetch_all(con)
+    .await?;
+    let mut indexes = Vec::with_capacity(res.len());
+    for row in res {
+        let name: String = row.get("name");
+        let method = match IndexMethod::from_name(row.get("method")) {
+            Some(m) => m,
+            None => {
+                log::warn!("skipping index {} with unknown method", name);
+                continue;
+            }
+        };
+        indexes.push(TableIndex {
+            name,
+            columns: row.get("columns"),
+            method,
+            unique: row.get("is_unique"),
+            predicate: row.get("predicate"),
+        });
+    }
+    Ok(indexes)
+}
+
+/// Foreign keys referencing the table and the views that depend on it,
+/// directly or through other views
+async fn get_dependents(
+    con: &mut sqlx::PgConnection,
+    table_name: &str,
+) -> Result<Vec<TableDependent>> {
+    let res = sqlx::query(
+        r#"
+    WITH RECURSIVE dependent_view(oid) AS (
+        SELECT rule.ev_class
+        FROM
+            pg_catalog.pg_depend AS dep
+            JOIN pg_catalog.pg_rewrite AS rule ON rule.oid = dep.objid
+        WHERE dep.classid = 'pg_catalog.pg_rewrite'::regclass
+            AND dep.refclassid = 'pg_catalog.pg_class'::regclass
+            AND dep.refobjid = ('public.' || pg_catalog.quote_ident($1))::regclass
+            AND rule.ev_class <> dep.refobjid
+        UNION
+        SELECT rule.ev_class
+        FROM
+            dependent_view AS view
+            JOIN pg_catalog.pg_depend AS dep
+                ON dep.refobjid = view.oid
+                AND dep.refclassid = 'pg_catalog.pg_class'::regclass
+                AND dep.classid = 'pg_catalog.pg_rewrite'::regclass
+            JOIN pg_catalog.pg_rewrite AS rule ON rule.oid = dep.objid
+        WHERE rule.ev_class <> dep.refobjid
+    )
+    SELECT
+        dependent.relname::text AS table_name,
+        con.conname::text AS constraint_name
+    FROM
+        pg_catalog.pg_constraint AS con
+        JOIN pg_catalog.pg_class AS dependent
+            ON dependent.oid = con.conrelid
+    WHERE con.contype = 'f'
+        AND con.confrelid = ('public.' || pg_catalog.quote_ident($1))::regclass
+        AND con.conrelid <> con.confrelid
+    UNION
+    SELECT
+        dependent.relname::text AS table_name,
+        NULL AS constraint_name
+    FROM
+        dependent_view AS view
+        JOIN pg_catalog.pg_class AS dependent ON dependent.oid = view.oid
+    ORDER BY table_name, constraint_name
+    "#,
+    )
+    .bind(table_name)
+    .fetch_all(con)
+    .await?;
+    Ok(res
+        .iter()
+        .map(|row| match row.get("constraint_name") {
+            Some(constraint) => TableDependent::ForeignKey {
+                table: row.get("table_name"),
+                constraint,
+            },
+            None => TableDependent::View(row.get("table_name")),
+        })
+        .collect())
+}
+
+/// Constraint as stored in the catalog
+struct CatalogConstraint {
+    name: String,
+    /// `pg_constraint.contype` code
+    kind: String,
+    deferrable: bool,
+    columns: Vec<String>,
+    foreign_schema: Option<String>,
+    foreign_table: Option<String>,
+    foreign_columns: Vec<String>,
+    on_delete: Option<ReferentialAction>,
+    on_update: Option<ReferentialAction>,
+    /// `pg_constraint.confmatchtype` code
+    match_type: String,
+    /// Check expression
+    expression: Option<String>,
+    /// Access method of an exclusion's index
+    exclusion_method: Option<String>,
+    /// Operator names of an exclusion, in element order
+    exclusion_operators: Vec<String>,
+    /// Whether an exclusion has a WHERE predicate
+    exclusion_partial: bool,
+    /// Full definition
+    definition: String,
+}
+
+impl CatalogConstraint {
+    /// Table-level form.
+    /// Errors for primary keys and anything that can't be represented.
+    fn to_table_constraint(&self, table_name: &str) -> Result<TableConstraint> {
+        let unsupported = || {
+            Error::UnsupportedConstraint(
+                table_name.to_string(),
+                self.name.clone(),
+                self.definition.clone(),
+            )
+        };
+        if self.deferrable {
+            return Err(unsupported());
+        }
+        match self.kind.as_str() {
+            "u" => Ok(TableConstraint::Unique(self.columns.clone())),
+            "f" => {
+                // Tables of other schemas and MATCH FULL aren't modelled
+                if self.foreign_schema.as_deref() != Some("public")
+                    || self.match_type != "s"
+                {
+                    return Err(unsupported());
+                }
+                Ok(TableConstraint::ForeignKey {
+                    columns: self.columns.clone(),
+                    table: self
+                        .foreign_table
+                        .clone()
+                        .ok_or_else(unsupported)?,
+                    foreign_columns: self.foreign_columns.clone(),
+                    on_delete: self.on_delete.ok_or_else(unsupported)?,
+                    on_update: self.on_update.ok_or_else(unsupported)?,
+                })
+            }
+            "c" => Ok(TableConstraint::Check(
+                self.expression.clone().ok_or_else(unsupported)?,
+            )),
+            "x" => {
+                // Expression elements are missing from the columns
+                if self.exclusion_partial
+                    || self.columns.len() != self.exclusion_operators.len()
+                {
+                    return Err(unsupported());
+                }
+                let method = self
+                    .exclusion_method
+                    .as_deref()
+                    .and_then(IndexMethod::from_name)
+                    .ok_or_else(unsupported)?;
+                Ok(TableConstraint::Exclude {
+                    method,
+                    elements: self
+                        .columns
+                        .iter()
+                        .zip(&self.exclusion_operators)
+                        .map(|(c, o)| ExclusionElement::new(c, o))
+                        .collect(),
+                })
+            }
+            _ => Err(unsupported()),
+        }
+    }
+}
+
+/// Constraints of a table, with their columns in constraint order
+async fn get_constraints(
+    con: &mut sqlx::PgConnection,
+    schema: &str,
+    table_name: &str,
+) -> Result<Vec<CatalogConstraint>> {
+    let res = sqlx::query(
+        r#"
+    SELECT
+        con.conname::text AS name,
+        con.contype::text AS kind,
+        con.condeferrable AS deferrable,
+        ARRAY(
+            SELECT attr.attname::text
+            FROM
+                unnest(con.conkey) WITH ORDINALITY AS k(attnum, ord)
+                JOIN pg_catalog.pg_attribute AS attr
+                    ON attr.attrelid = con.conrelid
+                    AND attr.attnum = k.attnum
+            ORDER BY k.ord
+        ) AS columns,
+        foreign_ns.nspname::text AS foreign_schema,
+        foreign_class.relname::text AS foreign_table,
+        ARRAY(
+            SELECT attr.attname::text
+            FROM
+                unnest(con.confkey) WITH ORDINALITY AS k(attnum, ord)
+                JOIN pg_catalog.pg_attribute AS attr
+                    ON attr.attrelid = con.confrelid
+                    AND attr.attnum = k.attnum
+            ORDER BY k.ord
+        ) AS foreign_columns,
+        con.confdeltype::text AS on_delete,
+        con.confupdtype::text AS on_update,
+        con.confmatchtype::text AS match_type,
+        pg_catalog.pg_get_expr(con.conbin, con.conrelid) AS expression,
+        exclusion_am.amname::text AS exclusion_method,
+        ARRAY(
+            SELECT op.oprname::text
+            FROM
+                unnest(con.conexclop) WITH ORDINALITY AS o(oid, ord)
+                JOIN pg_catalog.pg_operator AS op ON op.oid = o.oid
+            ORDER BY o.ord
+        ) AS exclusion_operators,
+        exclusion_idx.indpred IS NOT NULL AS exclusion_partial,
+        pg_catalog.pg_get_constraintdef(con.oid) AS definition
+    FROM
+        pg_catalog.pg_constraint AS con
+        LEFT JOIN pg_catalog.pg_class AS foreign_class
+            ON foreign_class.oid = con.confrelid
+        LEFT JOIN pg_catalog.pg_namespace AS foreign_ns
+            ON foreign_ns.oid = foreign_class.relnamespace
+        LEFT JOIN pg_catalog.pg_class AS exclusion_class
+            ON con.contype = 'x' AND exclusion_class.oid = con.conindid
+        LEFT JOIN pg_catalog.pg_am AS exclusion_am
+            ON exclusion_am.oid = exclusion_class.relam
+        LEFT JOIN pg_catalog.pg_index AS exclusion_idx
+            ON con.contype = 'x' AND exclusion_idx.indexrelid = con.conindid
+    WHERE con.conrelid = (
+        pg_catalog.quote_ident($2) || '.' || pg_catalog.quote_ident($1)
+    )::regclass
+    ORDER BY con.conname
+    "#,
+    )
+    .bind(table_name)
+    .bind(schema)
+    .fetch_all(con)
+    .await?;
+    Ok(res
+        .iter()
+        .map(|row| CatalogConstraint {
+            name: row.get("name"),
+            kind: row.get("kind"),
+            deferrable: row.get("deferrable"),
+            columns: row.get("columns"),
+            foreign_schema: row.get("foreign_schema"),
+            foreign_table: row.get("foreign_table"),
+            foreign_columns: row.get("foreign_columns"),
+            on_delete: ReferentialAction::from_code(row.get("on_delete")),
+            on_update: ReferentialAction::from_code(row.get("on_update")),
+            match_type: row.get("match_type"),
+            expression: row.get("expression"),
+            exclusion_method: row.get("exclusion_method"),
+            exclusion_operators: row.get("exclusion_operators"),
+            exclusion_partial: row
+                .get::<Option<bool>, _>("exclusion_partial")
+                .unwrap_or(false),
+            definition: row.get("definition"),
+        })
+        .collect())
+}
+
+/// Names of the single-column constraints of the given kind on a column.
+/// Kind is the `pg_constraint.contype` code, e.g. "u" for unique.
+async fn get_col_constraint_names(
+    con: &mut sqlx::PgConnection,
+    table_name: &str,
+    col_name: &str,
+    kind: &str,
+) -> Result<Vec<String>> {
+    let res = sqlx::query(
+        r#"
+    SELECT con.conname::text
+    FROM
+        pg_catalog.pg_constraint AS con
+        JOIN pg_catalog.pg_attribute AS attr
+            ON attr.attrelid = con.conrelid
+            AND attr.attnum = ANY(con.conkey)
+    WHERE con.conrelid = ('public.' || pg_catalog.quote_ident($1))::regclass
+        AND con.contype::text = $2
+        AND attr.attname = $3
+        AND array_length(con.conkey, 1) = 1
+    "#,
+    )
+    .bind(table_name)
+    .bind(kind)
+    .bind(col_name)
+    .fetch_all(con)
+    .await?;
+    Ok(res.iter().map(|row| row.get(0)).collect())
+}
+
+/// Whether the database rejected a row for its data rather than failing
+fn is_row_error(e: &dyn sqlx::error::DatabaseError) -> bool {
+    // Data exceptions and integrity constraint violations
+    matches!(e.code(), Some(code) if code.starts_with("22") || code.starts_with("23"))
+}
+
+/// Reports a filter value the database could not convert, such as numeric
+/// or interval text, as a bad filter
+fn filter_error(e: sqlx::Error) -> Error {
+    match e {
+        sqlx::Error::Database(e) if matches!(e.code(), Some(code) if code.starts_with("22")) => {
+            Error::InvalidFilter(e.message().to_string())
+        }
+        e => e.into(),
+    }
+}
+
+/// Describes why the database rejected a row.
+/// The column is taken from the violated constraint if it has only one.
+async fn get_row_error(
+    con: &mut sqlx::PgConnection,
+    table_name: &str,
+    row_index: usize,
+    e: &dyn sqlx::error::DatabaseError,
+) -> Result<RowError> {
+    let e = e.downcast_ref::<sqlx::postgres::PgDatabaseError>();
+    let mut column = e.column().map(|c| c.to_string());
+    if let (None, Some(constraint)) = (&column, e.constraint()) {
+        column = get_constraints(con, "public", table_name)
+            .await?
+            .into_iter()
+            .find(|c| c.name == constraint)
+            .filter(|c| c.columns.len() == 1)
+            .map(|c| c.columns[0].clone());
+    }
+    let reason = match e.detail() {
+        Some(detail) => format!("{}: {}", e.message(), detail),
+        None => e.message().to_string(),
+    };
+    Ok(RowError {
+        row: row_index,
+        column,
+        value: None,
+        reason,
+    })
+}
+
+/// Converts the given columns of a row to their types in the table
+fn convert_row<T: AsRef<str>>(
+    table: &TableMeta,
+    row_index: usize,
+    row: &RowJson,
+    col_names: &[T],
+) -> Result<Vec<BindValue>> {
+    let mut values = Vec::with_capacity(col_names.len());
+    let mut errors = Vec::new();
+    for col_name in col_names {
+        let col = table.get_col(col_name).ok_or_else(|| {
+            Error::NoSuchColumns(vec![col_name.as_ref().to_string()])
+        })?;
+        let value = row
+            .get(col_name.as_ref())
+            .unwrap_or(&serde_json::Value::Null);
+        match BindValue::from_json(value, &col.postgres_type) {
+            Ok(v) => values.push(v),
+            Err(reason) => errors.push(RowError {
+                row: row_index,
+                column: Some(col.name.clone()),
+                value: Some(value.clone()),
+                reason,
+            }),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(Error::InvalidRows(errors));
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use table::ForeignKey;
+    use types::PostgresType;
+
+    fn row_stream(rows: Vec<RowJson>) -> RowStream {
+        Box::pin(futures::stream::iter(rows.into_iter().map(Ok)))
+    }
+
+    const TEST_DB_NAME: &str = "postgres_test_user";
+    const TEST_EXPORT_DB_NAME: &str = "postgres_test_export";
+    const TEST_CACHE_DB_NAME: &str = "postgres_test_cache";
+    const TEST_PAGE_DB_NAME: &str = "postgres_test_page";
+    const TEST_SCHEMA_DB_NAME: &str = "postgres_test_schema";
+
+    // Test database
+    #[tokio::test]
+    async fn test_user() {
+        let _ = pretty_env_logger::try_init();
+        let test_config = crate::tests::gen_test_config("anything");
+        crate::tests::setup_test_db(TEST_DB_NAME).await;
+        let db = UserDB::new(test_config.clone(), TEST_DB_NAME)
+            .await
+            .unwrap();
+
+        assert!(db.get_all_table_names().await.unwrap().is_empty());
+
+        log::info!("create table");
+
+        let primary_table = crate::tests::get_test_primary_table();
+
+        db.create_table(&primary_table).await.unwrap();
+        assert!(!db.get_all_table_names().await.unwrap().is_empty());
+        assert!(db
+            .get_all_table_names()
+            .await
+            .unwrap()
+            .contains(&primary_table.name));
+
+        let secondary_table = crate::tests::get_test_secondary_table();
+
+        db.create_table(&secondary_table).await.unwrap();
+        assert!(!db.get_all_table_names().await.unwrap().is_empty());
+        assert!(db
+            .get_all_table_names()
+            .await
+            .unwrap()
+            .contains(&secondary_table.name));
+
+        let date_table = crate::tests::get_date_table();
+
+        db.create_table(&date_table).await.unwrap();
+        assert!(!db.get_all_table_names().await.unwrap().is_empty());
         assert!(db
             .get_all_table_names()
             .await
@@ -304,184 +2235,2730 @@ mod tests {
             Error::TableAlreadyExists(name) if name == primary_table.name
         ));
 
+        log::info!("create a table with invalid expressions");
+
+        let mut invalid_table = TableMeta::new(
+            "invalid",
+            vec![ColMeta::new()
+                .name("age")
+                .postgres_type(PostgresType::Integer)
+                .check("age >= nonexistent")],
+        );
+        assert!(matches!(
+            db.create_table(&invalid_table).await.unwrap_err(),
+            Error::InvalidDefinition(_)
+        ));
+        invalid_table.cols[0].check =
+            Some("age >= 0); DROP TABLE x; --".to_string());
+        assert!(matches!(
+            db.create_table(&invalid_table).await.unwrap_err(),
+            Error::InvalidDefinition(_)
+        ));
+        assert!(matches!(
+            db.create_index(
+                primary_table.name.as_str(),
+                &TableIndex::new("invalid", &["id"]).predicate("id >")
+            )
+            .await
+            .unwrap_err(),
+            Error::InvalidDefinition(_)
+        ));
+
         log::info!("get table names");
 
         assert_eq!(
             db.get_all_table_names().await.unwrap(),
-            vec![
-                primary_table.name.clone(),
-                date_table.name.clone(),
-                secondary_table.name.clone()
-            ]
+            vec![
+                primary_table.name.clone(),
+                secondary_table.name.clone(),
+                date_table.name.clone()
+            ]
+        );
+
+        log::info!("get metadata");
+
+        let primary_meta = db
+            .get_table_meta(primary_table.name.as_str())
+            .await
+            .unwrap();
+        assert_eq!(primary_meta, primary_table);
+        let secondary_meta = db
+            .get_table_meta(secondary_table.name.as_str())
+            .await
+            .unwrap();
+        assert_eq!(secondary_meta, secondary_table);
+
+        log::info!("created and introspected types match");
+
+        let types_table = crate::tests::get_types_table();
+        db.create_table(&types_table).await.unwrap();
+        assert_eq!(
+            db.get_table_meta(types_table.name.as_str()).await.unwrap(),
+            types_table
+        );
+
+        log::info!("insert every type");
+
+        let types_data = crate::tests::get_types_data();
+        db.insert_table_data(
+            types_table.name.as_str(),
+            &types_data,
+            &InsertOptions::default(),
+        )
+        .await
+        .unwrap();
+        let types_obtained =
+            db.get_table_data(types_table.name.as_str()).await.unwrap();
+        assert_eq!(types_obtained.len(), 1);
+        // No precision lost
+        assert_eq!(types_obtained[0]["col2"], types_data[0]["col2"]);
+        // Reading back through json rounds numerics, check the stored text
+        let numeric: String =
+            sqlx::query("SELECT \"col5\"::text FROM \"types\"")
+                .fetch_one(db.get_pool())
+                .await
+                .unwrap()
+                .get(0);
+        assert_eq!(numeric, "12345678901234567890.123456789");
+        assert_eq!(types_obtained[0]["col18"], "\\x00ff");
+        assert_eq!(types_obtained[0]["col21"], types_data[0]["col21"]);
+
+        log::info!("insert values that don't convert");
+
+        let mut bad_row = RowJson::new();
+        bad_row.insert("col1".to_string(), serde_json::json!(1.5));
+        bad_row.insert("col7".to_string(), serde_json::json!("fine"));
+        bad_row.insert("col15".to_string(), serde_json::json!("a0ee"));
+        match db
+            .insert_table_data(
+                types_table.name.as_str(),
+                &[types_data[0].clone(), bad_row],
+                &InsertOptions::default(),
+            )
+            .await
+            .unwrap_err()
+        {
+            Error::InvalidRows(errors) => {
+                assert_eq!(errors.len(), 2);
+                assert_eq!(errors[0].row, 1);
+                assert_eq!(errors[0].column, Some("col1".to_string()));
+                assert_eq!(errors[0].value, Some(serde_json::json!(1.5)));
+                assert_eq!(errors[1].column, Some("col15".to_string()));
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        log::info!("numeric and interval text is parsed by postgres");
+
+        let parsed: Vec<RowJson> = serde_json::from_str(
+            r#"[
+                {"col5": "1e3", "col19": "1 day 02:00"},
+                {"col5": "1.2.3"},
+                {"col19": "1 fortnight"}
+            ]"#,
+        )
+        .unwrap();
+        match db
+            .insert_table_data(
+                types_table.name.as_str(),
+                &parsed,
+                &InsertOptions::default(),
+            )
+            .await
+            .unwrap_err()
+        {
+            Error::InvalidRows(errors) => {
+                assert_eq!(
+                    errors.iter().map(|e| e.row).collect::<Vec<usize>>(),
+                    vec![1, 2]
+                );
+                assert!(errors[1].reason.contains("interval"));
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        db.insert_table_data(
+            types_table.name.as_str(),
+            &parsed[..1],
+            &InsertOptions::default(),
+        )
+        .await
+        .unwrap();
+        let page = db
+            .get_table_page(
+                types_table.name.as_str(),
+                &ReadOptions::default()
+                    .filter("col19.eq.26:00".parse().unwrap()),
+            )
+            .await
+            .unwrap();
+        assert_eq!(page.total, 1);
+        assert_eq!(page.rows[0]["col5"], 1000);
+        assert!(matches!(
+            db.get_table_page(
+                types_table.name.as_str(),
+                &ReadOptions::default()
+                    .filter("col19.eq.fortnight".parse().unwrap()),
+            )
+            .await
+            .unwrap_err(),
+            Error::InvalidFilter(_)
+        ));
+
+        db.remove_table(types_table.name.as_str(), false)
+            .await
+            .unwrap();
+
+        log::info!("alter table");
+
+        let alters = vec![
+            TableAlter::AddColumn(
+                ColMeta::new().name("age").postgres_type(PostgresType::Text),
+            ),
+            TableAlter::SetType {
+                name: "age".to_string(),
+                postgres_type: PostgresType::Integer,
+                using: Some("\"age\"::integer".to_string()),
+            },
+            TableAlter::SetNotNull {
+                name: "age".to_string(),
+                not_null: true,
+            },
+            TableAlter::SetUnique {
+                name: "email".to_string(),
+                unique: false,
+            },
+            TableAlter::RenameColumn {
+                name: "email".to_string(),
+                new_name: "contact".to_string(),
+            },
+            TableAlter::SetUnique {
+                name: "contact".to_string(),
+                unique: true,
+            },
+            TableAlter::SetForeignKey {
+                name: "age".to_string(),
+                foreign_key: Some(ForeignKey::new("primary", "id")),
+            },
+        ];
+        db.alter_table(primary_table.name.as_str(), &alters)
+            .await
+            .unwrap();
+        let mut expected = primary_table.clone();
+        for alter in &alters {
+            expected.apply_alter(alter).unwrap();
+        }
+        let altered = db
+            .get_table_meta(primary_table.name.as_str())
+            .await
+            .unwrap();
+        assert_eq!(altered, expected);
+        assert_ne!(altered, primary_table);
+
+        log::info!("failed alter leaves the table as it was");
+
+        assert!(matches!(
+            db.alter_table(
+                primary_table.name.as_str(),
+                &[
+                    TableAlter::DropColumn("age".to_string()),
+                    TableAlter::DropColumn("age".to_string())
+                ]
+            )
+            .await
+            .unwrap_err(),
+            Error::NoSuchColumns(cs) if cs == vec!["age".to_string()]
+        ));
+        // Fails in the database after the first change went through
+        assert!(matches!(
+            db.alter_table(
+                primary_table.name.as_str(),
+                &[
+                    TableAlter::DropColumn("age".to_string()),
+                    TableAlter::SetType {
+                        name: "contact".to_string(),
+                        postgres_type: PostgresType::Integer,
+                        using: None,
+                    }
+                ]
+            )
+            .await
+            .unwrap_err(),
+            Error::InvalidDefinition(_)
+        ));
+        assert_eq!(
+            db.get_table_meta(primary_table.name.as_str())
+                .await
+                .unwrap(),
+            expected
+        );
+
+        log::info!("alter back");
+
+        db.alter_table(
+            primary_table.name.as_str(),
+            &[
+                TableAlter::DropColumn("age".to_string()),
+                TableAlter::RenameColumn {
+                    name: "contact".to_string(),
+                    new_name: "email".to_string(),
+                },
+            ],
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            db.get_table_meta(primary_table.name.as_str())
+                .await
+                .unwrap(),
+            primary_table
+        );
+
+        log::info!("plan and apply a schema");
+
+        let current = db.get_all_meta().await.unwrap();
+        assert!(db.plan_schema(&current).await.unwrap().is_empty());
+        let mut desired = current.clone();
+        desired[0].cols.push(
+            ColMeta::new()
+                .name("age")
+                .postgres_type(PostgresType::Integer),
+        );
+        desired[1].cols.remove(4);
+        let plan = db.plan_schema(&desired).await.unwrap();
+        assert_eq!(plan.len(), 2);
+        assert!(matches!(
+            db.apply_schema(&desired, false).await.unwrap_err(),
+            Error::DestructivePlan(w) if w.len() == 1
+        ));
+        assert_eq!(db.get_all_meta().await.unwrap(), current);
+        assert_eq!(db.apply_schema(&desired, true).await.unwrap(), plan);
+        assert_eq!(db.get_all_meta().await.unwrap(), desired);
+        assert!(db.plan_schema(&desired).await.unwrap().is_empty());
+        db.apply_schema(&current, true).await.unwrap();
+        assert_eq!(db.get_all_meta().await.unwrap(), current);
+
+        log::info!("plan expressions not in their catalog form");
+
+        let mut with_bmi = current.clone();
+        with_bmi.push(
+            TableMeta::new(
+                "bmi",
+                vec![
+                    ColMeta::new()
+                        .name("height")
+                        .postgres_type(PostgresType::Real)
+                        .check("height > 0"),
+                    ColMeta::new()
+                        .name("weight")
+                        .postgres_type(PostgresType::Real),
+                    ColMeta::new()
+                        .name("bmi")
+                        .postgres_type(PostgresType::Real)
+                        .generated("weight / (height * height)"),
+                    ColMeta::new()
+                        .name("age")
+                        .postgres_type(PostgresType::Integer)
+                        .default("0")
+                        .check("age >= 0"),
+                    ColMeta::new()
+                        .name("note")
+                        .postgres_type(PostgresType::Text)
+                        .default("'none'"),
+                ],
+            )
+            .constraints(vec![TableConstraint::Check(
+                "weight < height * 100".to_string(),
+            )])
+            .indexes(vec![
+                TableIndex::new("bmi_adult", &["bmi"]).predicate("age >= 18")
+            ]),
+        );
+        db.apply_schema(&with_bmi, false).await.unwrap();
+        assert!(db.plan_schema(&with_bmi).await.unwrap().is_empty());
+        db.apply_schema(&current, true).await.unwrap();
+
+        log::info!("plan a type change that needs a cast");
+
+        let mut with_scores = current.clone();
+        with_scores.push(TableMeta::new(
+            "score",
+            vec![ColMeta::new()
+                .name("value")
+                .postgres_type(PostgresType::Text)],
+        ));
+        db.apply_schema(&with_scores, false).await.unwrap();
+        db.insert_table_data(
+            "score",
+            &crate::tests::rows(serde_json::json!([
+                {"value": "1"},
+                {"value": "20"}
+            ])),
+            &InsertOptions::default(),
+        )
+        .await
+        .unwrap();
+        with_scores.last_mut().unwrap().cols[0].postgres_type =
+            PostgresType::Integer;
+        db.apply_schema(&with_scores, true).await.unwrap();
+        assert_eq!(
+            db.get_table_data("score").await.unwrap(),
+            crate::tests::rows(
+                serde_json::json!([{"value": 1}, {"value": 20}])
+            )
+        );
+        db.apply_schema(&current, true).await.unwrap();
+
+        log::info!("get all metadata");
+
+        let all_meta = db.get_all_meta().await.unwrap();
+        assert_eq!(
+            all_meta,
+            vec![
+                primary_table.clone(),
+                secondary_table.clone(),
+                date_table.clone()
+            ]
+        );
+
+        log::info!("get data");
+
+        assert_eq!(
+            db.get_table_data(primary_table.name.as_str())
+                .await
+                .unwrap(),
+            vec![]
+        );
+
+        log::info!("insert data");
+
+        let primary_data = crate::tests::get_primary_data();
+        let secondary_data_partial = crate::tests::get_secondary_data_part();
+        let secondary_data_null = crate::tests::get_secondary_data_null();
+        let secondary_data = crate::tests::get_secondary_data();
+        let date_data = crate::tests::get_date_data();
+
+        // Concatenate secondary data into what's expected to be in the table
+        let secondary_data_partial_filled: Vec<RowJson> =
+            secondary_data_partial
+                .iter()
+                .cloned()
+                .map(|mut row| {
+                    row.insert("sick".to_string(), serde_json::Value::Null);
+                    row.insert("symptoms".to_string(), serde_json::Value::Null);
+                    row.insert(
+                        "locations".to_string(),
+                        serde_json::Value::Null,
+                    );
+                    row
+                })
+                .collect();
+        let mut secondary_data_full: Vec<RowJson> =
+            secondary_data_partial_filled.clone();
+        secondary_data_full.append(&mut secondary_data_null.clone());
+        secondary_data_full.append(&mut secondary_data.clone());
+
+        log::info!("insert primary");
+
+        db.insert_table_data(
+            primary_table.name.as_str(),
+            &primary_data,
+            &InsertOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        // Secondary data insert
+        db.insert_table_data(
+            secondary_table.name.as_str(),
+            &secondary_data_partial,
+            &InsertOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        log::info!("insert secondary null");
+
+        db.insert_table_data(
+            secondary_table.name.as_str(),
+            &secondary_data_null,
+            &InsertOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        db.insert_table_data(
+            secondary_table.name.as_str(),
+            &secondary_data,
+            &InsertOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        log::info!("insert date");
+        db.insert_table_data(
+            date_table.name.as_str(),
+            &date_data,
+            &InsertOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        log::info!("insert empty data");
+
+        assert!(matches!(
+            db.insert_table_data(
+                primary_table.name.as_str(),
+                &[],
+                &InsertOptions::default()
+            )
+            .await
+            .unwrap_err(),
+            Error::InsertEmptyData
+        ));
+
+        log::info!("get data");
+
+        assert_eq!(
+            db.get_table_data(primary_table.name.as_str())
+                .await
+                .unwrap(),
+            primary_data
+        );
+
+        assert_eq!(
+            db.get_table_data(secondary_table.name.as_str())
+                .await
+                .unwrap(),
+            secondary_data_full,
+        );
+
+        assert_eq!(
+            db.get_table_data(date_table.name.as_str()).await.unwrap(),
+            date_data,
+        );
+
+        // Boxed so that the test future stays small enough for the stack
+        Box::pin(async {
+            log::info!("get pages");
+
+            // Sick first, then unknown, then by key
+            let mut secondary_sorted = secondary_data_full.clone();
+            secondary_sorted.sort_by_key(|row| {
+                (
+                    row["sick"].is_null(),
+                    !row["sick"].as_bool().unwrap_or(false),
+                    row["id"].as_i64(),
+                    row["timepoint"].as_i64(),
+                )
+            });
+            let sorted = page::ReadOptions::default()
+                .sort(vec![page::SortKey::new("sick").descending(true)])
+                .limit(1);
+            let mut paged = Vec::new();
+            let mut options = sorted.clone();
+            loop {
+                let page = db
+                    .get_table_page(secondary_table.name.as_str(), &options)
+                    .await
+                    .unwrap();
+                assert_eq!(page.total as usize, secondary_data_full.len());
+                paged.extend(page.rows);
+                match page.next_cursor {
+                    Some(cursor) => options = sorted.clone().cursor(&cursor),
+                    None => break,
+                }
+            }
+            assert_eq!(paged, secondary_sorted);
+            let page = db
+                .get_table_page(
+                    secondary_table.name.as_str(),
+                    &sorted.clone().limit(2).offset(1).columns(&["timepoint"]),
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                page.rows,
+                secondary_sorted[1..3]
+                    .iter()
+                    .map(|row| {
+                        let mut projected = RowJson::new();
+                        projected.insert(
+                            "timepoint".to_string(),
+                            row["timepoint"].clone(),
+                        );
+                        projected
+                    })
+                    .collect::<Vec<RowJson>>()
+            );
+            assert!(page.next_cursor.is_some());
+            let filtered = |filter: &str| {
+                sorted.clone().limit(10).filter(filter.parse().unwrap())
+            };
+            let page = db
+                .get_table_page(
+                    secondary_table.name.as_str(),
+                    &filtered("and(sick.eq.false,timepoint.ge.2)"),
+                )
+                .await
+                .unwrap();
+            assert_eq!(page.total, 1);
+            assert_eq!(page.rows, vec![secondary_data[1].clone()]);
+            let page = db
+                .get_table_page(
+                    secondary_table.name.as_str(),
+                    &filtered("symptoms->s1.eq.true"),
+                )
+                .await
+                .unwrap();
+            assert_eq!(page.total, 2);
+            assert_eq!(page.rows, secondary_data);
+            // The total counts the filtered rows before the page
+            let page = db
+                .get_table_page(
+                    secondary_table.name.as_str(),
+                    &filtered("id.in.(1,2)").limit(1).offset(4),
+                )
+                .await
+                .unwrap();
+            assert_eq!(page.total as usize, secondary_data_full.len());
+            assert_eq!(page.rows.len(), 1);
+            assert!(matches!(
+                db.get_table_page(
+                    secondary_table.name.as_str(),
+                    &filtered("age.is.null"),
+                )
+                .await
+                .unwrap_err(),
+                Error::NoSuchColumns(_)
+            ));
+            log::info!("stream rows");
+
+            let streamed: Vec<RowJson> = db
+                .stream_table_data(secondary_table.name.as_str(), &sorted)
+                .await
+                .unwrap()
+                .try_collect()
+                .await
+                .unwrap();
+            // The limit applies to the whole stream
+            assert_eq!(streamed, secondary_sorted[..1].to_vec());
+            let streamed: Vec<RowJson> = db
+                .stream_table_data(
+                    secondary_table.name.as_str(),
+                    &filtered("timepoint.ge.2"),
+                )
+                .await
+                .unwrap()
+                .try_collect()
+                .await
+                .unwrap();
+            assert_eq!(
+                streamed,
+                secondary_sorted
+                    .iter()
+                    .filter(|row| row["timepoint"].as_i64() >= Some(2))
+                    .cloned()
+                    .collect::<Vec<RowJson>>()
+            );
+            // More rows than are fetched ahead, the reader can stop early
+            let mut many = Vec::new();
+            for i in 0..STREAM_BUFFER_ROWS * 4 {
+                let mut row = RowJson::new();
+                row.insert(
+                    "date".to_string(),
+                    serde_json::json!(format!(
+                        "2020-01-01T00:00:{:02}+00:00",
+                        i % 60
+                    )),
+                );
+                many.push(row);
+            }
+            db.copy_table_data(
+                date_table.name.as_str(),
+                row_stream(many.clone()),
+            )
+            .await
+            .unwrap();
+            let mut stream = db
+                .stream_table_data(
+                    date_table.name.as_str(),
+                    &page::ReadOptions::default(),
+                )
+                .await
+                .unwrap();
+            assert!(stream.try_next().await.unwrap().is_some());
+            drop(stream);
+            let streamed: Vec<RowJson> = db
+                .stream_table_data(
+                    date_table.name.as_str(),
+                    &page::ReadOptions::default(),
+                )
+                .await
+                .unwrap()
+                .try_collect()
+                .await
+                .unwrap();
+            assert_eq!(streamed.len(), many.len() + date_data.len());
+            db.remove_all_table_data(date_table.name.as_str())
+                .await
+                .unwrap();
+            db.insert_table_data(
+                date_table.name.as_str(),
+                &date_data,
+                &InsertOptions::default(),
+            )
+            .await
+            .unwrap();
+
+            // No primary key to continue from
+            let page = db
+                .get_table_page(
+                    date_table.name.as_str(),
+                    &page::ReadOptions::default().limit(1),
+                )
+                .await
+                .unwrap();
+            assert_eq!(page.rows.len(), 1);
+            assert!(page.next_cursor.is_none());
+            assert!(matches!(
+                db.get_table_page(
+                    secondary_table.name.as_str(),
+                    &page::ReadOptions::default().columns(&["age"]),
+                )
+                .await
+                .unwrap_err(),
+                Error::NoSuchColumns(_)
+            ));
+        })
+        .await;
+
+        log::info!("update by primary key");
+
+        let fix: Vec<RowJson> = serde_json::from_str(
+            r#"[{"id": "1", "email": "fixed@example.com"}]"#,
+        )
+        .unwrap();
+        db.update_table_data(primary_table.name.as_str(), &fix)
+            .await
+            .unwrap();
+        let mut primary_fixed = primary_data.clone();
+        primary_fixed[0]["email"] = serde_json::json!("fixed@example.com");
+        // Updated rows move to the end of the heap
+        let mut primary_obtained = db
+            .get_table_data(primary_table.name.as_str())
+            .await
+            .unwrap();
+        primary_obtained.sort_by_key(|row| row["id"].as_i64());
+        assert_eq!(primary_obtained, primary_fixed);
+        let mut secondary_fix = RowJson::new();
+        for key in &["id", "timepoint"] {
+            secondary_fix
+                .insert(key.to_string(), secondary_data_full[0][*key].clone());
+        }
+        secondary_fix.insert("sick".to_string(), serde_json::Value::Null);
+        db.update_table_data(secondary_table.name.as_str(), &[secondary_fix])
+            .await
+            .unwrap();
+        assert!(db
+            .get_table_data(secondary_table.name.as_str())
+            .await
+            .unwrap()
+            .iter()
+            .any(|row| row["id"] == secondary_data_full[0]["id"]
+                && row["timepoint"] == secondary_data_full[0]["timepoint"]
+                && row["sick"].is_null()));
+        let bad_updates: Vec<RowJson> = serde_json::from_str(
+            r#"[
+                {"id": 1, "email": "lost@example.com"},
+                {"email": "no-key@example.com"},
+                {"id": 99, "email": "nobody@example.com"},
+                {"id": 2},
+                {"id": "two", "email": "two@example.com"}
+            ]"#,
+        )
+        .unwrap();
+        match db
+            .update_table_data(primary_table.name.as_str(), &bad_updates)
+            .await
+            .unwrap_err()
+        {
+            Error::InvalidRows(errors) => {
+                assert_eq!(
+                    errors.iter().map(|e| e.row).collect::<Vec<usize>>(),
+                    vec![1, 2, 3, 4]
+                );
+                assert_eq!(errors[0].column, Some("id".to_string()));
+                assert_eq!(errors[1].reason, "no row with this primary key");
+                assert_eq!(errors[3].value, Some(serde_json::json!("two")));
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        primary_obtained = db
+            .get_table_data(primary_table.name.as_str())
+            .await
+            .unwrap();
+        primary_obtained.sort_by_key(|row| row["id"].as_i64());
+        assert_eq!(primary_obtained, primary_fixed);
+        assert!(matches!(
+            db.update_table_data(date_table.name.as_str(), &date_data)
+                .await
+                .unwrap_err(),
+            Error::NoPrimaryKey(_)
+        ));
+
+        log::info!("upsert");
+
+        let upserts: Vec<RowJson> = serde_json::from_str(
+            r#"[
+                {"id": 2, "email": "upserted@example.com"},
+                {"id": 3, "email": "new@example.com"}
+            ]"#,
+        )
+        .unwrap();
+        let report = db
+            .insert_table_data(
+                primary_table.name.as_str(),
+                &upserts,
+                &InsertOptions::default().on_conflict(OnConflict::UpdateAll),
+            )
+            .await
+            .unwrap();
+        assert_eq!((report.inserted, report.updated), (1, 1));
+        assert_eq!(report.keys.len(), 2);
+        primary_obtained = db
+            .get_table_data(primary_table.name.as_str())
+            .await
+            .unwrap();
+        primary_obtained.sort_by_key(|row| row["id"].as_i64());
+        assert_eq!(primary_obtained[1]["email"], "upserted@example.com");
+        assert_eq!(primary_obtained.len(), 3);
+        let report = db
+            .insert_table_data(
+                primary_table.name.as_str(),
+                &upserts,
+                &InsertOptions::default().on_conflict(OnConflict::DoNothing),
+            )
+            .await
+            .unwrap();
+        assert_eq!(report.unchanged, 2);
+        assert!(report.keys.is_empty());
+        // Conflicts on the email, not on the primary key
+        let same_email: Vec<RowJson> =
+            serde_json::from_str(r#"[{"id": 4, "email": "new@example.com"}]"#)
+                .unwrap();
+        assert!(matches!(
+            db.insert_table_data(
+                primary_table.name.as_str(),
+                &same_email,
+                &InsertOptions::default().on_conflict(OnConflict::DoNothing),
+            )
+            .await
+            .unwrap_err(),
+            Error::InvalidRows(_)
+        ));
+        let email_key: String = sqlx::query(
+            "SELECT conname::text FROM pg_catalog.pg_constraint \
+            WHERE conrelid = 'public.primary'::regclass AND contype = 'u'",
+        )
+        .fetch_one(db.get_pool())
+        .await
+        .unwrap()
+        .get(0);
+        let report = db
+            .insert_table_data(
+                primary_table.name.as_str(),
+                &same_email,
+                &InsertOptions::default()
+                    .on_conflict(OnConflict::DoNothing)
+                    .constraint(email_key.as_str()),
+            )
+            .await
+            .unwrap();
+        assert_eq!(report.unchanged, 1);
+        assert!(matches!(
+            db.insert_table_data(
+                primary_table.name.as_str(),
+                &same_email,
+                &InsertOptions::default()
+                    .on_conflict(OnConflict::DoNothing)
+                    .constraint("primary_nonexistent_key"),
+            )
+            .await
+            .unwrap_err(),
+            Error::NoSuchUniqueConstraint(_, _)
+        ));
+        let secondary_row = db
+            .get_table_data(secondary_table.name.as_str())
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|row| !row["symptoms"].is_null())
+            .unwrap();
+        let mut secondary_upsert = secondary_row.clone();
+        secondary_upsert.insert("sick".to_string(), serde_json::json!(true));
+        secondary_upsert
+            .insert("symptoms".to_string(), serde_json::Value::Null);
+        for on_conflict in &[OnConflict::UpdateNonNull, OnConflict::UpdateAll] {
+            let report = db
+                .insert_table_data(
+                    secondary_table.name.as_str(),
+                    &[secondary_upsert.clone()],
+                    &InsertOptions::default().on_conflict(on_conflict.clone()),
+                )
+                .await
+                .unwrap();
+            assert_eq!(report.updated, 1);
+            let upserted = db
+                .get_table_data(secondary_table.name.as_str())
+                .await
+                .unwrap()
+                .into_iter()
+                .find(|row| {
+                    row["id"] == secondary_row["id"]
+                        && row["timepoint"] == secondary_row["timepoint"]
+                })
+                .unwrap();
+            assert_eq!(upserted["sick"], true);
+            // Null values only overwrite when updating all columns
+            if *on_conflict == OnConflict::UpdateAll {
+                assert!(upserted["symptoms"].is_null());
+            } else {
+                assert_eq!(upserted["symptoms"], secondary_row["symptoms"]);
+            }
+        }
+
+        // Boxed so that the test future stays small enough for the stack
+        Box::pin(async {
+            log::info!("import csv");
+
+            let csv = "ID;E-mail;note\n\
+                10;csv@example.com;first\n\
+                11;;no email\n\
+                12;csv@example.com;same email\n\
+                13;csv13@example.com\n";
+            let csv_options = import::CsvOptions::default()
+                .delimiter(';')
+                .map("ID", Some("id"))
+                .map("E-mail", Some("email"))
+                .map("note", None);
+            let failed_rows = |errors: &[RowError]| {
+                errors.iter().map(|e| e.row).collect::<Vec<usize>>()
+            };
+            let preview = db
+                .import_csv(
+                    primary_table.name.as_str(),
+                    csv.as_bytes(),
+                    &csv_options,
+                    &InsertOptions::default().dry_run(true),
+                )
+                .await
+                .unwrap();
+            assert_eq!(preview.rows.len(), 3);
+            assert_eq!(preview.rows[0]["email"], "csv@example.com");
+            assert!(preview.rows[1]["email"].is_null());
+            assert_eq!(preview.insert.inserted, 1);
+            assert_eq!(failed_rows(&preview.insert.errors), vec![1, 2, 3]);
+            assert_eq!(
+                preview.insert.errors[0].column,
+                Some("email".to_string())
+            );
+            match db
+                .import_csv(
+                    primary_table.name.as_str(),
+                    csv.as_bytes(),
+                    &csv_options,
+                    &InsertOptions::default(),
+                )
+                .await
+                .unwrap_err()
+            {
+                Error::InvalidRows(errors) => {
+                    assert_eq!(failed_rows(&errors), vec![1, 2, 3])
+                }
+                e => panic!("unexpected error {:?}", e),
+            }
+            let primary_before = db
+                .get_table_data(primary_table.name.as_str())
+                .await
+                .unwrap();
+            assert!(!primary_before.iter().any(|row| row["id"] == 10));
+            let report = db
+                .import_csv(
+                    primary_table.name.as_str(),
+                    csv.as_bytes(),
+                    &csv_options,
+                    &InsertOptions::default().partial(true),
+                )
+                .await
+                .unwrap();
+            assert!(report.rows.is_empty());
+            assert_eq!(report.insert.inserted, 1);
+            assert_eq!(failed_rows(&report.insert.errors), vec![1, 2, 3]);
+            assert_eq!(
+                db.get_table_data(primary_table.name.as_str())
+                    .await
+                    .unwrap()
+                    .len(),
+                primary_before.len() + 1
+            );
+            assert!(matches!(
+                db.import_csv(
+                    primary_table.name.as_str(),
+                    b"ID;E-mail;note\n",
+                    &csv_options,
+                    &InsertOptions::default(),
+                )
+                .await
+                .unwrap_err(),
+                Error::InsertEmptyData
+            ));
+        })
+        .await;
+
+        log::info!("remove rows by key and filter");
+
+        let secondary_rows = db
+            .get_table_data(secondary_table.name.as_str())
+            .await
+            .unwrap();
+        assert!(secondary_rows.len() > 2);
+        let key_of = |row: &RowJson| {
+            let mut key = RowJson::new();
+            for col in &["id", "timepoint"] {
+                key.insert(col.to_string(), row[*col].clone());
+            }
+            key
+        };
+        let by_filter: RowSelection =
+            serde_json::from_value(serde_json::json!({
+                "filter": {"and": [
+                    {"eq": {"column": "id", "value": secondary_rows[0]["id"]}},
+                    {"eq": {
+                        "column": "timepoint",
+                        "value": secondary_rows[0]["timepoint"]
+                    }}
+                ]}
+            }))
+            .unwrap();
+        assert_eq!(
+            db.remove_table_rows(
+                secondary_table.name.as_str(),
+                &by_filter,
+                false
+            )
+            .await
+            .unwrap(),
+            1
+        );
+        let by_key = RowSelection::Keys(vec![key_of(&secondary_rows[1])]);
+        assert_eq!(
+            db.remove_table_rows(secondary_table.name.as_str(), &by_key, false)
+                .await
+                .unwrap(),
+            1
+        );
+        // Already removed
+        assert_eq!(
+            db.remove_table_rows(secondary_table.name.as_str(), &by_key, false)
+                .await
+                .unwrap(),
+            0
+        );
+        let everything =
+            RowSelection::Filter(filter::RowFilter::NotNull("id".to_string()));
+        assert!(matches!(
+            db.remove_table_rows(
+                secondary_table.name.as_str(),
+                &everything,
+                false
+            )
+            .await
+            .unwrap_err(),
+            Error::SelectionMatchesAll(_)
+        ));
+        assert_eq!(
+            db.get_table_data(secondary_table.name.as_str())
+                .await
+                .unwrap()
+                .len(),
+            secondary_rows.len() - 2
+        );
+        assert!(matches!(
+            db.remove_table_rows(
+                secondary_table.name.as_str(),
+                &RowSelection::Filter(filter::RowFilter::IsNull(
+                    "nonexistent".to_string()
+                )),
+                false
+            )
+            .await
+            .unwrap_err(),
+            Error::NoSuchColumns(_)
+        ));
+        assert_eq!(
+            db.remove_table_rows(
+                secondary_table.name.as_str(),
+                &everything,
+                true
+            )
+            .await
+            .unwrap() as usize,
+            secondary_rows.len() - 2
+        );
+        // The only row of a table, by key
+        db.insert_table_data(
+            secondary_table.name.as_str(),
+            &secondary_rows[2..3],
+            &InsertOptions::default(),
+        )
+        .await
+        .unwrap();
+        let only_key = RowSelection::Keys(vec![key_of(&secondary_rows[2])]);
+        assert_eq!(
+            db.remove_table_rows(
+                secondary_table.name.as_str(),
+                &only_key,
+                false
+            )
+            .await
+            .unwrap(),
+            1
+        );
+
+        log::info!("remove data");
+
+        db.remove_all_table_data(secondary_table.name.as_str())
+            .await
+            .unwrap();
+
+        db.remove_all_table_data(primary_table.name.as_str())
+            .await
+            .unwrap();
+
+        log::info!("get data");
+
+        assert_eq!(
+            db.get_table_data(primary_table.name.as_str())
+                .await
+                .unwrap(),
+            vec![]
+        );
+
+        log::info!("remove table");
+
+        db.remove_table(secondary_table.name.as_str(), false)
+            .await
+            .unwrap();
+
+        log::info!("get table names");
+
+        assert_eq!(
+            db.get_all_table_names().await.unwrap(),
+            vec![primary_table.name.clone(), date_table.name.clone()]
+        );
+
+        log::info!("create table again");
+
+        db.create_table(&secondary_table).await.unwrap();
+
+        log::info!("rename a referenced table");
+
+        db.rename_table(primary_table.name.as_str(), "renamed")
+            .await
+            .unwrap();
+        let mut renamed_secondary = secondary_table.clone();
+        renamed_secondary.cols[0].foreign_key =
+            Some(ForeignKey::new("renamed", "id"));
+        assert_eq!(
+            db.get_table_meta(secondary_table.name.as_str())
+                .await
+                .unwrap(),
+            renamed_secondary
+        );
+        assert!(matches!(
+            db.rename_table("renamed", date_table.name.as_str())
+                .await
+                .unwrap_err(),
+            Error::TableAlreadyExists(name) if name == date_table.name
+        ));
+        assert!(matches!(
+            db.rename_table("nonexistent", "other").await.unwrap_err(),
+            Error::NoSuchTable(name) if name == "nonexistent"
+        ));
+        db.rename_table("renamed", primary_table.name.as_str())
+            .await
+            .unwrap();
+
+        log::info!("remove table that others reference");
+
+        sqlx::query(
+            "CREATE VIEW \"primary_view\" AS SELECT * FROM \"primary\"",
+        )
+        .execute(db.get_pool())
+        .await
+        .unwrap();
+        sqlx::query(
+            "CREATE VIEW \"email_view\" AS SELECT \"email\" FROM \"primary_view\"",
+        )
+        .execute(db.get_pool())
+        .await
+        .unwrap();
+        let dependents = vec![
+            TableDependent::View("email_view".to_string()),
+            TableDependent::View("primary_view".to_string()),
+            TableDependent::ForeignKey {
+                table: secondary_table.name.clone(),
+                constraint: "secondary_id_fkey".to_string(),
+            },
+        ];
+        assert_eq!(
+            db.get_table_dependents(primary_table.name.as_str())
+                .await
+                .unwrap(),
+            dependents
+        );
+        assert!(matches!(
+            db.remove_table(primary_table.name.as_str(), false)
+                .await
+                .unwrap_err(),
+            Error::TableHasDependents(name, deps)
+                if name == primary_table.name && deps == dependents
+        ));
+        assert_eq!(
+            db.remove_table(primary_table.name.as_str(), true)
+                .await
+                .unwrap(),
+            dependents
+        );
+        assert_eq!(
+            db.get_all_table_names().await.unwrap(),
+            vec![secondary_table.name.clone(), date_table.name.clone()]
+        );
+        let mut orphan_secondary = secondary_table.clone();
+        orphan_secondary.cols[0].foreign_key = None;
+        assert_eq!(
+            db.get_table_meta(secondary_table.name.as_str())
+                .await
+                .unwrap(),
+            orphan_secondary
+        );
+        assert!(db
+            .get_table_dependents(secondary_table.name.as_str())
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Boxed so that the test future stays small enough for the stack
+        Box::pin(async {
+            log::info!("defaults, checks and generated columns");
+
+            sqlx::query("CREATE SEQUENCE \"measurement_seq\"")
+                .execute(db.get_pool())
+                .await
+                .unwrap();
+            let measurement_cols = vec![
+                ColMeta::new()
+                    .name("id")
+                    .postgres_type(PostgresType::BigInt)
+                    .default("nextval('measurement_seq'::regclass)")
+                    .primary_key(true),
+                ColMeta::new()
+                    .name("visit")
+                    .postgres_type(PostgresType::Timestamptz)
+                    .default("now()")
+                    .not_null(true),
+                ColMeta::new()
+                    .name("age")
+                    .postgres_type(PostgresType::Integer)
+                    .check("((age >= 0) AND (age < 150))"),
+                ColMeta::new()
+                    .name("height")
+                    .postgres_type(PostgresType::DoublePrecision),
+                ColMeta::new()
+                    .name("weight")
+                    .postgres_type(PostgresType::DoublePrecision),
+                ColMeta::new()
+                    .name("bmi")
+                    .postgres_type(PostgresType::DoublePrecision)
+                    .generated("(weight / (height * height))"),
+            ];
+            let measurement_table =
+                TableMeta::new("measurement", measurement_cols);
+            db.create_table(&measurement_table).await.unwrap();
+            assert_eq!(
+                db.get_table_meta(measurement_table.name.as_str())
+                    .await
+                    .unwrap(),
+                measurement_table
+            );
+            let measurement: RowJson = serde_json::from_str(
+                r#"{"age": 30, "height": 2.0, "weight": 80.0}"#,
+            )
+            .unwrap();
+            db.insert_table_data(
+                measurement_table.name.as_str(),
+                &[measurement.clone(), measurement],
+                &InsertOptions::default(),
+            )
+            .await
+            .unwrap();
+            let measurements = db
+                .get_table_data(measurement_table.name.as_str())
+                .await
+                .unwrap();
+            assert_eq!(measurements.len(), 2);
+            assert_eq!(measurements[0]["id"], 1);
+            assert_eq!(measurements[1]["id"], 2);
+            assert!(measurements[0]["visit"].is_string());
+            assert_eq!(measurements[0]["bmi"], 20.0);
+            let out_of_range: RowJson =
+                serde_json::from_str(r#"{"age": -1}"#).unwrap();
+            assert!(db
+                .insert_table_data(
+                    measurement_table.name.as_str(),
+                    &[out_of_range],
+                    &InsertOptions::default()
+                )
+                .await
+                .is_err());
+
+            log::info!("batch inserts are all or nothing unless partial");
+
+            let batch: Vec<RowJson> = serde_json::from_str(
+                r#"[
+                    {"age": 40},
+                    {"age": 200},
+                    {"age": "old"},
+                    {"age": 50}
+                ]"#,
+            )
+            .unwrap();
+            match db
+                .insert_table_data(
+                    measurement_table.name.as_str(),
+                    &batch,
+                    &InsertOptions::default(),
+                )
+                .await
+                .unwrap_err()
+            {
+                Error::InvalidRows(errors) => {
+                    assert_eq!(errors.len(), 2);
+                    assert_eq!(errors[0].row, 1);
+                    assert_eq!(errors[0].column, Some("age".to_string()));
+                    assert!(errors[0].value.is_none());
+                    assert!(errors[0].reason.contains("check constraint"));
+                    assert_eq!(errors[1].row, 2);
+                    assert_eq!(errors[1].value, Some(serde_json::json!("old")));
+                }
+                e => panic!("unexpected error {:?}", e),
+            }
+            assert_eq!(
+                db.get_table_data(measurement_table.name.as_str())
+                    .await
+                    .unwrap()
+                    .len(),
+                2
+            );
+            let report = db
+                .insert_table_data(
+                    measurement_table.name.as_str(),
+                    &batch,
+                    &InsertOptions::default().partial(true),
+                )
+                .await
+                .unwrap();
+            assert_eq!(report.keys.len(), 2);
+            assert_eq!(
+                report.errors.iter().map(|e| e.row).collect::<Vec<usize>>(),
+                vec![1, 2]
+            );
+            assert_eq!(
+                db.get_table_data(measurement_table.name.as_str())
+                    .await
+                    .unwrap()
+                    .len(),
+                4
+            );
+
+            log::info!("bulk load with copy");
+
+            let bulk: Vec<RowJson> = serde_json::from_str(
+                r#"[
+                    {"visit": "2021-01-01T10:00:00Z", "age": 20, "height": 2.0},
+                    {"visit": "2021-01-02T10:00:00Z", "age": "25"}
+                ]"#,
+            )
+            .unwrap();
+            assert_eq!(
+                db.copy_table_data(
+                    measurement_table.name.as_str(),
+                    row_stream(bulk)
+                )
+                .await
+                .unwrap(),
+                2
+            );
+            let measurements = db
+                .get_table_data(measurement_table.name.as_str())
+                .await
+                .unwrap();
+            assert_eq!(measurements.len(), 6);
+            assert_eq!(measurements[5]["age"], 25);
+            // Missing values are null, not defaults
+            assert!(measurements[4]["weight"].is_null());
+            assert!(measurements[5]["height"].is_null());
+            // Defaults only apply to the columns left out entirely
+            assert!(measurements[4]["id"].is_number());
+            let unconvertible: Vec<RowJson> = serde_json::from_str(
+                r#"[{"age": "x"}, {"age": 20}, {"age": 1.5}]"#,
+            )
+            .unwrap();
+            match db
+                .copy_table_data(
+                    measurement_table.name.as_str(),
+                    row_stream(unconvertible),
+                )
+                .await
+                .unwrap_err()
+            {
+                Error::InvalidRows(errors) => {
+                    assert_eq!(
+                        errors.iter().map(|e| e.row).collect::<Vec<usize>>(),
+                        vec![0, 2]
+                    );
+                }
+                e => panic!("unexpected error {:?}", e),
+            }
+            let violating: Vec<RowJson> = serde_json::from_str(
+                r#"[
+                        {"visit": "2021-01-03T10:00:00Z", "age": 20},
+                        {"visit": "2021-01-03T10:00:00Z", "age": 200}
+                    ]"#,
+            )
+            .unwrap();
+            match db
+                .copy_table_data(
+                    measurement_table.name.as_str(),
+                    row_stream(violating),
+                )
+                .await
+                .unwrap_err()
+            {
+                Error::InvalidRows(errors) => {
+                    assert_eq!(errors.len(), 1);
+                    assert_eq!(errors[0].row, 1);
+                    assert_eq!(errors[0].column, Some("age".to_string()));
+                }
+                e => panic!("unexpected error {:?}", e),
+            }
+            assert!(matches!(
+                db.copy_table_data(
+                    measurement_table.name.as_str(),
+                    row_stream(vec![serde_json::from_str(
+                        r#"{"nonexistent": 1}"#
+                    )
+                    .unwrap()])
+                )
+                .await
+                .unwrap_err(),
+                Error::NoSuchColumns(_)
+            ));
+            let added: Vec<RowJson> = serde_json::from_str(
+                r#"[{"age": 20}, {"age": 21, "height": 2.0}]"#,
+            )
+            .unwrap();
+            match db
+                .copy_table_data(
+                    measurement_table.name.as_str(),
+                    row_stream(added),
+                )
+                .await
+                .unwrap_err()
+            {
+                Error::InvalidRows(errors) => {
+                    assert_eq!(errors.len(), 1);
+                    assert_eq!(errors[0].row, 1);
+                    assert_eq!(errors[0].column, Some("height".to_string()));
+                }
+                e => panic!("unexpected error {:?}", e),
+            }
+            assert_eq!(
+                db.get_table_data(measurement_table.name.as_str())
+                    .await
+                    .unwrap()
+                    .len(),
+                6
+            );
+
+            log::info!("alter defaults and checks");
+
+            db.alter_table(
+                measurement_table.name.as_str(),
+                &[
+                    TableAlter::SetDefault {
+                        name: "age".to_string(),
+                        default: Some("18".to_string()),
+                    },
+                    TableAlter::SetCheck {
+                        name: "age".to_string(),
+                        check: Some("(age >= 18)".to_string()),
+                    },
+                    TableAlter::SetDefault {
+                        name: "visit".to_string(),
+                        default: None,
+                    },
+                ],
+            )
+            .await
+            .unwrap();
+            let mut altered_measurement = measurement_table.clone();
+            altered_measurement.cols[1].default = None;
+            altered_measurement.cols[2].default = Some("18".to_string());
+            altered_measurement.cols[2].check = Some("(age >= 18)".to_string());
+            assert_eq!(
+                db.get_table_meta(measurement_table.name.as_str())
+                    .await
+                    .unwrap(),
+                altered_measurement
+            );
+        })
+        .await;
+
+        log::info!("generated primary keys");
+
+        let participant_table = TableMeta::new(
+            "participant",
+            vec![
+                ColMeta::new()
+                    .name("id")
+                    .postgres_type(PostgresType::Integer)
+                    .identity(true)
+                    .primary_key(true),
+                ColMeta::new()
+                    .name("code")
+                    .postgres_type(PostgresType::Uuid)
+                    .default("gen_random_uuid()")
+                    .primary_key(true),
+                ColMeta::new()
+                    .name("name")
+                    .postgres_type(PostgresType::Text),
+            ],
+        );
+        db.create_table(&participant_table).await.unwrap();
+        assert_eq!(
+            db.get_table_meta(participant_table.name.as_str())
+                .await
+                .unwrap(),
+            participant_table
+        );
+        let participants: Vec<RowJson> = serde_json::from_str(
+            r#"[
+                {"name": "first"},
+                {},
+                {"id": null, "code": null, "name": "third"}
+            ]"#,
+        )
+        .unwrap();
+        let keys = db
+            .insert_table_data(
+                participant_table.name.as_str(),
+                &participants,
+                &InsertOptions::default(),
+            )
+            .await
+            .unwrap()
+            .keys;
+        assert_eq!(keys.len(), 3);
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(key.len(), 2);
+            assert_eq!(key["id"], i + 1);
+            assert_eq!(key["code"].as_str().unwrap().len(), 36);
+        }
+        assert_ne!(keys[0]["code"], keys[1]["code"]);
+        let stored = db
+            .get_table_data(participant_table.name.as_str())
+            .await
+            .unwrap();
+        assert_eq!(stored[1]["code"], keys[1]["code"]);
+        assert!(stored[1]["name"].is_null());
+
+        log::info!("insert without a primary key returns empty keys");
+
+        let date_keys = db
+            .insert_table_data(
+                date_table.name.as_str(),
+                &date_data,
+                &InsertOptions::default(),
+            )
+            .await
+            .unwrap()
+            .keys;
+        assert!(date_keys.iter().all(|k| k.is_empty()));
+
+        // Boxed so that the test future stays small enough for the stack
+        Box::pin(async {
+            log::info!("composite foreign keys with actions");
+
+            let visit_table = TableMeta::new(
+                "visit",
+                vec![
+                    ColMeta::new()
+                        .name("participant_id")
+                        .postgres_type(PostgresType::Integer)
+                        .primary_key(true),
+                    ColMeta::new()
+                        .name("visit")
+                        .postgres_type(PostgresType::Integer)
+                        .primary_key(true),
+                    ColMeta::new()
+                        .name("site")
+                        .postgres_type(PostgresType::Text),
+                ],
+            )
+            .constraints(vec![TableConstraint::Unique(vec![
+                "site".to_string(),
+                "visit".to_string(),
+            ])]);
+            let visit_fk = TableConstraint::ForeignKey {
+                columns: vec!["participant_id".to_string(), "visit".to_string()],
+                table: "visit".to_string(),
+                foreign_columns: vec![
+                    "participant_id".to_string(),
+                    "visit".to_string(),
+                ],
+                on_delete: ReferentialAction::Cascade,
+                on_update: ReferentialAction::Restrict,
+            };
+            let note_table = TableMeta::new(
+                "note",
+                vec![
+                    ColMeta::new()
+                        .name("participant_id")
+                        .postgres_type(PostgresType::Integer),
+                    ColMeta::new()
+                        .name("visit")
+                        .postgres_type(PostgresType::Integer),
+                    ColMeta::new()
+                        .name("text")
+                        .postgres_type(PostgresType::Text),
+                ],
+            )
+            .constraints(vec![visit_fk.clone()]);
+            let mut with_visit = db.get_all_meta().await.unwrap();
+            with_visit.push(note_table.clone());
+            with_visit.push(visit_table.clone());
+            db.apply_schema(&with_visit, false).await.unwrap();
+            assert_eq!(
+                db.get_table_meta(visit_table.name.as_str()).await.unwrap(),
+                visit_table
+            );
+            assert_eq!(
+                db.get_table_meta(note_table.name.as_str()).await.unwrap(),
+                note_table
+            );
+            let visits: Vec<RowJson> = serde_json::from_str(
+                r#"[
+                    {"participant_id": 1, "visit": 1},
+                    {"participant_id": 1, "visit": 2}
+                ]"#,
+            )
+            .unwrap();
+            db.insert_table_data(
+                visit_table.name.as_str(),
+                &visits,
+                &InsertOptions::default(),
+            )
+            .await
+            .unwrap();
+            let notes: Vec<RowJson> = serde_json::from_str(
+                r#"[
+                    {"participant_id": 1, "visit": 1, "text": "first"},
+                    {"participant_id": 1, "visit": 2, "text": "second"}
+                ]"#,
+            )
+            .unwrap();
+            db.insert_table_data(
+                note_table.name.as_str(),
+                &notes,
+                &InsertOptions::default(),
+            )
+            .await
+            .unwrap();
+            let missing_visit: RowJson =
+                serde_json::from_str(r#"{"participant_id": 1, "visit": 3}"#)
+                    .unwrap();
+            assert!(db
+                .insert_table_data(
+                    note_table.name.as_str(),
+                    &[missing_visit],
+                    &InsertOptions::default()
+                )
+                .await
+                .is_err());
+            sqlx::query("DELETE FROM \"visit\" WHERE \"visit\" = 1")
+                .execute(db.get_pool())
+                .await
+                .unwrap();
+            let notes_left =
+                db.get_table_data(note_table.name.as_str()).await.unwrap();
+            assert_eq!(notes_left.len(), 1);
+            assert_eq!(notes_left[0]["text"], "second");
+
+            log::info!("drop table constraints through a plan");
+
+            let mut without_constraints = db.get_all_meta().await.unwrap();
+            for table in &mut without_constraints {
+                table.constraints.clear();
+            }
+            let applied =
+                db.apply_schema(&without_constraints, false).await.unwrap();
+            assert_eq!(
+                applied
+                    .iter()
+                    .map(|c| c.change.clone())
+                    .collect::<Vec<SchemaChange>>(),
+                vec![
+                    SchemaChange::DropConstraint(note_table.name.clone(), visit_fk),
+                    SchemaChange::DropConstraint(
+                        visit_table.name.clone(),
+                        visit_table.constraints[0].clone()
+                    ),
+                ]
+            );
+            assert!(db
+                .get_table_meta(note_table.name.as_str())
+                .await
+                .unwrap()
+                .constraints
+                .is_empty());
+
+            log::info!("indexes");
+
+            let indexed_table = TableMeta::new(
+                "symptom_report",
+                vec![
+                    ColMeta::new()
+                        .name("id")
+                        .postgres_type(PostgresType::Integer)
+                        .primary_key(true),
+                    ColMeta::new()
+                        .name("timepoint")
+                        .postgres_type(PostgresType::Integer),
+                    ColMeta::new()
+                        .name("sick")
+                        .postgres_type(PostgresType::Boolean),
+                    ColMeta::new()
+                        .name("symptoms")
+                        .postgres_type(PostgresType::Jsonb)
+                        .unique(true),
+                ],
+            )
+            .indexes(vec![
+                TableIndex::new("symptom_report_symptoms", &["symptoms"])
+                    .method(IndexMethod::Gin),
+                TableIndex::new("symptom_report_sick", &["timepoint", "id"])
+                    .predicate("sick"),
+            ]);
+            db.create_table(&indexed_table).await.unwrap();
+            assert_eq!(
+                db.get_table_meta(indexed_table.name.as_str())
+                    .await
+                    .unwrap(),
+                indexed_table
+            );
+            let unique_index =
+                TableIndex::new("symptom_report_timepoint", &["timepoint", "sick"])
+                    .unique(true)
+                    .predicate("(timepoint > 0)");
+            db.create_index(indexed_table.name.as_str(), &unique_index)
+                .await
+                .unwrap();
+            assert_eq!(
+                db.get_indexes(indexed_table.name.as_str()).await.unwrap(),
+                vec![
+                    indexed_table.indexes[1].clone(),
+                    indexed_table.indexes[0].clone(),
+                    unique_index.clone()
+                ]
+            );
+            assert!(matches!(
+                db.create_index(indexed_table.name.as_str(), &unique_index)
+                    .await
+                    .unwrap_err(),
+                Error::IndexAlreadyExists(name) if name == unique_index.name
+            ));
+            assert!(matches!(
+                db.create_index(
+                    indexed_table.name.as_str(),
+                    &TableIndex::new("taken", &["nonexistent"])
+                )
+                .await
+                .unwrap_err(),
+                Error::NoSuchColumns(cols) if cols == vec!["nonexistent"]
+            ));
+            db.remove_index(
+                indexed_table.name.as_str(),
+                unique_index.name.as_str(),
+            )
+            .await
+            .unwrap();
+            assert!(matches!(
+                db.remove_index(
+                    indexed_table.name.as_str(),
+                    unique_index.name.as_str()
+                )
+                .await
+                .unwrap_err(),
+                Error::NoSuchIndex(name) if name == unique_index.name
+            ));
+            // Indexes of constraints are not removable this way
+            assert!(matches!(
+                db.remove_index(indexed_table.name.as_str(), "symptom_report_pkey")
+                    .await
+                    .unwrap_err(),
+                Error::NoSuchIndex(_)
+            ));
+
+            log::info!("indexes through a plan");
+
+            let mut reindexed = db.get_all_meta().await.unwrap();
+            let i = reindexed
+                .iter()
+                .position(|t| t.name == indexed_table.name)
+                .unwrap();
+            reindexed[i].indexes = vec![unique_index.clone()];
+            db.apply_schema(&reindexed, false).await.unwrap();
+            assert_eq!(
+                db.get_indexes(indexed_table.name.as_str()).await.unwrap(),
+                vec![unique_index]
+            );
+
+            log::info!("table-level checks and exclusions");
+
+            let booking_table = TableMeta::new(
+                "booking",
+                vec![
+                    ColMeta::new()
+                        .name("room")
+                        .postgres_type(PostgresType::Text),
+                    ColMeta::new()
+                        .name("start")
+                        .postgres_type(PostgresType::Timestamptz),
+                    ColMeta::new()
+                        .name("finish")
+                        .postgres_type(PostgresType::Timestamptz),
+                ],
+            )
+            .constraints(vec![
+                TableConstraint::Check("(start < finish)".to_string()),
+                TableConstraint::Exclude {
+                    method: IndexMethod::Hash,
+                    elements: vec![ExclusionElement::new("room", "=")],
+                },
+            ]);
+            db.create_table(&booking_table).await.unwrap();
+            assert_eq!(
+                db.get_table_meta(booking_table.name.as_str())
+                    .await
+                    .unwrap(),
+                booking_table
+            );
+
+            log::info!("constraints that can't be represented");
+
+            sqlx::query(
+                "CREATE TABLE \"deferred\"(\
+                    \"id\" integer UNIQUE DEFERRABLE INITIALLY DEFERRED\
+                )",
+            )
+            .execute(db.get_pool())
+            .await
+            .unwrap();
+            assert!(matches!(
+                db.get_table_meta("deferred").await.unwrap_err(),
+                Error::UnsupportedConstraint(table, name, _)
+                    if table == "deferred" && name == "deferred_id_key"
+            ));
+            sqlx::query("CREATE SCHEMA \"other\"")
+                .execute(db.get_pool())
+                .await
+                .unwrap();
+            sqlx::query(
+                "CREATE TABLE \"other\".\"participant\"(\"id\" integer PRIMARY KEY)",
+            )
+            .execute(db.get_pool())
+            .await
+            .unwrap();
+            sqlx::query(
+                "CREATE TABLE \"cross_schema\"(\
+                    \"id\" integer REFERENCES \"other\".\"participant\"(\"id\")\
+                )",
+            )
+            .execute(db.get_pool())
+            .await
+            .unwrap();
+            assert!(matches!(
+                db.get_table_meta("cross_schema").await.unwrap_err(),
+                Error::UnsupportedConstraint(table, _, _) if table == "cross_schema"
+            ));
+            sqlx::query(
+                "CREATE TABLE \"lowered\"(\
+                    \"email\" text,\
+                    EXCLUDE USING hash (lower(\"email\") WITH =)\
+                )",
+            )
+            .execute(db.get_pool())
+            .await
+            .unwrap();
+            assert!(matches!(
+                db.get_table_meta("lowered").await.unwrap_err(),
+                Error::UnsupportedConstraint(table, _, _) if table == "lowered"
+            ));
+        })
+        .await;
+
+        log::info!("same-named tables of other schemas are ignored");
+
+        let public_participant = db
+            .get_table_meta(participant_table.name.as_str())
+            .await
+            .unwrap();
+        assert_eq!(public_participant, participant_table);
+        // The user's own schema comes first in the default search path
+        sqlx::query(
+            "DO $$ BEGIN \
+            EXECUTE format('CREATE SCHEMA %I', current_user); \
+            EXECUTE format(\
+                'CREATE TABLE %I.\"participant\"(\"other\" xml)', \
+                current_user\
+            ); \
+            END $$",
+        )
+        .execute(db.get_pool())
+        .await
+        .unwrap();
+        assert_eq!(
+            db.get_table_meta(participant_table.name.as_str())
+                .await
+                .unwrap(),
+            public_participant
+        );
+
+        log::info!("metadata is cached until the schema changes");
+
+        assert!(db.version_tracked);
+        let cached = db
+            .get_table_meta(participant_table.name.as_str())
+            .await
+            .unwrap();
+        assert_eq!(
+            db.cache.lock().get_table(participant_table.name.as_str()),
+            Some(cached.clone())
+        );
+        // Changed outside of the app
+        sqlx::query(
+            "ALTER TABLE \"public\".\"participant\" ADD COLUMN \"site\" text",
+        )
+        .execute(db.get_pool())
+        .await
+        .unwrap();
+        let mut expected = cached;
+        expected.cols.push(ColMeta::new().name("site"));
+        assert_eq!(
+            db.get_table_meta(participant_table.name.as_str())
+                .await
+                .unwrap(),
+            expected
+        );
+        sqlx::query("DROP TABLE \"public\".\"participant\" CASCADE")
+            .execute(db.get_pool())
+            .await
+            .unwrap();
+        assert!(matches!(
+            db.get_table_meta(participant_table.name.as_str())
+                .await
+                .unwrap_err(),
+            Error::NoSuchTable(_)
+        ));
+
+        // Remove test DB -----------------------------------------------------
+        crate::tests::remove_test_db(&db.db).await;
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let _ = pretty_env_logger::try_init();
+        let test_config = crate::tests::gen_test_config("anything");
+        crate::tests::setup_test_db(TEST_CACHE_DB_NAME).await;
+        let mut db = UserDB::new(test_config.clone(), TEST_CACHE_DB_NAME)
+            .await
+            .unwrap();
+        let primary_table = crate::tests::get_test_primary_table();
+        db.create_table(&primary_table).await.unwrap();
+
+        log::info!("uncommitted changes made outside of the app");
+
+        assert!(db.version_tracked);
+        let mut tx = db.get_pool().begin().await.unwrap();
+        sqlx::query("ALTER TABLE \"primary\" ADD COLUMN \"site\" text")
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        assert_eq!(
+            db.get_table_meta(primary_table.name.as_str())
+                .await
+                .unwrap(),
+            primary_table
+        );
+        tx.commit().await.unwrap();
+        let mut expected = primary_table.clone();
+        expected.cols.push(ColMeta::new().name("site"));
+        assert_eq!(
+            db.get_table_meta(primary_table.name.as_str())
+                .await
+                .unwrap(),
+            expected
+        );
+
+        log::info!("reads racing a change without version tracking");
+
+        db.version_tracked = false;
+        let generation = db.validate_cache().await.unwrap();
+        let stale = db
+            .read_table_meta(primary_table.name.as_str())
+            .await
+            .unwrap();
+        db.alter_table(
+            primary_table.name.as_str(),
+            &[TableAlter::DropColumn("site".to_string())],
+        )
+        .await
+        .unwrap();
+        db.cache.lock().set_table(generation, stale);
+        db.cache
+            .lock()
+            .set_table_names(generation, vec!["stale".to_string()]);
+        assert_eq!(
+            db.get_table_meta(primary_table.name.as_str())
+                .await
+                .unwrap(),
+            primary_table
+        );
+        assert_eq!(
+            db.get_all_table_names().await.unwrap(),
+            vec![primary_table.name.clone()]
         );
 
-        log::info!("get metadata");
+        // Remove test DB -----------------------------------------------------
+        crate::tests::remove_test_db(&db.db).await;
+    }
 
-        let primary_meta = db
-            .get_table_meta(primary_table.name.as_str())
-            .await
-            .unwrap();
-        assert_eq!(primary_meta, primary_table);
-        let secondary_meta = db
-            .get_table_meta(secondary_table.name.as_str())
+    #[tokio::test]
+    async fn test_apply_schema() {
+        let _ = pretty_env_logger::try_init();
+        let test_config = crate::tests::gen_test_config("anything");
+        crate::tests::setup_test_db(TEST_SCHEMA_DB_NAME).await;
+        let db = UserDB::new(test_config.clone(), TEST_SCHEMA_DB_NAME)
             .await
             .unwrap();
-        assert_eq!(secondary_meta, secondary_table);
 
-        log::info!("get all metadata");
+        log::info!("plan tables that already exist with expressions");
 
-        let all_meta = db.get_all_meta().await.unwrap();
-        assert_eq!(
-            all_meta,
+        let bmi = TableMeta::new(
+            "bmi",
             vec![
-                primary_table.clone(),
-                date_table.clone(),
-                secondary_table.clone()
-            ]
-        );
+                ColMeta::new()
+                    .name("height")
+                    .postgres_type(PostgresType::Real)
+                    .check("height > 0"),
+                ColMeta::new()
+                    .name("age")
+                    .postgres_type(PostgresType::Integer),
+            ],
+        )
+        .indexes(vec![
+            TableIndex::new("bmi_adult", &["height"]).predicate("age >= 18")
+        ]);
+        let with_bmi = vec![bmi];
+        db.apply_schema(&with_bmi, false).await.unwrap();
+        // The canonical copies don't clash with the tables or their indexes
+        assert!(db.plan_schema(&with_bmi).await.unwrap().is_empty());
+        assert_eq!(db.get_all_table_names().await.unwrap(), vec!["bmi"]);
 
-        log::info!("get data");
+        log::info!("tables that reference each other");
 
+        let mut cyclic = with_bmi;
+        cyclic.extend(vec![
+            TableMeta::new(
+                "subject",
+                vec![
+                    ColMeta::new()
+                        .name("id")
+                        .postgres_type(PostgresType::Integer)
+                        .primary_key(true),
+                    ColMeta::new()
+                        .name("first_visit")
+                        .postgres_type(PostgresType::Integer)
+                        .foreign_key(ForeignKey::new("visit", "id")),
+                ],
+            ),
+            TableMeta::new(
+                "visit",
+                vec![
+                    ColMeta::new()
+                        .name("id")
+                        .postgres_type(PostgresType::Integer)
+                        .primary_key(true),
+                    ColMeta::new()
+                        .name("subject")
+                        .postgres_type(PostgresType::Integer)
+                        .foreign_key(ForeignKey::new("subject", "id")),
+                ],
+            ),
+        ]);
+        db.apply_schema(&cyclic, false).await.unwrap();
         assert_eq!(
-            db.get_table_data(primary_table.name.as_str())
-                .await
-                .unwrap(),
-            vec![]
+            db.get_table_meta("subject").await.unwrap().cols[1].foreign_key,
+            Some(ForeignKey::new("visit", "id"))
         );
+        assert_eq!(
+            db.get_table_meta("visit").await.unwrap().cols[1].foreign_key,
+            Some(ForeignKey::new("subject", "id"))
+        );
+        assert!(db.plan_schema(&cyclic).await.unwrap().is_empty());
+        db.apply_schema(&[], true).await.unwrap();
+        assert!(db.get_all_table_names().await.unwrap().is_empty());
 
-        log::info!("insert data");
-
-        let primary_data = crate::tests::get_primary_data();
-        let secondary_data_partial = crate::tests::get_secondary_data_part();
-        let secondary_data_null = crate::tests::get_secondary_data_null();
-        let secondary_data = crate::tests::get_secondary_data();
-        let date_data = crate::tests::get_date_data();
-
-        // Concatenate secondary data into what's expected to be in the table
-        let secondary_data_partial_filled: Vec<RowJson> =
-            secondary_data_partial
-                .iter()
-                .cloned()
-                .map(|mut row| {
-                    row.insert("sick".to_string(), serde_json::Value::Null);
-                    row.insert("symptoms".to_string(), serde_json::Value::Null);
-                    row.insert(
-                        "locations".to_string(),
-                        serde_json::Value::Null,
-                    );
-                    row
-                })
-                .collect();
-        let mut secondary_data_full: Vec<RowJson> =
-            secondary_data_partial_filled.clone();
-        secondary_data_full.append(&mut secondary_data_null.clone());
-        secondary_data_full.append(&mut secondary_data.clone());
-
-        log::info!("insert primary");
+        // Remove test DB -----------------------------------------------------
+        crate::tests::remove_test_db(&db.db).await;
+    }
 
-        db.insert_table_data(primary_table.name.as_str(), &primary_data)
+    #[tokio::test]
+    async fn test_page_keys() {
+        let _ = pretty_env_logger::try_init();
+        let test_config = crate::tests::gen_test_config("anything");
+        crate::tests::setup_test_db(TEST_PAGE_DB_NAME).await;
+        let db = UserDB::new(test_config.clone(), TEST_PAGE_DB_NAME)
             .await
             .unwrap();
 
-        // Secondary data insert
-        db.insert_table_data(
-            secondary_table.name.as_str(),
-            &secondary_data_partial,
-        )
-        .await
-        .unwrap();
-
-        log::info!("insert secondary null");
+        log::info!("keys that json numbers can't hold exactly");
 
+        let reading = TableMeta::new(
+            "reading",
+            vec![
+                ColMeta::new()
+                    .name("id")
+                    .postgres_type(PostgresType::BigInt)
+                    .primary_key(true),
+                ColMeta::new()
+                    .name("value")
+                    .postgres_type(PostgresType::Numeric(None)),
+            ],
+        );
+        db.create_table(&reading).await.unwrap();
         db.insert_table_data(
-            secondary_table.name.as_str(),
-            &secondary_data_null,
+            reading.name.as_str(),
+            &crate::tests::rows(serde_json::json!([
+                {"id": 9007199254740992_i64, "value": "0.10000000000000000001"},
+                {"id": 9007199254740993_i64, "value": "0.10000000000000000002"},
+                {"id": 9007199254740994_i64, "value": "0.10000000000000000003"},
+            ])),
+            &InsertOptions::default(),
         )
         .await
         .unwrap();
+        let paged_ids = |sorted: page::ReadOptions| {
+            let db = &db;
+            let table_name = reading.name.as_str();
+            async move {
+                let mut ids = Vec::new();
+                let mut options = sorted.clone();
+                loop {
+                    let page =
+                        db.get_table_page(table_name, &options).await.unwrap();
+                    ids.extend(page.rows.iter().map(|r| r["id"].as_i64()));
+                    match page.next_cursor {
+                        Some(cursor) => {
+                            options = sorted.clone().cursor(&cursor)
+                        }
+                        None => break,
+                    }
+                }
+                ids
+            }
+        };
+        assert_eq!(
+            paged_ids(page::ReadOptions::default().limit(1)).await,
+            vec![
+                Some(9007199254740992),
+                Some(9007199254740993),
+                Some(9007199254740994)
+            ]
+        );
+        assert_eq!(
+            paged_ids(
+                page::ReadOptions::default()
+                    .sort(vec![page::SortKey::new("value").descending(true)])
+                    .limit(1)
+            )
+            .await,
+            vec![
+                Some(9007199254740994),
+                Some(9007199254740993),
+                Some(9007199254740992)
+            ]
+        );
 
-        db.insert_table_data(secondary_table.name.as_str(), &secondary_data)
-            .await
-            .unwrap();
+        // Remove test DB -----------------------------------------------------
+        crate::tests::remove_test_db(&db.db).await;
+    }
 
-        log::info!("insert date");
-        db.insert_table_data(date_table.name.as_str(), &date_data)
+    #[tokio::test]
+    async fn test_export() {
+        let _ = pretty_env_logger::try_init();
+        let test_config = crate::tests::gen_test_config("anything");
+        crate::tests::setup_test_db(TEST_EXPORT_DB_NAME).await;
+        let db = UserDB::new(test_config.clone(), TEST_EXPORT_DB_NAME)
             .await
             .unwrap();
 
-        log::info!("insert empty data");
+        log::info!("export table csv");
 
+        let primary_table = crate::tests::get_test_primary_table();
+        db.create_table(&primary_table).await.unwrap();
+        let visit = TableMeta::new(
+            "visit",
+            vec![
+                ColMeta::new()
+                    .name("id")
+                    .postgres_type(PostgresType::Integer)
+                    .primary_key(true),
+                ColMeta::new()
+                    .name("symptoms")
+                    .postgres_type(PostgresType::Jsonb),
+            ],
+        );
+        db.create_table(&visit).await.unwrap();
+        let visits = crate::tests::rows(serde_json::json!([
+            {"id": 1, "symptoms": {"fever": true}},
+            {"id": 2, "symptoms": null},
+            {"id": 3, "symptoms": {"cough": "dry"}}
+        ]));
+        db.insert_table_data("visit", &visits, &InsertOptions::default())
+            .await
+            .unwrap();
+        let read = |file: ByteStream| async move {
+            let chunks: Vec<Vec<u8>> = file.try_collect().await.unwrap();
+            chunks.concat()
+        };
+        let file = db
+            .export_table_csv(
+                "visit",
+                &ReadOptions::default().sort(vec!["-id".parse().unwrap()]),
+                &CsvExportOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(read(file).await).unwrap(),
+            "id,symptoms\n\
+            3,\"{\"\"cough\"\":\"\"dry\"\"}\"\n\
+            2,\n\
+            1,\"{\"\"fever\"\":true}\"\n"
+        );
+        let flattened = CsvExportOptions::default()
+            .null("NA")
+            .json(export::JsonColumns::Flatten);
+        let file = db
+            .export_table_csv(
+                "visit",
+                &ReadOptions::default().filter("id.ne.2".parse().unwrap()),
+                &flattened,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(read(file).await).unwrap(),
+            "id,symptoms.cough,symptoms.fever\n1,NA,true\n3,dry,NA\n"
+        );
         assert!(matches!(
-            db.insert_table_data(primary_table.name.as_str(), &[])
-                .await
-                .unwrap_err(),
-            Error::InsertEmptyData
+            db.export_table_csv(
+                "visit",
+                &ReadOptions::default().columns(&["age"]),
+                &flattened,
+            )
+            .await
+            .err()
+            .unwrap(),
+            Error::NoSuchColumns(_)
         ));
 
-        log::info!("get data");
+        log::info!("export project csv");
 
+        let archive = db.export_project_csv(&flattened).await.unwrap();
+        let mut zip = async_zip::base::read::stream::ZipFileReader::new(
+            futures::io::Cursor::new(read(archive).await),
+        );
+        let mut files = Vec::new();
+        while let Some(mut entry) = zip.next_with_entry().await.unwrap() {
+            let name = entry.reader().entry().filename().as_str().unwrap();
+            let name = name.to_string();
+            let mut contents = String::new();
+            futures::AsyncReadExt::read_to_string(
+                entry.reader_mut(),
+                &mut contents,
+            )
+            .await
+            .unwrap();
+            files.push((name, contents));
+            zip = entry.done().await.unwrap();
+        }
+        let table_names = db.get_all_table_names().await.unwrap();
         assert_eq!(
-            db.get_table_data(primary_table.name.as_str())
-                .await
-                .unwrap(),
-            primary_data
+            files
+                .iter()
+                .map(|(name, _)| name.clone())
+                .collect::<Vec<_>>(),
+            table_names
+                .iter()
+                .map(|name| format!("{}.csv", name))
+                .collect::<Vec<String>>()
         );
+        assert!(files.contains(&(
+            "visit.csv".to_string(),
+            "id,symptoms.cough,symptoms.fever\n\
+            1,NA,true\n2,NA,NA\n3,dry,NA\n"
+                .to_string()
+        )));
+        let primary_header: Vec<&str> =
+            primary_table.cols.iter().map(|c| c.name.as_str()).collect();
+        assert!(files.contains(&(
+            format!("{}.csv", primary_table.name),
+            format!("{}\n", primary_header.join(","))
+        )));
+
+        log::info!("export table parquet and arrow");
 
+        let measure = TableMeta::new(
+            "measure",
+            vec![
+                ColMeta::new()
+                    .name("weight")
+                    .postgres_type(PostgresType::Numeric(Some((6, 2)))),
+                ColMeta::new()
+                    .name("taken")
+                    .postgres_type(PostgresType::Timestamptz),
+                ColMeta::new()
+                    .name("fasting")
+                    .postgres_type(PostgresType::Boolean),
+            ],
+        );
+        db.create_table(&measure).await.unwrap();
+        let measures = crate::tests::rows(serde_json::json!([{
+            "weight": 1234.56,
+            "taken": "2021-03-01T08:30:00+02:00",
+            "fasting": true
+        }]));
+        db.insert_table_data("measure", &measures, &InsertOptions::default())
+            .await
+            .unwrap();
+        let file = db
+            .export_table_arrow(
+                "measure",
+                &ReadOptions::default(),
+                &ArrowOptions::default(),
+                ArrowFormat::Arrow,
+            )
+            .await
+            .unwrap();
+        let batches: Vec<arrow::record_batch::RecordBatch> =
+            arrow::ipc::reader::StreamReader::try_new(
+                std::io::Cursor::new(read(file).await),
+                None,
+            )
+            .unwrap()
+            .collect::<std::result::Result<_, _>>()
+            .unwrap();
+        let batch = &batches[0];
+        assert_eq!(batch.num_rows(), 1);
         assert_eq!(
-            db.get_table_data(secondary_table.name.as_str())
-                .await
+            arrow::util::display::array_value_to_string(batch.column(0), 0)
                 .unwrap(),
-            secondary_data_full,
+            "1234.56"
         );
-
         assert_eq!(
-            db.get_table_data(date_table.name.as_str()).await.unwrap(),
-            date_data,
+            arrow::util::display::array_value_to_string(batch.column(1), 0)
+                .unwrap(),
+            "2021-03-01T06:30:00Z"
+        );
+        let file = db
+            .export_table_arrow(
+                "visit",
+                &ReadOptions::default(),
+                &ArrowOptions::default().json(columnar::JsonArrow::Struct),
+                ArrowFormat::Parquet,
+            )
+            .await
+            .unwrap();
+        let batches: Vec<arrow::record_batch::RecordBatch> =
+            parquet::arrow::arrow_reader::ParquetRecordBatchReader::try_new(
+                bytes::Bytes::from(read(file).await),
+                1024,
+            )
+            .unwrap()
+            .collect::<std::result::Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            arrow::util::display::array_value_to_string(
+                batches[0].column(1),
+                2
+            )
+            .unwrap(),
+            "{cough: dry, fever: }"
         );
+        assert!(matches!(
+            db.export_table_arrow(
+                "visit",
+                &ReadOptions::default().columns(&["age"]),
+                &ArrowOptions::default(),
+                ArrowFormat::Parquet,
+            )
+            .await
+            .err()
+            .unwrap(),
+            Error::NoSuchColumns(_)
+        ));
 
-        log::info!("remove data");
+        log::info!("export project parquet");
 
-        db.remove_all_table_data(secondary_table.name.as_str())
+        let archive = db
+            .export_project_arrow(
+                &ArrowOptions::default(),
+                ArrowFormat::Parquet,
+            )
             .await
             .unwrap();
-
-        db.remove_all_table_data(primary_table.name.as_str())
+        let mut zip = async_zip::base::read::stream::ZipFileReader::new(
+            futures::io::Cursor::new(read(archive).await),
+        );
+        let mut names = Vec::new();
+        while let Some(mut entry) = zip.next_with_entry().await.unwrap() {
+            let name = entry.reader().entry().filename().as_str().unwrap();
+            names.push(name.to_string());
+            let mut contents = Vec::new();
+            futures::AsyncReadExt::read_to_end(
+                entry.reader_mut(),
+                &mut contents,
+            )
             .await
             .unwrap();
-
-        log::info!("get data");
-
+            assert!(contents.starts_with(b"PAR1"));
+            zip = entry.done().await.unwrap();
+        }
         assert_eq!(
-            db.get_table_data(primary_table.name.as_str())
+            names,
+            db.get_all_table_names()
                 .await
-                .unwrap(),
-            vec![]
+                .unwrap()
+                .iter()
+                .map(|name| format!("{}.parquet", name))
+                .collect::<Vec<String>>()
         );
+        db.remove_table("measure", false).await.unwrap();
 
-        log::info!("remove table");
+        log::info!("export and import xlsx");
 
-        db.remove_table(secondary_table.name.as_str())
+        let exported = db.export_xlsx().await.unwrap();
+        db.remove_all_table_data("visit").await.unwrap();
+        let report = db
+            .import_xlsx(
+                &exported,
+                &XlsxOptions::default(),
+                &InsertOptions::default(),
+            )
             .await
             .unwrap();
+        let inserted: Vec<(&str, &str, u64)> = report
+            .sheets
+            .iter()
+            .map(|s| (s.sheet.as_str(), s.table.as_str(), s.insert.inserted))
+            .collect();
+        assert_eq!(
+            inserted,
+            vec![
+                (primary_table.name.as_str(), primary_table.name.as_str(), 0),
+                ("visit", "visit", 3)
+            ]
+        );
+        assert_eq!(db.get_table_data("visit").await.unwrap(), visits);
 
-        log::info!("get table names");
+        let mut text_id = visit.clone();
+        text_id.cols[0].postgres_type = PostgresType::Text;
+        let bad_rows = crate::tests::rows(serde_json::json!([
+            {"id": "4", "symptoms": null},
+            {"id": "four", "symptoms": null}
+        ]));
+        let bad = workbook::write_xlsx(&[(text_id, bad_rows)]).unwrap();
+        match db
+            .import_xlsx(
+                &bad,
+                &XlsxOptions::default(),
+                &InsertOptions::default(),
+            )
+            .await
+            .unwrap_err()
+        {
+            Error::InvalidSheetRows(errors) => {
+                assert_eq!(errors.len(), 1);
+                assert_eq!(errors[0].sheet, "visit");
+                assert_eq!(errors[0].error.row, 3);
+                assert_eq!(errors[0].error.column, Some("id".to_string()));
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert_eq!(db.get_table_data("visit").await.unwrap().len(), 3);
+        let report = db
+            .import_xlsx(
+                &bad,
+                &XlsxOptions::default(),
+                &InsertOptions::default().partial(true),
+            )
+            .await
+            .unwrap();
+        assert_eq!(report.sheets[0].insert.inserted, 1);
+        assert_eq!(db.get_table_data("visit").await.unwrap().len(), 4);
+        assert!(matches!(
+            db.import_xlsx(
+                &bad,
+                &XlsxOptions::default().map("visit", Some("missing")),
+                &InsertOptions::default(),
+            )
+            .await
+            .unwrap_err(),
+            Error::InvalidXlsx(_)
+        ));
+
+        log::info!("column labels");
 
+        let labelled = TableMeta::new(
+            "labelled",
+            vec![
+                ColMeta::new()
+                    .name("sex")
+                    .postgres_type(PostgresType::Integer)
+                    .label("Participant's sex")
+                    .value_labels(vec![
+                        table::ValueLabel::new(serde_json::json!(1), "male"),
+                        table::ValueLabel::new(serde_json::json!(2), "female"),
+                    ]),
+                ColMeta::new()
+                    .name("note")
+                    .postgres_type(PostgresType::Text),
+            ],
+        );
+        db.create_table(&labelled).await.unwrap();
+        assert_eq!(db.get_table_meta("labelled").await.unwrap(), labelled);
+        db.alter_table(
+            "labelled",
+            &[
+                TableAlter::SetLabels {
+                    name: "sex".to_string(),
+                    label: None,
+                    value_labels: vec![],
+                },
+                TableAlter::AddColumn(
+                    ColMeta::new().name("age").label("Age (years)"),
+                ),
+            ],
+        )
+        .await
+        .unwrap();
+        let meta = db.get_table_meta("labelled").await.unwrap();
         assert_eq!(
-            db.get_all_table_names().await.unwrap(),
-            vec![primary_table.name.clone(), date_table.name.clone()]
+            meta.cols[0],
+            ColMeta::new()
+                .name("sex")
+                .postgres_type(PostgresType::Integer)
         );
+        assert_eq!(meta.cols[2].label, Some("Age (years)".to_string()));
+        sqlx::query("COMMENT ON COLUMN \"labelled\".\"note\" IS 'Free text'")
+            .execute(db.get_pool())
+            .await
+            .unwrap();
+        db.invalidate_cache();
+        let meta = db.get_table_meta("labelled").await.unwrap();
+        assert_eq!(meta.cols[1].label, Some("Free text".to_string()));
 
-        log::info!("create table again");
+        log::info!("export for statistical packages");
 
-        db.create_table(&secondary_table).await.unwrap();
+        db.alter_table(
+            "labelled",
+            &[TableAlter::SetLabels {
+                name: "sex".to_string(),
+                label: Some("Sex".to_string()),
+                value_labels: labelled.cols[0].value_labels.clone(),
+            }],
+        )
+        .await
+        .unwrap();
+        let labelled_rows = crate::tests::rows(serde_json::json!([
+            {"sex": 1, "note": "fine", "age": "40"},
+            {"sex": 2, "note": null, "age": null}
+        ]));
+        db.insert_table_data(
+            "labelled",
+            &labelled_rows,
+            &InsertOptions::default(),
+        )
+        .await
+        .unwrap();
+        let file = |export| match export {
+            StatsExport::File(data) => data,
+            StatsExport::Archive(_) => panic!("expected a file"),
+        };
+        let dta = file(
+            db.export_table_stats("labelled", StatsFormat::Stata)
+                .await
+                .unwrap(),
+        );
+        assert!(dta.starts_with(b"<stata_dta>"));
+        assert!(dta.windows(4).any(|w| w == b"male"));
+        let sav = file(
+            db.export_table_stats("labelled", StatsFormat::Spss)
+                .await
+                .unwrap(),
+        );
+        assert!(sav.starts_with(b"$FL2"));
+        let archive = match db
+            .export_table_stats("labelled", StatsFormat::R)
+            .await
+            .unwrap()
+        {
+            StatsExport::Archive(archive) => read(archive).await,
+            StatsExport::File(_) => panic!("expected an archive"),
+        };
+        let mut zip = async_zip::base::read::stream::ZipFileReader::new(
+            futures::io::Cursor::new(archive),
+        );
+        let mut files = Vec::new();
+        while let Some(mut entry) = zip.next_with_entry().await.unwrap() {
+            let name = entry.reader().entry().filename().as_str().unwrap();
+            let name = name.to_string();
+            let mut contents = String::new();
+            futures::AsyncReadExt::read_to_string(
+                entry.reader_mut(),
+                &mut contents,
+            )
+            .await
+            .unwrap();
+            files.push((name, contents));
+            zip = entry.done().await.unwrap();
+        }
+        assert_eq!(files[0].0, "labelled.csv");
+        assert_eq!(files[0].1, "sex,note,age\n1,fine,40\n2,,\n");
+        assert_eq!(files[1].0, "tables.R");
+        assert!(files[1]
+            .1
+            .contains("attr(data[[\"sex\"]], \"label\") <- \"Sex\""));
+        let archive =
+            read(db.export_project_stats(StatsFormat::Stata).await.unwrap())
+                .await;
+        assert!(archive.starts_with(b"PK"));
+        assert!(matches!(
+            db.export_table_stats("missing", StatsFormat::Stata)
+                .await
+                .err()
+                .unwrap(),
+            Error::NoSuchTable(_)
+        ));
 
-        log::info!("remove table that others reference");
+        log::info!("export and import odm");
 
+        let document =
+            String::from_utf8(db.export_odm("study").await.unwrap()).unwrap();
+        assert!(document.contains("<ItemGroupDef OID=\"IG.labelled\""));
+        assert!(document.contains("<TranslatedText>female</TranslatedText>"));
+        let mut copy = db.get_table_meta("labelled").await.unwrap();
+        copy.name = "labelled_copy".to_string();
+        let copied = odm::write_odm(
+            "study",
+            &[(copy.clone(), db.get_table_data("labelled").await.unwrap())],
+        )
+        .unwrap();
+        assert!(matches!(
+            db.import_odm(
+                document.as_bytes(),
+                &OdmOptions::default(),
+                &InsertOptions::default()
+            )
+            .await
+            .unwrap_err(),
+            Error::TableAlreadyExists(_)
+        ));
+        let report = db
+            .import_odm(
+                &copied,
+                &OdmOptions::default(),
+                &InsertOptions::default().dry_run(true),
+            )
+            .await
+            .unwrap();
+        assert_eq!(report.item_groups[0].table, "labelled_copy");
+        assert_eq!(report.item_groups[0].insert.inserted, 2);
         assert!(matches!(
-            db.remove_table(primary_table.name.as_str()).await.unwrap_err(),
-            Error::Sqlx(sqlx::Error::Database(e))
-                if e.code().unwrap() == "2BP01"
+            db.get_table_meta("labelled_copy").await.unwrap_err(),
+            Error::NoSuchTable(_)
         ));
+        db.import_odm(
+            &copied,
+            &OdmOptions::default(),
+            &InsertOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(db.get_table_meta("labelled_copy").await.unwrap(), copy);
+        assert_eq!(
+            db.get_table_data("labelled_copy").await.unwrap(),
+            db.get_table_data("labelled").await.unwrap()
+        );
+
+        let mut bad = copy.clone();
+        bad.name = "labelled_bad".to_string();
+        let bad_rows = crate::tests::rows(serde_json::json!([
+            {"sex": 1},
+            {"sex": "one"}
+        ]));
+        let bad = odm::write_odm("study", &[(bad, bad_rows)]).unwrap();
+        match db
+            .import_odm(&bad, &OdmOptions::default(), &InsertOptions::default())
+            .await
+            .unwrap_err()
+        {
+            Error::InvalidItemGroupRows(errors) => {
+                assert_eq!(errors.len(), 1);
+                assert_eq!(errors[0].item_group, "IG.labelled_bad");
+                assert_eq!(errors[0].error.row, 2);
+                assert_eq!(errors[0].error.column, Some("sex".to_string()));
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert!(!db
+            .get_all_table_names()
+            .await
+            .unwrap()
+            .contains(&"labelled_bad".to_string()));
+        let report = db
+            .import_odm(
+                &bad,
+                &OdmOptions::default(),
+                &InsertOptions::default().partial(true),
+            )
+            .await
+            .unwrap();
+        assert_eq!(report.item_groups[0].insert.inserted, 1);
+        assert_eq!(db.get_table_data("labelled_bad").await.unwrap().len(), 1);
 
         // Remove test DB -----------------------------------------------------
         crate::tests::remove_test_db(&db.db).await;
@@ -0,0 +1,1249 @@
+use super::table::{ColMeta, InsertReport, RowJson, TableMeta, ValueLabel};
+use super::types::PostgresType;
+use crate::{error::RowError, Error, Result};
+use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::Writer;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Namespace of ODM 1.3
+pub const ODM_NAMESPACE: &str = "http://www.cdisc.org/ns/odm/v1.3";
+/// Namespace of our extensions to ODM
+pub const ODC_NAMESPACE: &str =
+    "https://github.com/khvorov45/opendatacapture/odm";
+
+/// Metadata version and study event all exported tables belong to
+const METADATA_OID: &str = "MDV.1";
+const STUDY_EVENT_OID: &str = "SE.DATA";
+
+/// How to read an ODM file into tables
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct OdmOptions {
+    /// Column added to every table to hold the key of the subject its rows
+    /// belong to. Subjects are left out if not given.
+    #[serde(default)]
+    pub subject_column: Option<String>,
+}
+
+impl OdmOptions {
+    pub fn subject_column(mut self, val: &str) -> Self {
+        self.subject_column = Some(val.to_string());
+        self
+    }
+}
+
+/// Result of importing an ODM file
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct OdmReport {
+    /// Item groups in the order their tables were created
+    pub item_groups: Vec<ItemGroupReport>,
+}
+
+/// Result of creating the table of an item group and inserting its data
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct ItemGroupReport {
+    pub item_group: String,
+    pub table: String,
+    /// Outcome of inserting the rows, with the rows numbered as the item
+    /// group's data in the file
+    pub insert: InsertReport,
+}
+
+/// Table read from the definition of an item group and its rows read from
+/// the clinical data
+#[derive(Debug, Clone, PartialEq)]
+pub struct OdmTable {
+    /// OID of the item group
+    pub item_group: String,
+    pub table: TableMeta,
+    pub rows: Vec<RowJson>,
+    /// Number of each row among the item group's data, starting at 1
+    pub records: Vec<usize>,
+    /// Failures of the item group data that were not read
+    pub errors: Vec<RowError>,
+}
+
+/// ODM document with the project's tables as item groups of one form each
+/// and their rows as the data of those item groups, written a row at a
+/// time.
+/// ODM keeps data by subject and tables have no subjects, so all rows go
+/// to one subject keyed by the study name. Exact column types are kept in
+/// an extension attribute.
+pub struct OdmWriter {
+    xml: XmlWriter,
+    study: String,
+    /// Whether the subject's data is started
+    subject: bool,
+    /// Table whose form data is being written
+    form: Option<String>,
+    /// Rows of that table written so far
+    form_rows: usize,
+}
+
+impl OdmWriter {
+    /// Starts the document with the definitions of the tables
+    pub fn new(study: &str, tables: &[TableMeta]) -> Result<Self> {
+        let created = chrono::Utc::now();
+        let study_oid = format!("ST.{}", study);
+        let mut xml = Writer::new_with_indent(Vec::new(), b' ', 2);
+        xml.write_event(Event::Decl(BytesDecl::new(
+            "1.0",
+            Some("UTF-8"),
+            None,
+        )))?;
+        xml.write_event(Event::Start(
+            BytesStart::new("ODM").with_attributes([
+                ("xmlns", ODM_NAMESPACE),
+                ("xmlns:odc", ODC_NAMESPACE),
+                ("ODMVersion", "1.3.2"),
+                ("FileType", "Snapshot"),
+                (
+                    "FileOID",
+                    format!("{}.{}", study, created.format("%Y%m%d%H%M%S"))
+                        .as_str(),
+                ),
+                (
+                    "CreationDateTime",
+                    created.format("%Y-%m-%dT%H:%M:%S%:z").to_string().as_str(),
+                ),
+            ]),
+        ))?;
+        xml.create_element("Study")
+            .with_attribute(("OID", study_oid.as_str()))
+            .write_inner_content(|w| {
+                write_global_variables(w, study)?;
+                write_metadata(w, study, tables)
+            })?;
+        xml.write_event(Event::Start(
+            BytesStart::new("ClinicalData").with_attributes([
+                ("StudyOID", study_oid.as_str()),
+                ("MetaDataVersionOID", METADATA_OID),
+            ]),
+        ))?;
+        Ok(Self {
+            xml,
+            study: study.to_string(),
+            subject: false,
+            form: None,
+            form_rows: 0,
+        })
+    }
+    /// Writes a row of a table as item group data. The rows of a table go
+    /// together, tables without rows get no form data.
+    pub fn write_row(
+        &mut self,
+        table: &TableMeta,
+        row: &RowJson,
+    ) -> Result<()> {
+        if !self.subject {
+            self.xml.write_event(Event::Start(
+                BytesStart::new("SubjectData")
+                    .with_attributes([("SubjectKey", self.study.as_str())]),
+            ))?;
+            self.xml.write_event(Event::Start(
+                BytesStart::new("StudyEventData")
+                    .with_attributes([("StudyEventOID", STUDY_EVENT_OID)]),
+            ))?;
+            self.subject = true;
+        }
+        if self.form.as_deref() != Some(table.name.as_str()) {
+            self.end_form()?;
+            self.xml.write_event(Event::Start(
+                BytesStart::new("FormData")
+                    .with_attributes([("FormOID", form_oid(table).as_str())]),
+            ))?;
+            self.form = Some(table.name.clone());
+            self.form_rows = 0;
+        }
+        self.form_rows += 1;
+        write_item_group_data(&mut self.xml, table, self.form_rows, row)?;
+        Ok(())
+    }
+    /// Ends the document
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        self.end_form()?;
+        if self.subject {
+            for name in ["StudyEventData", "SubjectData"] {
+                self.xml.write_event(Event::End(BytesEnd::new(name)))?;
+            }
+        }
+        for name in ["ClinicalData", "ODM"] {
+            self.xml.write_event(Event::End(BytesEnd::new(name)))?;
+        }
+        Ok(self.xml.into_inner())
+    }
+    fn end_form(&mut self) -> Result<()> {
+        if self.form.take().is_some() {
+            self.xml
+                .write_event(Event::End(BytesEnd::new("FormData")))?;
+        }
+        Ok(())
+    }
+}
+
+/// ODM document of tables whose rows are already read
+pub fn write_odm(
+    study: &str,
+    tables: &[(TableMeta, Vec<RowJson>)],
+) -> Result<Vec<u8>> {
+    let metas: Vec<TableMeta> = tables.iter().map(|(t, _)| t.clone()).collect();
+    let mut writer = OdmWriter::new(study, &metas)?;
+    for (table, rows) in tables {
+        for row in rows {
+            writer.write_row(table, row)?;
+        }
+    }
+    writer.finish()
+}
+
+type XmlWriter = Writer<Vec<u8>>;
+
+fn write_global_variables(
+    w: &mut XmlWriter,
+    study: &str,
+) -> std::io::Result<()> {
+    w.create_element("GlobalVariables")
+        .write_inner_content(|w| {
+            for name in ["StudyName", "StudyDescription", "ProtocolName"] {
+                w.create_element(name)
+                    .write_text_content(BytesText::new(study))?;
+            }
+            Ok(())
+        })?;
+    Ok(())
+}
+
+fn write_metadata(
+    w: &mut XmlWriter,
+    study: &str,
+    tables: &[TableMeta],
+) -> std::io::Result<()> {
+    w.create_element("MetaDataVersion")
+        .with_attributes([("OID", METADATA_OID), ("Name", study)])
+        .write_inner_content(|w| {
+            w.create_element("Protocol").write_inner_content(|w| {
+                w.create_element("StudyEventRef")
+                    .with_attributes([
+                        ("StudyEventOID", STUDY_EVENT_OID),
+                        ("Mandatory", "Yes"),
+                    ])
+                    .write_empty()?;
+                Ok(())
+            })?;
+            w.create_element("StudyEventDef")
+                .with_attributes([
+                    ("OID", STUDY_EVENT_OID),
+                    ("Name", study),
+                    ("Repeating", "No"),
+                    ("Type", "Common"),
+                ])
+                .write_inner_content(|w| {
+                    for table in tables {
+                        w.create_element("FormRef")
+                            .with_attributes([
+                                ("FormOID", form_oid(table).as_str()),
+                                ("Mandatory", "No"),
+                            ])
+                            .write_empty()?;
+                    }
+                    Ok(())
+                })?;
+            for table in tables {
+                write_form_def(w, table)?;
+            }
+            for table in tables {
+                write_item_group_def(w, table)?;
+            }
+            for table in tables {
+                for col in &table.cols {
+                    write_item_def(w, table, col)?;
+                }
+            }
+            for table in tables {
+                for col in &table.cols {
+                    write_code_list(w, table, col)?;
+                }
+            }
+            Ok(())
+        })?;
+    Ok(())
+}
+
+fn write_form_def(w: &mut XmlWriter, table: &TableMeta) -> std::io::Result<()> {
+    w.create_element("FormDef")
+        .with_attributes([
+            ("OID", form_oid(table).as_str()),
+            ("Name", table.name.as_str()),
+            ("Repeating", "No"),
+        ])
+        .write_inner_content(|w| {
+            w.create_element("ItemGroupRef")
+                .with_attributes([
+                    ("ItemGroupOID", item_group_oid(table).as_str()),
+                    ("Mandatory", "No"),
+                ])
+                .write_empty()?;
+            Ok(())
+        })?;
+    Ok(())
+}
+
+/// Primary key columns are the item group's keys
+fn write_item_group_def(
+    w: &mut XmlWriter,
+    table: &TableMeta,
+) -> std::io::Result<()> {
+    w.create_element("ItemGroupDef")
+        .with_attributes([
+            ("OID", item_group_oid(table).as_str()),
+            ("Name", table.name.as_str()),
+            ("Repeating", "Yes"),
+        ])
+        .write_inner_content(|w| {
+            let mut key_sequence = 0;
+            for col in &table.cols {
+                let mandatory = col.not_null || col.primary_key;
+                let mut item_ref =
+                    w.create_element("ItemRef").with_attributes([
+                        ("ItemOID", item_oid(table, col).as_str()),
+                        ("Mandatory", if mandatory { "Yes" } else { "No" }),
+                    ]);
+                if col.primary_key {
+                    key_sequence += 1;
+                    item_ref = item_ref.with_attribute((
+                        "KeySequence",
+                        key_sequence.to_string().as_str(),
+                    ));
+                }
+                item_ref.write_empty()?;
+            }
+            Ok(())
+        })?;
+    Ok(())
+}
+
+/// The label is the item's question, value labels its code list
+fn write_item_def(
+    w: &mut XmlWriter,
+    table: &TableMeta,
+    col: &ColMeta,
+) -> std::io::Result<()> {
+    let (data_type, length, significant_digits) = odm_type(&col.postgres_type);
+    let postgres_type = col.postgres_type.to_string();
+    let mut item_def = w.create_element("ItemDef").with_attributes([
+        ("OID", item_oid(table, col).as_str()),
+        ("Name", col.name.as_str()),
+        ("DataType", data_type),
+    ]);
+    if let Some(length) = length {
+        item_def =
+            item_def.with_attribute(("Length", length.to_string().as_str()));
+    }
+    if let Some(digits) = significant_digits {
+        item_def = item_def
+            .with_attribute(("SignificantDigits", digits.to_string().as_str()));
+    }
+    item_def =
+        item_def.with_attribute(("odc:PostgresType", postgres_type.as_str()));
+    if col.label.is_none() && col.value_labels.is_empty() {
+        item_def.write_empty()?;
+        return Ok(());
+    }
+    item_def.write_inner_content(|w| {
+        if let Some(label) = &col.label {
+            w.create_element("Question").write_inner_content(|w| {
+                w.create_element("TranslatedText")
+                    .write_text_content(BytesText::new(label))?;
+                Ok(())
+            })?;
+        }
+        if !col.value_labels.is_empty() {
+            w.create_element("CodeListRef")
+                .with_attribute((
+                    "CodeListOID",
+                    code_list_oid(table, col).as_str(),
+                ))
+                .write_empty()?;
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
+fn write_code_list(
+    w: &mut XmlWriter,
+    table: &TableMeta,
+    col: &ColMeta,
+) -> std::io::Result<()> {
+    if col.value_labels.is_empty() {
+        return Ok(());
+    }
+    // Code lists only come in some of the item types
+    let data_type = match odm_type(&col.postgres_type).0 {
+        data_type @ ("integer" | "float" | "string") => data_type,
+        _ => "text",
+    };
+    w.create_element("CodeList")
+        .with_attributes([
+            ("OID", code_list_oid(table, col).as_str()),
+            ("Name", col.name.as_str()),
+            ("DataType", data_type),
+        ])
+        .write_inner_content(|w| {
+            for value_label in &col.value_labels {
+                let value = value_text(&value_label.value).unwrap_or_default();
+                w.create_element("CodeListItem")
+                    .with_attribute((
+                        "CodedValue".as_bytes(),
+                        escape_value(&value).as_bytes(),
+                    ))
+                    .write_inner_content(|w| {
+                        w.create_element("Decode").write_inner_content(
+                            |w| {
+                                w.create_element("TranslatedText")
+                                    .write_text_content(BytesText::new(
+                                        &value_label.label,
+                                    ))?;
+                                Ok(())
+                            },
+                        )?;
+                        Ok(())
+                    })?;
+            }
+            Ok(())
+        })?;
+    Ok(())
+}
+
+/// Rows are numbered from 1 in the order they come in, nulls are left out
+fn write_item_group_data(
+    w: &mut XmlWriter,
+    table: &TableMeta,
+    repeat_key: usize,
+    row: &RowJson,
+) -> std::io::Result<()> {
+    w.create_element("ItemGroupData")
+        .with_attributes([
+            ("ItemGroupOID", item_group_oid(table).as_str()),
+            ("ItemGroupRepeatKey", repeat_key.to_string().as_str()),
+        ])
+        .write_inner_content(|w| {
+            for col in &table.cols {
+                let value = match row.get(&col.name).and_then(value_text) {
+                    Some(value) => value,
+                    None => continue,
+                };
+                w.create_element("ItemData")
+                    .with_attribute(("ItemOID", item_oid(table, col).as_str()))
+                    .with_attribute((
+                        "Value".as_bytes(),
+                        escape_value(&value).as_bytes(),
+                    ))
+                    .write_empty()?;
+            }
+            Ok(())
+        })?;
+    Ok(())
+}
+
+fn form_oid(table: &TableMeta) -> String {
+    format!("F.{}", table.name)
+}
+
+fn item_group_oid(table: &TableMeta) -> String {
+    format!("IG.{}", table.name)
+}
+
+fn item_oid(table: &TableMeta, col: &ColMeta) -> String {
+    format!("IT.{}.{}", table.name, col.name)
+}
+
+fn code_list_oid(table: &TableMeta, col: &ColMeta) -> String {
+    format!("CL.{}.{}", table.name, col.name)
+}
+
+/// ODM data type of a column type with its length and significant digits
+fn odm_type(
+    postgres_type: &PostgresType,
+) -> (&'static str, Option<u32>, Option<u32>) {
+    use PostgresType as T;
+    match postgres_type {
+        T::SmallInt | T::Integer | T::BigInt => ("integer", None, None),
+        T::Real | T::DoublePrecision | T::Numeric(None) => {
+            ("float", None, None)
+        }
+        T::Numeric(Some((precision, scale))) => {
+            ("float", Some(*precision), Some(*scale))
+        }
+        T::Varchar(length) => ("string", *length, None),
+        T::Uuid => ("string", Some(36), None),
+        T::Boolean => ("boolean", None, None),
+        T::Date => ("date", None, None),
+        T::Time => ("time", None, None),
+        T::Timestamp | T::Timestamptz => ("datetime", None, None),
+        T::Text | T::Json | T::Jsonb | T::Bytea | T::Interval | T::Array(_) => {
+            ("text", None, None)
+        }
+    }
+}
+
+/// Column type of an ODM data type, with its length and significant
+/// digits if given
+fn postgres_type(
+    data_type: &str,
+    length: Option<u32>,
+    significant_digits: Option<u32>,
+) -> PostgresType {
+    use PostgresType as T;
+    match (data_type, length, significant_digits) {
+        // ODM integers have no bounds
+        ("integer", _, _) => T::BigInt,
+        ("float", Some(length), Some(digits)) => {
+            T::Numeric(Some((length, digits)))
+        }
+        ("float", _, _) | ("double", _, _) => T::DoublePrecision,
+        ("boolean", _, _) => T::Boolean,
+        ("date", _, _) => T::Date,
+        ("time", _, _) => T::Time,
+        ("datetime", _, _) => T::Timestamp,
+        ("string", length, _) => T::Varchar(length),
+        _ => T::Text,
+    }
+}
+
+/// Text of a value, `None` for null
+fn value_text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone()),
+        _ => Some(value.to_string()),
+    }
+}
+
+/// Attribute value escaped so that line breaks and tabs survive attribute
+/// value normalization on reading
+fn escape_value(value: &str) -> String {
+    quick_xml::escape::escape(value)
+        .replace('\n', "&#10;")
+        .replace('\r', "&#13;")
+        .replace('\t', "&#9;")
+}
+
+/// Item definition as read from a document
+#[derive(Debug, Clone, Default)]
+struct ItemDef {
+    name: String,
+    data_type: String,
+    length: Option<u32>,
+    significant_digits: Option<u32>,
+    /// Exact column type if the document was written by us
+    postgres_type: Option<String>,
+    question: Option<String>,
+    description: Option<String>,
+    code_list: Option<String>,
+}
+
+impl ItemDef {
+    /// Column of the item. The question is the label, or else the
+    /// description.
+    fn col_meta(
+        &self,
+        item_ref: &ItemRef,
+        code_lists: &HashMap<String, Vec<(String, String)>>,
+    ) -> Result<ColMeta> {
+        let postgres_type = match &self.postgres_type {
+            Some(postgres_type) => postgres_type.parse()?,
+            None => postgres_type(
+                &self.data_type,
+                self.length,
+                self.significant_digits,
+            ),
+        };
+        let mut col = ColMeta::new()
+            .name(&self.name)
+            .postgres_type(postgres_type)
+            .not_null(item_ref.mandatory)
+            .primary_key(item_ref.key_sequence.is_some());
+        col.label = self.question.clone().or_else(|| self.description.clone());
+        if let Some(oid) = &self.code_list {
+            let items = code_lists.get(oid).ok_or_else(|| {
+                Error::InvalidOdm(format!(
+                    "item \"{}\" refers to code list \"{}\" that is not \
+                    defined",
+                    self.name, oid
+                ))
+            })?;
+            col.value_labels = items
+                .iter()
+                .map(|(value, label)| {
+                    ValueLabel::new(
+                        coded_value(value, &col.postgres_type),
+                        label,
+                    )
+                })
+                .collect();
+        }
+        Ok(col)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct ItemRef {
+    item: String,
+    mandatory: bool,
+    key_sequence: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ItemGroupDef {
+    oid: String,
+    name: String,
+    items: Vec<ItemRef>,
+}
+
+/// Values of an item group's items by item OID
+#[derive(Debug, Clone, Default)]
+struct ItemGroupData {
+    item_group: String,
+    subject: Option<String>,
+    items: Vec<(String, String)>,
+}
+
+/// Definitions of a document's first metadata version and its data
+#[derive(Debug, Clone, Default)]
+struct Document {
+    item_groups: Vec<ItemGroupDef>,
+    items: HashMap<String, ItemDef>,
+    /// Coded values with their decodes by code list OID
+    code_lists: HashMap<String, Vec<(String, String)>>,
+    data: Vec<ItemGroupData>,
+}
+
+/// Where text content being read goes
+enum TextTarget {
+    Question,
+    Description,
+    Decode,
+    /// Value of a typed item data element with the item's OID
+    Item(String),
+}
+
+/// Tables of the item groups of an ODM document with the rows of their
+/// data, named after the item groups and the items.
+/// Item data are given as text for the insert to convert, except for json
+/// and array columns. Data of items outside of their item group are
+/// reported as failures.
+pub fn read_odm(data: &[u8], options: &OdmOptions) -> Result<Vec<OdmTable>> {
+    let document = read_document(data)?;
+    let mut tables = Vec::new();
+    for group in &document.item_groups {
+        let mut cols: Vec<ColMeta> = Vec::new();
+        if let Some(subject) = &options.subject_column {
+            cols.push(
+                ColMeta::new()
+                    .name(subject)
+                    .postgres_type(PostgresType::Text),
+            );
+        }
+        for item_ref in &group.items {
+            let item = document.items.get(&item_ref.item).ok_or_else(|| {
+                Error::InvalidOdm(format!(
+                    "item group \"{}\" refers to item \"{}\" that is not \
+                    defined",
+                    group.oid, item_ref.item
+                ))
+            })?;
+            if cols.iter().any(|col| col.name == item.name) {
+                return Err(Error::ColumnAlreadyExists(item.name.clone()));
+            }
+            cols.push(item.col_meta(item_ref, &document.code_lists)?);
+        }
+        if tables.iter().any(|t: &OdmTable| t.table.name == group.name) {
+            return Err(Error::TableAlreadyExists(group.name.clone()));
+        }
+        tables.push(OdmTable {
+            item_group: group.oid.clone(),
+            table: TableMeta::new(&group.name, cols),
+            rows: Vec::new(),
+            records: Vec::new(),
+            errors: Vec::new(),
+        });
+    }
+    let mut counts = vec![0; tables.len()];
+    for data in &document.data {
+        let i = document
+            .item_groups
+            .iter()
+            .position(|group| group.oid == data.item_group)
+            .ok_or_else(|| {
+                Error::InvalidOdm(format!(
+                    "data of item group \"{}\" that is not defined",
+                    data.item_group
+                ))
+            })?;
+        counts[i] += 1;
+        let record = counts[i];
+        let table = &tables[i].table;
+        let mut row = RowJson::new();
+        if let Some(subject) = &options.subject_column {
+            let key = data.subject.clone().map_or(Value::Null, Value::String);
+            row.insert(subject.clone(), key);
+        }
+        let mut errors = Vec::new();
+        for (oid, text) in &data.items {
+            let col = document.item_groups[i]
+                .items
+                .iter()
+                .find(|item_ref| item_ref.item == *oid)
+                .and_then(|item_ref| document.items.get(&item_ref.item))
+                .and_then(|item| table.get_col(&item.name));
+            let col = match col {
+                Some(col) => col,
+                None => {
+                    errors.push(RowError {
+                        row: record,
+                        column: None,
+                        value: Some(Value::String(text.clone())),
+                        reason: format!(
+                            "item \"{}\" is not in item group \"{}\"",
+                            oid, data.item_group
+                        ),
+                    });
+                    continue;
+                }
+            };
+            match read_value(text, col) {
+                Ok(value) => {
+                    row.insert(col.name.clone(), value);
+                }
+                Err(reason) => errors.push(RowError {
+                    row: record,
+                    column: Some(col.name.clone()),
+                    value: Some(Value::String(text.clone())),
+                    reason,
+                }),
+            }
+        }
+        if errors.is_empty() {
+            tables[i].rows.push(row);
+            tables[i].records.push(record);
+        } else {
+            tables[i].errors.extend(errors);
+        }
+    }
+    Ok(tables)
+}
+
+/// Value of an item for the column's type, returns the reason on failure
+fn read_value(text: &str, col: &ColMeta) -> std::result::Result<Value, String> {
+    use PostgresType as T;
+    match col.postgres_type {
+        T::Json | T::Jsonb | T::Array(_) => serde_json::from_str(text)
+            .map_err(|e| format!("\"{}\" is not json: {}", text, e)),
+        _ => Ok(Value::String(text.to_string())),
+    }
+}
+
+/// Coded value of a code list as the value of the column, numbers and
+/// booleans are kept as such
+fn coded_value(text: &str, postgres_type: &PostgresType) -> Value {
+    use PostgresType as T;
+    let parsed = match postgres_type {
+        T::SmallInt
+        | T::Integer
+        | T::BigInt
+        | T::Real
+        | T::DoublePrecision
+        | T::Numeric(_)
+        | T::Boolean => serde_json::from_str(text).ok(),
+        _ => None,
+    };
+    match parsed {
+        Some(value @ (Value::Number(_) | Value::Bool(_))) => value,
+        _ => Value::String(text.to_string()),
+    }
+}
+
+/// Definitions and data of a document. Elements are matched by their
+/// local names so that any namespace prefix is accepted.
+fn read_document(data: &[u8]) -> Result<Document> {
+    let xml = std::str::from_utf8(data).map_err(invalid)?;
+    let mut reader =
+        quick_xml::Reader::from_str(xml.trim_start_matches('\u{feff}'));
+    let mut document = Document::default();
+    // Local names of the open elements
+    let mut open: Vec<String> = Vec::new();
+    let mut metadata_versions = 0;
+    let mut item: Option<String> = None;
+    let mut code_list: Option<String> = None;
+    let mut subject: Option<String> = None;
+    let mut text: Option<(TextTarget, String)> = None;
+    loop {
+        let event = reader.read_event().map_err(invalid)?;
+        match &event {
+            Event::Start(e) | Event::Empty(e) => {
+                let name = local_name(e)?;
+                let parent = open.last().map(|n| n.as_str()).unwrap_or("");
+                let grandparent = open
+                    .len()
+                    .checked_sub(2)
+                    .map(|i| open[i].as_str())
+                    .unwrap_or("");
+                let in_metadata = metadata_versions == 1
+                    && open.iter().any(|n| n == "MetaDataVersion");
+                match name.as_str() {
+                    "MetaDataVersion" => metadata_versions += 1,
+                    "ItemGroupDef" if in_metadata => {
+                        let oid = required_attribute(e, "OID")?;
+                        let name = attribute(e, "Name")?
+                            .unwrap_or_else(|| oid.clone());
+                        document.item_groups.push(ItemGroupDef {
+                            oid,
+                            name,
+                            items: Vec::new(),
+                        });
+                    }
+                    "ItemRef" if in_metadata && parent == "ItemGroupDef" => {
+                        let item_ref = ItemRef {
+                            item: required_attribute(e, "ItemOID")?,
+                            mandatory: attribute(e, "Mandatory")?.as_deref()
+                                == Some("Yes"),
+                            key_sequence: number_attribute(e, "KeySequence")?,
+                        };
+                        if let Some(group) = document.item_groups.last_mut() {
+                            group.items.push(item_ref);
+                        }
+                    }
+                    "ItemDef" if in_metadata => {
+                        let oid = required_attribute(e, "OID")?;
+                        let item_def = ItemDef {
+                            name: attribute(e, "Name")?
+                                .unwrap_or_else(|| oid.clone()),
+                            data_type: attribute(e, "DataType")?
+                                .unwrap_or_default(),
+                            length: number_attribute(e, "Length")?,
+                            significant_digits: number_attribute(
+                                e,
+                                "SignificantDigits",
+                            )?,
+                            postgres_type: attribute(e, "PostgresType")?,
+                            ..ItemDef::default()
+                        };
+                        document.items.insert(oid.clone(), item_def);
+                        item = Some(oid);
+                    }
+                    "CodeListRef" if in_metadata && parent == "ItemDef" => {
+                        let oid = required_attribute(e, "CodeListOID")?;
+                        if let Some(item) = item
+                            .as_ref()
+                            .and_then(|oid| document.items.get_mut(oid))
+                        {
+                            item.code_list = Some(oid);
+                        }
+                    }
+                    "CodeList" if in_metadata => {
+                        let oid = required_attribute(e, "OID")?;
+                        document.code_lists.insert(oid.clone(), Vec::new());
+                        code_list = Some(oid);
+                    }
+                    "CodeListItem" if in_metadata => {
+                        let value = required_attribute(e, "CodedValue")?;
+                        if let Some(items) = code_list
+                            .as_ref()
+                            .and_then(|oid| document.code_lists.get_mut(oid))
+                        {
+                            items.push((value, String::new()));
+                        }
+                    }
+                    "TranslatedText" if in_metadata => {
+                        let target = match (grandparent, parent) {
+                            ("ItemDef", "Question") => {
+                                Some(TextTarget::Question)
+                            }
+                            ("ItemDef", "Description") => {
+                                Some(TextTarget::Description)
+                            }
+                            ("CodeListItem", "Decode") => {
+                                Some(TextTarget::Decode)
+                            }
+                            _ => None,
+                        };
+                        text = target.map(|target| (target, String::new()));
+                    }
+                    "SubjectData" => {
+                        subject = attribute(e, "SubjectKey")?;
+                    }
+                    "ItemGroupData" => {
+                        document.data.push(ItemGroupData {
+                            item_group: required_attribute(e, "ItemGroupOID")?,
+                            subject: subject.clone(),
+                            items: Vec::new(),
+                        });
+                    }
+                    "ItemData" if parent == "ItemGroupData" => {
+                        let oid = required_attribute(e, "ItemOID")?;
+                        let is_null =
+                            attribute(e, "IsNull")?.as_deref() == Some("Yes");
+                        if let (Some(value), Some(data), false) = (
+                            attribute(e, "Value")?,
+                            document.data.last_mut(),
+                            is_null,
+                        ) {
+                            data.items.push((oid, value));
+                        }
+                    }
+                    // Typed item data like ItemDataString hold the value as
+                    // text
+                    typed
+                        if parent == "ItemGroupData"
+                            && typed.starts_with("ItemData") =>
+                    {
+                        let oid = required_attribute(e, "ItemOID")?;
+                        let is_null =
+                            attribute(e, "IsNull")?.as_deref() == Some("Yes");
+                        if !is_null {
+                            text = Some((TextTarget::Item(oid), String::new()));
+                        }
+                    }
+                    _ => {}
+                }
+                if let Event::Start(_) = event {
+                    open.push(name);
+                } else {
+                    // Empty elements have no text
+                    text = None;
+                }
+            }
+            Event::End(_) => {
+                let name = open.pop().unwrap_or_default();
+                match name.as_str() {
+                    "MetaDataVersion" => {
+                        item = None;
+                        code_list = None;
+                    }
+                    "SubjectData" => subject = None,
+                    _ => {}
+                }
+                let done = name == "TranslatedText"
+                    || matches!(&text, Some((TextTarget::Item(_), _)));
+                if !done {
+                    continue;
+                }
+                match text.take() {
+                    Some((TextTarget::Question, s)) => {
+                        if let Some(item) = item
+                            .as_ref()
+                            .and_then(|oid| document.items.get_mut(oid))
+                        {
+                            item.question.get_or_insert(s);
+                        }
+                    }
+                    Some((TextTarget::Description, s)) => {
+                        if let Some(item) = item
+                            .as_ref()
+                            .and_then(|oid| document.items.get_mut(oid))
+                        {
+                            item.description.get_or_insert(s);
+                        }
+                    }
+                    Some((TextTarget::Decode, s)) => {
+                        if let Some((_, label)) = code_list
+                            .as_ref()
+                            .and_then(|oid| document.code_lists.get_mut(oid))
+                            .and_then(|items| items.last_mut())
+                        {
+                            if label.is_empty() {
+                                *label = s;
+                            }
+                        }
+                    }
+                    Some((TextTarget::Item(oid), s)) => {
+                        if let Some(data) = document.data.last_mut() {
+                            data.items.push((oid, s));
+                        }
+                    }
+                    None => {}
+                }
+            }
+            Event::Text(t) => {
+                if let Some((_, s)) = &mut text {
+                    s.push_str(&t.xml10_content().map_err(invalid)?);
+                }
+            }
+            Event::CData(t) => {
+                if let Some((_, s)) = &mut text {
+                    s.push_str(&t.xml10_content().map_err(invalid)?);
+                }
+            }
+            Event::GeneralRef(r) => {
+                if let Some((_, s)) = &mut text {
+                    match r.resolve_char_ref().map_err(invalid)? {
+                        Some(c) => s.push(c),
+                        None => {
+                            let entity = r.decode().map_err(invalid)?;
+                            let resolved =
+                                quick_xml::escape::resolve_predefined_entity(
+                                    &entity,
+                                )
+                                .ok_or_else(
+                                    || {
+                                        Error::InvalidOdm(format!(
+                                            "unknown entity \"{}\"",
+                                            entity
+                                        ))
+                                    },
+                                )?;
+                            s.push_str(resolved);
+                        }
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    if metadata_versions == 0 {
+        return Err(Error::InvalidOdm(
+            "document has no metadata version".to_string(),
+        ));
+    }
+    Ok(document)
+}
+
+fn local_name(e: &BytesStart) -> Result<String> {
+    std::str::from_utf8(e.local_name().as_ref())
+        .map(|name| name.to_string())
+        .map_err(invalid)
+}
+
+/// Value of the attribute with the local name
+fn attribute(e: &BytesStart, name: &str) -> Result<Option<String>> {
+    for attr in e.attributes() {
+        let attr = attr.map_err(invalid)?;
+        if attr.key.local_name().as_ref() == name.as_bytes() {
+            let value = attr
+                .normalized_value(quick_xml::XmlVersion::Implicit1_0)
+                .map_err(invalid)?;
+            return Ok(Some(value.into_owned()));
+        }
+    }
+    Ok(None)
+}
+
+fn required_attribute(e: &BytesStart, name: &str) -> Result<String> {
+    attribute(e, name)?.ok_or_else(|| {
+        Error::InvalidOdm(format!(
+            "{} has no {}",
+            String::from_utf8_lossy(e.local_name().as_ref()),
+            name
+        ))
+    })
+}
+
+fn number_attribute(e: &BytesStart, name: &str) -> Result<Option<u32>> {
+    match attribute(e, name)? {
+        Some(value) => value.trim().parse().map(Some).map_err(|_| {
+            Error::InvalidOdm(format!("{} \"{}\" is not a number", name, value))
+        }),
+        None => Ok(None),
+    }
+}
+
+fn invalid(e: impl std::fmt::Display) -> Error {
+    Error::InvalidOdm(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    #[test]
+    fn odm_round_trip() {
+        let _ = pretty_env_logger::try_init();
+        let visit = crate::tests::get_visit_table();
+        let subject = TableMeta::new(
+            "subject",
+            vec![ColMeta::new()
+                .name("id")
+                .postgres_type(PostgresType::Text)
+                .primary_key(true)],
+        );
+        let mut rows = crate::tests::get_visit_data();
+        rows[0]["note"] = json!("first line\n\tsecond & <last>");
+
+        log::info!("write");
+        let data = write_odm(
+            "study",
+            &[(visit.clone(), rows), (subject.clone(), Vec::new())],
+        )
+        .unwrap();
+        let document = String::from_utf8(data.clone()).unwrap();
+        for part in [
+            "<ODM xmlns=\"http://www.cdisc.org/ns/odm/v1.3\"",
+            "<ItemRef ItemOID=\"IT.visit.id\" Mandatory=\"Yes\" \
+            KeySequence=\"1\"/>",
+            "<ItemRef ItemOID=\"IT.visit.weight\" Mandatory=\"No\"/>",
+            "<ItemDef OID=\"IT.visit.weight\" Name=\"weight\" \
+            DataType=\"float\" Length=\"5\" SignificantDigits=\"1\" \
+            odc:PostgresType=\"numeric(5,1)\">",
+            "<TranslatedText>Body weight (kg)</TranslatedText>",
+            "<CodeListItem CodedValue=\"2\">",
+            "<ItemData ItemOID=\"IT.visit.note\" \
+            Value=\"first line&#10;&#9;second &amp; &lt;last&gt;\"/>",
+            "<ItemGroupData ItemGroupOID=\"IG.visit\" \
+            ItemGroupRepeatKey=\"2\">",
+        ] {
+            assert!(document.contains(part), "{} not in {}", part, document);
+        }
+        assert!(!document.contains("FormOID=\"F.subject\">"));
+
+        log::info!("read back");
+        let tables = read_odm(&data, &OdmOptions::default()).unwrap();
+        assert_eq!(tables.len(), 2);
+        assert_eq!(tables[0].item_group, "IG.visit");
+        // Foreign keys aren't part of ODM
+        let mut read_visit = visit.clone();
+        read_visit.cols[1].foreign_key = None;
+        assert_eq!(tables[0].table, read_visit);
+        assert_eq!(tables[1].table, subject);
+        let expected = crate::tests::rows(json!([
+            {
+                "id": "1",
+                "subject": "1001",
+                "date": "2021-03-01",
+                "seen": "2021-03-01T08:30:00+02:00",
+                "sick": "true",
+                "weight": "72.5",
+                "sex": "1",
+                "symptoms": {"cough": true, "fever": 38.5},
+                "note": "first line\n\tsecond & <last>"
+            },
+            {"id": "2", "subject": "1002", "sex": "2", "symptoms": [1, 2]}
+        ]));
+        assert_eq!(tables[0].rows, expected);
+        assert_eq!(tables[0].records, vec![1, 2]);
+        assert!(tables[0].errors.is_empty());
+        assert!(tables[1].rows.is_empty());
+
+        log::info!("subject column");
+        let tables = read_odm(
+            &data,
+            &OdmOptions::default().subject_column("participant"),
+        )
+        .unwrap();
+        assert_eq!(tables[0].table.cols[0].name, "participant");
+        assert_eq!(tables[0].rows[1]["participant"], json!("study"));
+        assert!(matches!(
+            read_odm(&data, &OdmOptions::default().subject_column("id"))
+                .unwrap_err(),
+            Error::ColumnAlreadyExists(_)
+        ));
+
+        log::info!("other systems");
+        let other = r#"<?xml version="1.0" encoding="UTF-8"?>
+<odm:ODM xmlns:odm="http://www.cdisc.org/ns/odm/v1.3" ODMVersion="1.3.2">
+  <odm:Study OID="S">
+    <odm:MetaDataVersion OID="V1" Name="Version 1">
+      <odm:ItemGroupDef OID="IG.DM" Name="demographics" Repeating="No">
+        <odm:ItemRef ItemOID="IT.AGE" Mandatory="Yes"/>
+        <odm:ItemRef ItemOID="IT.SEX" Mandatory="No"/>
+        <odm:ItemRef ItemOID="IT.DOB" Mandatory="No"/>
+      </odm:ItemGroupDef>
+      <odm:ItemDef OID="IT.AGE" Name="age" DataType="integer">
+        <odm:Description>
+          <odm:TranslatedText xml:lang="en">Age &amp; years</odm:TranslatedText>
+        </odm:Description>
+      </odm:ItemDef>
+      <odm:ItemDef OID="IT.SEX" Name="sex" DataType="string" Length="1">
+        <odm:CodeListRef CodeListOID="CL.SEX"/>
+      </odm:ItemDef>
+      <odm:ItemDef OID="IT.DOB" Name="dob" DataType="date"/>
+      <odm:CodeList OID="CL.SEX" Name="sex" DataType="string">
+        <odm:CodeListItem CodedValue="F">
+          <odm:Decode><odm:TranslatedText>Female</odm:TranslatedText></odm:Decode>
+        </odm:CodeListItem>
+      </odm:CodeList>
+    </odm:MetaDataVersion>
+  </odm:Study>
+  <odm:ClinicalData StudyOID="S" MetaDataVersionOID="V1">
+    <odm:SubjectData SubjectKey="1001">
+      <odm:StudyEventData StudyEventOID="SE">
+        <odm:FormData FormOID="F">
+          <odm:ItemGroupData ItemGroupOID="IG.DM">
+            <odm:ItemDataInteger ItemOID="IT.AGE">40</odm:ItemDataInteger>
+            <odm:ItemData ItemOID="IT.SEX" Value="F"/>
+            <odm:ItemData ItemOID="IT.DOB" IsNull="Yes"/>
+          </odm:ItemGroupData>
+          <odm:ItemGroupData ItemGroupOID="IG.DM">
+            <odm:ItemData ItemOID="IT.WEIGHT" Value="80"/>
+          </odm:ItemGroupData>
+        </odm:FormData>
+      </odm:StudyEventData>
+    </odm:SubjectData>
+  </odm:ClinicalData>
+</odm:ODM>"#;
+        let tables = read_odm(
+            other.as_bytes(),
+            &OdmOptions::default().subject_column("subject"),
+        )
+        .unwrap();
+        assert_eq!(
+            tables[0].table,
+            TableMeta::new(
+                "demographics",
+                vec![
+                    ColMeta::new()
+                        .name("subject")
+                        .postgres_type(PostgresType::Text),
+                    ColMeta::new()
+                        .name("age")
+                        .postgres_type(PostgresType::BigInt)
+                        .not_null(true)
+                        .label("Age & years"),
+                    ColMeta::new()
+                        .name("sex")
+                        .postgres_type(PostgresType::Varchar(Some(1)))
+                        .value_labels(vec![ValueLabel::new(
+                            json!("F"),
+                            "Female"
+                        )]),
+                    ColMeta::new()
+                        .name("dob")
+                        .postgres_type(PostgresType::Date),
+                ]
+            )
+        );
+        assert_eq!(
+            tables[0].rows,
+            vec![json!({"subject": "1001", "age": "40", "sex": "F"})
+                .as_object()
+                .unwrap()
+                .clone()]
+        );
+        assert_eq!(tables[0].records, vec![1]);
+        assert_eq!(tables[0].errors.len(), 1);
+        assert_eq!(tables[0].errors[0].row, 2);
+
+        log::info!("invalid");
+        for invalid in [
+            "not xml <",
+            "<ODM></ODM>",
+            "<ODM><Study><MetaDataVersion/></Study><ClinicalData>\
+            <SubjectData SubjectKey=\"1\"><ItemGroupData ItemGroupOID=\"IG\"/>\
+            </SubjectData></ClinicalData></ODM>",
+            "<ODM><Study><MetaDataVersion><ItemGroupDef OID=\"IG\">\
+            <ItemRef ItemOID=\"IT\"/></ItemGroupDef></MetaDataVersion>\
+            </Study></ODM>",
+        ] {
+            assert!(matches!(
+                read_odm(invalid.as_bytes(), &OdmOptions::default())
+                    .unwrap_err(),
+                Error::InvalidOdm(_)
+            ));
+        }
+        let bad_json = ColMeta::new()
+            .name("symptoms")
+            .postgres_type(PostgresType::Jsonb);
+        assert!(read_value("{", &bad_json).is_err());
+    }
+}
@@ -0,0 +1,453 @@
+use super::filter::{CompiledFilter, RowFilter};
+use super::table::{RowJson, TableMeta};
+use super::value::BindValue;
+use crate::{Error, Result};
+
+/// Column to order rows by
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortKey {
+    pub column: String,
+    pub descending: bool,
+}
+
+impl SortKey {
+    pub fn new(column: &str) -> Self {
+        Self {
+            column: column.to_string(),
+            descending: false,
+        }
+    }
+    pub fn descending(mut self, val: bool) -> Self {
+        self.descending = val;
+        self
+    }
+    /// Entry for the `ORDER BY` clause. Nulls sort after all values, like
+    /// Postgres does by default for ascending order.
+    fn order_entry(&self) -> String {
+        format!(
+            "\"{}\" {} NULLS LAST",
+            self.column,
+            if self.descending { "DESC" } else { "ASC" }
+        )
+    }
+    /// Rows that come after the value in this column's order
+    fn after(
+        &self,
+        table: &TableMeta,
+        value: &Option<String>,
+        params: &mut Vec<BindValue>,
+    ) -> String {
+        match value {
+            // Nulls are last
+            None => "FALSE".to_string(),
+            Some(value) => format!(
+                "(\"{0}\" {1} {2}) OR (\"{0}\" IS NULL)",
+                self.column,
+                if self.descending { "<" } else { ">" },
+                self.param(table, value, params)
+            ),
+        }
+    }
+    /// Rows with the value in this column
+    fn at(
+        &self,
+        table: &TableMeta,
+        value: &Option<String>,
+        params: &mut Vec<BindValue>,
+    ) -> String {
+        match value {
+            None => format!("\"{}\" IS NULL", self.column),
+            Some(value) => format!(
+                "\"{}\" = {}",
+                self.column,
+                self.param(table, value, params)
+            ),
+        }
+    }
+    /// Adds the value as text, returns its placeholder cast back to the
+    /// column's type
+    fn param(
+        &self,
+        table: &TableMeta,
+        value: &str,
+        params: &mut Vec<BindValue>,
+    ) -> String {
+        params.push(BindValue::Text(value.to_string()));
+        format!(
+            "${}::{}",
+            params.len(),
+            table.get_col(&self.column).unwrap().postgres_type
+        )
+    }
+}
+
+/// `column` for ascending and `-column` for descending order
+impl std::str::FromStr for SortKey {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix('-') {
+            Some(column) => Ok(Self::new(column).descending(true)),
+            None => Ok(Self::new(s)),
+        }
+    }
+}
+
+/// Options for reading table data. In a query string lists are comma
+/// separated, e.g. `?limit=50&sort=-visit,id&columns=id,visit`, and the
+/// filter is json or in its compact form, e.g. `?filter=sick.eq.true`.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct ReadOptions {
+    /// Most rows to return, all if not given
+    #[serde(default)]
+    pub limit: Option<u64>,
+    /// Rows to skip
+    #[serde(default)]
+    pub offset: u64,
+    /// Columns to order by before the primary key
+    #[serde(default, deserialize_with = "comma_separated")]
+    pub sort: Vec<SortKey>,
+    /// Columns to return, all if empty
+    #[serde(default, deserialize_with = "comma_separated")]
+    pub columns: Vec<String>,
+    /// Return the rows after the one the cursor points to
+    #[serde(default)]
+    pub cursor: Option<String>,
+    /// Only return the rows that match
+    #[serde(default, deserialize_with = "filter_expression")]
+    pub filter: Option<RowFilter>,
+}
+
+impl ReadOptions {
+    pub fn limit(mut self, val: u64) -> Self {
+        self.limit = Some(val);
+        self
+    }
+    pub fn offset(mut self, val: u64) -> Self {
+        self.offset = val;
+        self
+    }
+    pub fn sort(mut self, val: Vec<SortKey>) -> Self {
+        self.sort = val;
+        self
+    }
+    pub fn columns(mut self, val: &[&str]) -> Self {
+        self.columns = val.iter().map(|c| c.to_string()).collect();
+        self
+    }
+    pub fn cursor(mut self, val: &str) -> Self {
+        self.cursor = Some(val.to_string());
+        self
+    }
+    pub fn filter(mut self, val: RowFilter) -> Self {
+        self.filter = Some(val);
+        self
+    }
+    /// Full order of the rows: the sort columns followed by the primary key
+    /// columns that are not among them
+    pub fn order(&self, table: &TableMeta) -> Result<Vec<SortKey>> {
+        let sort_cols: Vec<&str> =
+            self.sort.iter().map(|k| k.column.as_str()).collect();
+        table.verify_cols_present(&sort_cols)?;
+        let mut order = self.sort.clone();
+        for col in table.get_primary_key() {
+            if !sort_cols.contains(&col) {
+                order.push(SortKey::new(col));
+            }
+        }
+        Ok(order)
+    }
+    /// Order of the rows and the condition on the rows to read, those that
+    /// match the filter and come after the cursor
+    pub fn compile(
+        &self,
+        table: &TableMeta,
+    ) -> Result<(Vec<SortKey>, CompiledFilter)> {
+        let order = self.order(table)?;
+        let mut filter = RowFilter::And(self.filter.iter().cloned().collect())
+            .compile(table)?;
+        if let Some(cursor) = &self.cursor {
+            let keyset = keyset_filter(
+                table,
+                &order,
+                &decode_cursor(cursor)?,
+                &mut filter.values,
+            )?;
+            filter.condition =
+                format!("({}) AND ({})", filter.condition, keyset);
+        }
+        Ok((order, filter))
+    }
+}
+
+/// Deserializes a comma separated list, empty entries are skipped
+fn comma_separated<'de, D, T>(
+    deserializer: D,
+) -> std::result::Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+    s.split(',')
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| entry.parse().map_err(serde::de::Error::custom))
+        .collect()
+}
+
+/// Deserializes a filter from json or its compact form
+fn filter_expression<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<RowFilter>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+    let filter = if s.trim_start().starts_with('{') {
+        serde_json::from_str(&s).map_err(serde::de::Error::custom)?
+    } else {
+        s.parse().map_err(serde::de::Error::custom)?
+    };
+    Ok(Some(filter))
+}
+
+/// One page of a table's rows
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TablePage {
+    pub rows: Vec<RowJson>,
+    /// Number of rows that match the filter
+    pub total: i64,
+    /// Cursor for the page after this one, none if this is the last page or
+    /// the table has no primary key to order by
+    pub next_cursor: Option<String>,
+}
+
+/// Opaque cursor holding the text of the values of the order columns of a
+/// row, text keeps values that json numbers can't hold exactly
+pub fn encode_cursor(values: &[Option<String>]) -> String {
+    base64::encode_config(
+        serde_json::to_string(values).unwrap(),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+pub fn decode_cursor(cursor: &str) -> Result<Vec<Option<String>>> {
+    let invalid = || Error::InvalidCursor(cursor.to_string());
+    let bytes = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| invalid())?;
+    serde_json::from_slice(&bytes).map_err(|_| invalid())
+}
+
+/// Condition for the rows that come after the row with the given values of
+/// the order columns. The values are added to the parameters after those
+/// already there.
+pub fn keyset_filter(
+    table: &TableMeta,
+    order: &[SortKey],
+    values: &[Option<String>],
+    params: &mut Vec<BindValue>,
+) -> Result<String> {
+    if values.len() != order.len() {
+        return Err(Error::InvalidCursor(format!(
+            "{} values for {} order columns",
+            values.len(),
+            order.len()
+        )));
+    }
+    // (a after) or (a at and b after) or (a at and b at and c after) ...
+    let mut alternatives = Vec::with_capacity(order.len());
+    for (i, key) in order.iter().enumerate() {
+        let mut conditions: Vec<String> = order[..i]
+            .iter()
+            .zip(values)
+            .map(|(k, v)| format!("({})", k.at(table, v, params)))
+            .collect();
+        conditions.push(format!("({})", key.after(table, &values[i], params)));
+        alternatives.push(format!("({})", conditions.join(" AND ")));
+    }
+    if alternatives.is_empty() {
+        return Ok("FALSE".to_string());
+    }
+    Ok(alternatives.join(" OR "))
+}
+
+/// Query for a page of rows ordered as given. Every row comes with the
+/// text of its order columns as a json array.
+/// Columns read as text keep values that json numbers can't hold exactly,
+/// like large numerics.
+pub fn construct_page_query(
+    table: &TableMeta,
+    columns: &[String],
+    as_text: &[&str],
+    order: &[SortKey],
+    condition: &str,
+    limit: Option<u64>,
+    offset: u64,
+) -> Result<String> {
+    table.verify_cols_present(columns)?;
+    table.verify_cols_present(as_text)?;
+    let projection = if columns.is_empty() && as_text.is_empty() {
+        format!("ROW_TO_JSON(\"{}\".*)", table.name)
+    } else {
+        let columns: Vec<&str> = if columns.is_empty() {
+            table.cols.iter().map(|c| c.name.as_str()).collect()
+        } else {
+            columns.iter().map(|c| c.as_str()).collect()
+        };
+        format!(
+            "json_build_object({})",
+            columns
+                .iter()
+                .map(|c| format!(
+                    "'{}',\"{}\"{}",
+                    c.replace('\'', "''"),
+                    c,
+                    if as_text.contains(c) { "::text" } else { "" }
+                ))
+                .collect::<Vec<String>>()
+                .join(",")
+        )
+    };
+    let order_values = format!(
+        "json_build_array({})",
+        order
+            .iter()
+            .map(|k| format!("\"{}\"::text", k.column))
+            .collect::<Vec<String>>()
+            .join(",")
+    );
+    let mut query = format!(
+        "SELECT {},{} FROM \"{}\" WHERE {}",
+        projection, order_values, table.name, condition
+    );
+    if !order.is_empty() {
+        query = format!(
+            "{} ORDER BY {}",
+            query,
+            order
+                .iter()
+                .map(|k| k.order_entry())
+                .collect::<Vec<String>>()
+                .join(",")
+        );
+    }
+    if let Some(limit) = limit {
+        query = format!("{} LIMIT {}", query, limit);
+    }
+    if offset > 0 {
+        query = format!("{} OFFSET {}", query, offset);
+    }
+    Ok(query)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn page_query() {
+        let _ = pretty_env_logger::try_init();
+        let table = crate::tests::get_test_secondary_table();
+
+        log::info!("options from a query string");
+        let opt: ReadOptions = serde_urlencoded::from_str(
+            "limit=10&sort=-sick,timepoint&columns=id,sick",
+        )
+        .unwrap();
+        assert_eq!(opt.limit, Some(10));
+        assert_eq!(opt.offset, 0);
+        assert_eq!(
+            opt.sort,
+            vec![
+                SortKey::new("sick").descending(true),
+                SortKey::new("timepoint")
+            ]
+        );
+        assert_eq!(opt.columns, vec!["id", "sick"]);
+
+        log::info!("order ends with the primary key");
+        let order = opt.order(&table).unwrap();
+        assert_eq!(
+            order,
+            vec![
+                SortKey::new("sick").descending(true),
+                SortKey::new("timepoint"),
+                SortKey::new("id")
+            ]
+        );
+        assert!(matches!(
+            ReadOptions::default()
+                .sort(vec![SortKey::new("age")])
+                .order(&table)
+                .unwrap_err(),
+            Error::NoSuchColumns(cols) if cols == vec!["age"]
+        ));
+        assert_eq!(
+            construct_page_query(
+                &table,
+                &opt.columns,
+                &[],
+                &order,
+                "TRUE",
+                opt.limit,
+                5
+            )
+            .unwrap(),
+            "SELECT json_build_object('id',\"id\",'sick',\"sick\"),\
+            json_build_array(\"sick\"::text,\"timepoint\"::text,\"id\"::text) \
+            FROM \"secondary\" WHERE TRUE \
+            ORDER BY \"sick\" DESC NULLS LAST,\"timepoint\" ASC NULLS LAST,\
+            \"id\" ASC NULLS LAST LIMIT 10 OFFSET 5"
+        );
+        assert_eq!(
+            construct_page_query(&table, &[], &[], &[], "FALSE", None, 0)
+                .unwrap(),
+            "SELECT ROW_TO_JSON(\"secondary\".*),json_build_array() \
+            FROM \"secondary\" WHERE FALSE"
+        );
+        assert_eq!(
+            construct_page_query(&table, &[], &["sick"], &[], "TRUE", None, 0)
+                .unwrap(),
+            "SELECT json_build_object('id',\"id\",'timepoint',\"timepoint\",\
+            'sick',\"sick\"::text,'symptoms',\"symptoms\",\
+            'locations',\"locations\"),json_build_array() \
+            FROM \"secondary\" WHERE TRUE"
+        );
+
+        log::info!("keyset");
+        let cursor = encode_cursor(&[
+            None,
+            Some("2".to_string()),
+            Some("1".to_string()),
+        ]);
+        let values = decode_cursor(&cursor).unwrap();
+        let mut params = vec![BindValue::Bool(true)];
+        assert_eq!(
+            keyset_filter(&table, &order, &values, &mut params).unwrap(),
+            "((FALSE)) \
+            OR ((\"sick\" IS NULL) AND ((\"timepoint\" > $2::integer) \
+            OR (\"timepoint\" IS NULL))) \
+            OR ((\"sick\" IS NULL) AND (\"timepoint\" = $3::integer) \
+            AND ((\"id\" > $4::integer) OR (\"id\" IS NULL)))"
+        );
+        assert_eq!(params.len(), 4);
+        assert_eq!(params[1], BindValue::Text("2".to_string()));
+        assert!(matches!(
+            decode_cursor("not a cursor").unwrap_err(),
+            Error::InvalidCursor(_)
+        ));
+        log::info!("cursor values are text");
+        assert!(matches!(
+            decode_cursor(&base64::encode_config(
+                "[null,2,1]",
+                base64::URL_SAFE_NO_PAD
+            ))
+            .unwrap_err(),
+            Error::InvalidCursor(_)
+        ));
+        assert!(matches!(
+            keyset_filter(&table, &order, &values[1..], &mut params)
+                .unwrap_err(),
+            Error::InvalidCursor(_)
+        ));
+    }
+}
@@ -0,0 +1,821 @@
+use super::table::{
+    ColMeta, TableAlter, TableConstraint, TableIndex, TableMeta, TableSpec,
+};
+use crate::{Error, Result};
+
+/// One change to a project's schema
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum SchemaChange {
+    CreateTable(TableMeta),
+    /// Table name and the change
+    AlterTable(String, TableAlter),
+    DropTable(String),
+    /// Table name and the table-level constraint
+    AddConstraint(String, TableConstraint),
+    /// Table name and the table-level constraint
+    DropConstraint(String, TableConstraint),
+    /// Table name and the index
+    CreateIndex(String, TableIndex),
+    /// Table name and the index name
+    DropIndex(String, String),
+}
+
+/// Change with a warning attached if it can lose data or fail on existing
+/// data
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PlannedChange {
+    pub change: SchemaChange,
+    pub warning: Option<String>,
+}
+
+impl PlannedChange {
+    fn new(change: SchemaChange) -> Self {
+        Self {
+            change,
+            warning: None,
+        }
+    }
+    fn warn(mut self, warning: String) -> Self {
+        self.warning = Some(warning);
+        self
+    }
+}
+
+/// Ordered changes that take a schema from one state to another
+pub type SchemaPlan = Vec<PlannedChange>;
+
+/// Options for applying a schema
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct ApplyOptions {
+    /// Apply plans that have warnings
+    #[serde(default)]
+    pub allow_destructive: bool,
+}
+
+/// Changes that take `current` to `desired`, in an order that works with
+/// foreign keys:
+/// 1. foreign keys, table constraints and indexes that change are dropped,
+/// 2. tables are dropped (referencing before referenced),
+/// 3. columns of the remaining tables are altered,
+/// 4. tables are created (referenced before referencing),
+/// 5. new foreign keys, table constraints and indexes are added.
+///
+/// Foreign keys that make the dropped or the created tables reference each
+/// other in a cycle are dropped in step 1 or added in step 5.
+///
+/// Tables and columns are matched by name, so a rename shows up as a drop
+/// and a create. Primary keys and generation expressions cannot be changed.
+pub fn plan(
+    current: &[TableMeta],
+    desired: &[TableMeta],
+) -> Result<SchemaPlan> {
+    // Compare constraints the way they are read back
+    let desired: TableSpec = desired
+        .iter()
+        .cloned()
+        .map(|mut t| {
+            t.normalize_constraints();
+            t
+        })
+        .collect();
+    let desired = desired.as_slice();
+    let find = |spec: &[TableMeta], name: &str| {
+        spec.iter().find(|t| t.name == name).cloned()
+    };
+
+    let mut fk_drops = Vec::new();
+    let mut col_alters = Vec::new();
+    let mut fk_adds = Vec::new();
+    for desired_table in desired {
+        let current_table = match find(current, desired_table.name.as_str()) {
+            Some(t) => t,
+            None => continue,
+        };
+        let table_name = desired_table.name.clone();
+        let alter = |a: TableAlter| {
+            PlannedChange::new(SchemaChange::AlterTable(table_name.clone(), a))
+        };
+        if primary_key(&current_table) != primary_key(desired_table) {
+            return Err(Error::PrimaryKeyChange(table_name));
+        }
+        for constraint in &current_table.constraints {
+            if !desired_table.constraints.contains(constraint) {
+                fk_drops.push(PlannedChange::new(
+                    SchemaChange::DropConstraint(
+                        table_name.clone(),
+                        constraint.clone(),
+                    ),
+                ));
+            }
+        }
+        for index in &current_table.indexes {
+            if !desired_table.indexes.contains(index) {
+                fk_drops.push(PlannedChange::new(SchemaChange::DropIndex(
+                    table_name.clone(),
+                    index.name.clone(),
+                )));
+            }
+        }
+        for index in &desired_table.indexes {
+            if !current_table.indexes.contains(index) {
+                fk_adds.push(PlannedChange::new(SchemaChange::CreateIndex(
+                    table_name.clone(),
+                    index.clone(),
+                )));
+            }
+        }
+        for constraint in &desired_table.constraints {
+            if !current_table.constraints.contains(constraint) {
+                let mut change =
+                    PlannedChange::new(SchemaChange::AddConstraint(
+                        table_name.clone(),
+                        constraint.clone(),
+                    ));
+                match constraint {
+                    TableConstraint::Unique(columns) => {
+                        change = change.warn(format!(
+                            "making columns {:?} of table \"{}\" unique fails \
+                            if they have duplicates",
+                            columns, table_name
+                        ));
+                    }
+                    TableConstraint::Check(_)
+                    | TableConstraint::Exclude { .. } => {
+                        change = change.warn(format!(
+                            "adding constraint to table \"{}\" fails if \
+                            existing rows violate it",
+                            table_name
+                        ));
+                    }
+                    TableConstraint::ForeignKey { .. } => {}
+                }
+                fk_adds.push(change);
+            }
+        }
+        for col in &current_table.cols {
+            if desired_table.get_col(&col.name).is_none() {
+                col_alters.push(
+                    alter(TableAlter::DropColumn(col.name.clone())).warn(
+                        format!(
+                            "column \"{}\" of table \"{}\" will be dropped \
+                            with its data",
+                            col.name, table_name
+                        ),
+                    ),
+                );
+            }
+        }
+        for col in &desired_table.cols {
+            let current_col = match current_table.get_col(&col.name) {
+                Some(c) => c,
+                None => {
+                    // Foreign key is added with the others
+                    let mut added = col.clone();
+                    added.foreign_key = None;
+                    let mut change = alter(TableAlter::AddColumn(added));
+                    if col.not_null {
+                        change = change.warn(format!(
+                            "adding not null column \"{}\" to table \"{}\" \
+                            fails if the table has rows",
+                            col.name, table_name
+                        ));
+                    }
+                    col_alters.push(change);
+                    if let Some(fk) = &col.foreign_key {
+                        fk_adds.push(alter(TableAlter::SetForeignKey {
+                            name: col.name.clone(),
+                            foreign_key: Some(fk.clone()),
+                        }));
+                    }
+                    continue;
+                }
+            };
+            if current_col.generated != col.generated {
+                return Err(Error::GeneratedColumnChange(
+                    table_name,
+                    col.name.clone(),
+                ));
+            }
+            col_alters.append(&mut diff_col(
+                current_col,
+                col,
+                &alter,
+                &table_name,
+            ));
+            if current_col.foreign_key != col.foreign_key {
+                if current_col.foreign_key.is_some() {
+                    fk_drops.push(alter(TableAlter::SetForeignKey {
+                        name: col.name.clone(),
+                        foreign_key: None,
+                    }));
+                }
+                if col.foreign_key.is_some() {
+                    fk_adds.push(alter(TableAlter::SetForeignKey {
+                        name: col.name.clone(),
+                        foreign_key: col.foreign_key.clone(),
+                    }));
+                }
+            }
+        }
+    }
+
+    let dropped: Vec<TableMeta> = current
+        .iter()
+        .filter(|t| find(desired, t.name.as_str()).is_none())
+        .cloned()
+        .collect();
+    let dropped = order_by_references(&dropped);
+    let mut table_drops = Vec::with_capacity(dropped.len());
+    for (i, table) in dropped.iter().enumerate().rev() {
+        // A table in a cycle is still referenced by the ones dropped after
+        // it, their foreign keys go first
+        let later: Vec<&str> =
+            dropped[i + 1..].iter().map(|t| t.name.as_str()).collect();
+        fk_drops.append(&mut split_references(
+            &mut table.clone(),
+            &later,
+            false,
+        ));
+        table_drops.push(
+            PlannedChange::new(SchemaChange::DropTable(table.name.clone()))
+                .warn(format!(
+                    "table \"{}\" will be dropped with its data",
+                    table.name
+                )),
+        );
+    }
+
+    let created: Vec<TableMeta> = desired
+        .iter()
+        .filter(|t| find(current, t.name.as_str()).is_none())
+        .cloned()
+        .collect();
+    let created = order_by_references(&created);
+    let mut table_creates = Vec::with_capacity(created.len());
+    for (i, table) in created.iter().enumerate() {
+        // A table in a cycle references ones created after it, those
+        // foreign keys are added with the others
+        let later: Vec<&str> =
+            created[i + 1..].iter().map(|t| t.name.as_str()).collect();
+        let mut table = table.clone();
+        fk_adds.append(&mut split_references(&mut table, &later, true));
+        table_creates
+            .push(PlannedChange::new(SchemaChange::CreateTable(table)));
+    }
+
+    let mut plan = fk_drops;
+    plan.append(&mut table_drops);
+    plan.append(&mut col_alters);
+    plan.append(&mut table_creates);
+    plan.append(&mut fk_adds);
+    Ok(plan)
+}
+
+/// Changes to an existing column except for the foreign key
+fn diff_col(
+    current: &ColMeta,
+    desired: &ColMeta,
+    alter: &dyn Fn(TableAlter) -> PlannedChange,
+    table_name: &str,
+) -> Vec<PlannedChange> {
+    let mut changes = Vec::new();
+    let name = desired.name.clone();
+    if current.postgres_type != desired.postgres_type {
+        // An explicit cast converts types without an assignment cast, e.g.
+        // text to integer. Strings keep the assignment cast, which fails
+        // rather than truncating values that are too long.
+        let using = if desired.postgres_type.is_string() {
+            None
+        } else {
+            Some(format!("\"{}\"::{}", name, desired.postgres_type))
+        };
+        changes.push(
+            alter(TableAlter::SetType {
+                name: name.clone(),
+                postgres_type: desired.postgres_type.clone(),
+                using,
+            })
+            .warn(format!(
+                "changing type of column \"{}\" of table \"{}\" from {} to \
+                {} can fail or lose data",
+                name, table_name, current.postgres_type, desired.postgres_type
+            )),
+        );
+    }
+    if current.identity != desired.identity {
+        changes.push(alter(TableAlter::SetIdentity {
+            name: name.clone(),
+            identity: desired.identity,
+        }));
+    }
+    if current.default != desired.default {
+        changes.push(alter(TableAlter::SetDefault {
+            name: name.clone(),
+            default: desired.default.clone(),
+        }));
+    }
+    if current.check != desired.check {
+        let mut change = alter(TableAlter::SetCheck {
+            name: name.clone(),
+            check: desired.check.clone(),
+        });
+        if desired.check.is_some() {
+            change = change.warn(format!(
+                "adding check to column \"{}\" of table \"{}\" fails if \
+                existing values violate it",
+                name, table_name
+            ));
+        }
+        changes.push(change);
+    }
+    if current.label != desired.label
+        || current.value_labels != desired.value_labels
+    {
+        changes.push(alter(TableAlter::SetLabels {
+            name: name.clone(),
+            label: desired.label.clone(),
+            value_labels: desired.value_labels.clone(),
+        }));
+    }
+    // Primary keys are always not null and unique
+    if current.primary_key {
+        return changes;
+    }
+    // Identity columns are always not null
+    if current.not_null != desired.not_null && !desired.identity {
+        let mut change = alter(TableAlter::SetNotNull {
+            name: name.clone(),
+            not_null: desired.not_null,
+        });
+        if desired.not_null {
+            change = change.warn(format!(
+                "making column \"{}\" of table \"{}\" not null fails if it \
+                has nulls",
+                name, table_name
+            ));
+        }
+        changes.push(change);
+    }
+    if current.unique != desired.unique {
+        let mut change = alter(TableAlter::SetUnique {
+            name: name.clone(),
+            unique: desired.unique,
+        });
+        if desired.unique {
+            change = change.warn(format!(
+                "making column \"{}\" of table \"{}\" unique fails if it has \
+                duplicates",
+                name, table_name
+            ));
+        }
+        changes.push(change);
+    }
+    changes
+}
+
+/// Primary key column names
+fn primary_key(table: &TableMeta) -> Vec<&str> {
+    let mut pk: Vec<&str> = table
+        .cols
+        .iter()
+        .filter(|c| c.primary_key)
+        .map(|c| c.name.as_str())
+        .collect();
+    pk.sort_unstable();
+    pk
+}
+
+/// Takes the table's foreign keys to any of the `tables` out of it and
+/// returns the changes that add them, or that drop them if `add` is false
+fn split_references(
+    table: &mut TableMeta,
+    tables: &[&str],
+    add: bool,
+) -> Vec<PlannedChange> {
+    let table_name = table.name.clone();
+    let mut changes = Vec::new();
+    for col in table.cols.iter_mut() {
+        let referenced = match &col.foreign_key {
+            Some(fk) => fk.table.as_str(),
+            None => continue,
+        };
+        if tables.contains(&referenced) {
+            let foreign_key = col.foreign_key.take();
+            changes.push(PlannedChange::new(SchemaChange::AlterTable(
+                table_name.clone(),
+                TableAlter::SetForeignKey {
+                    name: col.name.clone(),
+                    foreign_key: if add { foreign_key } else { None },
+                },
+            )));
+        }
+    }
+    table.constraints.retain(|constraint| match constraint {
+        TableConstraint::ForeignKey { table, .. }
+            if tables.contains(&table.as_str()) =>
+        {
+            changes.push(PlannedChange::new(if add {
+                SchemaChange::AddConstraint(
+                    table_name.clone(),
+                    constraint.clone(),
+                )
+            } else {
+                SchemaChange::DropConstraint(
+                    table_name.clone(),
+                    constraint.clone(),
+                )
+            }));
+            false
+        }
+        _ => true,
+    });
+    changes
+}
+
+/// Orders tables so that the ones referenced by foreign keys come before the
+/// ones referencing them. Only references within `tables` count, cycles are
+/// left in their original order.
+fn order_by_references(tables: &[TableMeta]) -> TableSpec {
+    let mut remaining: TableSpec = tables.to_vec();
+    let mut ordered = TableSpec::with_capacity(tables.len());
+    while !remaining.is_empty() {
+        let ready = remaining.iter().position(|t| {
+            !t.referenced_tables().iter().any(|&referenced| {
+                referenced != t.name
+                    && remaining.iter().any(|r| r.name == referenced)
+            })
+        });
+        // Cycle, take the first one
+        ordered.push(remaining.remove(ready.unwrap_or(0)));
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::user::table::{ForeignKey, IndexMethod, ReferentialAction};
+    use crate::db::user::types::PostgresType;
+
+    fn changes(plan: &[PlannedChange]) -> Vec<SchemaChange> {
+        plan.iter().map(|c| c.change.clone()).collect()
+    }
+
+    #[test]
+    fn plan_schema() {
+        let _ = pretty_env_logger::try_init();
+        let primary = crate::tests::get_test_primary_table();
+        let secondary = crate::tests::get_test_secondary_table();
+
+        log::info!("no changes");
+        let current = vec![primary.clone(), secondary.clone()];
+        assert!(plan(&current, &current).unwrap().is_empty());
+
+        log::info!("create in dependency order");
+        let created = plan(&[], &[secondary.clone(), primary.clone()]).unwrap();
+        assert_eq!(
+            changes(&created),
+            vec![
+                SchemaChange::CreateTable(primary.clone()),
+                SchemaChange::CreateTable(secondary.clone())
+            ]
+        );
+        assert!(created.iter().all(|c| c.warning.is_none()));
+
+        log::info!("drop in dependency order");
+        let dropped = plan(&current, &[]).unwrap();
+        assert_eq!(
+            changes(&dropped),
+            vec![
+                SchemaChange::DropTable(secondary.name.clone()),
+                SchemaChange::DropTable(primary.name.clone())
+            ]
+        );
+        assert!(dropped.iter().all(|c| c.warning.is_some()));
+
+        log::info!("tables that reference each other");
+        let subject = TableMeta::new(
+            "subject",
+            vec![
+                ColMeta::new()
+                    .name("id")
+                    .postgres_type(PostgresType::Integer)
+                    .primary_key(true),
+                ColMeta::new()
+                    .name("first_visit")
+                    .postgres_type(PostgresType::Integer)
+                    .foreign_key(ForeignKey::new("visit", "id")),
+            ],
+        );
+        let visit_fk = TableConstraint::ForeignKey {
+            columns: vec!["subject".to_string()],
+            table: "subject".to_string(),
+            foreign_columns: vec!["id".to_string()],
+            on_delete: ReferentialAction::Cascade,
+            on_update: ReferentialAction::NoAction,
+        };
+        let visit = TableMeta::new(
+            "visit",
+            vec![
+                ColMeta::new()
+                    .name("id")
+                    .postgres_type(PostgresType::Integer)
+                    .primary_key(true),
+                ColMeta::new()
+                    .name("subject")
+                    .postgres_type(PostgresType::Integer),
+            ],
+        )
+        .constraints(vec![visit_fk.clone()]);
+        let cyclic = vec![subject.clone(), visit.clone()];
+        let mut created_subject = subject.clone();
+        created_subject.cols[1].foreign_key = None;
+        assert_eq!(
+            changes(&plan(&[], &cyclic).unwrap()),
+            vec![
+                SchemaChange::CreateTable(created_subject),
+                SchemaChange::CreateTable(visit.clone()),
+                SchemaChange::AlterTable(
+                    "subject".to_string(),
+                    TableAlter::SetForeignKey {
+                        name: "first_visit".to_string(),
+                        foreign_key: Some(ForeignKey::new("visit", "id"))
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            changes(&plan(&cyclic, &[]).unwrap()),
+            vec![
+                SchemaChange::AlterTable(
+                    "subject".to_string(),
+                    TableAlter::SetForeignKey {
+                        name: "first_visit".to_string(),
+                        foreign_key: None
+                    }
+                ),
+                SchemaChange::DropTable("visit".to_string()),
+                SchemaChange::DropTable("subject".to_string()),
+            ]
+        );
+        let created = plan(&[], &[visit.clone(), subject.clone()]).unwrap();
+        let mut created_visit = visit;
+        created_visit.constraints.clear();
+        assert_eq!(
+            changes(&created),
+            vec![
+                SchemaChange::CreateTable(created_visit),
+                SchemaChange::CreateTable(subject),
+                SchemaChange::AddConstraint("visit".to_string(), visit_fk),
+            ]
+        );
+
+        log::info!("alter columns");
+        let mut desired_primary = primary.clone();
+        desired_primary.cols[1].postgres_type = PostgresType::Varchar(None);
+        desired_primary.cols[1].unique = false;
+        desired_primary.cols.push(
+            ColMeta::new()
+                .name("parent")
+                .postgres_type(PostgresType::Integer)
+                .not_null(true)
+                .foreign_key(ForeignKey::new("primary", "id")),
+        );
+        let mut desired_secondary = secondary.clone();
+        desired_secondary.cols.remove(4);
+        let third_cols = vec![ColMeta::new()
+            .name("id")
+            .postgres_type(PostgresType::Integer)
+            .foreign_key(ForeignKey::new("primary", "id"))];
+        let third = TableMeta::new("third", third_cols);
+        let altered = plan(
+            &current,
+            &[desired_primary.clone(), desired_secondary, third.clone()],
+        )
+        .unwrap();
+        let alter =
+            |t: &str, a: TableAlter| SchemaChange::AlterTable(t.to_string(), a);
+        let mut added = desired_primary.cols[2].clone();
+        added.foreign_key = None;
+        assert_eq!(
+            changes(&altered),
+            vec![
+                alter(
+                    "primary",
+                    TableAlter::SetType {
+                        name: "email".to_string(),
+                        postgres_type: PostgresType::Varchar(None),
+                        using: None
+                    }
+                ),
+                alter(
+                    "primary",
+                    TableAlter::SetUnique {
+                        name: "email".to_string(),
+                        unique: false
+                    }
+                ),
+                alter("primary", TableAlter::AddColumn(added)),
+                alter(
+                    "secondary",
+                    TableAlter::DropColumn("locations".to_string())
+                ),
+                SchemaChange::CreateTable(third),
+                alter(
+                    "primary",
+                    TableAlter::SetForeignKey {
+                        name: "parent".to_string(),
+                        foreign_key: Some(ForeignKey::new("primary", "id"))
+                    }
+                ),
+            ]
+        );
+        let warned: Vec<bool> =
+            altered.iter().map(|c| c.warning.is_some()).collect();
+        assert_eq!(warned, vec![true, false, true, true, false, false]);
+
+        log::info!("cast to types without an assignment cast");
+        let mut desired_primary = primary.clone();
+        desired_primary.cols[1].postgres_type = PostgresType::Integer;
+        assert_eq!(
+            changes(
+                &plan(&current, &[desired_primary, secondary.clone()]).unwrap()
+            ),
+            vec![alter(
+                "primary",
+                TableAlter::SetType {
+                    name: "email".to_string(),
+                    postgres_type: PostgresType::Integer,
+                    using: Some("\"email\"::integer".to_string())
+                }
+            )]
+        );
+
+        log::info!("change a foreign key");
+        let mut desired_secondary = secondary.clone();
+        desired_secondary.cols[0].foreign_key =
+            Some(ForeignKey::new("other", "id"));
+        assert_eq!(
+            changes(
+                &plan(&current, &[primary.clone(), desired_secondary]).unwrap()
+            ),
+            vec![
+                alter(
+                    "secondary",
+                    TableAlter::SetForeignKey {
+                        name: "id".to_string(),
+                        foreign_key: None
+                    }
+                ),
+                alter(
+                    "secondary",
+                    TableAlter::SetForeignKey {
+                        name: "id".to_string(),
+                        foreign_key: Some(ForeignKey::new("other", "id"))
+                    }
+                ),
+            ]
+        );
+
+        log::info!("defaults and checks");
+        let mut desired_primary = primary.clone();
+        desired_primary.cols[1].default = Some("'none'::text".to_string());
+        desired_primary.cols[1].check = Some("(email <> ''::text)".to_string());
+        let altered =
+            plan(&current, &[desired_primary.clone(), secondary.clone()])
+                .unwrap();
+        assert_eq!(
+            changes(&altered),
+            vec![
+                alter(
+                    "primary",
+                    TableAlter::SetDefault {
+                        name: "email".to_string(),
+                        default: Some("'none'::text".to_string())
+                    }
+                ),
+                alter(
+                    "primary",
+                    TableAlter::SetCheck {
+                        name: "email".to_string(),
+                        check: Some("(email <> ''::text)".to_string())
+                    }
+                ),
+            ]
+        );
+        assert!(altered[0].warning.is_none());
+        assert!(altered[1].warning.is_some());
+
+        log::info!("labels");
+        let mut labelled = primary.clone();
+        labelled.cols[1].label = Some("Contact email".to_string());
+        assert_eq!(
+            changes(&plan(&current, &[labelled, secondary.clone()]).unwrap()),
+            vec![alter(
+                "primary",
+                TableAlter::SetLabels {
+                    name: "email".to_string(),
+                    label: Some("Contact email".to_string()),
+                    value_labels: vec![]
+                }
+            )]
+        );
+
+        log::info!("table constraints");
+        let unique = TableConstraint::Unique(vec![
+            "sick".to_string(),
+            "timepoint".to_string(),
+        ]);
+        let foreign_key = TableConstraint::ForeignKey {
+            columns: vec!["id".to_string(), "timepoint".to_string()],
+            table: "visit".to_string(),
+            foreign_columns: vec!["id".to_string(), "timepoint".to_string()],
+            on_delete: ReferentialAction::Cascade,
+            on_update: ReferentialAction::NoAction,
+        };
+        let current_constrained = vec![
+            primary.clone(),
+            secondary.clone().constraints(vec![unique.clone()]),
+        ];
+        let desired_constrained = vec![
+            primary.clone(),
+            secondary.clone().constraints(vec![
+                foreign_key.clone(),
+                // Column-level once read back
+                TableConstraint::Unique(vec!["symptoms".to_string()]),
+            ]),
+        ];
+        let constrained =
+            plan(&current_constrained, &desired_constrained).unwrap();
+        assert_eq!(
+            changes(&constrained),
+            vec![
+                SchemaChange::DropConstraint(
+                    "secondary".to_string(),
+                    unique.clone()
+                ),
+                alter(
+                    "secondary",
+                    TableAlter::SetUnique {
+                        name: "symptoms".to_string(),
+                        unique: true
+                    }
+                ),
+                SchemaChange::AddConstraint(
+                    "secondary".to_string(),
+                    foreign_key.clone()
+                ),
+            ]
+        );
+        let mut created_secondary =
+            secondary.clone().constraints(vec![foreign_key]);
+        created_secondary.cols[3].unique = true;
+        assert_eq!(
+            changes(&plan(&[], &[desired_constrained[1].clone()]).unwrap()),
+            vec![SchemaChange::CreateTable(created_secondary)]
+        );
+
+        log::info!("indexes");
+        let index = TableIndex::new("secondary_symptoms", &["symptoms"]);
+        let changed_index = index.clone().method(IndexMethod::Gin);
+        let indexed = plan(
+            &[
+                primary.clone(),
+                secondary.clone().indexes(vec![index.clone()]),
+            ],
+            &[
+                primary.clone(),
+                secondary.clone().indexes(vec![changed_index.clone()]),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            changes(&indexed),
+            vec![
+                SchemaChange::DropIndex(
+                    "secondary".to_string(),
+                    index.name.clone()
+                ),
+                SchemaChange::CreateIndex(
+                    "secondary".to_string(),
+                    changed_index
+                ),
+            ]
+        );
+
+        log::info!("generation expression changes are refused");
+        desired_primary.cols[1].generated = Some("'x'::text".to_string());
+        assert!(matches!(
+            plan(&current, &[desired_primary, secondary.clone()]).unwrap_err(),
+            Error::GeneratedColumnChange(t, c) if t == "primary" && c == "email"
+        ));
+
+        log::info!("primary key changes are refused");
+        let mut desired_secondary = secondary;
+        desired_secondary.cols[1].primary_key = false;
+        assert!(matches!(
+            plan(&current, &[primary, desired_secondary]).unwrap_err(),
+            Error::PrimaryKeyChange(t) if t == "secondary"
+        ));
+    }
+}
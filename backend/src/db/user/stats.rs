@@ -0,0 +1,1305 @@
+use super::export::{zip_files, ArchiveFile};
+use super::table::{ColMeta, RowJson, TableMeta};
+use super::types::PostgresType;
+use super::value::BindValue;
+use super::{fetch_rows, ByteStream, RowStream, STREAM_BUFFER_ROWS};
+use crate::db::Pool;
+use crate::Result;
+use futures::{StreamExt, TryStreamExt};
+use serde_json::Value;
+use std::convert::{TryFrom, TryInto};
+
+/// Seconds from 1582-10-14, where SPSS counts time from, to 1970-01-01
+const SPSS_EPOCH_OFFSET: f64 = 12_219_379_200.0;
+/// Days from 1960-01-01, where Stata counts time from, to 1970-01-01
+const STATA_EPOCH_OFFSET: f64 = 3653.0;
+const SECONDS_PER_DAY: f64 = 86400.0;
+
+/// Longest Stata fixed width string, longer ones are stored as strLs
+const STATA_MAX_STR: usize = 2045;
+/// Stata variable type codes
+const STATA_STRL: u16 = 32768;
+const STATA_DOUBLE: u16 = 65526;
+const STATA_LONG: u16 = 65528;
+const STATA_BYTE: u16 = 65530;
+/// Stata's system missing values
+const STATA_MISSING_DOUBLE: u64 = 0x7fe0_0000_0000_0000;
+const STATA_MISSING_LONG: i32 = 0x7fff_ffe5;
+const STATA_MISSING_BYTE: i8 = 101;
+/// Range of non-missing Stata longs
+const STATA_MAX_LONG: f64 = 2_147_483_620.0;
+const STATA_MIN_LONG: f64 = -2_147_483_647.0;
+/// Words Stata doesn't allow as variable names
+const STATA_RESERVED: &[&str] = &[
+    "_all", "_b", "byte", "_coef", "_cons", "double", "float", "if", "in",
+    "int", "long", "_n", "_N", "_pi", "_pred", "_rc", "_skip", "strL", "using",
+    "with",
+];
+
+/// Longest SPSS string, longer values are cut
+const SPSS_MAX_STR: usize = 255;
+/// SPSS format type codes
+const SPSS_FORMAT_A: i32 = 1;
+const SPSS_FORMAT_F: i32 = 5;
+const SPSS_FORMAT_DATE: i32 = 20;
+const SPSS_FORMAT_TIME: i32 = 21;
+const SPSS_FORMAT_DATETIME: i32 = 22;
+/// Words SPSS doesn't allow as variable names
+const SPSS_RESERVED: &[&str] = &[
+    "ALL", "AND", "BY", "EQ", "GE", "GT", "LE", "LT", "NE", "NOT", "OR", "TO",
+    "WITH",
+];
+
+/// Statistical package a table is exported for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatsFormat {
+    /// Stata 14 `.dta` file
+    Stata,
+    /// SPSS `.sav` file
+    Spss,
+    /// CSV files with an R script that reads them
+    R,
+}
+
+impl StatsFormat {
+    /// Extension of a table's export, a zip archive for R
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Stata => "dta",
+            Self::Spss => "sav",
+            Self::R => "zip",
+        }
+    }
+    /// Media type of a table's export
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Stata => "application/x-stata-dta",
+            Self::Spss => "application/x-spss-sav",
+            Self::R => "application/zip",
+        }
+    }
+}
+
+impl std::str::FromStr for StatsFormat {
+    type Err = ();
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        match s {
+            "dta" => Ok(Self::Stata),
+            "sav" => Ok(Self::Spss),
+            "r" => Ok(Self::R),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Export of a table for a statistical package
+pub enum StatsExport {
+    /// Stata or SPSS file
+    File(Vec<u8>),
+    /// Zip archive of the CSV file and the R script, written as it is read
+    Archive(ByteStream),
+}
+
+/// Rows of a table to write for a statistical package
+#[derive(Debug, Clone)]
+pub struct StatsTable {
+    pub table: TableMeta,
+    /// Query for the rows and the values of its parameters
+    pub query: String,
+    pub values: Vec<BindValue>,
+}
+
+/// Zip archive of the tables' files for the package, written as it is
+/// read: a file per table for Stata and SPSS, a CSV file per table and the
+/// script `tables.R` for R.
+/// Each table's rows are only fetched once its file is reached. CSV files
+/// are written as the rows are read, Stata and SPSS files hold one table's
+/// rows at a time.
+/// The stream ends after an error.
+pub fn zip_stats(
+    pool: Pool,
+    tables: Vec<StatsTable>,
+    format: StatsFormat,
+) -> ByteStream {
+    let metas: Vec<TableMeta> =
+        tables.iter().map(|t| t.table.clone()).collect();
+    let mut files: Vec<ArchiveFile> = tables
+        .into_iter()
+        .map(|table| {
+            let pool = pool.clone();
+            let file: ArchiveFile = (
+                format!("{}.{}", table.table.name, file_extension(format)),
+                Box::new(move || {
+                    let StatsTable {
+                        table,
+                        query,
+                        values,
+                    } = table;
+                    let rows = fetch_rows(pool, query, values);
+                    let write = match format {
+                        StatsFormat::R => return Ok(write_r_csv(table, rows)),
+                        StatsFormat::Stata => write_dta,
+                        StatsFormat::Spss => write_sav,
+                    };
+                    let file: ByteStream =
+                        Box::pin(futures::stream::once(async move {
+                            let rows: Vec<RowJson> = rows.try_collect().await?;
+                            write(&table, &rows)
+                        }));
+                    Ok(file)
+                }),
+            );
+            file
+        })
+        .collect();
+    if format == StatsFormat::R {
+        let script = write_r_script(&metas).into_bytes();
+        files.push((
+            "tables.R".to_string(),
+            Box::new(move || {
+                let script: ByteStream =
+                    Box::pin(futures::stream::once(async move { Ok(script) }));
+                Ok(script)
+            }),
+        ));
+    }
+    zip_files(files)
+}
+
+/// Extension of a table's file in an archive
+fn file_extension(format: StatsFormat) -> &'static str {
+    match format {
+        StatsFormat::R => "csv",
+        format => format.extension(),
+    }
+}
+
+/// How values of a column are stored in a statistical package
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Integer,
+    Float,
+    /// 1 for true, 0 for false
+    Boolean,
+    /// Days since 1970-01-01
+    Date,
+    /// Seconds since midnight
+    Time,
+    /// Seconds since 1970-01-01 in UTC
+    Timestamp,
+    Text,
+}
+
+impl Kind {
+    fn new(postgres_type: &PostgresType) -> Self {
+        use PostgresType as T;
+        match postgres_type {
+            T::SmallInt | T::Integer | T::BigInt => Self::Integer,
+            T::Real | T::DoublePrecision | T::Numeric(_) => Self::Float,
+            T::Boolean => Self::Boolean,
+            T::Date => Self::Date,
+            T::Time => Self::Time,
+            T::Timestamp | T::Timestamptz => Self::Timestamp,
+            T::Text
+            | T::Varchar(_)
+            | T::Uuid
+            | T::Json
+            | T::Jsonb
+            | T::Bytea
+            | T::Interval
+            | T::Array(_) => Self::Text,
+        }
+    }
+}
+
+/// Value as stored in a statistical package
+#[derive(Debug, Clone, PartialEq)]
+enum Cell {
+    Missing,
+    Number(f64),
+    Text(String),
+}
+
+impl Cell {
+    fn new(value: &Value, kind: Kind) -> Self {
+        let number = |n: Option<f64>| n.map_or(Self::Missing, Self::Number);
+        match (kind, value) {
+            (_, Value::Null) => Self::Missing,
+            (Kind::Text, Value::String(s)) => Self::Text(s.clone()),
+            (Kind::Text, value) => Self::Text(value.to_string()),
+            (_, Value::Number(n)) => number(n.as_f64()),
+            (Kind::Boolean, Value::Bool(b)) => Self::Number(f64::from(*b)),
+            (Kind::Integer | Kind::Float, Value::String(s)) => {
+                number(s.parse().ok().filter(|n: &f64| n.is_finite()))
+            }
+            (Kind::Date, Value::String(s)) => number(
+                chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d").ok().map(
+                    |d| (d - chrono::NaiveDate::default()).num_days() as f64,
+                ),
+            ),
+            (Kind::Time, Value::String(s)) => number(
+                chrono::NaiveTime::parse_from_str(s, "%H:%M:%S%.f")
+                    .ok()
+                    .map(|t| seconds(t - chrono::NaiveTime::MIN)),
+            ),
+            (Kind::Timestamp, Value::String(s)) => number(
+                parse_timestamp(s)
+                    .map(|t| seconds(t - chrono::NaiveDateTime::default())),
+            ),
+            _ => Self::Missing,
+        }
+    }
+    fn number(&self) -> Option<f64> {
+        match self {
+            Self::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+    fn text(&self) -> &str {
+        match self {
+            Self::Text(s) => s.as_str(),
+            _ => "",
+        }
+    }
+}
+
+/// Timestamp as Postgres writes it in json, in UTC if it has an offset
+fn parse_timestamp(s: &str) -> Option<chrono::NaiveDateTime> {
+    match chrono::DateTime::parse_from_rfc3339(s) {
+        Ok(t) => Some(t.naive_utc()),
+        Err(_) => {
+            chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f")
+                .ok()
+        }
+    }
+}
+
+fn seconds(duration: chrono::TimeDelta) -> f64 {
+    match duration.num_microseconds() {
+        Some(us) => us as f64 / 1e6,
+        None => duration.num_seconds() as f64,
+    }
+}
+
+/// Column of a table as a variable of a statistical package
+#[derive(Debug, Clone)]
+struct Variable<'a> {
+    col: &'a ColMeta,
+    /// Name valid in the package
+    name: String,
+    kind: Kind,
+    cells: Vec<Cell>,
+}
+
+impl<'a> Variable<'a> {
+    /// Variables of all the table's columns with names made valid by `valid`
+    fn all(
+        table: &'a TableMeta,
+        rows: &[RowJson],
+        valid: &dyn Fn(&str) -> String,
+        max_name: usize,
+    ) -> Vec<Self> {
+        let mut taken: Vec<String> = Vec::new();
+        table
+            .cols
+            .iter()
+            .map(|col| {
+                let kind = Kind::new(&col.postgres_type);
+                let name = unique_name(valid(&col.name), &taken, max_name);
+                taken.push(name.to_lowercase());
+                Self {
+                    col,
+                    name,
+                    kind,
+                    cells: rows
+                        .iter()
+                        .map(|row| {
+                            Cell::new(
+                                row.get(&col.name).unwrap_or(&Value::Null),
+                                kind,
+                            )
+                        })
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+    /// Largest number of bytes of the values
+    fn max_len(&self) -> usize {
+        self.cells.iter().map(|c| c.text().len()).max().unwrap_or(0)
+    }
+    /// Labels of numeric values, booleans as 1 and 0
+    fn numeric_labels(&self) -> Vec<(f64, &str)> {
+        self.col
+            .value_labels
+            .iter()
+            .filter_map(|l| {
+                let value = match &l.value {
+                    Value::Number(n) => n.as_f64()?,
+                    Value::Bool(b) => f64::from(*b),
+                    _ => return None,
+                };
+                Some((value, l.label.as_str()))
+            })
+            .collect()
+    }
+}
+
+/// Name that differs from the taken ones (in lowercase) by a numeric suffix
+fn unique_name(name: String, taken: &[String], max_len: usize) -> String {
+    let mut candidate = name.clone();
+    let mut i = 1;
+    while taken.contains(&candidate.to_lowercase()) {
+        let suffix = format!("_{}", i);
+        candidate =
+            format!("{}{}", truncate(&name, max_len - suffix.len()), suffix);
+        i += 1;
+    }
+    candidate
+}
+
+/// Longest start of the string with at most the given number of bytes
+fn truncate(s: &str, max_bytes: usize) -> &str {
+    if s.len() <= max_bytes {
+        return s;
+    }
+    let mut end = max_bytes;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// The string cut or padded to the given number of bytes
+fn fixed(s: &str, len: usize, pad: u8) -> Vec<u8> {
+    let mut bytes = truncate(s, len).as_bytes().to_vec();
+    bytes.resize(len, pad);
+    bytes
+}
+
+/// ASCII letters, digits and the allowed characters of the name, others
+/// replaced by underscores
+fn ascii_name(name: &str, allowed: &[char]) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || allowed.contains(&c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Stata variable name: letters, digits and underscores, not starting with
+/// a digit, at most 32 characters
+fn stata_name(name: &str) -> String {
+    let mut name = ascii_name(name, &['_']);
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        || STATA_RESERVED.contains(&name.as_str())
+    {
+        name = format!("_{}", name);
+    }
+    truncate(&name, 32).to_string()
+}
+
+/// SPSS variable name: letters, digits and `_.@#$`, starting with a letter,
+/// at most 64 characters
+fn spss_name(name: &str) -> String {
+    let mut name = ascii_name(name, &['_', '.', '@', '#', '$']);
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic())
+        || SPSS_RESERVED.contains(&name.to_uppercase().as_str())
+    {
+        name = format!("v_{}", name);
+    }
+    truncate(&name, 64).trim_end_matches('.').to_string()
+}
+
+/// Stata 14 (format 118) file of the table's rows.
+/// Integers that don't fit Stata's long are stored as doubles, dates as
+/// `%td` and timestamps in UTC and times of day as `%tc`. Strings too long
+/// for fixed width are stored as strLs. Value labels are kept for integer
+/// values only, as Stata can't label others.
+pub fn write_dta(table: &TableMeta, rows: &[RowJson]) -> Result<Vec<u8>> {
+    let vars = Variable::all(table, rows, &stata_name, 32);
+    let types: Vec<u16> = vars.iter().map(stata_type).collect();
+
+    let mut file = Vec::new();
+    let tag = |file: &mut Vec<u8>, tag: &str| {
+        file.extend_from_slice(tag.as_bytes());
+    };
+    tag(&mut file, "<stata_dta><header><release>118</release>");
+    tag(&mut file, "<byteorder>LSF</byteorder><K>");
+    file.extend_from_slice(&(vars.len() as u16).to_le_bytes());
+    tag(&mut file, "</K><N>");
+    file.extend_from_slice(&(rows.len() as u64).to_le_bytes());
+    tag(&mut file, "</N><label>");
+    let label = truncate(&table.name, 80);
+    file.extend_from_slice(&(label.len() as u16).to_le_bytes());
+    file.extend_from_slice(label.as_bytes());
+    // Without a timestamp
+    tag(&mut file, "</label><timestamp>\0</timestamp></header>");
+
+    // Offsets of the sections, filled in at the end
+    let mut map = [0u64; 14];
+    map[1] = file.len() as u64;
+    tag(&mut file, "<map>");
+    let map_start = file.len();
+    file.resize(map_start + map.len() * 8, 0);
+    tag(&mut file, "</map>");
+
+    map[2] = file.len() as u64;
+    tag(&mut file, "<variable_types>");
+    for t in &types {
+        file.extend_from_slice(&t.to_le_bytes());
+    }
+    tag(&mut file, "</variable_types>");
+
+    map[3] = file.len() as u64;
+    tag(&mut file, "<varnames>");
+    for var in &vars {
+        file.extend(fixed(&var.name, 129, 0));
+    }
+    tag(&mut file, "</varnames>");
+
+    map[4] = file.len() as u64;
+    tag(&mut file, "<sortlist>");
+    file.resize(file.len() + (vars.len() + 1) * 2, 0);
+    tag(&mut file, "</sortlist>");
+
+    map[5] = file.len() as u64;
+    tag(&mut file, "<formats>");
+    for (var, t) in vars.iter().zip(&types) {
+        file.extend(fixed(&stata_format(var, *t), 57, 0));
+    }
+    tag(&mut file, "</formats>");
+
+    // Value label sets are named after their variables
+    let labelled: Vec<bool> = vars
+        .iter()
+        .zip(&types)
+        .map(|(var, t)| !stata_labels(var, *t).is_empty())
+        .collect();
+    map[6] = file.len() as u64;
+    tag(&mut file, "<value_label_names>");
+    for (var, labelled) in vars.iter().zip(&labelled) {
+        let name = if *labelled { var.name.as_str() } else { "" };
+        file.extend(fixed(name, 129, 0));
+    }
+    tag(&mut file, "</value_label_names>");
+
+    map[7] = file.len() as u64;
+    tag(&mut file, "<variable_labels>");
+    for var in &vars {
+        let label = var.col.label.as_deref().unwrap_or("");
+        let label: String = label.chars().take(80).collect();
+        file.extend(fixed(&label, 321, 0));
+    }
+    tag(&mut file, "</variable_labels>");
+
+    map[8] = file.len() as u64;
+    tag(&mut file, "<characteristics></characteristics>");
+
+    map[9] = file.len() as u64;
+    tag(&mut file, "<data>");
+    let mut strls = Vec::new();
+    for row in 0..rows.len() {
+        for (v, (var, t)) in vars.iter().zip(&types).enumerate() {
+            let cell = &var.cells[row];
+            match *t {
+                STATA_STRL => {
+                    let text = cell.text();
+                    // Empty strings are (0, 0)
+                    let (v, o) = if text.is_empty() {
+                        (0, 0)
+                    } else {
+                        strls.push((v as u32 + 1, row as u64 + 1, text));
+                        (v as u16 + 1, row as u64 + 1)
+                    };
+                    file.extend_from_slice(&v.to_le_bytes());
+                    file.extend_from_slice(&o.to_le_bytes()[..6]);
+                }
+                STATA_DOUBLE => {
+                    let value = match cell.number() {
+                        Some(n) => stata_number(n, var.kind),
+                        None => f64::from_bits(STATA_MISSING_DOUBLE),
+                    };
+                    file.extend_from_slice(&value.to_le_bytes());
+                }
+                STATA_LONG => {
+                    let value = match cell.number() {
+                        Some(n) => stata_number(n, var.kind) as i32,
+                        None => STATA_MISSING_LONG,
+                    };
+                    file.extend_from_slice(&value.to_le_bytes());
+                }
+                STATA_BYTE => {
+                    let value = match cell.number() {
+                        Some(n) => n as i8,
+                        None => STATA_MISSING_BYTE,
+                    };
+                    file.extend_from_slice(&value.to_le_bytes());
+                }
+                width => file.extend(fixed(cell.text(), width as usize, 0)),
+            }
+        }
+    }
+    tag(&mut file, "</data>");
+
+    map[10] = file.len() as u64;
+    tag(&mut file, "<strls>");
+    for (v, o, text) in strls {
+        tag(&mut file, "GSO");
+        file.extend_from_slice(&v.to_le_bytes());
+        file.extend_from_slice(&o.to_le_bytes());
+        // Null-terminated text
+        file.push(130);
+        file.extend_from_slice(&(text.len() as u32 + 1).to_le_bytes());
+        file.extend_from_slice(text.as_bytes());
+        file.push(0);
+    }
+    tag(&mut file, "</strls>");
+
+    map[11] = file.len() as u64;
+    tag(&mut file, "<value_labels>");
+    for ((var, t), labelled) in vars.iter().zip(&types).zip(&labelled) {
+        if !labelled {
+            continue;
+        }
+        let labels = stata_labels(var, *t);
+        let mut offsets = Vec::new();
+        let mut text = Vec::new();
+        for (_, label) in &labels {
+            offsets.push(text.len() as i32);
+            text.extend_from_slice(truncate(label, 32000).as_bytes());
+            text.push(0);
+        }
+        let mut set = Vec::new();
+        set.extend_from_slice(&(labels.len() as i32).to_le_bytes());
+        set.extend_from_slice(&(text.len() as i32).to_le_bytes());
+        for offset in offsets {
+            set.extend_from_slice(&offset.to_le_bytes());
+        }
+        for (value, _) in &labels {
+            set.extend_from_slice(&value.to_le_bytes());
+        }
+        set.extend(text);
+        tag(&mut file, "<lbl>");
+        file.extend_from_slice(&(set.len() as i32).to_le_bytes());
+        file.extend(fixed(&var.name, 129, 0));
+        file.extend_from_slice(&[0; 3]);
+        file.extend(set);
+        tag(&mut file, "</lbl>");
+    }
+    tag(&mut file, "</value_labels>");
+
+    map[12] = file.len() as u64;
+    tag(&mut file, "</stata_dta>");
+    map[13] = file.len() as u64;
+    for (i, offset) in map.iter().enumerate() {
+        let start = map_start + i * 8;
+        file[start..start + 8].copy_from_slice(&offset.to_le_bytes());
+    }
+    Ok(file)
+}
+
+/// Stata storage type of a variable
+fn stata_type(var: &Variable) -> u16 {
+    match var.kind {
+        Kind::Boolean => STATA_BYTE,
+        Kind::Date => STATA_LONG,
+        Kind::Integer
+            if var
+                .cells
+                .iter()
+                .filter_map(Cell::number)
+                .all(|n| (STATA_MIN_LONG..=STATA_MAX_LONG).contains(&n)) =>
+        {
+            STATA_LONG
+        }
+        Kind::Integer | Kind::Float | Kind::Time | Kind::Timestamp => {
+            STATA_DOUBLE
+        }
+        Kind::Text => match var.max_len() {
+            len if len > STATA_MAX_STR => STATA_STRL,
+            len => len.max(1) as u16,
+        },
+    }
+}
+
+/// Stata display format of a variable
+fn stata_format(var: &Variable, t: u16) -> String {
+    match (var.kind, t) {
+        (Kind::Date, _) => "%td".to_string(),
+        (Kind::Timestamp, _) => "%tc".to_string(),
+        (Kind::Time, _) => "%tcHH:MM:SS".to_string(),
+        (_, STATA_STRL) => "%9s".to_string(),
+        (_, STATA_BYTE) => "%8.0g".to_string(),
+        (_, STATA_LONG) => "%12.0g".to_string(),
+        (_, STATA_DOUBLE) => "%10.0g".to_string(),
+        (_, width) => format!("%-{}s", width),
+    }
+}
+
+/// Number in Stata's units, days or milliseconds since 1960-01-01
+fn stata_number(n: f64, kind: Kind) -> f64 {
+    match kind {
+        Kind::Date => n + STATA_EPOCH_OFFSET,
+        Kind::Timestamp => {
+            ((n + STATA_EPOCH_OFFSET * SECONDS_PER_DAY) * 1000.0).round()
+        }
+        Kind::Time => (n * 1000.0).round(),
+        _ => n,
+    }
+}
+
+/// Value labels Stata can keep: integers of numeric variables
+fn stata_labels<'a>(var: &'a Variable, t: u16) -> Vec<(i32, &'a str)> {
+    if !matches!(t, STATA_BYTE | STATA_LONG | STATA_DOUBLE)
+        || !matches!(var.kind, Kind::Integer | Kind::Float | Kind::Boolean)
+    {
+        return Vec::new();
+    }
+    var.numeric_labels()
+        .into_iter()
+        .filter(|(value, _)| {
+            value.fract() == 0.0
+                && (STATA_MIN_LONG..=STATA_MAX_LONG).contains(value)
+        })
+        .map(|(value, label)| (value as i32, label))
+        .collect()
+}
+
+/// SPSS system file of the table's rows, uncompressed and in UTF-8.
+/// Dates, times and timestamps (in UTC) use SPSS's date formats.
+/// Strings longer than 255 bytes are cut. Value labels are kept for numeric
+/// values, and for strings of variables at most 8 bytes wide.
+pub fn write_sav(table: &TableMeta, rows: &[RowJson]) -> Result<Vec<u8>> {
+    let vars = Variable::all(table, rows, &spss_name, 64);
+    // String width or 0 for numbers
+    let widths: Vec<usize> = vars
+        .iter()
+        .map(|var| match var.kind {
+            Kind::Text => var.max_len().clamp(1, SPSS_MAX_STR),
+            _ => 0,
+        })
+        .collect();
+    let segments = |width: usize| width.div_ceil(8).max(1);
+    let short_names = spss_short_names(&vars);
+
+    let mut file = Vec::new();
+    let int = |file: &mut Vec<u8>, n: i32| file.extend(n.to_le_bytes());
+    file.extend_from_slice(b"$FL2");
+    file.extend(fixed("@(#) SPSS DATA FILE opendatacapture", 60, b' '));
+    // Layout code
+    int(&mut file, 2);
+    int(
+        &mut file,
+        widths.iter().map(|w| segments(*w)).sum::<usize>() as i32,
+    );
+    // No compression and no weight variable
+    int(&mut file, 0);
+    int(&mut file, 0);
+    int(&mut file, i32::try_from(rows.len()).unwrap_or(-1));
+    // Compression bias
+    file.extend(100f64.to_le_bytes());
+    let now = chrono::Utc::now();
+    file.extend(fixed(&now.format("%d %b %y").to_string(), 9, b' '));
+    file.extend(fixed(&now.format("%H:%M:%S").to_string(), 8, b' '));
+    file.extend(fixed(&table.name, 64, b' '));
+    file.extend([0; 3]);
+
+    // Variables, strings wider than 8 bytes take a record per 8 bytes
+    let mut indexes = Vec::new();
+    let mut index = 1;
+    for ((var, width), short_name) in vars.iter().zip(&widths).zip(&short_names)
+    {
+        indexes.push(index);
+        index += segments(*width) as i32;
+        let format = spss_format(var, *width);
+        int(&mut file, 2);
+        int(&mut file, *width as i32);
+        int(&mut file, var.col.label.is_some() as i32);
+        // No missing values
+        int(&mut file, 0);
+        int(&mut file, format);
+        int(&mut file, format);
+        file.extend(fixed(short_name, 8, b' '));
+        if let Some(label) = &var.col.label {
+            let label = truncate(label, 255);
+            int(&mut file, label.len() as i32);
+            file.extend(fixed(label, label.len().div_ceil(4) * 4, b' '));
+        }
+        for _ in 1..segments(*width) {
+            int(&mut file, 2);
+            int(&mut file, -1);
+            file.extend([0; 16]);
+            file.extend([b' '; 8]);
+        }
+    }
+
+    // Value labels, a set per variable
+    for ((var, width), index) in vars.iter().zip(&widths).zip(&indexes) {
+        let labels: Vec<([u8; 8], &str)> = if *width == 0 {
+            var.numeric_labels()
+                .into_iter()
+                .map(|(value, label)| (value.to_le_bytes(), label))
+                .collect()
+        } else if *width <= 8 {
+            var.col
+                .value_labels
+                .iter()
+                .filter_map(|l| match &l.value {
+                    Value::String(s) if s.len() <= 8 => {
+                        let value = fixed(s, 8, b' ').try_into().ok()?;
+                        Some((value, l.label.as_str()))
+                    }
+                    _ => None,
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        if labels.is_empty() {
+            continue;
+        }
+        int(&mut file, 3);
+        int(&mut file, labels.len() as i32);
+        for (value, label) in labels {
+            let label = truncate(label, 120);
+            file.extend(value);
+            file.push(label.len() as u8);
+            file.extend(fixed(
+                label,
+                (label.len() + 1).div_ceil(8) * 8 - 1,
+                b' ',
+            ));
+        }
+        int(&mut file, 4);
+        int(&mut file, 1);
+        int(&mut file, *index);
+    }
+
+    let extension =
+        |file: &mut Vec<u8>, subtype: i32, size: i32, data: &[u8]| {
+            int(file, 7);
+            int(file, subtype);
+            int(file, size);
+            int(file, data.len() as i32 / size);
+            file.extend_from_slice(data);
+        };
+    // Machine integers: version 1.0.0, IEEE floats, little endian, UTF-8
+    let integers: Vec<u8> = [1, 0, 0, -1, 1, 1, 2, 65001]
+        .iter()
+        .flat_map(|n: &i32| n.to_le_bytes())
+        .collect();
+    extension(&mut file, 3, 4, &integers);
+    // Machine floats: system missing, highest and lowest
+    let floats: Vec<u8> =
+        [-f64::MAX, f64::MAX, f64::from_bits(0xffef_ffff_ffff_fffe)]
+            .iter()
+            .flat_map(|n| n.to_le_bytes())
+            .collect();
+    extension(&mut file, 4, 8, &floats);
+    let long_names: Vec<String> = vars
+        .iter()
+        .zip(&short_names)
+        .map(|(var, short_name)| format!("{}={}", short_name, var.name))
+        .collect();
+    extension(&mut file, 13, 1, long_names.join("\t").as_bytes());
+    extension(&mut file, 20, 1, b"UTF-8");
+    int(&mut file, 999);
+    int(&mut file, 0);
+
+    for row in 0..rows.len() {
+        for (var, width) in vars.iter().zip(&widths) {
+            let cell = &var.cells[row];
+            if *width == 0 {
+                let value = match cell.number() {
+                    Some(n) => spss_number(n, var.kind),
+                    None => -f64::MAX,
+                };
+                file.extend(value.to_le_bytes());
+            } else {
+                let text = truncate(cell.text(), *width);
+                file.extend(fixed(text, segments(*width) * 8, b' '));
+            }
+        }
+    }
+    Ok(file)
+}
+
+/// Unique names of at most 8 uppercase bytes that SPSS identifies variables
+/// by, the long names are mapped to them
+fn spss_short_names(vars: &[Variable]) -> Vec<String> {
+    let mut taken: Vec<String> = Vec::new();
+    for var in vars {
+        let name = unique_name(
+            truncate(&var.name.to_uppercase(), 8).to_string(),
+            &taken,
+            8,
+        );
+        taken.push(name.to_lowercase());
+    }
+    taken.iter().map(|n| n.to_uppercase()).collect()
+}
+
+/// SPSS print and write format of a variable
+fn spss_format(var: &Variable, width: usize) -> i32 {
+    let (format, width, decimals) = match var.kind {
+        Kind::Text => (SPSS_FORMAT_A, width, 0),
+        Kind::Date => (SPSS_FORMAT_DATE, 11, 0),
+        Kind::Time => (SPSS_FORMAT_TIME, 8, 0),
+        Kind::Timestamp => (SPSS_FORMAT_DATETIME, 20, 0),
+        Kind::Boolean => (SPSS_FORMAT_F, 1, 0),
+        Kind::Integer => (SPSS_FORMAT_F, 20, 0),
+        Kind::Float => (SPSS_FORMAT_F, 8, 2),
+    };
+    (format << 16) | ((width as i32) << 8) | decimals
+}
+
+/// Number in SPSS's units, seconds since 1582-10-14 for dates
+fn spss_number(n: f64, kind: Kind) -> f64 {
+    match kind {
+        Kind::Date => n * SECONDS_PER_DAY + SPSS_EPOCH_OFFSET,
+        Kind::Timestamp => n + SPSS_EPOCH_OFFSET,
+        _ => n,
+    }
+}
+
+/// CSV file of the table's rows as the R script reads them: booleans as
+/// `TRUE`/`FALSE`, timestamps in UTC and nulls as empty fields.
+/// Written as the rows are read, the stream ends after an error.
+pub fn write_r_csv(table: TableMeta, rows: RowStream) -> ByteStream {
+    let finish = |writer: csv::Writer<Vec<u8>>| -> Result<Vec<u8>> {
+        writer.into_inner().map_err(|e| e.into_error().into())
+    };
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    let header = writer
+        .write_record(table.cols.iter().map(|c| c.name.as_str()))
+        .map_err(Into::into)
+        .and_then(|_| finish(writer));
+    let records = rows.ready_chunks(STREAM_BUFFER_ROWS).map(move |rows| {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        for row in rows {
+            let row = row?;
+            writer.write_record(
+                table.cols.iter().map(|col| r_field(col, &row)),
+            )?;
+        }
+        finish(writer)
+    });
+    Box::pin(futures::stream::once(async move { header }).chain(records))
+}
+
+/// Field of a column in the R CSV file
+fn r_field(col: &ColMeta, row: &RowJson) -> String {
+    let value = row.get(&col.name).unwrap_or(&Value::Null);
+    match (Kind::new(&col.postgres_type), value) {
+        (_, Value::Null) => String::new(),
+        (_, Value::Bool(true)) => "TRUE".to_string(),
+        (_, Value::Bool(false)) => "FALSE".to_string(),
+        (Kind::Timestamp, Value::String(s)) => match parse_timestamp(s) {
+            Some(t) => t.format("%Y-%m-%d %H:%M:%S%.f").to_string(),
+            None => s.clone(),
+        },
+        (_, Value::String(s)) => s.clone(),
+        (_, value) => value.to_string(),
+    }
+}
+
+/// R script that reads the tables' CSV files from its directory into the
+/// list `tables`.
+/// Column labels go to the `label` attribute. Columns whose values are all
+/// labelled become factors, others keep their labels in the `labels`
+/// attribute, like haven does.
+pub fn write_r_script(tables: &[TableMeta]) -> String {
+    let mut script = String::from(
+        "# Reads the exported tables into the list `tables`\n\
+        label_values <- function(x, labels) {\n  \
+        if (all(is.na(x) | x %in% labels)) {\n    \
+        factor(x, levels = labels, labels = names(labels))\n  \
+        } else {\n    \
+        structure(x, labels = labels)\n  \
+        }\n\
+        }\n\
+        tables <- list()\n",
+    );
+    for table in tables {
+        let classes: Vec<String> = table
+            .cols
+            .iter()
+            .map(|col| {
+                format!(
+                    "{} = \"{}\"",
+                    r_string(&col.name),
+                    r_class(&col.postgres_type)
+                )
+            })
+            .collect();
+        script.push_str(&format!(
+            "\ndata <- read.csv(\n  {},\n  colClasses = c({}),\n  \
+            na.strings = \"\",\n  check.names = FALSE,\n  \
+            encoding = \"UTF-8\"\n)\n",
+            r_string(&format!("{}.csv", table.name)),
+            classes.join(", ")
+        ));
+        for col in &table.cols {
+            let column = format!("data[[{}]]", r_string(&col.name));
+            match Kind::new(&col.postgres_type) {
+                Kind::Date => {
+                    script.push_str(&format!("{0} <- as.Date({0})\n", column))
+                }
+                Kind::Timestamp => script.push_str(&format!(
+                    "{0} <- as.POSIXct({0}, tz = \"UTC\", \
+                    format = \"%Y-%m-%d %H:%M:%OS\")\n",
+                    column
+                )),
+                _ => {}
+            }
+            let labels: Vec<String> = col
+                .value_labels
+                .iter()
+                .map(|l| {
+                    let value = match &l.value {
+                        Value::String(s) => r_string(s),
+                        Value::Bool(true) => "TRUE".to_string(),
+                        Value::Bool(false) => "FALSE".to_string(),
+                        value => value.to_string(),
+                    };
+                    format!("{} = {}", r_string(&l.label), value)
+                })
+                .collect();
+            if !labels.is_empty() {
+                script.push_str(&format!(
+                    "{0} <- label_values({0}, c({1}))\n",
+                    column,
+                    labels.join(", ")
+                ));
+            }
+            if let Some(label) = &col.label {
+                script.push_str(&format!(
+                    "attr({}, \"label\") <- {}\n",
+                    column,
+                    r_string(label)
+                ));
+            }
+        }
+        script.push_str(&format!(
+            "tables[[{}]] <- data\n",
+            r_string(&table.name)
+        ));
+    }
+    script
+}
+
+/// Class R reads a column's values as
+fn r_class(postgres_type: &PostgresType) -> &'static str {
+    use PostgresType as T;
+    match postgres_type {
+        T::SmallInt | T::Integer => "integer",
+        T::BigInt | T::Real | T::DoublePrecision | T::Numeric(_) => "numeric",
+        T::Boolean => "logical",
+        _ => "character",
+    }
+}
+
+/// R string literal
+fn r_string(s: &str) -> String {
+    let mut literal = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::table::ValueLabel;
+    use super::*;
+    use serde_json::json;
+
+    fn find(data: &[u8], pattern: &[u8]) -> usize {
+        data.windows(pattern.len())
+            .position(|w| w == pattern)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn export_stats() {
+        let _ = pretty_env_logger::try_init();
+        let mut table = crate::tests::get_visit_table();
+        table.cols.extend([
+            ColMeta::new()
+                .name("if")
+                .postgres_type(PostgresType::Text)
+                .value_labels(vec![ValueLabel::new(json!("m"), "mild")]),
+            ColMeta::new()
+                .name("SEX")
+                .postgres_type(PostgresType::Integer),
+            ColMeta::new()
+                .name("date of birth")
+                .postgres_type(PostgresType::Date),
+        ]);
+        let note = "x".repeat(3000);
+        let mut rows = crate::tests::get_visit_data();
+        rows[0]["note"] = json!(note);
+        for (row, (when, sex, birth)) in rows.iter_mut().zip([
+            (json!("m"), json!(3), json!("1980-05-17")),
+            (json!(null), json!(null), json!(null)),
+        ]) {
+            row.insert("if".to_string(), when);
+            row.insert("SEX".to_string(), sex);
+            row.insert("date of birth".to_string(), birth);
+        }
+        // 2021-03-01 06:30 UTC
+        let days = 18687.0;
+        let seen = days * SECONDS_PER_DAY + 23400.0;
+        // 1980-05-17
+        let birth = 3789.0;
+
+        log::info!("stata");
+        let dta = write_dta(&table, &rows).unwrap();
+        assert!(dta.starts_with(
+            b"<stata_dta><header><release>118</release>\
+            <byteorder>LSF</byteorder><K>\x0c\x00</K><N>\x02\0\0\0\0\0\0\0</N>"
+        ));
+        let map_start = find(&dta, b"<map>") + 5;
+        let map: Vec<u64> = (0..14)
+            .map(|i| {
+                let start = map_start + i * 8;
+                u64::from_le_bytes(dta[start..start + 8].try_into().unwrap())
+            })
+            .collect();
+        let tags = [
+            "<stata_dta>",
+            "<map>",
+            "<variable_types>",
+            "<varnames>",
+            "<sortlist>",
+            "<formats>",
+            "<value_label_names>",
+            "<variable_labels>",
+            "<characteristics>",
+            "<data>",
+            "<strls>",
+            "<value_labels>",
+            "</stata_dta>",
+        ];
+        for (offset, tag) in map.iter().zip(tags) {
+            assert!(dta[*offset as usize..].starts_with(tag.as_bytes()));
+        }
+        assert_eq!(map[13] as usize, dta.len());
+        let types_start = map[2] as usize + "<variable_types>".len();
+        let types: Vec<u16> = dta[types_start..types_start + 24]
+            .chunks(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                STATA_LONG,
+                4,
+                STATA_LONG,
+                STATA_DOUBLE,
+                STATA_BYTE,
+                STATA_DOUBLE,
+                STATA_LONG,
+                27,
+                STATA_STRL,
+                1,
+                STATA_LONG,
+                STATA_LONG
+            ]
+        );
+        let names_start = map[3] as usize + "<varnames>".len();
+        let names: Vec<String> = dta[names_start..names_start + 12 * 129]
+            .chunks(129)
+            .map(|c| {
+                String::from_utf8(c.split(|b| *b == 0).next().unwrap().to_vec())
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "id",
+                "subject",
+                "date",
+                "seen",
+                "sick",
+                "weight",
+                "sex",
+                "symptoms",
+                "note",
+                "_if",
+                "SEX_1",
+                "date_of_birth"
+            ]
+        );
+        let symptoms = |text: &[u8]| {
+            let mut field = text.to_vec();
+            field.resize(27, 0);
+            field
+        };
+        let mut expected = b"<data>".to_vec();
+        expected.extend(1i32.to_le_bytes());
+        expected.extend(b"1001");
+        expected.extend(((days + STATA_EPOCH_OFFSET) as i32).to_le_bytes());
+        expected.extend(
+            ((seen + STATA_EPOCH_OFFSET * SECONDS_PER_DAY) * 1000.0)
+                .to_le_bytes(),
+        );
+        expected.push(1);
+        expected.extend(72.5f64.to_le_bytes());
+        expected.extend(1i32.to_le_bytes());
+        expected.extend(symptoms(br#"{"cough":true,"fever":38.5}"#));
+        expected.extend([9, 0, 1, 0, 0, 0, 0, 0]);
+        expected.push(b'm');
+        expected.extend(3i32.to_le_bytes());
+        expected.extend(((birth + STATA_EPOCH_OFFSET) as i32).to_le_bytes());
+        expected.extend(2i32.to_le_bytes());
+        expected.extend(b"1002");
+        expected.extend(STATA_MISSING_LONG.to_le_bytes());
+        expected.extend(f64::from_bits(STATA_MISSING_DOUBLE).to_le_bytes());
+        expected.push(STATA_MISSING_BYTE as u8);
+        expected.extend(f64::from_bits(STATA_MISSING_DOUBLE).to_le_bytes());
+        expected.extend(2i32.to_le_bytes());
+        expected.extend(symptoms(b"[1,2]"));
+        expected.extend([0; 8]);
+        expected.push(0);
+        expected.extend(STATA_MISSING_LONG.to_le_bytes());
+        expected.extend(STATA_MISSING_LONG.to_le_bytes());
+        expected.extend(b"</data><strls>GSO");
+        expected.extend(9u32.to_le_bytes());
+        expected.extend(1u64.to_le_bytes());
+        expected.push(130);
+        expected.extend(3001u32.to_le_bytes());
+        assert!(dta[map[9] as usize..].starts_with(&expected));
+        let value_labels = &dta[map[11] as usize..map[12] as usize];
+        assert_eq!(
+            value_labels.windows(5).filter(|w| w == b"<lbl>").count(),
+            2
+        );
+        let mut sex_labels = b"sex".to_vec();
+        sex_labels.extend([0; 129 - 3 + 3]);
+        sex_labels.extend(2i32.to_le_bytes());
+        sex_labels.extend(12i32.to_le_bytes());
+        sex_labels.extend(0i32.to_le_bytes());
+        sex_labels.extend(5i32.to_le_bytes());
+        sex_labels.extend(1i32.to_le_bytes());
+        sex_labels.extend(2i32.to_le_bytes());
+        sex_labels.extend(b"male\0female\0</lbl>");
+        find(value_labels, &sex_labels);
+
+        log::info!("spss");
+        let sav = write_sav(&table, &rows).unwrap();
+        assert!(sav.starts_with(b"$FL2@(#) SPSS DATA FILE"));
+        let int_at =
+            |at: usize| i32::from_le_bytes(sav[at..at + 4].try_into().unwrap());
+        // Layout code, case size, compression, weight and cases
+        assert_eq!(
+            (64..84).step_by(4).map(int_at).collect::<Vec<i32>>(),
+            vec![2, 46, 0, 0, 2]
+        );
+        find(
+            &sav,
+            b"ID=id\tSUBJECT=subject\tDATE=date\tSEEN=seen\tSICK=sick\t\
+            WEIGHT=weight\tSEX=sex\tSYMPTOMS=symptoms\tNOTE=note\tIF=if\t\
+            SEX_1=SEX_1\tDATE_OF_=date_of_birth",
+        );
+        find(&sav, b"m       \x04mild   ");
+        find(&sav, b"\x10\0\0\0Body weight (kg)");
+        let data = &sav[sav.len() - 2 * 46 * 8..];
+        let symptoms = |text: &[u8]| {
+            let mut field = text.to_vec();
+            field.resize(32, b' ');
+            field
+        };
+        let mut expected = Vec::new();
+        expected.extend(1f64.to_le_bytes());
+        expected.extend(b"1001    ");
+        for n in [
+            days * SECONDS_PER_DAY + SPSS_EPOCH_OFFSET,
+            seen + SPSS_EPOCH_OFFSET,
+            1.0,
+            72.5,
+            1.0,
+        ] {
+            expected.extend(f64::to_le_bytes(n));
+        }
+        expected.extend(symptoms(br#"{"cough":true,"fever":38.5}"#));
+        expected.extend("x".repeat(255).as_bytes());
+        expected.push(b' ');
+        expected.extend(b"m       ");
+        expected.extend(3f64.to_le_bytes());
+        expected.extend(
+            (birth * SECONDS_PER_DAY + SPSS_EPOCH_OFFSET).to_le_bytes(),
+        );
+        expected.extend(2f64.to_le_bytes());
+        expected.extend(b"1002    ");
+        for _ in 0..4 {
+            expected.extend((-f64::MAX).to_le_bytes());
+        }
+        expected.extend(2f64.to_le_bytes());
+        expected.extend(symptoms(b"[1,2]"));
+        expected.extend([b' '; 256 + 8]);
+        for _ in 0..2 {
+            expected.extend((-f64::MAX).to_le_bytes());
+        }
+        assert_eq!(data, expected.as_slice());
+        let dictionary_end = sav.len() - data.len() - 8;
+        assert_eq!(int_at(dictionary_end), 999);
+
+        log::info!("r");
+        let csv: Vec<Vec<u8>> = write_r_csv(
+            table.clone(),
+            Box::pin(futures::stream::iter(rows.into_iter().map(Ok))),
+        )
+        .try_collect()
+        .await
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(csv.concat()).unwrap(),
+            format!(
+                "id,subject,date,seen,sick,weight,sex,symptoms,note,if,SEX,\
+                date of birth\n\
+                1,1001,2021-03-01,2021-03-01 06:30:00,TRUE,72.5,1,\
+                \"{{\"\"cough\"\":true,\"\"fever\"\":38.5}}\",{},m,3,1980-05-17\n\
+                2,1002,,,,,2,\"[1,2]\",,,,\n",
+                note
+            )
+        );
+        let script = write_r_script(&[table]);
+        for line in [
+            "data <- read.csv(\n  \"visit.csv\",\n  colClasses = c(\
+            \"id\" = \"integer\", \"subject\" = \"character\", \
+            \"date\" = \"character\", \"seen\" = \"character\", \
+            \"sick\" = \"logical\", \"weight\" = \"numeric\", \
+            \"sex\" = \"integer\", \"symptoms\" = \"character\", \
+            \"note\" = \"character\", \"if\" = \"character\", \
+            \"SEX\" = \"integer\", \"date of birth\" = \"character\"),",
+            "data[[\"seen\"]] <- as.POSIXct(data[[\"seen\"]], tz = \"UTC\", \
+            format = \"%Y-%m-%d %H:%M:%OS\")\n",
+            "data[[\"date\"]] <- as.Date(data[[\"date\"]])\n",
+            "data[[\"sex\"]] <- label_values(data[[\"sex\"]], \
+            c(\"male\" = 1, \"female\" = 2))\n",
+            "data[[\"sick\"]] <- label_values(data[[\"sick\"]], \
+            c(\"no\" = FALSE, \"yes\" = TRUE))\n",
+            "attr(data[[\"weight\"]], \"label\") <- \
+            \"Body weight (kg)\"\n",
+            "tables[[\"visit\"]] <- data\n",
+        ] {
+            assert!(script.contains(line), "{} not in {}", line, script);
+        }
+        assert_eq!(r_string("say \"hi\"\n"), "\"say \\\"hi\\\"\\n\"");
+    }
+}
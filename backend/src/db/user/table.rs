@@ -1,4 +1,6 @@
-use crate::{Error, Result};
+use super::expression::check_expression;
+use super::types::PostgresType;
+use crate::{error::RowError, Error, Result};
 
 /// Column specification
 pub type ColSpec = Vec<ColMeta>;
@@ -27,21 +29,471 @@ impl ForeignKey {
     }
 }
 
+/// Label of a coded value, e.g. "male" for `1`
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ValueLabel {
+    pub value: serde_json::Value,
+    pub label: String,
+}
+
+impl ValueLabel {
+    pub fn new(value: serde_json::Value, label: &str) -> Self {
+        Self {
+            value,
+            label: label.to_string(),
+        }
+    }
+}
+
+/// Labels of a column as stored in its comment
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+struct ColComment {
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    value_labels: Vec<ValueLabel>,
+}
+
+/// Comment query storing the labels of a column, removing its comment if
+/// there are none
+pub fn construct_comment_query(
+    table_name: &str,
+    col_name: &str,
+    label: &Option<String>,
+    value_labels: &[ValueLabel],
+) -> String {
+    let comment = if label.is_none() && value_labels.is_empty() {
+        "NULL".to_string()
+    } else {
+        let comment = ColComment {
+            label: label.clone(),
+            value_labels: value_labels.to_vec(),
+        };
+        format!(
+            "'{}'",
+            serde_json::to_string(&comment).unwrap().replace('\'', "''")
+        )
+    };
+    format!(
+        "COMMENT ON COLUMN \"{}\".\"{}\" IS {}",
+        table_name, col_name, comment
+    )
+}
+
+/// Labels from a column comment. Comments that weren't written by us are
+/// taken as the label.
+pub fn parse_comment(comment: &str) -> (Option<String>, Vec<ValueLabel>) {
+    match serde_json::from_str::<ColComment>(comment) {
+        Ok(comment) => (comment.label, comment.value_labels),
+        Err(_) => (Some(comment.to_string()), Vec::new()),
+    }
+}
+
+/// What happens to referencing rows when the referenced row changes
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub enum ReferentialAction {
+    #[default]
+    NoAction,
+    Restrict,
+    Cascade,
+    SetNull,
+    SetDefault,
+}
+
+impl ReferentialAction {
+    /// From the `pg_constraint.confdeltype`/`confupdtype` code
+    pub fn from_code(code: &str) -> Option<Self> {
+        match code {
+            "a" => Some(Self::NoAction),
+            "r" => Some(Self::Restrict),
+            "c" => Some(Self::Cascade),
+            "n" => Some(Self::SetNull),
+            "d" => Some(Self::SetDefault),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ReferentialAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoAction => write!(f, "NO ACTION"),
+            Self::Restrict => write!(f, "RESTRICT"),
+            Self::Cascade => write!(f, "CASCADE"),
+            Self::SetNull => write!(f, "SET NULL"),
+            Self::SetDefault => write!(f, "SET DEFAULT"),
+        }
+    }
+}
+
+/// Table-level constraint.
+/// Single-column unique constraints, single-column foreign keys without
+/// actions and checks involving one column are column-level and read back as
+/// such.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum TableConstraint {
+    /// Combination of the columns is unique
+    Unique(Vec<String>),
+    /// Columns reference the foreign columns of another table
+    ForeignKey {
+        columns: Vec<String>,
+        table: String,
+        foreign_columns: Vec<String>,
+        #[serde(default)]
+        on_delete: ReferentialAction,
+        #[serde(default)]
+        on_update: ReferentialAction,
+    },
+    /// SQL expression rows must satisfy, e.g. `(start < finish)`
+    Check(String),
+    /// No two rows match on all the elements, e.g.
+    /// `EXCLUDE USING gist (room WITH =, during WITH &&)`
+    Exclude {
+        #[serde(default)]
+        method: IndexMethod,
+        elements: Vec<ExclusionElement>,
+    },
+}
+
+/// Column of an exclusion constraint and the operator rows are compared with
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ExclusionElement {
+    pub column: String,
+    /// Operator name, e.g. `=` or `&&`
+    pub operator: String,
+}
+
+impl ExclusionElement {
+    pub fn new(column: &str, operator: &str) -> Self {
+        Self {
+            column: column.to_string(),
+            operator: operator.to_string(),
+        }
+    }
+    /// Operators are made of operator characters only and can't start a
+    /// comment
+    fn check_operator(&self) -> Result<()> {
+        const CHARS: &str = "+-*/<>=~!@#%^&|`?";
+        if self.operator.is_empty()
+            || !self.operator.chars().all(|c| CHARS.contains(c))
+            || self.operator.contains("--")
+            || self.operator.contains("/*")
+        {
+            return Err(Error::InvalidDefinition(format!(
+                "invalid exclusion operator \"{}\"",
+                self.operator
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl TableConstraint {
+    /// Checks the SQL of checks and the operators of exclusions, see
+    /// `check_expression`
+    pub fn check_expressions(&self) -> Result<()> {
+        match self {
+            Self::Check(sql) => check_expression(sql),
+            Self::Exclude { elements, .. } => {
+                elements.iter().try_for_each(|e| e.check_operator())
+            }
+            Self::Unique(_) | Self::ForeignKey { .. } => Ok(()),
+        }
+    }
+    /// Columns of the constrained table, empty for checks and exclusions
+    pub fn columns(&self) -> &[String] {
+        match self {
+            Self::Unique(columns) | Self::ForeignKey { columns, .. } => columns,
+            Self::Check(_) | Self::Exclude { .. } => &[],
+        }
+    }
+    /// Entry for the create query
+    pub fn create_query_entry(&self) -> String {
+        let quote = |cols: &[String]| {
+            cols.iter()
+                .map(|c| format!("\"{}\"", c))
+                .collect::<Vec<String>>()
+                .join(",")
+        };
+        match self {
+            Self::Unique(columns) => format!("UNIQUE({})", quote(columns)),
+            Self::ForeignKey {
+                columns,
+                table,
+                foreign_columns,
+                on_delete,
+                on_update,
+            } => {
+                let mut entry = format!(
+                    "FOREIGN KEY({}) REFERENCES \"{}\"({})",
+                    quote(columns),
+                    table,
+                    quote(foreign_columns)
+                );
+                if *on_delete != ReferentialAction::NoAction {
+                    entry = format!("{} ON DELETE {}", entry, on_delete);
+                }
+                if *on_update != ReferentialAction::NoAction {
+                    entry = format!("{} ON UPDATE {}", entry, on_update);
+                }
+                entry
+            }
+            Self::Check(expression) => format!("CHECK ({})", expression),
+            Self::Exclude { method, elements } => format!(
+                "EXCLUDE USING {} ({})",
+                method,
+                elements
+                    .iter()
+                    .map(|e| format!("\"{}\" WITH {}", e.column, e.operator))
+                    .collect::<Vec<String>>()
+                    .join(",")
+            ),
+        }
+    }
+}
+
+/// Index access method
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub enum IndexMethod {
+    #[default]
+    BTree,
+    Hash,
+    Gist,
+    SpGist,
+    Gin,
+    Brin,
+}
+
+impl IndexMethod {
+    /// From the `pg_am.amname` name
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "btree" => Some(Self::BTree),
+            "hash" => Some(Self::Hash),
+            "gist" => Some(Self::Gist),
+            "spgist" => Some(Self::SpGist),
+            "gin" => Some(Self::Gin),
+            "brin" => Some(Self::Brin),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for IndexMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BTree => write!(f, "btree"),
+            Self::Hash => write!(f, "hash"),
+            Self::Gist => write!(f, "gist"),
+            Self::SpGist => write!(f, "spgist"),
+            Self::Gin => write!(f, "gin"),
+            Self::Brin => write!(f, "brin"),
+        }
+    }
+}
+
+/// Index on columns of a table.
+/// Indexes backing primary keys and unique constraints are not listed.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TableIndex {
+    /// Index name, unique among all the project's indexes
+    pub name: String,
+    pub columns: Vec<String>,
+    #[serde(default)]
+    pub method: IndexMethod,
+    #[serde(default)]
+    pub unique: bool,
+    /// SQL condition of a partial index, e.g. `(age >= 18)`
+    #[serde(default)]
+    pub predicate: Option<String>,
+}
+
+impl TableIndex {
+    pub fn new(name: &str, columns: &[&str]) -> Self {
+        Self {
+            name: name.to_string(),
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+            method: IndexMethod::BTree,
+            unique: false,
+            predicate: None,
+        }
+    }
+    pub fn method(mut self, val: IndexMethod) -> Self {
+        self.method = val;
+        self
+    }
+    pub fn unique(mut self, val: bool) -> Self {
+        self.unique = val;
+        self
+    }
+    pub fn predicate(mut self, val: &str) -> Self {
+        self.predicate = Some(val.to_string());
+        self
+    }
+    /// Checks the predicate, see `check_expression`
+    pub fn check_expressions(&self) -> Result<()> {
+        self.predicate.as_deref().map_or(Ok(()), check_expression)
+    }
+    /// Create query for the index on the given table
+    pub fn construct_create_query(&self, table_name: &str) -> String {
+        let mut query = format!(
+            "CREATE {}INDEX \"{}\" ON \"{}\" USING {}({})",
+            if self.unique { "UNIQUE " } else { "" },
+            self.name,
+            table_name,
+            self.method,
+            self.columns
+                .iter()
+                .map(|c| format!("\"{}\"", c))
+                .collect::<Vec<String>>()
+                .join(",")
+        );
+        if let Some(predicate) = &self.predicate {
+            query = format!("{} WHERE {}", query, predicate);
+        }
+        query
+    }
+}
+
+/// Object that depends on a table and is dropped with it on cascade
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum TableDependent {
+    /// Foreign key constraint of another table
+    ForeignKey { table: String, constraint: String },
+    /// View selecting from the table
+    View(String),
+}
+
+/// Options for removing a table
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct RemoveTableOptions {
+    /// Drop the table's dependents too
+    #[serde(default)]
+    pub cascade: bool,
+}
+
+/// What to do with an inserted row that conflicts with an existing one
+#[derive(
+    Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum OnConflict {
+    /// Fail the row
+    #[default]
+    Error,
+    /// Set every submitted column, null values included
+    UpdateAll,
+    /// Set the submitted columns that are not null
+    UpdateNonNull,
+    /// Keep the existing row
+    DoNothing,
+}
+
+/// Options for inserting rows
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct InsertOptions {
+    /// Insert the valid rows and report the invalid ones instead of
+    /// inserting nothing
+    #[serde(default)]
+    pub partial: bool,
+    /// What to do with rows that conflict with existing ones
+    #[serde(default)]
+    pub on_conflict: OnConflict,
+    /// Unique constraint that conflicts are resolved on, the primary key if
+    /// not given
+    #[serde(default)]
+    pub constraint: Option<String>,
+    /// Report what would happen, failures included, without inserting
+    /// anything
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+impl InsertOptions {
+    pub fn partial(mut self, val: bool) -> Self {
+        self.partial = val;
+        self
+    }
+    pub fn on_conflict(mut self, val: OnConflict) -> Self {
+        self.on_conflict = val;
+        self
+    }
+    pub fn constraint(mut self, val: &str) -> Self {
+        self.constraint = Some(val.to_string());
+        self
+    }
+    pub fn dry_run(mut self, val: bool) -> Self {
+        self.dry_run = val;
+        self
+    }
+}
+
+/// Outcome of inserting rows
+#[derive(
+    Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize,
+)]
+pub struct InsertReport {
+    /// Primary key of every inserted or updated row, in order
+    pub keys: Vec<RowJson>,
+    /// Number of rows inserted as new
+    pub inserted: u64,
+    /// Number of existing rows updated
+    pub updated: u64,
+    /// Number of conflicting rows left as they were
+    pub unchanged: u64,
+    /// Rows that were not inserted
+    pub errors: Vec<RowError>,
+}
+
 /// Column metadata
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct ColMeta {
     /// Column name
     pub name: String,
     /// Column type as understood by Postgres
-    pub postgres_type: String,
+    pub postgres_type: PostgresType,
     /// Whether it's allowed to be null
     pub not_null: bool,
     /// Whether values are allowed to duplicate
     pub unique: bool,
     /// Whether it's a primary key
     pub primary_key: bool,
+    /// Whether values are generated from a sequence when not given.
+    /// Identity columns are always not null.
+    #[serde(default)]
+    pub identity: bool,
     /// Optional foreign key
     pub foreign_key: Option<ForeignKey>,
+    /// Default value as an SQL expression, e.g. `now()`
+    pub default: Option<String>,
+    /// SQL expression the column's values must satisfy, e.g. `(age >= 0)`
+    pub check: Option<String>,
+    /// SQL expression a stored generated column is computed from
+    pub generated: Option<String>,
+    /// Description for people, e.g. `Body weight (kg)`
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Labels of the coded values the column holds
+    #[serde(default)]
+    pub value_labels: Vec<ValueLabel>,
 }
 
 impl ColMeta {
@@ -49,8 +501,8 @@ impl ColMeta {
         self.name = val.to_string();
         self
     }
-    pub fn postgres_type(mut self, val: &str) -> Self {
-        self.postgres_type = val.to_string();
+    pub fn postgres_type(mut self, val: PostgresType) -> Self {
+        self.postgres_type = val;
         self
     }
     pub fn not_null(mut self, val: bool) -> Self {
@@ -65,29 +517,83 @@ impl ColMeta {
         self.primary_key = val;
         self
     }
+    pub fn identity(mut self, val: bool) -> Self {
+        self.identity = val;
+        self
+    }
     pub fn foreign_key(mut self, val: ForeignKey) -> Self {
         self.foreign_key = Some(val);
         self
     }
+    pub fn default(mut self, val: &str) -> Self {
+        self.default = Some(val.to_string());
+        self
+    }
+    pub fn check(mut self, val: &str) -> Self {
+        self.check = Some(val.to_string());
+        self
+    }
+    pub fn generated(mut self, val: &str) -> Self {
+        self.generated = Some(val.to_string());
+        self
+    }
+    pub fn label(mut self, val: &str) -> Self {
+        self.label = Some(val.to_string());
+        self
+    }
+    pub fn value_labels(mut self, val: Vec<ValueLabel>) -> Self {
+        self.value_labels = val;
+        self
+    }
     pub fn new() -> Self {
         Self {
             name: "".to_string(),
-            postgres_type: "".to_string(),
+            postgres_type: PostgresType::Text,
             not_null: false,
             unique: false,
             primary_key: false,
+            identity: false,
             foreign_key: None,
+            default: None,
+            check: None,
+            generated: None,
+            label: None,
+            value_labels: Vec::new(),
+        }
+    }
+    /// Checks the default, check and generation expressions, see
+    /// `check_expression`
+    pub fn check_expressions(&self) -> Result<()> {
+        for expression in [&self.default, &self.check, &self.generated]
+            .iter()
+            .filter_map(|e| e.as_deref())
+        {
+            check_expression(expression)?;
         }
+        Ok(())
     }
     /// Entry for the create query
     pub fn construct_create_query_entry(&self) -> String {
         let mut entry = format!("\"{}\" {}", self.name, self.postgres_type);
+        if self.identity {
+            entry = format!("{} GENERATED BY DEFAULT AS IDENTITY", entry);
+        }
+        if let Some(generated) = &self.generated {
+            entry =
+                format!("{} GENERATED ALWAYS AS ({}) STORED", entry, generated);
+        }
+        if let Some(default) = &self.default {
+            entry = format!("{} DEFAULT {}", entry, default);
+        }
         if self.not_null {
             entry = format!("{} NOT NULL", entry);
         }
         if self.unique {
             entry = format!("{} UNIQUE", entry);
         }
+        if let Some(check) = &self.check {
+            entry = format!("{} CHECK ({})", entry, check);
+        }
         // Ignore primary key because inlining multiple primary keys does not
         // work
         if let Some(foreign_key) = &self.foreign_key {
@@ -95,6 +601,18 @@ impl ColMeta {
         }
         entry
     }
+    /// Comment query storing the labels, if there are any
+    pub fn construct_comment_query(&self, table_name: &str) -> Option<String> {
+        if self.label.is_none() && self.value_labels.is_empty() {
+            return None;
+        }
+        Some(construct_comment_query(
+            table_name,
+            self.name.as_str(),
+            &self.label,
+            &self.value_labels,
+        ))
+    }
 }
 
 impl Default for ColMeta {
@@ -106,10 +624,15 @@ impl Default for ColMeta {
 impl PartialEq for ColMeta {
     fn eq(&self, other: &Self) -> bool {
         if self.name != other.name
-            || self.postgres_type.to_lowercase()
-                != other.postgres_type.to_lowercase()
+            || self.postgres_type != other.postgres_type
             || self.primary_key != other.primary_key
+            || self.identity != other.identity
             || self.foreign_key != other.foreign_key
+            || self.default != other.default
+            || self.check != other.check
+            || self.generated != other.generated
+            || self.label != other.label
+            || self.value_labels != other.value_labels
         {
             return false;
         }
@@ -117,17 +640,214 @@ impl PartialEq for ColMeta {
         if self.primary_key {
             return true;
         }
-        self.unique == other.unique && self.not_null == other.not_null
+        // Don't check not null if identity
+        self.unique == other.unique
+            && (self.identity || self.not_null == other.not_null)
     }
 }
 
-/// Table metadata
+/// Change to an existing table's columns
 #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum TableAlter {
+    /// Add a column
+    AddColumn(ColMeta),
+    /// Drop a column by name
+    DropColumn(String),
+    /// Rename a column
+    RenameColumn { name: String, new_name: String },
+    /// Change a column's type. `using` is an SQL expression computing the
+    /// new value when there is no implicit conversion from the old type.
+    SetType {
+        name: String,
+        postgres_type: PostgresType,
+        using: Option<String>,
+    },
+    /// Allow or disallow nulls
+    SetNotNull { name: String, not_null: bool },
+    /// Allow or disallow duplicates
+    SetUnique { name: String, unique: bool },
+    /// Replace or remove the column's foreign key
+    SetForeignKey {
+        name: String,
+        foreign_key: Option<ForeignKey>,
+    },
+    /// Start or stop generating the column's values
+    SetIdentity { name: String, identity: bool },
+    /// Replace or remove the column's default
+    SetDefault {
+        name: String,
+        default: Option<String>,
+    },
+    /// Replace or remove the column's check
+    SetCheck { name: String, check: Option<String> },
+    /// Replace the column's labels
+    SetLabels {
+        name: String,
+        label: Option<String>,
+        #[serde(default)]
+        value_labels: Vec<ValueLabel>,
+    },
+}
+
+impl TableAlter {
+    /// Checks the SQL expressions of the change, see `check_expression`
+    pub fn check_expressions(&self) -> Result<()> {
+        match self {
+            Self::AddColumn(col) => col.check_expressions(),
+            Self::SetType {
+                using: Some(sql), ..
+            }
+            | Self::SetDefault {
+                default: Some(sql), ..
+            }
+            | Self::SetCheck {
+                check: Some(sql), ..
+            } => check_expression(sql),
+            _ => Ok(()),
+        }
+    }
+    /// Name of the (existing) column the change is about
+    pub fn col_name(&self) -> &str {
+        match self {
+            Self::AddColumn(col) => col.name.as_str(),
+            Self::DropColumn(name)
+            | Self::RenameColumn { name, .. }
+            | Self::SetType { name, .. }
+            | Self::SetNotNull { name, .. }
+            | Self::SetUnique { name, .. }
+            | Self::SetForeignKey { name, .. }
+            | Self::SetIdentity { name, .. }
+            | Self::SetDefault { name, .. }
+            | Self::SetCheck { name, .. }
+            | Self::SetLabels { name, .. } => name.as_str(),
+        }
+    }
+    /// Alter queries. `replaced` are the names of the constraints currently
+    /// on the column that the change replaces (its unique constraints for
+    /// `SetUnique`, its foreign keys for `SetForeignKey`, its checks for
+    /// `SetCheck`).
+    pub fn construct_queries(
+        &self,
+        table_name: &str,
+        replaced: &[String],
+    ) -> Vec<String> {
+        let alter = format!("ALTER TABLE \"{}\"", table_name);
+        let drop_replaced = || {
+            replaced
+                .iter()
+                .map(|c| format!("{} DROP CONSTRAINT \"{}\"", alter, c))
+                .collect::<Vec<String>>()
+        };
+        match self {
+            Self::AddColumn(col) => {
+                let mut entry = col.construct_create_query_entry();
+                if col.primary_key {
+                    entry = format!("{} PRIMARY KEY", entry);
+                }
+                let mut queries =
+                    vec![format!("{} ADD COLUMN {}", alter, entry)];
+                queries.extend(col.construct_comment_query(table_name));
+                queries
+            }
+            Self::DropColumn(name) => {
+                vec![format!("{} DROP COLUMN \"{}\"", alter, name)]
+            }
+            Self::RenameColumn { name, new_name } => vec![format!(
+                "{} RENAME COLUMN \"{}\" TO \"{}\"",
+                alter, name, new_name
+            )],
+            Self::SetType {
+                name,
+                postgres_type,
+                using,
+            } => {
+                let mut query = format!(
+                    "{} ALTER COLUMN \"{}\" TYPE {}",
+                    alter, name, postgres_type
+                );
+                if let Some(using) = using {
+                    query = format!("{} USING {}", query, using);
+                }
+                vec![query]
+            }
+            Self::SetNotNull { name, not_null } => vec![format!(
+                "{} ALTER COLUMN \"{}\" {} NOT NULL",
+                alter,
+                name,
+                if *not_null { "SET" } else { "DROP" }
+            )],
+            Self::SetUnique { name, unique } => {
+                if !unique {
+                    drop_replaced()
+                } else if replaced.is_empty() {
+                    vec![format!("{} ADD UNIQUE(\"{}\")", alter, name)]
+                } else {
+                    vec![]
+                }
+            }
+            Self::SetForeignKey { name, foreign_key } => {
+                let mut queries = drop_replaced();
+                if let Some(foreign_key) = foreign_key {
+                    queries.push(format!(
+                        "{} ADD FOREIGN KEY(\"{}\") {}",
+                        alter,
+                        name,
+                        foreign_key.create_query_entry()
+                    ));
+                }
+                queries
+            }
+            Self::SetIdentity { name, identity } => vec![if *identity {
+                format!(
+                    "{} ALTER COLUMN \"{}\" ADD GENERATED BY DEFAULT AS IDENTITY",
+                    alter, name
+                )
+            } else {
+                format!("{} ALTER COLUMN \"{}\" DROP IDENTITY", alter, name)
+            }],
+            Self::SetDefault { name, default } => vec![match default {
+                Some(default) => format!(
+                    "{} ALTER COLUMN \"{}\" SET DEFAULT {}",
+                    alter, name, default
+                ),
+                None => {
+                    format!("{} ALTER COLUMN \"{}\" DROP DEFAULT", alter, name)
+                }
+            }],
+            Self::SetCheck { check, .. } => {
+                let mut queries = drop_replaced();
+                if let Some(check) = check {
+                    queries.push(format!("{} ADD CHECK ({})", alter, check));
+                }
+                queries
+            }
+            Self::SetLabels {
+                name,
+                label,
+                value_labels,
+            } => vec![construct_comment_query(
+                table_name,
+                name,
+                label,
+                value_labels,
+            )],
+        }
+    }
+}
+
+/// Table metadata
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct TableMeta {
     /// Table name
     pub name: String,
     /// Table columns
     pub cols: ColSpec,
+    /// Table-level constraints
+    #[serde(default)]
+    pub constraints: Vec<TableConstraint>,
+    /// Indexes other than those of keys and constraints
+    #[serde(default)]
+    pub indexes: Vec<TableIndex>,
 }
 
 impl TableMeta {
@@ -135,10 +855,90 @@ impl TableMeta {
         Self {
             name: String::from(name),
             cols,
+            constraints: Vec::new(),
+            indexes: Vec::new(),
+        }
+    }
+    pub fn constraints(mut self, val: Vec<TableConstraint>) -> Self {
+        self.constraints = val;
+        self
+    }
+    pub fn indexes(mut self, val: Vec<TableIndex>) -> Self {
+        self.indexes = val;
+        self
+    }
+    /// Checks the SQL expressions of the columns, constraints and indexes,
+    /// see `check_expression`
+    pub fn check_expressions(&self) -> Result<()> {
+        for col in &self.cols {
+            col.check_expressions()?;
+        }
+        for constraint in &self.constraints {
+            constraint.check_expressions()?;
+        }
+        for index in &self.indexes {
+            index.check_expressions()?;
+        }
+        Ok(())
+    }
+    /// Moves single-column unique constraints and single-column foreign keys
+    /// without actions to their columns, which is how they are read back
+    pub fn normalize_constraints(&mut self) {
+        let cols = &mut self.cols;
+        self.constraints.retain(|constraint| {
+            let col = match constraint.columns() {
+                [name] => match cols.iter_mut().find(|c| &c.name == name) {
+                    Some(col) => col,
+                    None => return true,
+                },
+                _ => return true,
+            };
+            match constraint {
+                TableConstraint::Unique(_) => {
+                    col.unique = true;
+                    false
+                }
+                TableConstraint::ForeignKey {
+                    table,
+                    foreign_columns,
+                    on_delete: ReferentialAction::NoAction,
+                    on_update: ReferentialAction::NoAction,
+                    ..
+                } if foreign_columns.len() == 1
+                    && col.foreign_key.is_none() =>
+                {
+                    col.foreign_key =
+                        Some(ForeignKey::new(table, &foreign_columns[0]));
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+    /// Names of the tables referenced by foreign keys, including this one
+    /// if it references itself
+    pub fn referenced_tables(&self) -> Vec<&str> {
+        let mut tables: Vec<&str> = self
+            .cols
+            .iter()
+            .filter_map(|c| c.foreign_key.as_ref().map(|fk| fk.table.as_str()))
+            .collect();
+        for constraint in &self.constraints {
+            if let TableConstraint::ForeignKey { table, .. } = constraint {
+                tables.push(table.as_str());
+            }
         }
+        tables
     }
     /// Create query
     pub fn construct_create_query(&self) -> String {
+        self.create_query("TABLE")
+    }
+    /// Create query for a temporary table, which only the session sees
+    pub fn construct_create_temp_query(&self) -> String {
+        self.create_query("TEMP TABLE")
+    }
+    fn create_query(&self, kind: &str) -> String {
         let all_columns: String = self
             .cols
             .iter()
@@ -157,42 +957,302 @@ impl TableMeta {
         if !primary_keys.is_empty() {
             primary_key_entry = format!(",PRIMARY KEY({})", primary_keys);
         }
+        let constraint_entry: String = self
+            .constraints
+            .iter()
+            .map(|c| format!(",{}", c.create_query_entry()))
+            .collect();
         format!(
-            "CREATE TABLE \"{}\"({}{})",
-            self.name, all_columns, primary_key_entry
+            "CREATE {} \"{}\"({}{}{})",
+            kind, self.name, all_columns, primary_key_entry, constraint_entry
         )
     }
-    /// Insert query with parameters
+    /// Comment queries storing the columns' labels, run after the create
+    /// query
+    pub fn construct_comment_queries(&self) -> Vec<String> {
+        self.cols
+            .iter()
+            .filter_map(|c| c.construct_comment_query(self.name.as_str()))
+            .collect()
+    }
+    /// Create queries for the indexes, run after the create query
+    pub fn construct_create_index_queries(&self) -> Vec<String> {
+        self.indexes
+            .iter()
+            .map(|i| i.construct_create_query(self.name.as_str()))
+            .collect()
+    }
+    /// Insert query with parameters.
+    /// Every parameter is cast to its column's type so that values bound as
+    /// text (numeric, uuid, interval, arrays) are accepted.
+    /// The query returns the row's primary key as a json object, so that
+    /// generated keys are known.
     pub fn construct_param_insert_query<T: AsRef<str>>(
         &self,
         cols: &[T],
+    ) -> Result<String> {
+        Ok(format!(
+            "{} {}",
+            self.construct_param_insert_entry(cols)?,
+            self.construct_returning_key_entry()
+        ))
+    }
+    /// Insert query with parameters that resolves conflicts on the primary
+    /// key or the named unique constraint.
+    /// On update, the given columns are set except the primary key columns
+    /// and the null columns are set to null.
+    /// Besides the key, the query returns whether the row was inserted
+    /// rather than updated. It returns nothing if the row was left as is.
+    pub fn construct_param_upsert_query<T: AsRef<str>, U: AsRef<str>>(
+        &self,
+        cols: &[T],
+        null_cols: &[U],
+        on_conflict: &OnConflict,
+        constraint: Option<&str>,
+    ) -> Result<String> {
+        if *on_conflict == OnConflict::Error {
+            return self.construct_param_insert_query(cols);
+        }
+        self.verify_cols_present(null_cols)?;
+        let target = match constraint {
+            Some(name) => format!("ON CONSTRAINT \"{}\"", name),
+            None => {
+                let key = self.get_primary_key();
+                if key.is_empty() {
+                    return Err(Error::NoPrimaryKey(self.name.clone()));
+                }
+                format!("(\"{}\")", key.join("\",\""))
+            }
+        };
+        let key = self.get_primary_key();
+        let mut set_entry: Vec<String> = cols
+            .iter()
+            .map(|c| c.as_ref())
+            .filter(|c| !key.contains(c))
+            .map(|c| format!("\"{0}\"=EXCLUDED.\"{0}\"", c))
+            .collect();
+        if *on_conflict == OnConflict::UpdateAll {
+            set_entry.extend(
+                null_cols
+                    .iter()
+                    .map(|c| c.as_ref())
+                    .filter(|c| !key.contains(c))
+                    .map(|c| format!("\"{}\"=NULL", c)),
+            );
+        }
+        let action = match on_conflict {
+            OnConflict::UpdateAll | OnConflict::UpdateNonNull
+                if !set_entry.is_empty() =>
+            {
+                format!("DO UPDATE SET {}", set_entry.join(","))
+            }
+            _ => "DO NOTHING".to_string(),
+        };
+        Ok(format!(
+            "{} ON CONFLICT {} {} {},(xmax = 0)",
+            self.construct_param_insert_entry(cols)?,
+            target,
+            action,
+            self.construct_returning_key_entry()
+        ))
+    }
+    /// Insert entry without returning clause
+    fn construct_param_insert_entry<T: AsRef<str>>(
+        &self,
+        cols: &[T],
     ) -> Result<String> {
         self.verify_cols_present(cols)?;
 
+        // Every column is generated or defaulted
+        if cols.is_empty() {
+            return Ok(format!("INSERT INTO \"{}\" DEFAULT VALUES", self.name));
+        }
+
         // The keys and values that will go into the query
         let mut key_entry = Vec::with_capacity(cols.len());
         let mut value_entry = Vec::with_capacity(cols.len());
         for (i, key) in cols.iter().enumerate() {
             key_entry.push(format!("\"{}\"", key.as_ref()));
-            value_entry.push(format!("${}", i + 1));
+            value_entry.push(format!(
+                "${}::{}",
+                i + 1,
+                self.get_col(key).unwrap().postgres_type
+            ));
         }
 
-        // Complete query
         Ok(format!(
             "INSERT INTO \"{}\"({}) VALUES({})",
             self.name,
             key_entry.join(","),
-            value_entry.join(",")
+            value_entry.join(","),
         ))
     }
-    // Checks that a column is present
-    fn contains_col<T: AsRef<str>>(&self, colname: T) -> bool {
-        for col in &self.cols {
-            if col.name == colname.as_ref() {
-                return true;
+    /// Returning clause for the primary key as a json object
+    fn construct_returning_key_entry(&self) -> String {
+        format!(
+            "RETURNING json_build_object({})",
+            self.cols
+                .iter()
+                .filter(|c| c.primary_key)
+                .map(|c| format!(
+                    "'{}',\"{}\"",
+                    c.name.replace('\'', "''"),
+                    c.name
+                ))
+                .collect::<Vec<String>>()
+                .join(",")
+        )
+    }
+    /// Names of the primary key columns
+    pub fn get_primary_key(&self) -> Vec<&str> {
+        self.cols
+            .iter()
+            .filter(|c| c.primary_key)
+            .map(|c| c.name.as_str())
+            .collect()
+    }
+    /// Parameterized query to update the given columns of the row addressed
+    /// by its primary key. The parameters are the values of the columns to
+    /// set followed by those of the primary key columns, cast like for
+    /// inserts.
+    pub fn construct_param_update_query<T: AsRef<str>>(
+        &self,
+        cols: &[T],
+    ) -> Result<String> {
+        self.verify_cols_present(cols)?;
+        let key = self.get_primary_key();
+        if key.is_empty() {
+            return Err(Error::NoPrimaryKey(self.name.clone()));
+        }
+        let param = |i: usize, name: &str| {
+            format!(
+                "\"{}\"=${}::{}",
+                name,
+                i + 1,
+                self.get_col(name).unwrap().postgres_type
+            )
+        };
+        let set_entry: Vec<String> = cols
+            .iter()
+            .enumerate()
+            .map(|(i, c)| param(i, c.as_ref()))
+            .collect();
+        let key_entry: Vec<String> = key
+            .iter()
+            .enumerate()
+            .map(|(i, k)| param(cols.len() + i, k))
+            .collect();
+        Ok(format!(
+            "UPDATE \"{}\" SET {} WHERE {}",
+            self.name,
+            set_entry.join(","),
+            key_entry.join(" AND ")
+        ))
+    }
+    /// Applies the change to the metadata.
+    /// Errors if the change addresses a column that's not there or adds one
+    /// that is.
+    pub fn apply_alter(&mut self, alter: &TableAlter) -> Result<()> {
+        if let TableAlter::AddColumn(col) = alter {
+            if self.contains_col(&col.name) {
+                return Err(Error::ColumnAlreadyExists(col.name.clone()));
+            }
+            self.cols.push(col.clone());
+            return Ok(());
+        }
+        let i = match self.cols.iter().position(|c| c.name == alter.col_name())
+        {
+            Some(i) => i,
+            None => {
+                return Err(Error::NoSuchColumns(vec![alter
+                    .col_name()
+                    .to_string()]))
+            }
+        };
+        match alter {
+            TableAlter::AddColumn(_) => unreachable!(),
+            TableAlter::DropColumn(name) => {
+                self.cols.remove(i);
+                // Postgres drops the constraints and indexes that involve
+                // the column
+                self.constraints.retain(|c| match c {
+                    TableConstraint::Exclude { elements, .. } => {
+                        !elements.iter().any(|e| &e.column == name)
+                    }
+                    _ => !c.columns().contains(name),
+                });
+                self.indexes.retain(|i| !i.columns.contains(name));
+            }
+            TableAlter::RenameColumn { name, new_name } => {
+                if self.contains_col(new_name) {
+                    return Err(Error::ColumnAlreadyExists(new_name.clone()));
+                }
+                self.cols[i].name = new_name.clone();
+                for constraint in &mut self.constraints {
+                    match constraint {
+                        TableConstraint::Unique(columns)
+                        | TableConstraint::ForeignKey { columns, .. } => {
+                            for c in columns.iter_mut().filter(|c| *c == name) {
+                                *c = new_name.clone();
+                            }
+                        }
+                        TableConstraint::Exclude { elements, .. } => {
+                            for e in elements
+                                .iter_mut()
+                                .filter(|e| e.column == *name)
+                            {
+                                e.column = new_name.clone();
+                            }
+                        }
+                        // Postgres updates the expression
+                        TableConstraint::Check(_) => {}
+                    }
+                }
+                for index in &mut self.indexes {
+                    for c in index.columns.iter_mut().filter(|c| *c == name) {
+                        *c = new_name.clone();
+                    }
+                }
+            }
+            TableAlter::SetType { postgres_type, .. } => {
+                self.cols[i].postgres_type = postgres_type.clone();
+            }
+            TableAlter::SetNotNull { not_null, .. } => {
+                self.cols[i].not_null = *not_null;
+            }
+            TableAlter::SetUnique { unique, .. } => {
+                self.cols[i].unique = *unique;
+            }
+            TableAlter::SetForeignKey { foreign_key, .. } => {
+                self.cols[i].foreign_key = foreign_key.clone();
+            }
+            TableAlter::SetIdentity { identity, .. } => {
+                self.cols[i].identity = *identity;
+            }
+            TableAlter::SetDefault { default, .. } => {
+                self.cols[i].default = default.clone();
+            }
+            TableAlter::SetCheck { check, .. } => {
+                self.cols[i].check = check.clone();
+            }
+            TableAlter::SetLabels {
+                label,
+                value_labels,
+                ..
+            } => {
+                self.cols[i].label = label.clone();
+                self.cols[i].value_labels = value_labels.clone();
             }
         }
-        false
+        Ok(())
+    }
+    /// Column metadata by name
+    pub fn get_col<T: AsRef<str>>(&self, colname: T) -> Option<&ColMeta> {
+        self.cols.iter().find(|c| c.name == colname.as_ref())
+    }
+    // Checks that a column is present
+    fn contains_col<T: AsRef<str>>(&self, colname: T) -> bool {
+        self.get_col(colname).is_some()
     }
     // Find all columns that are not present
     fn find_cols_not_present<T: AsRef<str>>(&self, cols: &[T]) -> Vec<String> {
@@ -201,8 +1261,8 @@ impl TableMeta {
             .map(|c| c.as_ref().to_string())
             .collect()
     }
-    // Verifies that all the given columns are present
-    fn verify_cols_present<T: AsRef<str>>(&self, cols: &[T]) -> Result<()> {
+    /// Verifies that all the given columns are present
+    pub fn verify_cols_present<T: AsRef<str>>(&self, cols: &[T]) -> Result<()> {
         let cols_not_present = self.find_cols_not_present(cols);
         if !cols_not_present.is_empty() {
             return Err(Error::NoSuchColumns(cols_not_present));
@@ -211,6 +1271,21 @@ impl TableMeta {
     }
 }
 
+impl PartialEq for TableMeta {
+    /// Constraints and indexes are compared regardless of order
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.cols == other.cols
+            && self.constraints.len() == other.constraints.len()
+            && self
+                .constraints
+                .iter()
+                .all(|c| other.constraints.contains(c))
+            && self.indexes.len() == other.indexes.len()
+            && self.indexes.iter().all(|i| other.indexes.contains(i))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -218,28 +1293,72 @@ mod tests {
     fn create_col() {
         let _ = pretty_env_logger::try_init();
         {
-            let col = ColMeta::new().name("name").postgres_type("TEXT");
-            assert_eq!(col.construct_create_query_entry(), "\"name\" TEXT");
+            let col = ColMeta::new()
+                .name("name")
+                .postgres_type(PostgresType::Text);
+            assert_eq!(col.construct_create_query_entry(), "\"name\" text");
         }
         {
             let col = ColMeta::new()
                 .name("name")
-                .postgres_type("TEXT")
+                .postgres_type(PostgresType::Text)
                 .primary_key(true)
                 .unique(true);
             assert_eq!(
                 col.construct_create_query_entry(),
-                "\"name\" TEXT UNIQUE"
+                "\"name\" text UNIQUE"
             )
         }
         {
             let col = ColMeta::new()
                 .name("name")
-                .postgres_type("TEXT")
+                .postgres_type(PostgresType::Text)
                 .foreign_key(ForeignKey::new("table", "column"));
             assert_eq!(
                 col.construct_create_query_entry(),
-                "\"name\" TEXT REFERENCES \"table\"(\"column\")"
+                "\"name\" text REFERENCES \"table\"(\"column\")"
+            )
+        }
+        {
+            let col = ColMeta::new()
+                .name("visit")
+                .postgres_type(PostgresType::Timestamptz)
+                .default("now()")
+                .not_null(true);
+            assert_eq!(
+                col.construct_create_query_entry(),
+                "\"visit\" timestamp with time zone DEFAULT now() NOT NULL"
+            )
+        }
+        {
+            let col = ColMeta::new()
+                .name("id")
+                .postgres_type(PostgresType::BigInt)
+                .identity(true);
+            assert_eq!(
+                col.construct_create_query_entry(),
+                "\"id\" bigint GENERATED BY DEFAULT AS IDENTITY"
+            )
+        }
+        {
+            let col = ColMeta::new()
+                .name("age")
+                .postgres_type(PostgresType::Integer)
+                .check("(age >= 0)");
+            assert_eq!(
+                col.construct_create_query_entry(),
+                "\"age\" integer CHECK ((age >= 0))"
+            )
+        }
+        {
+            let col = ColMeta::new()
+                .name("bmi")
+                .postgres_type(PostgresType::Real)
+                .generated("(weight / (height * height))");
+            assert_eq!(
+                col.construct_create_query_entry(),
+                "\"bmi\" real GENERATED ALWAYS AS \
+                ((weight / (height * height))) STORED"
             )
         }
     }
@@ -257,7 +1376,11 @@ mod tests {
             );
         }
 
-        cols.push(ColMeta::new().name("name").postgres_type("TEXT"));
+        cols.push(
+            ColMeta::new()
+                .name("name")
+                .postgres_type(PostgresType::Text),
+        );
 
         log::info!("no primary key");
         {
@@ -265,7 +1388,7 @@ mod tests {
             assert_eq!(
                 table.construct_create_query(),
                 "CREATE TABLE \"table\"(\
-                    \"name\" TEXT\
+                    \"name\" text\
                 )"
             );
         }
@@ -273,7 +1396,7 @@ mod tests {
         cols.push(
             ColMeta::new()
                 .name("id")
-                .postgres_type("INTEGER")
+                .postgres_type(PostgresType::Integer)
                 .primary_key(true),
         );
 
@@ -283,8 +1406,8 @@ mod tests {
             assert_eq!(
                 table.construct_create_query(),
                 "CREATE TABLE \"table\"(\
-                    \"name\" TEXT,\
-                    \"id\" INTEGER,\
+                    \"name\" text,\
+                    \"id\" integer,\
                     PRIMARY KEY(\"id\")\
                 )"
             );
@@ -293,7 +1416,7 @@ mod tests {
         cols.push(
             ColMeta::new()
                 .name("foreign_id")
-                .postgres_type("INTEGER")
+                .postgres_type(PostgresType::Integer)
                 .foreign_key(ForeignKey::new("foreign_table", "foreign_column"))
                 .not_null(true)
                 .unique(true)
@@ -302,18 +1425,118 @@ mod tests {
 
         log::info!("foreign key");
         {
-            let table = TableMeta::new("table", cols);
+            let table = TableMeta::new("table", cols.clone());
             assert_eq!(
                 table.construct_create_query(),
                 "CREATE TABLE \"table\"(\
-                    \"name\" TEXT,\
-                    \"id\" INTEGER,\
-                    \"foreign_id\" INTEGER NOT NULL UNIQUE REFERENCES \
+                    \"name\" text,\
+                    \"id\" integer,\
+                    \"foreign_id\" integer NOT NULL UNIQUE REFERENCES \
                     \"foreign_table\"(\"foreign_column\"),\
                     PRIMARY KEY(\"id\",\"foreign_id\")\
                 )"
             );
         }
+
+        log::info!("table constraints");
+        {
+            let table = TableMeta::new("table", cols).constraints(vec![
+                TableConstraint::Unique(vec![
+                    "name".to_string(),
+                    "id".to_string(),
+                ]),
+                TableConstraint::ForeignKey {
+                    columns: vec!["id".to_string(), "foreign_id".to_string()],
+                    table: "other".to_string(),
+                    foreign_columns: vec!["a".to_string(), "b".to_string()],
+                    on_delete: ReferentialAction::Cascade,
+                    on_update: ReferentialAction::NoAction,
+                },
+            ]);
+            assert_eq!(
+                table.construct_create_query(),
+                "CREATE TABLE \"table\"(\
+                    \"name\" text,\
+                    \"id\" integer,\
+                    \"foreign_id\" integer NOT NULL UNIQUE REFERENCES \
+                    \"foreign_table\"(\"foreign_column\"),\
+                    PRIMARY KEY(\"id\",\"foreign_id\"),\
+                    UNIQUE(\"name\",\"id\"),\
+                    FOREIGN KEY(\"id\",\"foreign_id\") \
+                    REFERENCES \"other\"(\"a\",\"b\") ON DELETE CASCADE\
+                )"
+            );
+            assert_eq!(
+                table.referenced_tables(),
+                vec!["foreign_table", "other"]
+            );
+        }
+
+        log::info!("labels");
+        {
+            let table = TableMeta::new(
+                "table",
+                vec![
+                    ColMeta::new().name("name"),
+                    ColMeta::new()
+                        .name("sex")
+                        .postgres_type(PostgresType::Integer)
+                        .label("Participant's sex")
+                        .value_labels(vec![
+                            ValueLabel::new(serde_json::json!(1), "male"),
+                            ValueLabel::new(serde_json::json!(2), "female"),
+                        ]),
+                ],
+            );
+            let comment = "{\"label\":\"Participant''s sex\",\
+                \"value_labels\":[{\"value\":1,\"label\":\"male\"},\
+                {\"value\":2,\"label\":\"female\"}]}";
+            assert_eq!(
+                table.construct_comment_queries(),
+                vec![format!(
+                    "COMMENT ON COLUMN \"table\".\"sex\" IS '{}'",
+                    comment
+                )]
+            );
+            let sex = &table.cols[1];
+            assert_eq!(
+                parse_comment(comment.replace("''", "'").as_str()),
+                (sex.label.clone(), sex.value_labels.clone())
+            );
+            assert_eq!(
+                parse_comment("Written elsewhere"),
+                (Some("Written elsewhere".to_string()), vec![])
+            );
+            assert_eq!(
+                TableAlter::SetLabels {
+                    name: "sex".to_string(),
+                    label: None,
+                    value_labels: vec![],
+                }
+                .construct_queries("table", &[]),
+                vec!["COMMENT ON COLUMN \"table\".\"sex\" IS NULL"]
+            );
+        }
+
+        log::info!("indexes");
+        {
+            let table = TableMeta::new("table", ColSpec::new()).indexes(vec![
+                TableIndex::new("table_name_id", &["name", "id"]),
+                TableIndex::new("table_symptoms", &["symptoms"])
+                    .method(IndexMethod::Gin)
+                    .unique(true)
+                    .predicate("(id > 0)"),
+            ]);
+            assert_eq!(
+                table.construct_create_index_queries(),
+                vec![
+                    "CREATE INDEX \"table_name_id\" ON \"table\" \
+                    USING btree(\"name\",\"id\")",
+                    "CREATE UNIQUE INDEX \"table_symptoms\" ON \"table\" \
+                    USING gin(\"symptoms\") WHERE (id > 0)"
+                ]
+            );
+        }
     }
     #[test]
     fn insert_table() {
@@ -324,7 +1547,14 @@ mod tests {
             table_data[0].keys().map(|k| k.to_string()).collect();
         assert_eq!(
             table.construct_param_insert_query(&col_names).unwrap(),
-            "INSERT INTO \"primary\"(\"id\",\"email\") VALUES($1,$2)"
+            "INSERT INTO \"primary\"(\"id\",\"email\") \
+            VALUES($1::integer,$2::text) \
+            RETURNING json_build_object('id',\"id\")"
+        );
+        assert_eq!(
+            table.construct_param_insert_query::<&str>(&[]).unwrap(),
+            "INSERT INTO \"primary\" DEFAULT VALUES \
+            RETURNING json_build_object('id',\"id\")"
         );
         col_names.push("another-name".to_string());
         assert!(matches!(
@@ -333,13 +1563,364 @@ mod tests {
         ));
     }
     #[test]
+    fn update_table() {
+        let _ = pretty_env_logger::try_init();
+        let table = crate::tests::get_test_primary_table();
+        assert_eq!(table.get_primary_key(), vec!["id"]);
+        assert_eq!(
+            table.construct_param_update_query(&["email"]).unwrap(),
+            "UPDATE \"primary\" SET \"email\"=$1::text \
+            WHERE \"id\"=$2::integer"
+        );
+        let visit = TableMeta::new(
+            "visit",
+            vec![
+                ColMeta::new()
+                    .name("participant")
+                    .postgres_type(PostgresType::Text)
+                    .primary_key(true),
+                ColMeta::new()
+                    .name("visit")
+                    .postgres_type(PostgresType::Integer)
+                    .primary_key(true),
+                ColMeta::new()
+                    .name("date")
+                    .postgres_type(PostgresType::Date),
+            ],
+        );
+        assert_eq!(
+            visit.construct_param_update_query(&["date"]).unwrap(),
+            "UPDATE \"visit\" SET \"date\"=$1::date \
+            WHERE \"participant\"=$2::text AND \"visit\"=$3::integer"
+        );
+        let no_key = TableMeta::new(
+            "no_key",
+            vec![ColMeta::new().name("a").postgres_type(PostgresType::Text)],
+        );
+        assert!(matches!(
+            no_key.construct_param_update_query(&["a"]).unwrap_err(),
+            Error::NoPrimaryKey(t) if t == "no_key"
+        ));
+    }
+    #[test]
+    fn upsert_table() {
+        let _ = pretty_env_logger::try_init();
+        let table = crate::tests::get_test_secondary_table();
+        let cols = ["id", "timepoint", "sick"];
+        let null_cols = ["symptoms"];
+        let upsert = |on_conflict, constraint| {
+            table
+                .construct_param_upsert_query(
+                    &cols,
+                    &null_cols,
+                    &on_conflict,
+                    constraint,
+                )
+                .unwrap()
+        };
+        let insert =
+            "INSERT INTO \"secondary\"(\"id\",\"timepoint\",\"sick\") \
+            VALUES($1::integer,$2::integer,$3::boolean)";
+        let returning = "RETURNING json_build_object(\
+            'id',\"id\",'timepoint',\"timepoint\")";
+        assert_eq!(
+            upsert(OnConflict::Error, None),
+            table.construct_param_insert_query(&cols).unwrap()
+        );
+        assert_eq!(
+            upsert(OnConflict::UpdateAll, None),
+            format!(
+                "{} ON CONFLICT (\"id\",\"timepoint\") \
+                DO UPDATE SET \"sick\"=EXCLUDED.\"sick\",\"symptoms\"=NULL \
+                {},(xmax = 0)",
+                insert, returning
+            )
+        );
+        assert_eq!(
+            upsert(OnConflict::UpdateNonNull, Some("secondary_pkey")),
+            format!(
+                "{} ON CONFLICT ON CONSTRAINT \"secondary_pkey\" \
+                DO UPDATE SET \"sick\"=EXCLUDED.\"sick\" {},(xmax = 0)",
+                insert, returning
+            )
+        );
+        assert_eq!(
+            upsert(OnConflict::DoNothing, None),
+            format!(
+                "{} ON CONFLICT (\"id\",\"timepoint\") DO NOTHING \
+                {},(xmax = 0)",
+                insert, returning
+            )
+        );
+
+        log::info!("nothing to update");
+        assert_eq!(
+            table
+                .construct_param_upsert_query::<_, &str>(
+                    &["id", "timepoint"],
+                    &[],
+                    &OnConflict::UpdateAll,
+                    None,
+                )
+                .unwrap(),
+            "INSERT INTO \"secondary\"(\"id\",\"timepoint\") \
+            VALUES($1::integer,$2::integer) \
+            ON CONFLICT (\"id\",\"timepoint\") DO NOTHING \
+            RETURNING json_build_object(\
+            'id',\"id\",'timepoint',\"timepoint\"),(xmax = 0)"
+        );
+
+        log::info!("no conflict target");
+        let no_key = TableMeta::new(
+            "no_key",
+            vec![ColMeta::new().name("a").postgres_type(PostgresType::Text)],
+        );
+        assert!(matches!(
+            no_key
+                .construct_param_upsert_query::<_, &str>(
+                    &["a"],
+                    &[],
+                    &OnConflict::DoNothing,
+                    None,
+                )
+                .unwrap_err(),
+            Error::NoPrimaryKey(t) if t == "no_key"
+        ));
+    }
+    #[test]
+    fn alter_table() {
+        let _ = pretty_env_logger::try_init();
+        let mut table = crate::tests::get_test_primary_table();
+        let alters = vec![
+            TableAlter::AddColumn(
+                ColMeta::new()
+                    .name("age")
+                    .postgres_type(PostgresType::Text)
+                    .not_null(true),
+            ),
+            TableAlter::RenameColumn {
+                name: "email".to_string(),
+                new_name: "contact".to_string(),
+            },
+            TableAlter::SetType {
+                name: "age".to_string(),
+                postgres_type: PostgresType::Integer,
+                using: Some("\"age\"::integer".to_string()),
+            },
+            TableAlter::SetNotNull {
+                name: "age".to_string(),
+                not_null: false,
+            },
+            TableAlter::SetUnique {
+                name: "contact".to_string(),
+                unique: false,
+            },
+            TableAlter::SetForeignKey {
+                name: "age".to_string(),
+                foreign_key: Some(ForeignKey::new("ages", "age")),
+            },
+            TableAlter::SetDefault {
+                name: "age".to_string(),
+                default: Some("18".to_string()),
+            },
+            TableAlter::SetCheck {
+                name: "age".to_string(),
+                check: Some("(age >= 0)".to_string()),
+            },
+            TableAlter::DropColumn("contact".to_string()),
+        ];
+        let replaced = vec!["primary_email_key".to_string()];
+        let queries: Vec<Vec<String>> = alters
+            .iter()
+            .map(|a| a.construct_queries(table.name.as_str(), &replaced))
+            .collect();
+        assert_eq!(
+            queries,
+            vec![
+                vec!["ALTER TABLE \"primary\" ADD COLUMN \"age\" text NOT NULL"],
+                vec![
+                    "ALTER TABLE \"primary\" \
+                    RENAME COLUMN \"email\" TO \"contact\""
+                ],
+                vec![
+                    "ALTER TABLE \"primary\" ALTER COLUMN \"age\" \
+                    TYPE integer USING \"age\"::integer"
+                ],
+                vec!["ALTER TABLE \"primary\" ALTER COLUMN \"age\" DROP NOT NULL"],
+                vec![
+                    "ALTER TABLE \"primary\" \
+                    DROP CONSTRAINT \"primary_email_key\""
+                ],
+                vec![
+                    "ALTER TABLE \"primary\" \
+                    DROP CONSTRAINT \"primary_email_key\"",
+                    "ALTER TABLE \"primary\" ADD FOREIGN KEY(\"age\") \
+                    REFERENCES \"ages\"(\"age\")"
+                ],
+                vec!["ALTER TABLE \"primary\" ALTER COLUMN \"age\" SET DEFAULT 18"],
+                vec![
+                    "ALTER TABLE \"primary\" \
+                    DROP CONSTRAINT \"primary_email_key\"",
+                    "ALTER TABLE \"primary\" ADD CHECK ((age >= 0))"
+                ],
+                vec!["ALTER TABLE \"primary\" DROP COLUMN \"contact\""],
+            ]
+        );
+        assert!(TableAlter::SetUnique {
+            name: "age".to_string(),
+            unique: true
+        }
+        .construct_queries("primary", &replaced)
+        .is_empty());
+
+        log::info!("apply to metadata");
+        table.constraints = vec![
+            TableConstraint::Unique(vec![
+                "id".to_string(),
+                "email".to_string(),
+            ]),
+            TableConstraint::Unique(vec!["id".to_string(), "age".to_string()]),
+        ];
+        for alter in &alters {
+            table.apply_alter(alter).unwrap();
+        }
+        // Renamed with the column, then dropped with it
+        assert_eq!(
+            table.constraints,
+            vec![TableConstraint::Unique(vec![
+                "id".to_string(),
+                "age".to_string()
+            ])]
+        );
+        let expected = vec![
+            table.cols[0].clone(),
+            ColMeta::new()
+                .name("age")
+                .postgres_type(PostgresType::Integer)
+                .foreign_key(ForeignKey::new("ages", "age"))
+                .default("18")
+                .check("(age >= 0)"),
+        ];
+        assert_eq!(table.cols, expected);
+        assert!(matches!(
+            table.apply_alter(&TableAlter::DropColumn("contact".to_string())),
+            Err(Error::NoSuchColumns(cs)) if cs == vec!["contact".to_string()]
+        ));
+        assert!(matches!(
+            table.apply_alter(&TableAlter::RenameColumn {
+                name: "age".to_string(),
+                new_name: "id".to_string()
+            }),
+            Err(Error::ColumnAlreadyExists(c)) if c == "id"
+        ));
+        assert!(matches!(
+            table.apply_alter(&TableAlter::AddColumn(ColMeta::new().name("id"))),
+            Err(Error::ColumnAlreadyExists(c)) if c == "id"
+        ));
+    }
+    #[test]
+    fn check_expressions() {
+        let _ = pretty_env_logger::try_init();
+        let col = ColMeta::new()
+            .name("age")
+            .postgres_type(PostgresType::Integer)
+            .default("0")
+            .check("age >= 0");
+        assert!(col.check_expressions().is_ok());
+        for bad in &[
+            "0; DROP TABLE \"primary\"",
+            "0 -- rest",
+            "0 /* rest */",
+            "length(pg_read_file('/etc/passwd'))",
+        ] {
+            assert!(matches!(
+                col.clone().default(bad).check_expressions(),
+                Err(Error::InvalidDefinition(_))
+            ));
+            assert!(matches!(
+                TableAlter::SetType {
+                    name: "age".to_string(),
+                    postgres_type: PostgresType::Text,
+                    using: Some(bad.to_string())
+                }
+                .check_expressions(),
+                Err(Error::InvalidDefinition(_))
+            ));
+            assert!(matches!(
+                TableAlter::SetDefault {
+                    name: "age".to_string(),
+                    default: Some(bad.to_string())
+                }
+                .check_expressions(),
+                Err(Error::InvalidDefinition(_))
+            ));
+            assert!(matches!(
+                TableAlter::SetCheck {
+                    name: "age".to_string(),
+                    check: Some(format!("age > {}", bad))
+                }
+                .check_expressions(),
+                Err(Error::InvalidDefinition(_))
+            ));
+        }
+        let table = TableMeta::new("table", vec![col])
+            .constraints(vec![TableConstraint::Check("true;".to_string())]);
+        assert!(matches!(
+            table.check_expressions(),
+            Err(Error::InvalidDefinition(_))
+        ));
+        assert!(matches!(
+            TableConstraint::Check(
+                "(query_to_xml('SELECT * FROM pg_authid', true, true, '') \
+                IS NOT NULL)"
+                    .to_string()
+            )
+            .check_expressions(),
+            Err(Error::InvalidDefinition(_))
+        ));
+        let exclusion = |operator: &str| TableConstraint::Exclude {
+            method: IndexMethod::Gist,
+            elements: vec![ExclusionElement::new("age", operator)],
+        };
+        assert!(exclusion("&&").check_expressions().is_ok());
+        for bad in &["", "=) WHERE (true", "=--", "=/*", "OPERATOR(=)"] {
+            assert!(matches!(
+                exclusion(bad).check_expressions(),
+                Err(Error::InvalidDefinition(_))
+            ));
+        }
+        assert_eq!(
+            TableConstraint::Exclude {
+                method: IndexMethod::Gist,
+                elements: vec![
+                    ExclusionElement::new("room", "="),
+                    ExclusionElement::new("during", "&&"),
+                ],
+            }
+            .create_query_entry(),
+            "EXCLUDE USING gist (\"room\" WITH =,\"during\" WITH &&)"
+        );
+        assert!(TableIndex::new("index", &["age"])
+            .predicate("(age >= 18)")
+            .check_expressions()
+            .is_ok());
+        for bad in &["age > 0 --", "lo_export(16384, '/tmp/out') > 0"] {
+            assert!(matches!(
+                TableIndex::new("index", &["age"])
+                    .predicate(bad)
+                    .check_expressions(),
+                Err(Error::InvalidDefinition(_))
+            ));
+        }
+    }
+    #[test]
     fn compare_metadata() {
         let primary_meta1 = crate::tests::get_test_primary_table();
         let secondary_meta1 = crate::tests::get_test_secondary_table();
         assert_ne!(primary_meta1, secondary_meta1);
 
         let mut primary_meta2 = primary_meta1.clone();
-        primary_meta2.cols[0].postgres_type = "integer".to_string();
+        primary_meta2.cols[0].postgres_type = "int4".parse().unwrap();
         assert_eq!(primary_meta1, primary_meta2);
 
         primary_meta2.cols[0].unique = true;
@@ -350,5 +1931,53 @@ mod tests {
 
         primary_meta2.cols[0].primary_key = false;
         assert_ne!(primary_meta1, primary_meta2);
+
+        let mut primary_meta2 = primary_meta1.clone();
+        primary_meta2.cols[1].default = Some("'none'::text".to_string());
+        assert_ne!(primary_meta1, primary_meta2);
+
+        let unique = TableConstraint::Unique(vec![
+            "id".to_string(),
+            "email".to_string(),
+        ]);
+        let foreign_key = TableConstraint::ForeignKey {
+            columns: vec!["id".to_string()],
+            table: "other".to_string(),
+            foreign_columns: vec!["id".to_string()],
+            on_delete: ReferentialAction::SetNull,
+            on_update: ReferentialAction::Restrict,
+        };
+        assert_eq!(
+            primary_meta1
+                .clone()
+                .constraints(vec![unique.clone(), foreign_key.clone()]),
+            primary_meta1
+                .clone()
+                .constraints(vec![foreign_key, unique.clone()])
+        );
+        assert_ne!(
+            primary_meta1.clone().constraints(vec![unique.clone()]),
+            primary_meta1
+        );
+
+        log::info!("single-column constraints are column-level");
+        let mut normalized = primary_meta1.clone().constraints(vec![
+            unique.clone(),
+            TableConstraint::Unique(vec!["email".to_string()]),
+            TableConstraint::ForeignKey {
+                columns: vec!["email".to_string()],
+                table: "other".to_string(),
+                foreign_columns: vec!["email".to_string()],
+                on_delete: ReferentialAction::NoAction,
+                on_update: ReferentialAction::NoAction,
+            },
+        ]);
+        normalized.normalize_constraints();
+        assert_eq!(normalized.constraints, vec![unique]);
+        assert!(normalized.cols[1].unique);
+        assert_eq!(
+            normalized.cols[1].foreign_key,
+            Some(ForeignKey::new("other", "email"))
+        );
     }
 }
@@ -0,0 +1,251 @@
+use crate::{Error, Result};
+
+/// Column type as understood by Postgres.
+/// Serialized as the spelling `format_type` uses in the catalog so that
+/// submitted and introspected metadata compare equal.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum PostgresType {
+    SmallInt,
+    Integer,
+    BigInt,
+    Real,
+    DoublePrecision,
+    /// Optional precision and scale
+    Numeric(Option<(u32, u32)>),
+    Text,
+    /// Optional maximum length
+    Varchar(Option<u32>),
+    Boolean,
+    Date,
+    Time,
+    Timestamp,
+    Timestamptz,
+    Uuid,
+    Json,
+    Jsonb,
+    Bytea,
+    Interval,
+    /// One-dimensional array of the element type
+    Array(Box<PostgresType>),
+}
+
+impl PostgresType {
+    /// Whether this is an array type
+    pub fn is_array(&self) -> bool {
+        matches!(self, Self::Array(_))
+    }
+    /// Whether values of this type, or the elements of this array type,
+    /// are strings
+    pub fn is_string(&self) -> bool {
+        match self {
+            Self::Text | Self::Varchar(_) => true,
+            Self::Array(el) => el.is_string(),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for PostgresType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SmallInt => write!(f, "smallint"),
+            Self::Integer => write!(f, "integer"),
+            Self::BigInt => write!(f, "bigint"),
+            Self::Real => write!(f, "real"),
+            Self::DoublePrecision => write!(f, "double precision"),
+            Self::Numeric(None) => write!(f, "numeric"),
+            Self::Numeric(Some((p, s))) => write!(f, "numeric({},{})", p, s),
+            Self::Text => write!(f, "text"),
+            Self::Varchar(None) => write!(f, "character varying"),
+            Self::Varchar(Some(n)) => write!(f, "character varying({})", n),
+            Self::Boolean => write!(f, "boolean"),
+            Self::Date => write!(f, "date"),
+            Self::Time => write!(f, "time without time zone"),
+            Self::Timestamp => write!(f, "timestamp without time zone"),
+            Self::Timestamptz => write!(f, "timestamp with time zone"),
+            Self::Uuid => write!(f, "uuid"),
+            Self::Json => write!(f, "json"),
+            Self::Jsonb => write!(f, "jsonb"),
+            Self::Bytea => write!(f, "bytea"),
+            Self::Interval => write!(f, "interval"),
+            Self::Array(el) => write!(f, "{}[]", el),
+        }
+    }
+}
+
+impl std::str::FromStr for PostgresType {
+    type Err = Error;
+    /// Accepts the `format_type` spellings as well as the common aliases
+    /// (`int4`, `varchar(n)`, `timestamptz` etc.), case-insensitive.
+    fn from_str(s: &str) -> Result<Self> {
+        let unsupported = || Error::UnsupportedPostgresType(s.to_string());
+        // Lowercase and collapse whitespace
+        let norm = s
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ")
+            .to_lowercase();
+        if let Some(el) = norm.strip_suffix("[]") {
+            let el: Self = el.parse().map_err(|_| unsupported())?;
+            // Postgres does not distinguish array dimensions
+            if el.is_array() {
+                return Ok(el);
+            }
+            return Ok(Self::Array(Box::new(el)));
+        }
+        // Split off the type modifier, e.g. "(10,2)"
+        let (name, args) = match norm.find('(') {
+            Some(i) => {
+                let args = norm[i..]
+                    .strip_prefix('(')
+                    .and_then(|a| a.strip_suffix(')'))
+                    .ok_or_else(unsupported)?;
+                let args = args
+                    .split(',')
+                    .map(|a| a.trim().parse::<u32>())
+                    .collect::<std::result::Result<Vec<u32>, _>>()
+                    .map_err(|_| unsupported())?;
+                (norm[..i].trim(), Some(args))
+            }
+            None => (norm.as_str(), None),
+        };
+        let typ = match (name, args.as_deref()) {
+            ("smallint", None) | ("int2", None) => Self::SmallInt,
+            ("integer", None) | ("int", None) | ("int4", None) => Self::Integer,
+            ("bigint", None) | ("int8", None) => Self::BigInt,
+            ("real", None) | ("float4", None) => Self::Real,
+            ("double precision", None) | ("float8", None) => {
+                Self::DoublePrecision
+            }
+            ("numeric", None) | ("decimal", None) => Self::Numeric(None),
+            ("numeric", Some([p])) | ("decimal", Some([p])) => {
+                Self::Numeric(Some((*p, 0)))
+            }
+            ("numeric", Some([p, s])) | ("decimal", Some([p, s])) => {
+                Self::Numeric(Some((*p, *s)))
+            }
+            ("text", None) => Self::Text,
+            ("character varying", None) | ("varchar", None) => {
+                Self::Varchar(None)
+            }
+            ("character varying", Some([n])) | ("varchar", Some([n])) => {
+                Self::Varchar(Some(*n))
+            }
+            ("boolean", None) | ("bool", None) => Self::Boolean,
+            ("date", None) => Self::Date,
+            ("time", None) | ("time without time zone", None) => Self::Time,
+            ("timestamp", None) | ("timestamp without time zone", None) => {
+                Self::Timestamp
+            }
+            ("timestamptz", None) | ("timestamp with time zone", None) => {
+                Self::Timestamptz
+            }
+            ("uuid", None) => Self::Uuid,
+            ("json", None) => Self::Json,
+            ("jsonb", None) => Self::Jsonb,
+            ("bytea", None) => Self::Bytea,
+            ("interval", None) => Self::Interval,
+            _ => return Err(unsupported()),
+        };
+        Ok(typ)
+    }
+}
+
+impl std::convert::TryFrom<String> for PostgresType {
+    type Error = Error;
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<PostgresType> for String {
+    fn from(t: PostgresType) -> Self {
+        t.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn parse_type() {
+        let _ = pretty_env_logger::try_init();
+        assert_eq!(
+            "INTEGER".parse::<PostgresType>().unwrap(),
+            PostgresType::Integer
+        );
+        assert_eq!(
+            "int4".parse::<PostgresType>().unwrap(),
+            PostgresType::Integer
+        );
+        assert_eq!(
+            "TIMESTAMPTZ".parse::<PostgresType>().unwrap(),
+            PostgresType::Timestamptz
+        );
+        assert_eq!(
+            "timestamp  with time zone".parse::<PostgresType>().unwrap(),
+            PostgresType::Timestamptz
+        );
+        assert_eq!(
+            "numeric(10)".parse::<PostgresType>().unwrap(),
+            PostgresType::Numeric(Some((10, 0)))
+        );
+        assert_eq!(
+            "DECIMAL(10, 2)".parse::<PostgresType>().unwrap(),
+            PostgresType::Numeric(Some((10, 2)))
+        );
+        assert_eq!(
+            "varchar(20)".parse::<PostgresType>().unwrap(),
+            PostgresType::Varchar(Some(20))
+        );
+        assert_eq!(
+            "text[]".parse::<PostgresType>().unwrap(),
+            PostgresType::Array(Box::new(PostgresType::Text))
+        );
+        assert_eq!(
+            "int[][]".parse::<PostgresType>().unwrap(),
+            PostgresType::Array(Box::new(PostgresType::Integer))
+        );
+        for bad in &["", "integer(3)", "varchar(a)", "numeric(1,2,3)", "xml"] {
+            assert!(matches!(
+                bad.parse::<PostgresType>().unwrap_err(),
+                Error::UnsupportedPostgresType(s) if &s == bad
+            ));
+        }
+    }
+    #[test]
+    fn round_trip_type() {
+        let _ = pretty_env_logger::try_init();
+        let types = vec![
+            PostgresType::SmallInt,
+            PostgresType::Integer,
+            PostgresType::BigInt,
+            PostgresType::Real,
+            PostgresType::DoublePrecision,
+            PostgresType::Numeric(None),
+            PostgresType::Numeric(Some((10, 2))),
+            PostgresType::Text,
+            PostgresType::Varchar(None),
+            PostgresType::Varchar(Some(5)),
+            PostgresType::Boolean,
+            PostgresType::Date,
+            PostgresType::Time,
+            PostgresType::Timestamp,
+            PostgresType::Timestamptz,
+            PostgresType::Uuid,
+            PostgresType::Json,
+            PostgresType::Jsonb,
+            PostgresType::Bytea,
+            PostgresType::Interval,
+            PostgresType::Array(Box::new(PostgresType::Numeric(Some((3, 1))))),
+        ];
+        for t in types {
+            assert_eq!(t.to_string().parse::<PostgresType>().unwrap(), t);
+            let json = serde_json::to_string(&t).unwrap();
+            assert_eq!(json, format!("\"{}\"", t));
+            assert_eq!(serde_json::from_str::<PostgresType>(&json).unwrap(), t);
+        }
+        assert!(serde_json::from_str::<PostgresType>("\"xml\"").is_err());
+    }
+}
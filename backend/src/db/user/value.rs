@@ -0,0 +1,432 @@
+use super::types::PostgresType;
+use serde_json::Value;
+use std::convert::TryInto;
+
+type Query<'q> = sqlx::query::Query<
+    'q,
+    sqlx::postgres::Postgres,
+    sqlx::postgres::PgArguments,
+>;
+
+/// A JSON value converted to what its target column expects.
+/// Types without a native Rust counterpart (numeric, uuid, interval) are
+/// kept as text and cast in the query. Numeric and interval text is left for
+/// Postgres to parse, its data exceptions are reported for the row.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BindValue {
+    Null,
+    Bool(bool),
+    SmallInt(i16),
+    Integer(i32),
+    BigInt(i64),
+    Real(f32),
+    Double(f64),
+    Text(String),
+    Bytes(Vec<u8>),
+    Date(chrono::NaiveDate),
+    Time(chrono::NaiveTime),
+    Timestamp(chrono::NaiveDateTime),
+    Timestamptz(chrono::DateTime<chrono::Utc>),
+    Json(Value),
+    /// Elements in their text form, cast to the column type in the query
+    Array(Vec<Option<String>>),
+}
+
+impl BindValue {
+    /// Converts a JSON value to the given type.
+    /// Strings are accepted for every type so that text sources (forms,
+    /// CSV) go through the same path. Returns the reason on failure.
+    pub fn from_json(
+        value: &Value,
+        typ: &PostgresType,
+    ) -> std::result::Result<Self, String> {
+        use PostgresType as T;
+        if value.is_null() {
+            return Ok(Self::Null);
+        }
+        let converted = match typ {
+            T::SmallInt => Self::SmallInt(
+                to_integer(value)?
+                    .try_into()
+                    .map_err(|_| "out of range for smallint")?,
+            ),
+            T::Integer => Self::Integer(
+                to_integer(value)?
+                    .try_into()
+                    .map_err(|_| "out of range for integer")?,
+            ),
+            T::BigInt => Self::BigInt(to_integer(value)?),
+            T::Real => {
+                let f = to_float(value)?;
+                if f.is_finite() && f.abs() > f32::MAX as f64 {
+                    return Err("out of range for real".to_string());
+                }
+                Self::Real(f as f32)
+            }
+            T::DoublePrecision => Self::Double(to_float(value)?),
+            T::Numeric(_) => Self::Text(to_numeric(value)?),
+            T::Text => Self::Text(to_str(value)?.to_string()),
+            T::Varchar(max) => {
+                let s = to_str(value)?;
+                if let Some(max) = max {
+                    if s.chars().count() > *max as usize {
+                        return Err(format!("longer than {} characters", max));
+                    }
+                }
+                Self::Text(s.to_string())
+            }
+            T::Boolean => Self::Bool(to_bool(value)?),
+            T::Date => Self::Date(
+                chrono::NaiveDate::parse_from_str(to_str(value)?, "%Y-%m-%d")
+                    .map_err(|e| e.to_string())?,
+            ),
+            T::Time => Self::Time(to_time(to_str(value)?)?),
+            T::Timestamp => Self::Timestamp(to_timestamp(to_str(value)?)?),
+            T::Timestamptz => {
+                Self::Timestamptz(to_timestamptz(to_str(value)?)?)
+            }
+            T::Uuid => Self::Text(to_uuid(to_str(value)?)?),
+            T::Json | T::Jsonb => Self::Json(value.clone()),
+            T::Bytea => Self::Bytes(to_bytes(to_str(value)?)?),
+            T::Interval => Self::Text(to_str(value)?.to_string()),
+            T::Array(el) => {
+                let arr = value.as_array().ok_or("expected an array")?;
+                let mut converted = Vec::with_capacity(arr.len());
+                for (i, v) in arr.iter().enumerate() {
+                    let v = Self::from_json(v, el)
+                        .map_err(|e| format!("element {}: {}", i, e))?;
+                    converted.push(v.to_text());
+                }
+                Self::Array(converted)
+            }
+        };
+        Ok(converted)
+    }
+
+    /// Postgres input text for the value, `None` for null
+    pub fn to_text(&self) -> Option<String> {
+        let text = match self {
+            Self::Null => return None,
+            Self::Bool(b) => b.to_string(),
+            Self::SmallInt(i) => i.to_string(),
+            Self::Integer(i) => i.to_string(),
+            Self::BigInt(i) => i.to_string(),
+            Self::Real(f) => f.to_string(),
+            Self::Double(f) => f.to_string(),
+            Self::Text(s) => s.clone(),
+            Self::Bytes(b) => format!("\\x{}", hex::encode(b)),
+            Self::Date(d) => d.to_string(),
+            Self::Time(t) => t.to_string(),
+            Self::Timestamp(t) => t.to_string(),
+            Self::Timestamptz(t) => t.to_rfc3339(),
+            Self::Json(v) => v.to_string(),
+            Self::Array(a) => {
+                let els: Vec<String> = a
+                    .iter()
+                    .map(|e| match e {
+                        None => "NULL".to_string(),
+                        Some(e) => format!(
+                            "\"{}\"",
+                            e.replace('\\', "\\\\").replace('"', "\\\"")
+                        ),
+                    })
+                    .collect();
+                format!("{{{}}}", els.join(","))
+            }
+        };
+        Some(text)
+    }
+
+    /// Field in the `COPY` text format, `\N` for null
+    pub fn to_copy_text(&self) -> String {
+        match self.to_text() {
+            None => "\\N".to_string(),
+            Some(text) => text
+                .replace('\\', "\\\\")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r")
+                .replace('\t', "\\t"),
+        }
+    }
+
+    /// Binds the value to the next query parameter
+    pub fn bind(self, query: Query<'_>) -> Query<'_> {
+        match self {
+            Self::Null => query.bind(Option::<String>::None),
+            Self::Bool(b) => query.bind(b),
+            Self::SmallInt(i) => query.bind(i),
+            Self::Integer(i) => query.bind(i),
+            Self::BigInt(i) => query.bind(i),
+            Self::Real(f) => query.bind(f),
+            Self::Double(f) => query.bind(f),
+            Self::Text(s) => query.bind(s),
+            Self::Bytes(b) => query.bind(b),
+            Self::Date(d) => query.bind(d),
+            Self::Time(t) => query.bind(t),
+            Self::Timestamp(t) => query.bind(t),
+            Self::Timestamptz(t) => query.bind(t),
+            Self::Json(v) => query.bind(v),
+            Self::Array(a) => query.bind(a),
+        }
+    }
+}
+
+fn to_str(value: &Value) -> std::result::Result<&str, String> {
+    value
+        .as_str()
+        .ok_or_else(|| "expected a string".to_string())
+}
+
+fn to_integer(value: &Value) -> std::result::Result<i64, String> {
+    match value {
+        Value::Number(n) => match (n.as_i64(), n.as_f64()) {
+            (Some(i), _) => Ok(i),
+            (None, Some(f))
+                if f.fract() == 0.0
+                    && f >= i64::MIN as f64
+                    && f <= i64::MAX as f64 =>
+            {
+                Ok(f as i64)
+            }
+            _ => Err(format!("{} is not an integer", n)),
+        },
+        Value::String(s) => s
+            .trim()
+            .parse()
+            .map_err(|_| format!("\"{}\" is not an integer", s)),
+        _ => Err("expected an integer".to_string()),
+    }
+}
+
+fn to_float(value: &Value) -> std::result::Result<f64, String> {
+    match value {
+        // Always representable as f64 without arbitrary precision
+        Value::Number(n) => Ok(n.as_f64().unwrap()),
+        Value::String(s) => match s.trim().to_lowercase().as_str() {
+            "nan" => Ok(f64::NAN),
+            "infinity" => Ok(f64::INFINITY),
+            "-infinity" => Ok(f64::NEG_INFINITY),
+            t => t.parse().map_err(|_| format!("\"{}\" is not a number", s)),
+        },
+        _ => Err("expected a number".to_string()),
+    }
+}
+
+/// Keeps the text as is so that no precision is lost
+fn to_numeric(value: &Value) -> std::result::Result<String, String> {
+    match value {
+        Value::Number(n) => Ok(n.to_string()),
+        Value::String(s) => Ok(s.trim().to_string()),
+        _ => Err("expected a number".to_string()),
+    }
+}
+
+fn to_bool(value: &Value) -> std::result::Result<bool, String> {
+    match value {
+        Value::Bool(b) => Ok(*b),
+        Value::Number(n) => match n.as_u64() {
+            Some(1) => Ok(true),
+            Some(0) => Ok(false),
+            _ => Err(format!("{} is not a boolean", n)),
+        },
+        Value::String(s) => match s.trim().to_lowercase().as_str() {
+            "true" | "t" | "yes" | "y" | "on" | "1" => Ok(true),
+            "false" | "f" | "no" | "n" | "off" | "0" => Ok(false),
+            _ => Err(format!("\"{}\" is not a boolean", s)),
+        },
+        _ => Err("expected a boolean".to_string()),
+    }
+}
+
+fn to_time(s: &str) -> std::result::Result<chrono::NaiveTime, String> {
+    chrono::NaiveTime::parse_from_str(s, "%H:%M:%S%.f")
+        .or_else(|_| chrono::NaiveTime::parse_from_str(s, "%H:%M"))
+        .map_err(|e| format!("\"{}\" is not a time: {}", s, e))
+}
+
+fn to_timestamp(s: &str) -> std::result::Result<chrono::NaiveDateTime, String> {
+    chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f")
+        .or_else(|_| {
+            chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f")
+        })
+        .map_err(|e| format!("\"{}\" is not a timestamp: {}", s, e))
+}
+
+fn to_timestamptz(
+    s: &str,
+) -> std::result::Result<chrono::DateTime<chrono::Utc>, String> {
+    chrono::DateTime::parse_from_rfc3339(s)
+        .or_else(|_| {
+            chrono::DateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f%#z")
+        })
+        .map(|t| t.with_timezone(&chrono::Utc))
+        .map_err(|e| format!("\"{}\" is not a timestamp with zone: {}", s, e))
+}
+
+/// Accepts hyphenated, braced and plain hex forms
+fn to_uuid(s: &str) -> std::result::Result<String, String> {
+    let hex: String = s
+        .trim()
+        .trim_start_matches('{')
+        .trim_end_matches('}')
+        .chars()
+        .filter(|c| *c != '-')
+        .collect();
+    if hex.len() != 32 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("\"{}\" is not a uuid", s));
+    }
+    Ok(hex)
+}
+
+/// Postgres hex format, e.g. "\\x0aff", as produced on output
+fn to_bytes(s: &str) -> std::result::Result<Vec<u8>, String> {
+    let hex = s
+        .strip_prefix("\\x")
+        .ok_or_else(|| format!("\"{}\" is not in \\x hex format", s))?;
+    hex::decode(hex).map_err(|e| format!("\"{}\": {}", s, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    #[test]
+    fn convert_values() {
+        let _ = pretty_env_logger::try_init();
+        use PostgresType as T;
+        let ok = |v: Value, t: T| BindValue::from_json(&v, &t).unwrap();
+        let err = |v: Value, t: T| BindValue::from_json(&v, &t).unwrap_err();
+
+        assert_eq!(ok(json!(null), T::Integer), BindValue::Null);
+
+        log::info!("integers");
+        assert_eq!(ok(json!(1), T::Integer), BindValue::Integer(1));
+        assert_eq!(ok(json!(2.0), T::SmallInt), BindValue::SmallInt(2));
+        assert_eq!(ok(json!(" 3 "), T::BigInt), BindValue::BigInt(3));
+        assert_eq!(
+            ok(json!(9007199254740993i64), T::BigInt),
+            BindValue::BigInt(9007199254740993)
+        );
+        assert_eq!(err(json!(1.5), T::Integer), "1.5 is not an integer");
+        assert_eq!(err(json!(40000), T::SmallInt), "out of range for smallint");
+        assert_eq!(err(json!(true), T::Integer), "expected an integer");
+
+        log::info!("floats and numerics");
+        assert_eq!(ok(json!(1.5), T::Real), BindValue::Real(1.5));
+        assert_eq!(
+            ok(json!("2.5"), T::DoublePrecision),
+            BindValue::Double(2.5)
+        );
+        assert_eq!(
+            ok(json!("12345678901234567890.123"), T::Numeric(None)),
+            BindValue::Text("12345678901234567890.123".to_string())
+        );
+        assert_eq!(
+            ok(json!(-1.5e3), T::Numeric(None)),
+            BindValue::Text("-1500.0".to_string())
+        );
+        assert_eq!(
+            ok(json!(".5E-3"), T::Numeric(Some((5, 2)))),
+            BindValue::Text(".5E-3".to_string())
+        );
+        assert_eq!(
+            ok(json!(" 1e3 "), T::Numeric(None)),
+            BindValue::Text("1e3".to_string())
+        );
+        assert_eq!(err(json!(true), T::Numeric(None)), "expected a number");
+
+        log::info!("text");
+        assert_eq!(ok(json!("a"), T::Text), BindValue::Text("a".to_string()));
+        assert_eq!(err(json!(1), T::Text), "expected a string");
+        assert_eq!(
+            ok(json!("äbc"), T::Varchar(Some(3))),
+            BindValue::Text("äbc".to_string())
+        );
+        assert_eq!(
+            err(json!("abcd"), T::Varchar(Some(3))),
+            "longer than 3 characters"
+        );
+
+        log::info!("booleans");
+        assert_eq!(ok(json!(true), T::Boolean), BindValue::Bool(true));
+        assert_eq!(ok(json!("No"), T::Boolean), BindValue::Bool(false));
+        assert_eq!(ok(json!(1), T::Boolean), BindValue::Bool(true));
+        assert_eq!(ok(json!(0), T::Boolean), BindValue::Bool(false));
+        assert_eq!(err(json!(2), T::Boolean), "2 is not a boolean");
+        assert_eq!(
+            err(json!("maybe"), T::Boolean),
+            "\"maybe\" is not a boolean"
+        );
+
+        log::info!("dates and times");
+        assert!(matches!(
+            ok(json!("2020-02-29"), T::Date),
+            BindValue::Date(_)
+        ));
+        assert!(BindValue::from_json(&json!("2021-02-29"), &T::Date).is_err());
+        assert!(matches!(ok(json!("13:45"), T::Time), BindValue::Time(_)));
+        assert!(matches!(
+            ok(json!("2020-01-01 13:45:00.5"), T::Timestamp),
+            BindValue::Timestamp(_)
+        ));
+        assert_eq!(
+            ok(json!("2020-01-01T10:00:00+10:00"), T::Timestamptz).to_text(),
+            Some("2020-01-01T00:00:00+00:00".to_string())
+        );
+        assert!(matches!(
+            ok(json!("2020-01-01 00:00:00+00"), T::Timestamptz),
+            BindValue::Timestamptz(_)
+        ));
+        assert!(BindValue::from_json(&json!("2020-01-01"), &T::Timestamptz)
+            .is_err());
+
+        log::info!("uuid, bytea, interval, json");
+        assert_eq!(
+            ok(json!("A0EEBC99-9C0B-4EF8-BB6D-6BB9BD380A11"), T::Uuid),
+            BindValue::Text("A0EEBC999C0B4EF8BB6D6BB9BD380A11".to_string())
+        );
+        assert_eq!(err(json!("a0ee"), T::Uuid), "\"a0ee\" is not a uuid");
+        assert_eq!(
+            ok(json!("\\x00ff"), T::Bytea),
+            BindValue::Bytes(vec![0, 255])
+        );
+        assert!(BindValue::from_json(&json!("00ff"), &T::Bytea).is_err());
+        assert_eq!(
+            ok(json!("1 day 02:00"), T::Interval),
+            BindValue::Text("1 day 02:00".to_string())
+        );
+        assert_eq!(err(json!(1), T::Interval), "expected a string");
+        assert_eq!(
+            ok(json!({"a": [1]}), T::Jsonb),
+            BindValue::Json(json!({"a": [1]}))
+        );
+
+        log::info!("arrays");
+        let arr = ok(json!([1, null, "3"]), T::Array(Box::new(T::Integer)));
+        assert_eq!(
+            arr,
+            BindValue::Array(vec![
+                Some("1".to_string()),
+                None,
+                Some("3".to_string())
+            ])
+        );
+        assert_eq!(arr.to_text(), Some("{\"1\",NULL,\"3\"}".to_string()));
+        assert_eq!(
+            err(json!([1, "a"]), T::Array(Box::new(T::Integer))),
+            "element 1: \"a\" is not an integer"
+        );
+        assert_eq!(
+            err(json!(1), T::Array(Box::new(T::Integer))),
+            "expected an array"
+        );
+
+        log::info!("copy text");
+        assert_eq!(BindValue::Null.to_copy_text(), "\\N");
+        assert_eq!(
+            ok(json!("a\tb\nc\\"), T::Text).to_copy_text(),
+            "a\\tb\\nc\\\\"
+        );
+        assert_eq!(ok(json!("\\x00ff"), T::Bytea).to_copy_text(), "\\\\x00ff");
+    }
+}
@@ -0,0 +1,603 @@
+use super::import::json_mapping;
+use super::table::{ColMeta, InsertReport, RowJson, TableMeta};
+use super::types::PostgresType;
+use crate::{error::RowError, Error, Result};
+use calamine::{Data, Range, Reader};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Sheet describing the columns of the other sheets
+pub const DICTIONARY_SHEET: &str = "dictionary";
+
+/// Longest sheet name Excel allows
+const MAX_SHEET_NAME: usize = 31;
+
+/// Largest integer a spreadsheet number holds exactly
+const MAX_EXACT_INTEGER: i64 = 1 << 53;
+
+/// How to read a workbook into tables
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct XlsxOptions {
+    /// Table of each sheet, null to skip the sheet. Unmapped sheets go to
+    /// the table the dictionary sheet names, or else to the table of the
+    /// same name. A json object in a query string.
+    #[serde(default, deserialize_with = "json_mapping")]
+    pub mapping: HashMap<String, Option<String>>,
+}
+
+impl XlsxOptions {
+    pub fn map(mut self, sheet: &str, table: Option<&str>) -> Self {
+        self.mapping
+            .insert(sheet.to_string(), table.map(|t| t.to_string()));
+        self
+    }
+}
+
+/// Result of importing a workbook
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct XlsxReport {
+    /// Sheets in the order they were inserted
+    pub sheets: Vec<SheetReport>,
+}
+
+/// Result of inserting a sheet
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct SheetReport {
+    pub sheet: String,
+    pub table: String,
+    /// Outcome of inserting the rows, with the rows numbered as in the
+    /// sheet
+    pub insert: InsertReport,
+}
+
+/// Rows read from a sheet
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SheetRows {
+    pub rows: Vec<RowJson>,
+    /// Sheet row number of each row
+    pub records: Vec<usize>,
+    /// Failures of the sheet rows that were not read
+    pub errors: Vec<RowError>,
+}
+
+/// Names of the sheets of the tables. Characters Excel does not allow are
+/// replaced, long names are cut and repeated names are numbered.
+pub fn sheet_names(tables: &[TableMeta]) -> Vec<String> {
+    let mut names: Vec<String> = vec![DICTIONARY_SHEET.to_string()];
+    for table in tables {
+        let base: String = table
+            .name
+            .chars()
+            .map(|c| if "[]:*?/\\".contains(c) { '_' } else { c })
+            .collect();
+        let base = base.trim_matches('\'').to_string();
+        let taken = |name: &str| {
+            name.is_empty()
+                || names
+                    .iter()
+                    .any(|n| n.to_lowercase() == name.to_lowercase())
+        };
+        let mut name: String = base.chars().take(MAX_SHEET_NAME).collect();
+        let mut i = 2;
+        while taken(&name) {
+            let suffix = format!("~{}", i);
+            name = base
+                .chars()
+                .take(MAX_SHEET_NAME - suffix.len())
+                .chain(suffix.chars())
+                .collect();
+            i += 1;
+        }
+        names.push(name);
+    }
+    names.remove(0);
+    names
+}
+
+/// Workbook with a dictionary sheet describing the tables' columns and a
+/// sheet of rows for every table, written a row at a time.
+/// Sheets of rows are kept in temporary files rather than in memory until
+/// the workbook is finished.
+pub struct XlsxWriter {
+    workbook: rust_xlsxwriter::Workbook,
+    /// Table names in sheet order
+    tables: Vec<String>,
+    /// Sheet name of each table
+    names: Vec<String>,
+    /// Next row of each table's sheet
+    next_rows: Vec<u32>,
+}
+
+impl XlsxWriter {
+    /// Starts the workbook with the dictionary sheet and the column names
+    /// of every table's sheet
+    pub fn new(tables: &[TableMeta]) -> Result<Self> {
+        use rust_xlsxwriter::{Format, Workbook};
+        let names = sheet_names(tables);
+        let header = Format::new().set_bold();
+        let mut workbook = Workbook::new();
+
+        let dictionary = workbook.add_worksheet().set_name(DICTIONARY_SHEET)?;
+        let dictionary_header = [
+            "sheet",
+            "table",
+            "column",
+            "type",
+            "primary key",
+            "not null",
+            "unique",
+            "identity",
+            "foreign key",
+            "default",
+            "check",
+            "generated",
+        ];
+        dictionary.write_row_with_format(0, 0, dictionary_header, &header)?;
+        dictionary.set_freeze_panes(1, 0)?;
+        let mut row = 1;
+        for (table, sheet) in tables.iter().zip(&names) {
+            for col in &table.cols {
+                dictionary.write_string(row, 0, sheet)?;
+                dictionary.write_string(row, 1, &table.name)?;
+                dictionary.write_string(row, 2, &col.name)?;
+                dictionary.write_string(
+                    row,
+                    3,
+                    col.postgres_type.to_string(),
+                )?;
+                dictionary.write_boolean(row, 4, col.primary_key)?;
+                dictionary.write_boolean(row, 5, col.not_null)?;
+                dictionary.write_boolean(row, 6, col.unique)?;
+                dictionary.write_boolean(row, 7, col.identity)?;
+                let foreign_key = col
+                    .foreign_key
+                    .as_ref()
+                    .map(|fk| format!("{}({})", fk.table, fk.column));
+                let texts =
+                    [&foreign_key, &col.default, &col.check, &col.generated];
+                for (i, text) in texts.iter().enumerate() {
+                    if let Some(text) = text {
+                        dictionary.write_string(row, 8 + i as u16, text)?;
+                    }
+                }
+                row += 1;
+            }
+        }
+
+        for (table, sheet) in tables.iter().zip(&names) {
+            let worksheet = workbook
+                .add_worksheet_with_constant_memory()
+                .set_name(sheet)?;
+            let cols: Vec<&str> =
+                table.cols.iter().map(|c| c.name.as_str()).collect();
+            worksheet.write_row_with_format(0, 0, cols, &header)?;
+            worksheet.set_freeze_panes(1, 0)?;
+        }
+        Ok(Self {
+            workbook,
+            tables: tables.iter().map(|t| t.name.clone()).collect(),
+            names,
+            next_rows: vec![1; tables.len()],
+        })
+    }
+    /// Writes a row on the table's sheet. Each sheet's rows are written in
+    /// order, one table's rows can follow another's.
+    pub fn write_row(
+        &mut self,
+        table: &TableMeta,
+        row: &RowJson,
+    ) -> Result<()> {
+        let i = self
+            .tables
+            .iter()
+            .position(|t| t == &table.name)
+            .ok_or_else(|| Error::NoSuchTable(table.name.clone()))?;
+        let worksheet = self.workbook.worksheet_from_name(&self.names[i])?;
+        for (j, col) in table.cols.iter().enumerate() {
+            let value = row.get(&col.name).unwrap_or(&Value::Null);
+            write_cell(worksheet, self.next_rows[i], j as u16, value, col)?;
+        }
+        self.next_rows[i] += 1;
+        Ok(())
+    }
+    /// Ends the workbook
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        Ok(self.workbook.save_to_buffer()?)
+    }
+}
+
+/// Workbook of tables whose rows are already read
+pub fn write_xlsx(tables: &[(TableMeta, Vec<RowJson>)]) -> Result<Vec<u8>> {
+    let metas: Vec<TableMeta> = tables.iter().map(|(t, _)| t.clone()).collect();
+    let mut writer = XlsxWriter::new(&metas)?;
+    for (table, rows) in tables {
+        for row in rows {
+            writer.write_row(table, row)?;
+        }
+    }
+    writer.finish()
+}
+
+/// Writes the value as the cell type that fits the column. Dates and times
+/// become spreadsheet dates, other values that are not numbers or booleans
+/// are written as text.
+fn write_cell(
+    worksheet: &mut rust_xlsxwriter::Worksheet,
+    row: u32,
+    col_index: u16,
+    value: &Value,
+    col: &ColMeta,
+) -> Result<()> {
+    use rust_xlsxwriter::Format;
+    use PostgresType as T;
+    match value {
+        Value::Null => {}
+        Value::Bool(b) => {
+            worksheet.write_boolean(row, col_index, *b)?;
+        }
+        Value::Number(n) => match (n.as_i64(), n.as_f64()) {
+            (Some(i), _) if i.unsigned_abs() > MAX_EXACT_INTEGER as u64 => {
+                worksheet.write_string(row, col_index, i.to_string())?;
+            }
+            (_, Some(f)) => {
+                worksheet.write_number(row, col_index, f)?;
+            }
+            _ => {
+                worksheet.write_string(row, col_index, n.to_string())?;
+            }
+        },
+        Value::String(s) => {
+            let date = |format: &str| Format::new().set_num_format(format);
+            let written = match &col.postgres_type {
+                T::Date => chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                    .map(|d| {
+                        worksheet.write_datetime_with_format(
+                            row,
+                            col_index,
+                            d,
+                            &date("yyyy-mm-dd"),
+                        )
+                    })
+                    .ok(),
+                T::Time => chrono::NaiveTime::parse_from_str(s, "%H:%M:%S%.f")
+                    .map(|t| {
+                        worksheet.write_datetime_with_format(
+                            row,
+                            col_index,
+                            t,
+                            &date("hh:mm:ss"),
+                        )
+                    })
+                    .ok(),
+                T::Timestamp => chrono::NaiveDateTime::parse_from_str(
+                    s,
+                    "%Y-%m-%dT%H:%M:%S%.f",
+                )
+                .map(|t| {
+                    worksheet.write_datetime_with_format(
+                        row,
+                        col_index,
+                        t,
+                        &date("yyyy-mm-dd hh:mm:ss"),
+                    )
+                })
+                .ok(),
+                // Spreadsheets have no time zones, timestamps are UTC
+                T::Timestamptz => chrono::DateTime::parse_from_rfc3339(s)
+                    .map(|t| {
+                        worksheet.write_datetime_with_format(
+                            row,
+                            col_index,
+                            t.naive_utc(),
+                            &date("yyyy-mm-dd hh:mm:ss"),
+                        )
+                    })
+                    .ok(),
+                _ => None,
+            };
+            match written {
+                Some(written) => {
+                    written?;
+                }
+                None => {
+                    worksheet.write_string(row, col_index, s)?;
+                }
+            }
+        }
+        Value::Array(_) | Value::Object(_) => {
+            worksheet.write_string(row, col_index, value.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+/// Sheets of a workbook in order
+pub fn read_workbook(data: &[u8]) -> Result<Vec<(String, Range<Data>)>> {
+    let mut workbook: calamine::Xlsx<_> =
+        calamine::Xlsx::new(std::io::Cursor::new(data))
+            .map_err(|e| Error::InvalidXlsx(e.to_string()))?;
+    let mut sheets = Vec::new();
+    for name in workbook.sheet_names() {
+        let range = workbook
+            .worksheet_range(&name)
+            .map_err(|e| Error::InvalidXlsx(e.to_string()))?;
+        sheets.push((name, range));
+    }
+    Ok(sheets)
+}
+
+/// Table of each sheet that is not skipped, in the order of the sheets
+pub fn sheet_tables(
+    sheets: &[(String, Range<Data>)],
+    options: &XlsxOptions,
+) -> Result<Vec<(String, String)>> {
+    let unknown: Vec<&String> = options
+        .mapping
+        .keys()
+        .filter(|name| !sheets.iter().any(|(sheet, _)| sheet == *name))
+        .collect();
+    if !unknown.is_empty() {
+        return Err(Error::InvalidXlsx(format!(
+            "mapped sheets {:?} are not in the workbook",
+            unknown
+        )));
+    }
+    let dictionary = sheets
+        .iter()
+        .find(|(sheet, _)| sheet == DICTIONARY_SHEET)
+        .map(|(_, range)| read_dictionary(range));
+    let mut tables = Vec::new();
+    for (sheet, _) in sheets {
+        let table = match (options.mapping.get(sheet), &dictionary) {
+            (Some(table), _) => table.clone(),
+            (None, _) if sheet == DICTIONARY_SHEET => None,
+            (None, Some(dictionary)) if dictionary.contains_key(sheet) => {
+                dictionary.get(sheet).cloned()
+            }
+            (None, _) => Some(sheet.clone()),
+        };
+        if let Some(table) = table {
+            tables.push((sheet.clone(), table));
+        }
+    }
+    Ok(tables)
+}
+
+/// Table of each sheet in a dictionary sheet
+fn read_dictionary(range: &Range<Data>) -> HashMap<String, String> {
+    let mut rows = range.rows();
+    let header: Vec<String> = match rows.next() {
+        Some(header) => header.iter().map(|c| c.to_string()).collect(),
+        None => return HashMap::new(),
+    };
+    let position = |name: &str| header.iter().position(|h| h == name);
+    let (sheet, table) = match (position("sheet"), position("table")) {
+        (Some(sheet), Some(table)) => (sheet, table),
+        _ => return HashMap::new(),
+    };
+    rows.filter_map(|row| match (row.get(sheet), row.get(table)) {
+        (Some(Data::String(sheet)), Some(Data::String(table))) => {
+            Some((sheet.clone(), table.clone()))
+        }
+        _ => None,
+    })
+    .collect()
+}
+
+/// Reads the rows of a sheet into rows for the table. The first row names
+/// the columns. Empty rows are skipped.
+/// Cells are converted to the json the insert expects for the columns'
+/// types, numbers and booleans in text columns are read as they are shown.
+pub fn read_sheet(table: &TableMeta, range: &Range<Data>) -> Result<SheetRows> {
+    // Sheet row number of the first row of the range
+    let first = range.start().map(|(row, _)| row as usize + 1).unwrap_or(1);
+    let mut rows = range.rows();
+    let names: Vec<String> = match rows.next() {
+        Some(header) => header.iter().map(|c| c.to_string()).collect(),
+        None => return Ok(SheetRows::default()),
+    };
+    // Columns without a name are left out
+    let present: Vec<&str> = names
+        .iter()
+        .map(|name| name.as_str())
+        .filter(|name| !name.is_empty())
+        .collect();
+    table.verify_cols_present(&present)?;
+    let mut read = SheetRows::default();
+    for (i, cells) in rows.enumerate() {
+        let record = first + i + 1;
+        if cells.iter().all(|cell| *cell == Data::Empty) {
+            continue;
+        }
+        let mut row = RowJson::new();
+        let mut failed = false;
+        for (cell, name) in cells.iter().zip(&names) {
+            let col = match table.get_col(name) {
+                Some(col) => col,
+                None => continue,
+            };
+            match read_cell(cell, col) {
+                Ok(value) => {
+                    row.insert(col.name.clone(), value);
+                }
+                Err(reason) => {
+                    failed = true;
+                    read.errors.push(RowError {
+                        row: record,
+                        column: Some(col.name.clone()),
+                        value: Some(Value::String(cell.to_string())),
+                        reason,
+                    });
+                }
+            }
+        }
+        if !failed {
+            read.rows.push(row);
+            read.records.push(record);
+        }
+    }
+    Ok(read)
+}
+
+/// Value of a cell for the column's type, returns the reason on failure
+fn read_cell(cell: &Data, col: &ColMeta) -> std::result::Result<Value, String> {
+    use PostgresType as T;
+    let value = match cell {
+        Data::Empty => return Ok(Value::Null),
+        Data::Error(e) => return Err(format!("cell has error {}", e)),
+        Data::String(s) | Data::DateTimeIso(s) | Data::DurationIso(s) => {
+            match &col.postgres_type {
+                T::Json | T::Jsonb | T::Array(_) => serde_json::from_str(s)
+                    .map_err(|e| format!("\"{}\" is not json: {}", s, e))?,
+                _ => Value::String(s.clone()),
+            }
+        }
+        Data::Bool(b) => Value::Bool(*b),
+        Data::Int(i) => Value::from(*i),
+        Data::Float(f) => {
+            if f.fract() == 0.0 && f.abs() <= MAX_EXACT_INTEGER as f64 {
+                Value::from(*f as i64)
+            } else {
+                serde_json::Number::from_f64(*f)
+                    .map(Value::Number)
+                    .ok_or_else(|| format!("{} is not a number", f))?
+            }
+        }
+        Data::DateTime(t) if t.is_duration() => {
+            let duration = t.as_duration().ok_or("not a valid duration")?;
+            format!("{} seconds", duration.num_milliseconds() as f64 / 1000.0)
+                .into()
+        }
+        Data::DateTime(t) => {
+            let t = t.as_datetime().ok_or("not a valid date")?;
+            match &col.postgres_type {
+                T::Date => t.format("%Y-%m-%d").to_string(),
+                T::Time => t.format("%H:%M:%S%.f").to_string(),
+                T::Timestamptz => {
+                    use chrono::TimeZone;
+                    chrono::Utc.from_utc_datetime(&t).to_rfc3339()
+                }
+                _ => t.format("%Y-%m-%dT%H:%M:%S%.f").to_string(),
+            }
+            .into()
+        }
+    };
+    let text = matches!(
+        col.postgres_type,
+        T::Text | T::Varchar(_) | T::Uuid | T::Interval | T::Bytea
+    );
+    if text && !value.is_string() {
+        return Ok(Value::String(value.to_string()));
+    }
+    Ok(value)
+}
+
+/// Tables in an order where the tables referenced by foreign keys come
+/// before the tables that reference them, as far as cycles allow
+pub fn insert_order(tables: &[TableMeta]) -> Vec<usize> {
+    let mut order: Vec<usize> = Vec::with_capacity(tables.len());
+    while order.len() < tables.len() {
+        let remaining: Vec<usize> =
+            (0..tables.len()).filter(|i| !order.contains(i)).collect();
+        let ready = remaining.iter().copied().find(|&i| {
+            tables[i].cols.iter().all(|col| match &col.foreign_key {
+                Some(fk) => !remaining
+                    .iter()
+                    .any(|&j| j != i && tables[j].name == fk.table),
+                None => true,
+            })
+        });
+        order.push(ready.unwrap_or(remaining[0]));
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    #[test]
+    fn xlsx_round_trip() {
+        let _ = pretty_env_logger::try_init();
+        let visit = crate::tests::get_visit_table();
+        let subject = TableMeta::new(
+            "subject",
+            vec![ColMeta::new()
+                .name("id")
+                .postgres_type(PostgresType::Text)
+                .primary_key(true)],
+        );
+        let rows = crate::tests::get_visit_data();
+
+        log::info!("sheet names");
+        let long = TableMeta::new(&"a".repeat(40), vec![]);
+        let odd = TableMeta::new("a/b", vec![]);
+        let dictionary = TableMeta::new("Dictionary", vec![]);
+        assert_eq!(
+            sheet_names(&[long.clone(), long, odd, dictionary]),
+            vec![
+                "a".repeat(31),
+                format!("{}~2", "a".repeat(29)),
+                "a_b".to_string(),
+                "Dictionary~2".to_string()
+            ]
+        );
+
+        log::info!("foreign keys first");
+        assert_eq!(insert_order(&[visit.clone(), subject.clone()]), vec![1, 0]);
+
+        log::info!("write and read back");
+        let data = write_xlsx(&[
+            (visit.clone(), rows.clone()),
+            (subject.clone(), Vec::new()),
+        ])
+        .unwrap();
+        let sheets = read_workbook(&data).unwrap();
+        assert_eq!(
+            sheets
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>(),
+            vec![DICTIONARY_SHEET, "visit", "subject"]
+        );
+        let tables =
+            sheet_tables(&sheets, &XlsxOptions::default().map("subject", None))
+                .unwrap();
+        assert_eq!(tables, vec![("visit".to_string(), "visit".to_string())]);
+        let read = read_sheet(&visit, &sheets[1].1).unwrap();
+        // Times come back in UTC
+        let mut expected = rows.clone();
+        expected[0]["seen"] = json!("2021-03-01T06:30:00+00:00");
+        assert_eq!(read.rows, expected);
+        assert_eq!(read.records, vec![2, 3]);
+        assert!(read.errors.is_empty());
+        assert!(read_sheet(&subject, &sheets[2].1).unwrap().rows.is_empty());
+
+        log::info!("invalid");
+        assert!(matches!(
+            read_sheet(&subject, &sheets[1].1).unwrap_err(),
+            Error::NoSuchColumns(_)
+        ));
+        assert!(matches!(
+            sheet_tables(&sheets, &XlsxOptions::default().map("other", None))
+                .unwrap_err(),
+            Error::InvalidXlsx(_)
+        ));
+        assert!(matches!(
+            read_workbook(b"not a workbook").unwrap_err(),
+            Error::InvalidXlsx(_)
+        ));
+        let number = ColMeta::new()
+            .name("id")
+            .postgres_type(PostgresType::Integer);
+        assert_eq!(read_cell(&Data::Float(3.0), &number), Ok(json!(3)));
+        assert_eq!(read_cell(&Data::Float(3.5), &number), Ok(json!(3.5)));
+        assert!(
+            read_cell(&Data::String("{".to_string()), &visit.cols[7]).is_err()
+        );
+        assert_eq!(
+            read_cell(&Data::Int(1003), &visit.cols[1]),
+            Ok(json!("1003"))
+        );
+    }
+}
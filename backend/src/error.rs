@@ -1,3 +1,5 @@
+use crate::db::user::table::{TableConstraint, TableDependent};
+
 /// API Errors
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -10,6 +12,30 @@ pub enum Error {
     #[error("table \"{0}\" already exists")]
     TableAlreadyExists(String),
 
+    /// Create an index with a name that is taken
+    #[error("index \"{0}\" already exists")]
+    IndexAlreadyExists(String),
+
+    /// Address an index that is not there
+    #[error("no such index: \"{0}\"")]
+    NoSuchIndex(String),
+
+    /// Constraint found in the catalog that the metadata can't represent
+    #[error("constraint \"{1}\" of table \"{0}\" is not supported: {2}")]
+    UnsupportedConstraint(String, String, String),
+
+    /// Resolve conflicts on a unique constraint that is not there
+    #[error("table \"{0}\" has no primary key or unique constraint \"{1}\"")]
+    NoSuchUniqueConstraint(String, String),
+
+    /// Drop a table-level constraint that is not there
+    #[error("table \"{0}\" has no constraint {1:?}")]
+    NoSuchConstraint(String, TableConstraint),
+
+    /// Remove a table that other objects depend on without cascading
+    #[error("table \"{0}\" has dependents: {1:?}")]
+    TableHasDependents(String, Vec<TableDependent>),
+
     /// Occurs when a row cannot be parsed as map
     #[error("failed to parse as map: {0}")]
     RowParse(serde_json::Value),
@@ -18,6 +44,66 @@ pub enum Error {
     #[error("data to be inserted is empty")]
     InsertEmptyData,
 
+    /// Add a column that is already present
+    #[error("column \"{0}\" already exists")]
+    ColumnAlreadyExists(String),
+
+    /// Schema plan would change a table's primary key
+    #[error("changing the primary key of table \"{0}\" is not supported")]
+    PrimaryKeyChange(String),
+
+    /// Schema plan would change a column's generation expression
+    #[error("changing the generation expression of column \"{1}\" of table \"{0}\" is not supported")]
+    GeneratedColumnChange(String, String),
+
+    /// Schema plan has destructive changes that were not allowed
+    #[error("plan has destructive changes: {0:?}")]
+    DestructivePlan(Vec<String>),
+
+    /// Column, constraint or index definition with SQL that is not allowed
+    /// or that the database rejects
+    #[error("invalid definition: {0}")]
+    InvalidDefinition(String),
+
+    /// Filter that can't be compiled for its table
+    #[error("invalid filter: {0}")]
+    InvalidFilter(String),
+
+    /// CSV file that can't be read into its table
+    #[error("invalid csv: {0}")]
+    InvalidCsv(String),
+
+    /// Workbook that can't be read into its tables
+    #[error("invalid xlsx: {0}")]
+    InvalidXlsx(String),
+
+    /// Rows of workbook sheets that could not be inserted, nothing was
+    /// inserted
+    #[error("failed to insert sheet rows: {0:?}")]
+    InvalidSheetRows(Vec<SheetRowError>),
+
+    /// ODM file that can't be read into tables
+    #[error("invalid odm: {0}")]
+    InvalidOdm(String),
+
+    /// Data of ODM item groups that could not be inserted, nothing was
+    /// inserted
+    #[error("failed to insert item group data: {0:?}")]
+    InvalidItemGroupRows(Vec<ItemGroupRowError>),
+
+    /// Page cursor that is malformed or does not fit the order
+    #[error("invalid cursor: {0}")]
+    InvalidCursor(String),
+
+    /// Remove rows with a selection that matches all of them without
+    /// confirming
+    #[error("selection matches all rows of table \"{0}\"")]
+    SelectionMatchesAll(String),
+
+    /// Address rows by primary key in a table without one
+    #[error("table \"{0}\" has no primary key")]
+    NoPrimaryKey(String),
+
     /// Occurs when addressing non-existent columns
     #[error("want to address columns {0:?} but they do not exist")]
     NoSuchColumns(Vec<String>),
@@ -26,6 +112,14 @@ pub enum Error {
     #[error("unimplemented value for insert format: {0}")]
     InsertFormatUnimplemented(serde_json::Value),
 
+    /// Rows of a batch that could not be inserted, nothing was inserted
+    #[error("failed to insert rows: {0:?}")]
+    InvalidRows(Vec<RowError>),
+
+    /// Column type that cannot be represented
+    #[error("unsupported postgres type: {0}")]
+    UnsupportedPostgresType(String),
+
     /// Unexpected access string
     #[error("unexpected access string: {0}")]
     UnexpectedAccessString(String),
@@ -71,6 +165,22 @@ pub enum Error {
     #[error(transparent)]
     IO(#[from] std::io::Error),
 
+    /// Represents all cases of `csv::Error`
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+
+    /// Represents all cases of `rust_xlsxwriter::XlsxError`
+    #[error(transparent)]
+    Xlsx(#[from] rust_xlsxwriter::XlsxError),
+
+    /// Represents all cases of `arrow::error::ArrowError`
+    #[error(transparent)]
+    Arrow(#[from] arrow::error::ArrowError),
+
+    /// Represents all cases of `parquet::errors::ParquetError`
+    #[error(transparent)]
+    Parquet(#[from] parquet::errors::ParquetError),
+
     /// Represents all cases of `serde_json::Error`
     #[error(transparent)]
     SerdeJson(#[from] serde_json::Error),
@@ -120,3 +230,53 @@ pub enum Unauthorized {
     #[error("got auth type: {0}; while expected 'Bearer'")]
     WrongAuthType(String),
 }
+
+/// Row that could not be inserted
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RowError {
+    /// Index of the row in the submitted data
+    pub row: usize,
+    /// Column at fault if known
+    pub column: Option<String>,
+    /// Value as submitted if it could not be converted
+    pub value: Option<serde_json::Value>,
+    /// Why the row was rejected
+    pub reason: String,
+}
+
+/// Row of a workbook sheet that could not be inserted.
+/// The row is numbered in the sheet, starting at 1 with the header.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SheetRowError {
+    pub sheet: String,
+    #[serde(flatten)]
+    pub error: RowError,
+}
+
+impl SheetRowError {
+    pub fn new(sheet: &str, error: RowError) -> Self {
+        Self {
+            sheet: sheet.to_string(),
+            error,
+        }
+    }
+}
+
+/// Data of an ODM item group that could not be inserted.
+/// The row is numbered among the item group's data, starting at 1.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ItemGroupRowError {
+    /// OID of the item group
+    pub item_group: String,
+    #[serde(flatten)]
+    pub error: RowError,
+}
+
+impl ItemGroupRowError {
+    pub fn new(item_group: &str, error: RowError) -> Self {
+        Self {
+            item_group: item_group.to_string(),
+            error,
+        }
+    }
+}
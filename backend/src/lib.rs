@@ -47,6 +47,7 @@ pub struct Opt {
 mod tests {
     use super::*;
     use crate::db::user::table::*;
+    use crate::db::user::types::PostgresType;
     use crate::db::DB;
     use sqlx::ConnectOptions;
 
@@ -133,54 +134,201 @@ mod tests {
 
     // Test primary table
     pub fn get_test_primary_table() -> TableMeta {
-        let mut cols = ColSpec::new();
-        cols.push(
+        let cols = vec![
             ColMeta::new()
                 .name("id")
-                .postgres_type("INTEGER")
+                .postgres_type(PostgresType::Integer)
                 .primary_key(true),
-        );
-        cols.push(
             ColMeta::new()
                 .name("email")
-                .postgres_type("TEXT")
+                .postgres_type(PostgresType::Text)
                 .not_null(true)
                 .unique(true),
-        );
+        ];
         TableMeta::new("primary", cols)
     }
 
     // Test secondary table
     pub fn get_test_secondary_table() -> TableMeta {
-        let mut cols = ColSpec::new();
-        cols.push(
+        let cols = vec![
             ColMeta::new()
                 .name("id")
-                .postgres_type("INTEGER")
+                .postgres_type(PostgresType::Integer)
                 .primary_key(true)
                 .foreign_key(ForeignKey::new("primary", "id")),
-        );
-        cols.push(
             ColMeta::new()
                 .name("timepoint")
-                .postgres_type("INTEGER")
+                .postgres_type(PostgresType::Integer)
                 .primary_key(true),
-        );
-        cols.push(ColMeta::new().name("sick").postgres_type("BOOLEAN"));
-        cols.push(ColMeta::new().name("symptoms").postgres_type("JSONB"));
-        cols.push(ColMeta::new().name("locations").postgres_type("JSONB"));
+            ColMeta::new()
+                .name("sick")
+                .postgres_type(PostgresType::Boolean),
+            ColMeta::new()
+                .name("symptoms")
+                .postgres_type(PostgresType::Jsonb),
+            ColMeta::new()
+                .name("locations")
+                .postgres_type(PostgresType::Jsonb),
+        ];
         TableMeta::new("secondary", cols)
     }
 
     // Table with a date column
     pub fn get_date_table() -> TableMeta {
-        let mut cols = ColSpec::new();
-        cols.push(
+        let cols = vec![ColMeta::new()
+            .name("date")
+            .postgres_type(PostgresType::Timestamptz)];
+        TableMeta::new("timestamptz-table", cols)
+    }
+
+    // Table with a column of every supported type
+    pub fn get_types_table() -> TableMeta {
+        let types = vec![
+            "smallint",
+            "INTEGER",
+            "int8",
+            "real",
+            "double precision",
+            "numeric",
+            "NUMERIC(10, 2)",
+            "text",
+            "varchar",
+            "VARCHAR(20)",
+            "bool",
+            "date",
+            "time",
+            "timestamp",
+            "timestamptz",
+            "uuid",
+            "json",
+            "jsonb",
+            "bytea",
+            "interval",
+            "integer[]",
+            "text[][]",
+        ];
+        let cols = types
+            .iter()
+            .enumerate()
+            .map(|(i, t)| {
+                ColMeta::new()
+                    .name(format!("col{}", i).as_str())
+                    .postgres_type(t.parse().unwrap())
+            })
+            .collect();
+        TableMeta::new("types", cols)
+    }
+
+    /// One row for the table with every supported type, in column order
+    pub fn get_types_data() -> Vec<RowJson> {
+        let values = vec![
+            "1",
+            "2",
+            "9007199254740993",
+            "1.5",
+            "2.5",
+            "\"12345678901234567890.123456789\"",
+            "\"12.34\"",
+            "\"text\"",
+            "\"varchar\"",
+            "\"varchar20\"",
+            "true",
+            "\"2020-01-01\"",
+            "\"13:45:00\"",
+            "\"2020-01-01T13:45:00\"",
+            "\"2020-01-01T13:45:00+00:00\"",
+            "\"a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11\"",
+            r#"{"a": 1}"#,
+            r#"{"b": [1, 2]}"#,
+            "\"\\\\x00ff\"",
+            "\"1 day\"",
+            "[1, null, 3]",
+            r#"["a", "b\"c"]"#,
+        ];
+        let mut row = RowJson::new();
+        for (i, v) in values.iter().enumerate() {
+            row.insert(format!("col{}", i), serde_json::from_str(v).unwrap());
+        }
+        vec![row]
+    }
+
+    // Visit table with a column of each kind the exporters handle
+    pub fn get_visit_table() -> TableMeta {
+        let cols = vec![
+            ColMeta::new()
+                .name("id")
+                .postgres_type(PostgresType::Integer)
+                .primary_key(true),
+            ColMeta::new()
+                .name("subject")
+                .postgres_type(PostgresType::Text)
+                .foreign_key(ForeignKey::new("subject", "id")),
             ColMeta::new()
                 .name("date")
-                .postgres_type("timestamp with time zone"),
-        );
-        TableMeta::new("timestamptz-table", cols)
+                .postgres_type(PostgresType::Date),
+            ColMeta::new()
+                .name("seen")
+                .postgres_type(PostgresType::Timestamptz),
+            ColMeta::new()
+                .name("sick")
+                .postgres_type(PostgresType::Boolean)
+                .value_labels(vec![
+                    ValueLabel::new(serde_json::json!(false), "no"),
+                    ValueLabel::new(serde_json::json!(true), "yes"),
+                ]),
+            ColMeta::new()
+                .name("weight")
+                .postgres_type(PostgresType::Numeric(Some((5, 1))))
+                .label("Body weight (kg)"),
+            ColMeta::new()
+                .name("sex")
+                .postgres_type(PostgresType::SmallInt)
+                .not_null(true)
+                .value_labels(vec![
+                    ValueLabel::new(serde_json::json!(1), "male"),
+                    ValueLabel::new(serde_json::json!(2), "female"),
+                ]),
+            ColMeta::new()
+                .name("symptoms")
+                .postgres_type(PostgresType::Jsonb),
+            ColMeta::new()
+                .name("note")
+                .postgres_type(PostgresType::Text),
+        ];
+        TableMeta::new("visit", cols)
+    }
+
+    /// Visit table data, one row filled in and one mostly null
+    pub fn get_visit_data() -> Vec<RowJson> {
+        rows(serde_json::json!([
+            {
+                "id": 1,
+                "subject": "1001",
+                "date": "2021-03-01",
+                "seen": "2021-03-01T08:30:00+02:00",
+                "sick": true,
+                "weight": 72.5,
+                "sex": 1,
+                "symptoms": {"cough": true, "fever": 38.5},
+                "note": "said \"fine\", went home"
+            },
+            {
+                "id": 2,
+                "subject": "1002",
+                "date": null,
+                "seen": null,
+                "sick": null,
+                "weight": null,
+                "sex": 2,
+                "symptoms": [1, 2],
+                "note": null
+            }
+        ]))
+    }
+
+    /// Rows from a json array of objects
+    pub fn rows(value: serde_json::Value) -> Vec<RowJson> {
+        serde_json::from_value(value).unwrap()
     }
 
     /// Primary table data
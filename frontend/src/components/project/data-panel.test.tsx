@@ -141,7 +141,10 @@ test("new row form - no data", async () => {
   // Should open automatically when page loads and there is no data
   mockedAxios.get.mockImplementation(
     constructGet({
-      getTableData: async () => ({ status: httpStatusCodes.OK, data: [] }),
+      getTableData: async () => ({
+        status: httpStatusCodes.OK,
+        data: { rows: [], total: 0, next_cursor: null },
+      }),
     })
   )
   // Render
@@ -330,7 +333,7 @@ test("meta/data mismatch", async () => {
       getTableMeta: async () => ({ status: httpStatusCodes.OK, data: table1 }),
       getTableData: async () => ({
         status: httpStatusCodes.OK,
-        data: table2data,
+        data: { rows: table2data, total: table2data.length, next_cursor: null },
       }),
     })
   )
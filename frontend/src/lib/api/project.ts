@@ -121,14 +121,14 @@ export async function removeTable(
     {
       validateStatus: (s) =>
         [
-          httpStatusCodes.NO_CONTENT,
+          httpStatusCodes.OK,
           httpStatusCodes.NOT_FOUND,
           httpStatusCodes.UNAUTHORIZED,
         ].includes(s),
       headers: { Authorization: `Bearer ${tok}` },
     }
   )
-  if (res.status !== httpStatusCodes.NO_CONTENT) {
+  if (res.status !== httpStatusCodes.OK) {
     throw Error(res.data)
   }
 }
@@ -203,14 +203,14 @@ export async function insertData(
   projectName: string,
   tableName: string,
   tableData: TableData
-): Promise<void> {
+): Promise<TableData> {
   let res = await axios.put(
     `${API_ROOT}/project/${projectName}/insert/${tableName}`,
     tableData,
     {
       validateStatus: (s) =>
         [
-          httpStatusCodes.NO_CONTENT,
+          httpStatusCodes.OK,
           httpStatusCodes.UNAUTHORIZED,
           httpStatusCodes.NOT_FOUND,
           httpStatusCodes.CONFLICT,
@@ -218,9 +218,17 @@ export async function insertData(
       headers: { Authorization: `Bearer ${tok}` },
     }
   )
-  if (res.status !== httpStatusCodes.NO_CONTENT) {
+  if (res.status !== httpStatusCodes.OK) {
     throw Error(res.data)
   }
+  let report = await decode(
+    t.type({
+      keys: t.array(t.UnknownRecord),
+      errors: t.array(t.UnknownRecord),
+    }),
+    res.data
+  )
+  return report.keys
 }
 
 export async function removeAllTableData(
@@ -265,5 +273,13 @@ export async function getTableData(
   if (res.status !== httpStatusCodes.OK) {
     throw Error(res.data)
   }
-  return await decode(t.array(t.UnknownRecord), res.data)
+  let page = await decode(
+    t.type({
+      rows: t.array(t.UnknownRecord),
+      total: t.number,
+      next_cursor: t.union([t.string, t.null]),
+    }),
+    res.data
+  )
+  return page.rows
 }
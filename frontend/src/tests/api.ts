@@ -28,10 +28,13 @@ export const defaultGet: RequestFns = {
     status: httpStatusCodes.OK,
     data: allTables.map((t) => t.meta.name),
   }),
-  getTableData: async (tableName: string) => ({
-    status: httpStatusCodes.OK,
-    data: findTableEntry(tableName).data,
-  }),
+  getTableData: async (tableName: string) => {
+    const rows = findTableEntry(tableName).data
+    return {
+      status: httpStatusCodes.OK,
+      data: { rows: rows, total: rows.length, next_cursor: null },
+    }
+  },
   getTableMeta: async (tableName: string) => ({
     status: httpStatusCodes.OK,
     data: findTableEntry(tableName).meta,
@@ -86,7 +89,7 @@ export const defaultDelete: RequestFns = {
   removeUser: async () => ({ status: httpStatusCodes.NO_CONTENT }),
   removeToken: async () => ({ status: httpStatusCodes.NO_CONTENT }),
   deleteProject: async () => ({ status: httpStatusCodes.NO_CONTENT }),
-  removeTable: async () => ({ status: httpStatusCodes.NO_CONTENT }),
+  removeTable: async () => ({ status: httpStatusCodes.OK, data: [] }),
   removeAllTableData: async () => ({ status: httpStatusCodes.NO_CONTENT }),
 }
 
@@ -117,7 +120,10 @@ export const defaultPut: RequestFns = {
   createUser: async () => ({ status: httpStatusCodes.NO_CONTENT }),
   createProject: async () => ({ status: httpStatusCodes.NO_CONTENT }),
   createTable: async () => ({ status: httpStatusCodes.NO_CONTENT }),
-  insertData: async () => ({ status: httpStatusCodes.NO_CONTENT }),
+  insertData: async () => ({
+    status: httpStatusCodes.OK,
+    data: { keys: [{}], errors: [] },
+  }),
 }
 
 export function constructPut(fns?: RequestFns) {